@@ -0,0 +1,694 @@
+//! Copying trees from any cursor into any representation.
+//!
+//! [copy_node] drives a [NodesCursor] over a subtree and reports its content to a [TreeSink],
+//! so every representation which can be read with a cursor can be copied into every representation which has a sink:
+//! - [BasicTreeBuilder] builds a [BasicTree].
+//! - [ChunkBuilder] builds a [Field] for a [Forest](crate::forest::mixed::Forest),
+//!   storing runs of nodes with the same shape as [UniformChunk]s.
+//! - [StreamWriter] serializes the content as it is copied. [read_stream] reads it back into any sink.
+
+use std::{
+    collections::{btree_map, HashMap},
+    rc::Rc,
+};
+
+use crate::{
+    forest::{
+        example_node::{BasicNode, BasicTree},
+        mixed::{Chunk, Field, MixedNode},
+        serialize::{DecodeError, Reader, Writer},
+        uniform_chunk::{ChunkSchema, OffsetSchema, UniformChunk},
+    },
+    visit::{walk_cursor, Flow, Visitor, WalkOptions},
+    CursorError, CursorFailure, CursorResult, EitherCursor, FieldKey, FieldsCursor, NodesCursor,
+    TreeType,
+};
+
+/// Receives the content of a tree, in depth first pre-order.
+///
+/// Calls are nested: each node's fields are reported between its `start_node` and `end_node`,
+/// and each field's nodes between its `start_field` and `end_field`.
+/// Empty fields are not reported.
+pub trait TreeSink {
+    fn start_node(&mut self, def: TreeType, payload: Option<&[u8]>);
+    fn end_node(&mut self);
+    fn start_field(&mut self, key: FieldKey);
+    fn end_field(&mut self);
+}
+
+/// Copies the subtree at the cursor's current node into `sink`, and returns the cursor at the same node.
+///
+/// Fails with [CursorError::Pending] if any of the subtree is pending.
+/// `sink` is then left part way through the subtree, so should be discarded.
+pub fn copy_node<C: NodesCursor>(cursor: C, sink: &mut impl TreeSink) -> CursorResult<C, C> {
+    let mut visitor = CopyVisitor { sink, field: None };
+    match walk_cursor(cursor, &WalkOptions::default(), &mut visitor) {
+        (cursor, Flow::Stop) => Err(CursorFailure::new(cursor, CursorError::Pending)),
+        (cursor, _) => Ok(cursor),
+    }
+}
+
+/// Reports the nodes and fields a traversal visits to a [TreeSink].
+struct CopyVisitor<'a, S> {
+    sink: &'a mut S,
+    /// Field which has been entered but not reported yet, since empty fields are not reported.
+    field: Option<FieldKey>,
+}
+
+impl<C: NodesCursor, S: TreeSink> Visitor<C> for CopyVisitor<'_, S> {
+    fn enter_node(&mut self, node: &C, _depth: usize) -> Flow {
+        if node.pending() {
+            return Flow::Stop;
+        }
+        if let Some(key) = self.field.take() {
+            self.sink.start_field(key);
+        }
+        self.sink
+            .start_node(node.node_type(), node.payload().as_deref());
+        Flow::Continue
+    }
+
+    fn exit_node(&mut self, _node: &C, _depth: usize) -> Flow {
+        self.sink.end_node();
+        Flow::Continue
+    }
+
+    fn enter_field(&mut self, key: &FieldKey, _depth: usize) -> Flow {
+        self.field = Some(key.clone());
+        Flow::Continue
+    }
+
+    fn exit_field(&mut self, _key: &FieldKey, _depth: usize) -> Flow {
+        if self.field.take().is_none() {
+            self.sink.end_field();
+        }
+        Flow::Continue
+    }
+}
+
+/// Copies `count` nodes starting at the cursor's current node into `sink`,
+/// and returns the cursor at the last node copied.
+///
+/// Fails, with the cursor back at the first node, with [CursorError::Pending] if any of them is pending (see [copy_node]),
+/// or with [CursorError::OutOfRange] if there are fewer than `count` nodes from the current one in its field.
+/// Either way, the nodes before the failure have been copied into `sink`.
+pub fn copy_nodes<C: NodesCursor>(
+    mut cursor: C,
+    count: u32,
+    sink: &mut impl TreeSink,
+) -> CursorResult<C, C> {
+    let first = cursor.field_index();
+    for i in 0..count {
+        if i > 0 {
+            cursor = match cursor.next_node() {
+                Ok(EitherCursor::Nodes(n)) => n,
+                Ok(EitherCursor::Fields(f)) => {
+                    let cursor = match f.enter_node(first) {
+                        Ok(n) => n,
+                        Err(_) => unreachable!("the first node copied is in this field"),
+                    };
+                    return Err(CursorFailure::new(cursor, CursorError::OutOfRange));
+                }
+                // Only the root has no field, and it is alone in its (virtual) one.
+                Err(failure) => {
+                    return Err(CursorFailure::new(failure.cursor, CursorError::OutOfRange))
+                }
+            };
+        }
+        cursor = copy_node(cursor, sink).map_err(|failure| {
+            let cursor = match failure.cursor.seek_nodes(-(i as i32)) {
+                Ok(EitherCursor::Nodes(n)) => n,
+                _ => unreachable!("the first node copied is in the same field"),
+            };
+            CursorFailure::new(cursor, failure.error)
+        })?;
+    }
+    Ok(cursor)
+}
+
+/// Builds [BasicNode]s.
+#[derive(Default)]
+pub struct BasicTreeBuilder {
+    /// Completed top level nodes.
+    roots: Vec<BasicNode>,
+    /// Nodes being built, and the key of the field of each which is being built.
+    stack: Vec<(BasicNode, Option<FieldKey>)>,
+}
+
+impl BasicTreeBuilder {
+    pub fn finish(self) -> BasicTree {
+        assert!(self.stack.is_empty(), "unfinished node");
+        BasicTree(self.roots)
+    }
+}
+
+impl TreeSink for BasicTreeBuilder {
+    fn start_node(&mut self, def: TreeType, payload: Option<&[u8]>) {
+        let node = BasicNode {
+            def,
+            payload: payload.map(|p| p.to_vec()),
+            fields: Default::default(),
+        };
+        self.stack.push((node, None));
+    }
+
+    fn end_node(&mut self) {
+        let (node, _) = self.stack.pop().expect("no node to end");
+        match self.stack.last_mut() {
+            Some((parent, Some(key))) => parent.fields.entry(key.clone()).or_default().push(node),
+            Some((_, None)) => panic!("node must be in a field"),
+            None => self.roots.push(node),
+        }
+    }
+
+    fn start_field(&mut self, key: FieldKey) {
+        self.stack.last_mut().expect("field must be in a node").1 = Some(key);
+    }
+
+    fn end_field(&mut self) {
+        self.stack.last_mut().expect("no field to end").1 = None;
+    }
+}
+
+/// Builds a [Field] of chunks.
+///
+/// Consecutive nodes with the same shape (type, payload size, and the same for each field, which must contain nodes of a single shape)
+/// are stored together in a [UniformChunk]. Other nodes, and those whose subtrees are deeper than [MAX_SHAPE_DEPTH]
+/// or have more data than a uniform chunk's u32 offsets can address, are stored as [MixedNode]s, whose fields are chunked the same way.
+#[derive(Default)]
+pub struct ChunkBuilder {
+    nodes: BasicTreeBuilder,
+}
+
+impl ChunkBuilder {
+    pub fn finish(self) -> Field {
+        chunk_field(&self.nodes.finish().0)
+    }
+}
+
+impl TreeSink for ChunkBuilder {
+    fn start_node(&mut self, def: TreeType, payload: Option<&[u8]>) {
+        self.nodes.start_node(def, payload)
+    }
+
+    fn end_node(&mut self) {
+        self.nodes.end_node()
+    }
+
+    fn start_field(&mut self, key: FieldKey) {
+        self.nodes.start_field(key)
+    }
+
+    fn end_field(&mut self) {
+        self.nodes.end_field()
+    }
+}
+
+/// Deepest [Shape] stored as a single [UniformChunk].
+/// Schemas contain the schemas of their fields, so deeper subtrees are split into [MixedNode]s instead,
+/// which also keeps the (recursive) [Shape] and [ChunkSchema] code shallow for deep trees.
+const MAX_SHAPE_DEPTH: u32 = 8;
+
+/// Shape of a subtree which can be stored in a [UniformChunk].
+#[derive(PartialEq, Eq)]
+struct Shape {
+    def: TreeType,
+    payload: Option<u16>,
+    /// Non-empty fields in key order, with their length and the shape of their nodes.
+    fields: Vec<(FieldKey, u32, Rc<Shape>)>,
+    /// Levels of nodes in the subtree.
+    depth: u32,
+    bytes_per_node: u32,
+}
+
+/// The shape of each node in some trees, keyed by address.
+type Shapes = HashMap<*const BasicNode, Option<Rc<Shape>>>;
+
+impl Shape {
+    /// None if the subtree does not have a uniform shape, or is too deep or too large.
+    /// `shapes` must contain the shapes of `node`'s children.
+    fn of(node: &BasicNode, shapes: &Shapes) -> Option<Rc<Shape>> {
+        let payload = match &node.payload {
+            Some(p) => Some(u16::try_from(p.len()).ok()?),
+            None => None,
+        };
+        let mut fields = vec![];
+        let mut depth = 1;
+        let mut bytes_per_node = payload.unwrap_or(0) as u32;
+        for (key, children) in node.fields.iter().filter(|(_, c)| !c.is_empty()) {
+            let shape = shapes[&(&children[0] as *const _)].clone()?;
+            for child in children[1..].iter() {
+                if shapes[&(child as *const _)].as_ref() != Some(&shape) {
+                    return None;
+                }
+            }
+            depth = depth.max(shape.depth + 1);
+            let length = u32::try_from(children.len()).ok()?;
+            bytes_per_node =
+                bytes_per_node.checked_add(length.checked_mul(shape.bytes_per_node)?)?;
+            fields.push((key.clone(), length, shape));
+        }
+        if depth > MAX_SHAPE_DEPTH {
+            return None;
+        }
+        Some(Rc::new(Shape {
+            def: node.def.clone(),
+            payload,
+            fields,
+            depth,
+            bytes_per_node,
+        }))
+    }
+
+    /// Shapes of `nodes` and all their descendants, each computed once.
+    fn of_all(nodes: &[BasicNode]) -> Shapes {
+        let mut shapes = Shapes::new();
+        // Nodes, with whether their children's shapes are known.
+        let mut stack: Vec<(&BasicNode, bool)> = nodes.iter().map(|n| (n, false)).collect();
+        while let Some((node, visited)) = stack.pop() {
+            if visited {
+                let shape = Shape::of(node, &shapes);
+                shapes.insert(node, shape);
+            } else {
+                stack.push((node, true));
+                stack.extend(node.fields.values().flatten().map(|c| (c, false)));
+            }
+        }
+        shapes
+    }
+
+    /// Schema for `count` nodes of this shape.
+    /// The payload is stored first, followed by each field's nodes in key order.
+    ///
+    /// None if the offsets do not fit in a u32.
+    fn schema(&self, count: u32) -> Option<ChunkSchema> {
+        let mut offset = self.payload.unwrap_or(0) as u32;
+        let mut fields: Vec<(FieldKey, OffsetSchema)> = vec![];
+        for (key, length, shape) in self.fields.iter() {
+            fields.push((
+                key.clone(),
+                OffsetSchema {
+                    byte_offset: offset,
+                    schema: shape.schema(*length)?,
+                },
+            ));
+            offset = offset.checked_add(length.checked_mul(shape.bytes_per_node)?)?;
+        }
+        Some(ChunkSchema::new(
+            self.def.clone(),
+            count,
+            self.bytes_per_node,
+            self.payload,
+            &fields,
+        ))
+    }
+}
+
+/// Appends the data for `node` in the layout of [Shape::schema].
+fn write_uniform(node: &BasicNode, out: &mut Vec<u8>) {
+    let mut stack = vec![node];
+    while let Some(node) = stack.pop() {
+        out.extend(node.payload.iter().flatten());
+        stack.extend(node.fields.values().flatten().rev());
+    }
+}
+
+/// A field being chunked by [chunk_field].
+struct FieldFrame<'a> {
+    /// The node being built which this field is in (None for the top level field),
+    /// its fields after this one, and this field's key.
+    parent: Option<(
+        MixedNode,
+        btree_map::Iter<'a, FieldKey, Vec<BasicNode>>,
+        FieldKey,
+    )>,
+    /// Nodes not chunked yet.
+    nodes: &'a [BasicNode],
+    chunks: Field,
+}
+
+/// Continues building `node` with the next non-empty one of `fields`,
+/// or if there are none adds it to the field on top of `stack`.
+fn continue_node<'a>(
+    stack: &mut Vec<FieldFrame<'a>>,
+    node: MixedNode,
+    mut fields: btree_map::Iter<'a, FieldKey, Vec<BasicNode>>,
+) {
+    match fields.find(|(_, c)| !c.is_empty()) {
+        Some((key, nodes)) => stack.push(FieldFrame {
+            parent: Some((node, fields, key.clone())),
+            nodes,
+            chunks: vec![],
+        }),
+        None => stack
+            .last_mut()
+            .expect("node must be in a field")
+            .chunks
+            .push(Chunk::Node(Rc::new(node))),
+    }
+}
+
+/// Uses an explicit stack, so chunking deep trees does not overflow the call stack.
+fn chunk_field(nodes: &[BasicNode]) -> Field {
+    let shapes = Shape::of_all(nodes);
+    let mut stack = vec![FieldFrame {
+        parent: None,
+        nodes,
+        chunks: vec![],
+    }];
+    loop {
+        let frame = stack.last_mut().unwrap();
+        let Some(node) = frame.nodes.first() else {
+            let done = stack.pop().unwrap();
+            match done.parent {
+                Some((mut node, fields, key)) => {
+                    node.fields_mut().insert(key, done.chunks);
+                    continue_node(&mut stack, node, fields);
+                }
+                None => return done.chunks,
+            }
+            continue;
+        };
+        let uniform = shapes[&(node as *const _)].as_ref().and_then(|shape| {
+            let end = frame
+                .nodes
+                .iter()
+                .position(|n| shapes[&(n as *const _)].as_ref() != Some(shape))
+                .unwrap_or(frame.nodes.len());
+            Some((end, shape.schema(u32::try_from(end).ok()?)?))
+        });
+        match uniform {
+            Some((end, schema)) => {
+                let mut data = vec![];
+                for node in frame.nodes[..end].iter() {
+                    write_uniform(node, &mut data);
+                }
+                frame.chunks.push(Chunk::Uniform(Rc::new(UniformChunk::new(
+                    Rc::new(schema),
+                    data,
+                ))));
+                frame.nodes = &frame.nodes[end..];
+            }
+            None => {
+                frame.nodes = &frame.nodes[1..];
+                let mixed = MixedNode::new(node.def.clone(), node.payload.clone());
+                continue_node(&mut stack, mixed, node.fields.iter());
+            }
+        }
+    }
+}
+
+const STREAM_VERSION: u8 = 0;
+
+const TAG_NODE: u8 = 0;
+const TAG_FIELD: u8 = 1;
+const TAG_END: u8 = 2;
+
+/// Serializes the calls made to it, so they can be replayed with [read_stream].
+///
+/// Unlike [crate::forest::serialize], which encodes chunks, this writes the content as it is received,
+/// without needing to know the length of fields in advance.
+pub struct StreamWriter {
+    writer: Writer,
+}
+
+impl Default for StreamWriter {
+    fn default() -> Self {
+        let mut writer = Writer::default();
+        writer.u8(STREAM_VERSION);
+        StreamWriter { writer }
+    }
+}
+
+impl StreamWriter {
+    pub fn finish(self) -> Vec<u8> {
+        self.writer.data
+    }
+}
+
+impl TreeSink for StreamWriter {
+    fn start_node(&mut self, def: TreeType, payload: Option<&[u8]>) {
+        self.writer.u8(TAG_NODE);
+        self.writer.string(&def.0);
+        self.writer.optional_bytes(payload);
+    }
+
+    fn end_node(&mut self) {
+        self.writer.u8(TAG_END);
+    }
+
+    fn start_field(&mut self, key: FieldKey) {
+        self.writer.u8(TAG_FIELD);
+        self.writer.string(&key.0);
+    }
+
+    fn end_field(&mut self) {
+        self.writer.u8(TAG_END);
+    }
+}
+
+/// Replays a stream written by [StreamWriter] into `sink`.
+///
+/// The stream is validated as it is read, so if it is invalid, `sink` may have received part of it.
+pub fn read_stream(data: &[u8], sink: &mut impl TreeSink) -> Result<(), DecodeError> {
+    let mut r = Reader::new(data);
+    if r.u8()? != STREAM_VERSION {
+        return Err(DecodeError("unsupported stream version"));
+    }
+    // For each open node or field, true if it is a field.
+    let mut open: Vec<bool> = vec![];
+    while !r.is_empty() {
+        match r.u8()? {
+            TAG_NODE => {
+                if open.last() == Some(&false) {
+                    return Err(DecodeError("node must be in a field"));
+                }
+                let def = TreeType(r.string()?);
+                sink.start_node(def, r.optional_bytes()?);
+                open.push(false);
+            }
+            TAG_FIELD => {
+                if open.last() != Some(&false) {
+                    return Err(DecodeError("field must be in a node"));
+                }
+                sink.start_field(FieldKey(r.string()?));
+                open.push(true);
+            }
+            TAG_END => match open.pop() {
+                Some(true) => sink.end_field(),
+                Some(false) => sink.end_node(),
+                None => return Err(DecodeError("unexpected end tag")),
+            },
+            _ => return Err(DecodeError("unknown stream tag")),
+        }
+    }
+    if !open.is_empty() {
+        return Err(DecodeError("unexpected end of data"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cursor::GenericNodesCursor,
+        dummy_cursor::DummyNodes,
+        forest::{
+            hash::{field_hashes, hash_node},
+            mixed::{Forest, MixedNodeRef},
+            path::Path,
+            store::{ChunkId, ChunkStore, MemoryStore},
+            test_stuff::{big_tree, describe_field, forest_with_root, key, leaf, walk_all_field},
+            tree::{Indexable, Tree},
+            uniform_chunk::UniformChunkNode,
+        },
+    };
+
+    fn copy_basic<C: NodesCursor>(cursor: C, count: u32) -> BasicTree {
+        let mut builder = BasicTreeBuilder::default();
+        copy_nodes(cursor, count, &mut builder).unwrap();
+        builder.finish()
+    }
+
+    #[test]
+    fn copy_dummy() {
+        let tree = copy_basic(DummyNodes::new(3, 2), 1);
+        assert_eq!(walk_all_field::<&BasicNode>(tree.view()), 1 + 3 + 9);
+        let leaf = &tree.0[0].fields[&FieldKey("child".into())][2];
+        assert_eq!(leaf.payload, Some(42f64.to_le_bytes().to_vec()));
+    }
+
+    #[test]
+    fn copy_into_uniform_chunks() {
+        let chunk = big_tree(5);
+        let cursor = GenericNodesCursor::<UniformChunkNode>::new(chunk.view());
+        let mut builder = ChunkBuilder::default();
+        copy_nodes(cursor, 5, &mut builder).unwrap();
+        let field = builder.finish();
+        assert_eq!(field.len(), 1);
+        assert!(matches!(&field[0], Chunk::Uniform(u) if u.get_count() == 5));
+        assert_eq!(field_hashes(&field).unwrap(), chunk.hashes());
+
+        // Nodes of different shapes are split into separate chunks.
+        let dummy = copy_basic(DummyNodes::new(2, 1), 1);
+        let mut nodes = copy_basic(GenericNodesCursor::<UniformChunkNode>::new(chunk.view()), 2);
+        nodes.0.insert(1, dummy.0.into_iter().next().unwrap());
+        let mut builder = ChunkBuilder::default();
+        copy_nodes(
+            GenericNodesCursor::<&BasicNode>::new(nodes.view()),
+            3,
+            &mut builder,
+        )
+        .unwrap();
+        let field = builder.finish();
+        assert_eq!(field.len(), 3);
+        let mut forest = Forest::new();
+        forest.set_root(FieldKey("root".into()), field);
+        assert_eq!(
+            describe_field::<MixedNodeRef>(forest.root(&FieldKey("root".into()))),
+            describe_field::<&BasicNode>(nodes.view())
+        );
+    }
+
+    #[test]
+    fn pending_content_is_an_error() {
+        // A chunk which is not in the store, so is pending.
+        let store: Rc<dyn ChunkStore> = Rc::new(MemoryStore::default());
+        let mut root = MixedNode::new(TreeType("root".into()), None);
+        root.fields_mut().insert(
+            key("children"),
+            vec![leaf(1), Chunk::lazy(store, ChunkId(0), 1)],
+        );
+        let forest = forest_with_root(root);
+
+        let cursor = forest.cursor_at(&Path::detached(key("root"), 0)).unwrap();
+        let Err(failure) = copy_node(cursor, &mut BasicTreeBuilder::default()) else {
+            panic!("pending content was copied");
+        };
+        assert_eq!(failure.error, CursorError::Pending);
+        assert_eq!(failure.cursor.node_type(), TreeType("root".into()));
+
+        let children = Path::detached(key("root"), 0).child(key("children"), 0);
+        let cursor = forest.cursor_at(&children).unwrap();
+        let Err(failure) = copy_nodes(cursor, 2, &mut BasicTreeBuilder::default()) else {
+            panic!("pending content was copied");
+        };
+        assert_eq!(failure.error, CursorError::Pending);
+        assert_eq!(failure.cursor.payload(), Some(vec![1]));
+    }
+
+    #[test]
+    fn copying_past_the_end_is_an_error() {
+        let mut root = MixedNode::new(TreeType("root".into()), None);
+        root.fields_mut()
+            .insert(key("children"), vec![leaf(1), leaf(2), leaf(3)]);
+        let forest = forest_with_root(root);
+        let second = Path::detached(key("root"), 0).child(key("children"), 1);
+
+        let mut builder = BasicTreeBuilder::default();
+        let Err(failure) = copy_nodes(forest.cursor_at(&second).unwrap(), 3, &mut builder) else {
+            panic!("copied past the end of the field");
+        };
+        assert_eq!(failure.error, CursorError::OutOfRange);
+        assert_eq!(failure.cursor.payload(), Some(vec![2]));
+        assert_eq!(builder.finish().0.len(), 2);
+
+        let Err(failure) = copy_nodes(forest.cursor(), 2, &mut BasicTreeBuilder::default()) else {
+            panic!("copied past the root");
+        };
+        assert_eq!(failure.error, CursorError::OutOfRange);
+        assert!(failure.cursor.is_root());
+    }
+
+    #[test]
+    fn copy_deep_tree() {
+        const DEPTH: u32 = 1_000_000;
+        let mut writer = StreamWriter::default();
+        copy_node(DummyNodes::new(1, DEPTH), &mut writer).unwrap();
+        let mut builder = BasicTreeBuilder::default();
+        read_stream(&writer.finish(), &mut builder).unwrap();
+        let tree = builder.finish();
+        assert_eq!(
+            walk_all_field::<&BasicNode>(tree.view()),
+            DEPTH as usize + 1
+        );
+    }
+
+    #[test]
+    fn chunk_deep_tree() {
+        const DEPTH: u32 = 200_000;
+        let mut builder = ChunkBuilder::default();
+        copy_node(DummyNodes::new(1, DEPTH), &mut builder).unwrap();
+        let mut forest = Forest::new();
+        forest.set_root(FieldKey("root".into()), builder.finish());
+        assert_eq!(
+            walk_all_field::<MixedNodeRef>(forest.root(&FieldKey("root".into()))),
+            DEPTH as usize + 1
+        );
+    }
+
+    #[test]
+    fn oversized_shapes_are_not_uniform() {
+        let node = |def: &str, fields: Vec<(FieldKey, Vec<BasicNode>)>| BasicNode {
+            def: TreeType(def.into()),
+            payload: Some(vec![0]),
+            fields: fields.into_iter().collect(),
+        };
+        let root = node(
+            "root",
+            vec![(
+                key("children"),
+                vec![node("leaf", vec![]), node("leaf", vec![])],
+            )],
+        );
+        // Children whose data (as far as the shapes say) is more than half of what a u32 can address.
+        let huge = Rc::new(Shape {
+            def: TreeType("leaf".into()),
+            payload: Some(1),
+            fields: vec![],
+            depth: 1,
+            bytes_per_node: u32::MAX / 2 + 1,
+        });
+        let mut shapes = Shape::of_all(std::slice::from_ref(&root));
+        for child in root.fields[&key("children")].iter() {
+            shapes.insert(child, Some(huge.clone()));
+        }
+        assert!(Shape::of(&root, &shapes).is_none());
+
+        let shape = Shape {
+            def: TreeType("root".into()),
+            payload: Some(1),
+            fields: vec![(key("a"), 1, huge.clone()), (key("b"), 1, huge)],
+            depth: 2,
+            bytes_per_node: u32::MAX,
+        };
+        assert!(shape.schema(1).is_none());
+    }
+
+    #[test]
+    fn stream_round_trip() {
+        let tree = copy_basic(DummyNodes::new(2, 3), 1);
+        let mut writer = StreamWriter::default();
+        copy_node(
+            GenericNodesCursor::<&BasicNode>::new(tree.view()),
+            &mut writer,
+        )
+        .unwrap();
+        let data = writer.finish();
+
+        let mut builder = BasicTreeBuilder::default();
+        read_stream(&data, &mut builder).unwrap();
+        let copy = builder.finish();
+        assert_eq!(
+            hash_node(&copy.view().index(0).unwrap()),
+            hash_node(&tree.view().index(0).unwrap())
+        );
+
+        // Only the version byte is a valid empty stream, but any other prefix is incomplete.
+        for length in 2..data.len() {
+            let mut builder = BasicTreeBuilder::default();
+            assert!(read_stream(&data[..length], &mut builder).is_err());
+        }
+    }
+}
@@ -1,6 +1,14 @@
+use std::{collections::BTreeMap, iter::once, mem::replace};
+
 use crate::{
-    forest::tree::{Indexable, Node},
-    EitherCursor, FieldKey, FieldsCursor, NodesCursor, TreeType, Value,
+    forest::{
+        path::{Path, PathStep},
+        store::StoreError,
+        tree::{Indexable, Node, NodeNav},
+        util::ImSlice,
+    },
+    CursorError, CursorFailure, CursorResult, EitherCursor, FieldKey, FieldsCursor, NodesCursor,
+    TreeType, Value,
 };
 
 pub struct GenericFieldsCursor<'a, T: Node<'a>> {
@@ -8,13 +16,43 @@ pub struct GenericFieldsCursor<'a, T: Node<'a>> {
     /// Cache of Nodes at the current key.
     nodes: T::TField,
     parents: Vec<BasicCursorLevel<'a, T>>,
+    roots: Roots<'a, T>,
 }
 
+/// Fields of the root: the detached fields, in key order.
+/// The one the cursor is in (if any) is moved out of here while it is, so fields do not need to be cloned.
+type Roots<'a, T> = Vec<(FieldKey, Option<<T as NodeNav<'a>>::TField>)>;
+
 struct BasicCursorLevel<'a, T: Node<'a>> {
-    nodes: BasicCursorNodesLevel<'a, T>,
+    parent: ParentLevel<'a, T>,
     fields: BasicCursorFieldsLevel<'a, T>,
 }
 
+impl<'a, T: Node<'a>> BasicCursorLevel<'a, T> {
+    /// Index of the node containing this field, in its own field.
+    fn parent_index(&self) -> u32 {
+        match &self.parent {
+            ParentLevel::Node(n) => n.index as u32,
+            ParentLevel::Root(_) => unreachable!("only the first level is in the root"),
+        }
+    }
+
+    /// Key of this field, and if it is being iterated.
+    fn field(&self) -> (FieldKey, bool) {
+        (
+            self.fields.key.clone(),
+            !matches!(self.fields.siblings, Siblings::None),
+        )
+    }
+}
+
+/// Node containing a field.
+enum ParentLevel<'a, T: Node<'a>> {
+    /// The root, and the index of the field in its [Roots].
+    Root(usize),
+    Node(BasicCursorNodesLevel<'a, T>),
+}
+
 struct BasicCursorNodesLevel<'a, T: Node<'a>> {
     index: usize,
     nodes: T::TField,
@@ -22,30 +60,219 @@ struct BasicCursorNodesLevel<'a, T: Node<'a>> {
 
 struct BasicCursorFieldsLevel<'a, T: Node<'a>> {
     key: FieldKey, // TODO: reference to some centralized Key object
-    fields: Option<T::TFields>,
+    siblings: Siblings<'a, T>,
+}
+
+/// What `next_field` iterates to.
+enum Siblings<'a, T: Node<'a>> {
+    /// Field was entered with `enter_field` instead of iterating, so `next_field` exits it.
+    None,
+    /// The remaining fields of the parent node.
+    Node(T::TFields),
+    /// The following fields of the root.
+    Roots,
 }
 
+/// Cursor over trees of any [Node] type.
+///
+/// Its root is a virtual node (with no type or value) whose fields are the detached fields it was created with,
+/// so navigating up always ends at the root.
 pub struct GenericNodesCursor<'a, T: Node<'a>> {
-    current: BasicCursorNodesLevel<'a, T>,
+    /// None at the root.
+    current: Option<BasicCursorNodesLevel<'a, T>>,
     parents: Vec<BasicCursorLevel<'a, T>>,
+    roots: Roots<'a, T>,
 }
 
 impl<'a, T: Node<'a>> GenericNodesCursor<'a, T> {
+    /// Cursor at the first node of `n`, which is the root's only field, [FieldKey::root].
     pub fn new(n: T::TField) -> GenericNodesCursor<'a, T> {
         GenericNodesCursor {
+            parents: vec![BasicCursorLevel {
+                parent: ParentLevel::Root(0),
+                fields: BasicCursorFieldsLevel {
+                    key: FieldKey::root(),
+                    siblings: Siblings::None,
+                },
+            }],
+            current: Some(BasicCursorNodesLevel { index: 0, nodes: n }),
+            roots: vec![(FieldKey::root(), None)],
+        }
+    }
+
+    /// Cursor at the root, whose fields are the non-empty fields in `roots`.
+    pub fn from_roots(
+        roots: impl IntoIterator<Item = (FieldKey, T::TField)>,
+    ) -> GenericNodesCursor<'a, T> {
+        let roots: BTreeMap<FieldKey, T::TField> = roots
+            .into_iter()
+            .filter(|(_, nodes)| nodes.len() > 0)
+            .collect();
+        GenericNodesCursor {
+            current: None,
             parents: vec![],
-            current: BasicCursorNodesLevel { index: 0, nodes: n },
+            roots: roots
+                .into_iter()
+                .map(|(key, nodes)| (key, Some(nodes)))
+                .collect(),
         }
     }
 
-    fn current_node(&self) -> T {
-        self.current.nodes.index(self.current.index).unwrap()
+    /// The current node. None for the root.
+    fn current_node(&self) -> Option<T> {
+        let current = self.current.as_ref()?;
+        Some(current.nodes.index(current.index).unwrap())
+    }
+
+    fn enter_root(mut self, index: usize, siblings: Siblings<'a, T>) -> GenericFieldsCursor<'a, T> {
+        let (key, nodes) = &mut self.roots[index];
+        let key = key.clone();
+        let nodes = nodes.take().expect("cursor is at the root");
+        GenericFieldsCursor {
+            nodes,
+            current: BasicCursorLevel {
+                parent: ParentLevel::Root(index),
+                fields: BasicCursorFieldsLevel { key, siblings },
+            },
+            parents: self.parents,
+            roots: self.roots,
+        }
     }
 }
 
 impl<'a, T: Node<'a>> GenericNodesCursor<'a, T> {
     pub fn is_leaf(&self) -> bool {
-        self.current_node().is_leaf()
+        self.current_node()
+            .map_or(self.roots.is_empty(), |node| node.is_leaf())
+    }
+
+    /// If the current node is `pending` because loading it failed, the error it failed with.
+    pub fn pending_error(&self) -> Option<StoreError> {
+        self.current_node().and_then(|node| node.load_error())
+    }
+
+    /// Where this cursor is, which does not borrow the tree.
+    pub fn position(&self) -> CursorPosition {
+        CursorPosition {
+            fields: self.parents.iter().map(BasicCursorLevel::field).collect(),
+            indexes: self.path().0.iter().map(|step| step.index).collect(),
+        }
+    }
+
+    /// Moves a cursor at the root to `position`, taken from a cursor over the same tree.
+    /// Returns None if there is nothing there.
+    pub fn restore(
+        self,
+        position: &CursorPosition,
+    ) -> Option<EitherCursor<Self, GenericFieldsCursor<'a, T>>> {
+        debug_assert!(self.is_root());
+        let mut nodes = self;
+        for (i, (key, iterating)) in position.fields.iter().enumerate() {
+            let field = if *iterating {
+                nodes.iterate_field(key)
+            } else {
+                nodes.enter_field(key.clone())
+            };
+            let field = match field {
+                EitherCursor::Fields(f) => f,
+                EitherCursor::Nodes(_) => return None,
+            };
+            match position.indexes.get(i) {
+                Some(index) => nodes = field.enter_node(*index).ok()?,
+                None => return Some(EitherCursor::Fields(field)),
+            }
+        }
+        Some(EitherCursor::Nodes(nodes))
+    }
+
+    /// Calls `f` with the payload of each node in the field `key` of each node in the current chunk
+    /// (see `chunk_start` and `chunk_length`), in order.
+    /// At the root, reads the detached field `key`.
+    ///
+    /// Fails with [CursorError::Pending] if any of the nodes (or those in the current chunk) are pending,
+    /// after calling `f` for the nodes before them.
+    pub fn for_each_field_payload(
+        &self,
+        key: &FieldKey,
+        f: &mut impl FnMut(Option<ImSlice>),
+    ) -> Result<(), CursorError> {
+        let current = match &self.current {
+            Some(current) => current,
+            None => {
+                if let Some((_, Some(nodes))) = self.roots.iter().find(|(k, _)| k == key) {
+                    for_each_payload::<T>(nodes, f)?;
+                }
+                return Ok(());
+            }
+        };
+        match current.nodes.uniform_chunk(current.index) {
+            Some(chunk) => {
+                for field in chunk.field_of_each(key) {
+                    field.payloads().for_each(&mut *f);
+                }
+            }
+            None => {
+                let (start, length) = current.nodes.chunk_range(current.index);
+                for index in start..start + length {
+                    let node = current.nodes.index(index).unwrap();
+                    if node.is_pending() {
+                        return Err(CursorError::Pending);
+                    }
+                    for_each_payload::<T>(&node.get_field(key.clone()), f)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Like `enter_field`, but as if iterating fields from `first_field`,
+    /// so `next_field` moves on to the fields after `key`.
+    fn iterate_field(self, key: &FieldKey) -> EitherCursor<Self, GenericFieldsCursor<'a, T>> {
+        let node = match self.current_node() {
+            Some(node) => node,
+            None => {
+                return match self.roots.iter().position(|(k, _)| k == key) {
+                    Some(index) => EitherCursor::Fields(self.enter_root(index, Siblings::Roots)),
+                    None => EitherCursor::Nodes(self),
+                }
+            }
+        };
+        let mut iter = node.get_fields();
+        match iter.find(|(k, _)| *k == key) {
+            Some((_, nodes)) => EitherCursor::Fields(GenericFieldsCursor {
+                nodes,
+                current: BasicCursorLevel {
+                    parent: ParentLevel::Node(self.current.unwrap()),
+                    fields: BasicCursorFieldsLevel {
+                        key: key.clone(),
+                        siblings: Siblings::Node(iter),
+                    },
+                },
+                parents: self.parents,
+                roots: self.roots,
+            }),
+            None => EitherCursor::Nodes(self),
+        }
+    }
+}
+
+/// Where a cursor is, as keys and indexes which do not borrow the tree.
+/// A cursor over the same tree can be moved back there with [GenericNodesCursor::restore].
+///
+/// Unlike a [Path], also records which fields are being iterated (entered with `first_field` or `next_field`),
+/// so `next_field` continues the iteration after restoring.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct CursorPosition {
+    /// Fields entered from the root, and if each is being iterated.
+    fields: Vec<(FieldKey, bool)>,
+    /// Index of the node in each field. One shorter than `fields` when in a field.
+    indexes: Vec<u32>,
+}
+
+impl CursorPosition {
+    /// True if this is a node (rather than a field).
+    pub fn is_node(&self) -> bool {
+        self.fields.len() == self.indexes.len()
     }
 }
 
@@ -53,89 +280,153 @@ impl<'a, T: Node<'a>> NodesCursor for GenericNodesCursor<'a, T> {
     type TFields = GenericFieldsCursor<'a, T>;
 
     fn field_index(&self) -> u32 {
-        self.current.index as u32
+        self.current.as_ref().map_or(0, |c| c.index as u32)
     }
 
     fn chunk_start(&self) -> u32 {
-        self.field_index()
+        self.current
+            .as_ref()
+            .map_or(0, |c| c.nodes.chunk_range(c.index).0 as u32)
     }
 
     fn chunk_length(&self) -> u32 {
-        1
+        self.current
+            .as_ref()
+            .map_or(1, |c| c.nodes.chunk_range(c.index).1 as u32)
     }
 
-    fn seek_nodes(mut self, offset: i32) -> EitherCursor<Self, Self::TFields> {
+    fn seek_nodes(mut self, offset: i32) -> CursorResult<EitherCursor<Self, Self::TFields>, Self> {
         // TODO: correct over/underflow handling.
-        let index = self.current.index as isize + offset as isize;
-        if index < 0 || (index as usize) >= self.current.nodes.len() {
-            EitherCursor::Fields(self.exit_node())
+        let index = self.field_index() as isize + offset as isize;
+        // The root is alone in its (virtual) field.
+        let len = self.current.as_ref().map_or(1, |c| c.nodes.len());
+        if index < 0 || (index as usize) >= len {
+            Ok(EitherCursor::Fields(self.exit_node()?))
         } else {
-            self.current.index = index as usize;
-            EitherCursor::Nodes(self)
+            if let Some(current) = &mut self.current {
+                current.index = index as usize;
+            }
+            Ok(EitherCursor::Nodes(self))
         }
     }
 
-    fn next_node(mut self) -> EitherCursor<Self, Self::TFields> {
-        self.current.index += 1;
-        if self.current.index < self.current.nodes.len() {
-            EitherCursor::Nodes(self)
-        } else {
-            EitherCursor::Fields(self.exit_node())
+    fn next_node(mut self) -> CursorResult<EitherCursor<Self, Self::TFields>, Self> {
+        if let Some(current) = self
+            .current
+            .as_mut()
+            .filter(|c| c.index + 1 < c.nodes.len())
+        {
+            current.index += 1;
+            return Ok(EitherCursor::Nodes(self));
         }
+        Ok(EitherCursor::Fields(self.exit_node()?))
     }
 
-    fn exit_node(mut self) -> Self::TFields {
-        let current = self.parents.pop().unwrap();
-        GenericFieldsCursor {
-            nodes: self.current.nodes,
-            current,
-            parents: self.parents,
+    fn exit_node(mut self) -> CursorResult<Self::TFields, Self> {
+        match self.parents.pop() {
+            Some(current) => Ok(GenericFieldsCursor {
+                nodes: self.current.expect("only the root has no parent").nodes,
+                current,
+                parents: self.parents,
+                roots: self.roots,
+            }),
+            None => Err(CursorFailure::new(self, CursorError::AtRoot)),
         }
     }
 
+    fn is_root(&self) -> bool {
+        self.current.is_none()
+    }
+
+    fn path(&self) -> Path {
+        let indexes = self
+            .parents
+            .iter()
+            .skip(1)
+            .map(BasicCursorLevel::parent_index)
+            .chain(self.current.as_ref().map(|c| c.index as u32));
+        Path(
+            self.parents
+                .iter()
+                .zip(indexes)
+                .map(|(level, index)| PathStep {
+                    key: level.fields.key.clone(),
+                    index,
+                })
+                .collect(),
+        )
+    }
+
     fn value(&self) -> Value {
-        let node = self.current_node();
-        todo!()
-        // Value(self.current.get(self.index).map(|n| n.get_payload()))
+        let node = match self.current_node() {
+            Some(node) => node,
+            None => return Value(None),
+        };
+        Value::of(node.get_payload())
+    }
+
+    fn payload(&self) -> Option<Vec<u8>> {
+        self.current_node()?.get_payload().map(|p| p.to_vec())
+    }
+
+    fn pending(&self) -> bool {
+        self.current_node().is_some_and(|node| node.is_pending())
     }
 
     fn first_field(self) -> EitherCursor<Self, Self::TFields> {
-        let mut iter = self.current_node().get_fields();
+        let node = match self.current_node() {
+            Some(node) => node,
+            None if self.roots.is_empty() => return EitherCursor::Nodes(self),
+            None => return EitherCursor::Fields(self.enter_root(0, Siblings::Roots)),
+        };
+        let mut iter = node.get_fields();
         let first = iter.next();
         match first {
             Some((key, nodes)) => EitherCursor::Fields(GenericFieldsCursor {
                 nodes,
                 current: BasicCursorLevel {
-                    nodes: self.current,
+                    parent: ParentLevel::Node(self.current.unwrap()),
                     fields: BasicCursorFieldsLevel {
                         key: key.clone(),
-                        fields: Some(iter),
+                        siblings: Siblings::Node(iter),
                     },
                 },
                 parents: self.parents,
+                roots: self.roots,
             }),
             None => EitherCursor::Nodes(self),
         }
     }
 
+    /// At the root, stays there (returning the cursor in `Nodes` mode) if there is no detached field `key`.
     fn enter_field(self, key: FieldKey) -> EitherCursor<Self, Self::TFields> {
+        let node = match self.current_node() {
+            Some(node) => node,
+            None => {
+                return match self.roots.iter().position(|(k, _)| *k == key) {
+                    Some(index) => EitherCursor::Fields(self.enter_root(index, Siblings::None)),
+                    None => EitherCursor::Nodes(self),
+                }
+            }
+        };
         EitherCursor::Fields(GenericFieldsCursor {
-            nodes: self.current_node().get_field(key.clone()),
+            nodes: node.get_field(key.clone()),
             current: BasicCursorLevel {
-                nodes: self.current,
+                parent: ParentLevel::Node(self.current.unwrap()),
                 fields: BasicCursorFieldsLevel {
-                    key: key.clone(),
-                    fields: None,
+                    key,
+                    siblings: Siblings::None,
                 },
             },
             parents: self.parents,
+            roots: self.roots,
         })
     }
 
+    /// The root's type is the empty string.
     fn node_type(&self) -> TreeType {
-        let def = self.current_node().get_def().clone();
-        todo!()
-        // TreeType(def)
+        self.current_node()
+            .map_or(TreeType(String::new()), |node| node.get_def())
     }
 }
 
@@ -143,51 +434,312 @@ impl<'a, T: Node<'a>> FieldsCursor for GenericFieldsCursor<'a, T> {
     type TNodes = GenericNodesCursor<'a, T>;
 
     fn next_field(mut self) -> EitherCursor<Self::TNodes, Self> {
-        let fields = &mut self.current.fields.fields;
-        match fields {
-            Some(f) => match f.next() {
-                Some((key, nodes)) => {
-                    self.current.fields.key = key.clone();
-                    self.nodes = nodes;
-                    EitherCursor::Fields(self)
-                }
-                None => EitherCursor::Nodes(self.exit_field()), // Was on last field.
-            },
-            None => EitherCursor::Nodes(self.exit_field()), // Used enter_field instead of iterating.
+        if self.next_sibling() {
+            EitherCursor::Fields(self)
+        } else {
+            EitherCursor::Nodes(self.parent())
         }
     }
 
-    fn exit_field(self) -> Self::TNodes {
-        GenericNodesCursor {
-            current: self.current.nodes,
-            parents: self.parents,
-        }
+    fn exit_field(self) -> CursorResult<Self::TNodes, Self> {
+        Ok(self.parent())
     }
 
     fn skip_pending_fields(self) -> EitherCursor<Self::TNodes, Self> {
-        EitherCursor::Fields(self)
+        let mut fields = self;
+        while is_pending_field::<T>(&fields.nodes) {
+            fields = match fields.next_field() {
+                EitherCursor::Fields(f) => f,
+                nodes => return nodes,
+            };
+        }
+        EitherCursor::Fields(fields)
+    }
+
+    fn field_key(&self) -> FieldKey {
+        self.current.fields.key.clone()
     }
 
     fn get_field_length(&self) -> u32 {
-        1
+        self.nodes.len() as u32
     }
 
     fn first_node(self) -> EitherCursor<Self::TNodes, Self> {
         if self.nodes.len() > 0 {
-            EitherCursor::Nodes(self.enter_node(0))
+            EitherCursor::Nodes(self.child(0))
         } else {
             EitherCursor::Fields(self)
         }
     }
 
-    fn enter_node(mut self, child_index: u32) -> Self::TNodes {
+    fn enter_node(self, child_index: u32) -> CursorResult<Self::TNodes, Self> {
+        if (child_index as usize) < self.nodes.len() {
+            Ok(self.child(child_index as usize))
+        } else {
+            Err(CursorFailure::new(self, CursorError::OutOfRange))
+        }
+    }
+}
+
+/// True if `nodes` is not empty and all of them are pending (see [FieldsCursor::skip_pending_fields]).
+/// Nodes are pending a whole chunk at a time, so this only checks the first node of each chunk.
+pub(crate) fn is_pending_field<'a, T: Node<'a>>(nodes: &T::TField) -> bool {
+    let mut index = 0;
+    while index < nodes.len() {
+        if !nodes.index(index).is_some_and(|node| node.is_pending()) {
+            return false;
+        }
+        let (start, length) = nodes.chunk_range(index);
+        index = start + length;
+    }
+    index > 0
+}
+
+/// Calls `f` with the payload of each node in `nodes`, in order.
+/// Nodes in uniform chunks are read with the chunk's fixed stride, instead of one at a time.
+///
+/// Fails with [CursorError::Pending] at the first pending node, after calling `f` for the nodes before it.
+fn for_each_payload<'a, T: Node<'a>>(
+    nodes: &T::TField,
+    f: &mut impl FnMut(Option<ImSlice>),
+) -> Result<(), CursorError> {
+    let mut index = 0;
+    while index < nodes.len() {
+        let (start, length) = nodes.chunk_range(index);
+        match nodes.uniform_chunk(index) {
+            Some(chunk) => chunk.payloads().for_each(&mut *f),
+            None => {
+                for i in start..start + length {
+                    let node = nodes.index(i).unwrap();
+                    if node.is_pending() {
+                        return Err(CursorError::Pending);
+                    }
+                    f(node.get_payload());
+                }
+            }
+        }
+        index = start + length;
+    }
+    Ok(())
+}
+
+impl<'a, T: Node<'a>> GenericFieldsCursor<'a, T> {
+    /// Calls `f` with the payload of each node in the current field, in order.
+    ///
+    /// Fails with [CursorError::Pending] at the first pending node, after calling `f` for the nodes before it.
+    pub fn for_each_payload(&self, f: &mut impl FnMut(Option<ImSlice>)) -> Result<(), CursorError> {
+        for_each_payload::<T>(&self.nodes, f)
+    }
+
+    /// Where this cursor is, which does not borrow the tree.
+    pub fn position(&self) -> CursorPosition {
+        let levels = || self.parents.iter().chain(once(&self.current));
+        CursorPosition {
+            fields: levels().map(BasicCursorLevel::field).collect(),
+            indexes: levels()
+                .skip(1)
+                .map(BasicCursorLevel::parent_index)
+                .collect(),
+        }
+    }
+
+    /// The node containing this field. Fields always have one: detached fields are in the root.
+    fn parent(mut self) -> GenericNodesCursor<'a, T> {
+        let current = match self.current.parent {
+            ParentLevel::Node(n) => Some(n),
+            ParentLevel::Root(index) => {
+                self.roots[index].1 = Some(self.nodes);
+                None
+            }
+        };
+        GenericNodesCursor {
+            current,
+            parents: self.parents,
+            roots: self.roots,
+        }
+    }
+
+    fn child(mut self, index: usize) -> GenericNodesCursor<'a, T> {
         self.parents.push(self.current);
         GenericNodesCursor {
-            current: BasicCursorNodesLevel {
-                index: child_index as usize,
+            current: Some(BasicCursorNodesLevel {
+                index,
                 nodes: self.nodes,
-            },
+            }),
             parents: self.parents,
+            roots: self.roots,
+        }
+    }
+
+    /// Moves to the next field being iterated. Returns false if there is none.
+    fn next_sibling(&mut self) -> bool {
+        match &mut self.current.fields.siblings {
+            Siblings::None => false, // Used enter_field instead of iterating.
+            Siblings::Node(fields) => match fields.next() {
+                Some((key, nodes)) => {
+                    self.current.fields.key = key.clone();
+                    self.nodes = nodes;
+                    true
+                }
+                None => false, // Was on last field.
+            },
+            Siblings::Roots => {
+                let index = match self.current.parent {
+                    ParentLevel::Root(index) => index,
+                    ParentLevel::Node(_) => unreachable!("only root fields iterate roots"),
+                };
+                let (key, next) = match self.roots.get_mut(index + 1) {
+                    Some((key, nodes)) => {
+                        (key.clone(), nodes.take().expect("cursor is in the root"))
+                    }
+                    None => return false,
+                };
+                self.roots[index].1 = Some(replace(&mut self.nodes, next));
+                self.current.parent = ParentLevel::Root(index + 1);
+                self.current.fields.key = key;
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forest::example_node::BasicNode;
+
+    fn leaf(def: &str) -> BasicNode {
+        BasicNode {
+            def: TreeType(def.into()),
+            payload: None,
+            fields: Default::default(),
         }
     }
+
+    #[test]
+    fn navigate_to_root() {
+        let a = vec![leaf("a0"), leaf("a1")];
+        let mut b = vec![leaf("b0")];
+        b[0].fields.insert(FieldKey("x".into()), vec![leaf("x0")]);
+        let cursor = GenericNodesCursor::<&BasicNode>::from_roots([
+            (FieldKey("b".into()), b.as_slice()),
+            (FieldKey("a".into()), a.as_slice()),
+            (FieldKey("empty".into()), &[][..]),
+        ]);
+        assert!(cursor.is_root());
+        assert_eq!(cursor.path(), Path::root());
+
+        // Detached fields are iterated in key order, skipping empty ones.
+        let mut keys = vec![];
+        let mut fields = match cursor.first_field() {
+            EitherCursor::Fields(f) => f,
+            EitherCursor::Nodes(_) => panic!(),
+        };
+        let cursor = loop {
+            keys.push(fields.field_key().0);
+            fields = match fields.next_field() {
+                EitherCursor::Fields(f) => f,
+                EitherCursor::Nodes(n) => break n,
+            };
+        };
+        assert_eq!(keys, ["a", "b"]);
+        assert!(cursor.is_root());
+
+        let fields = match cursor.enter_field(FieldKey("b".into())) {
+            EitherCursor::Fields(f) => f,
+            EitherCursor::Nodes(_) => panic!(),
+        };
+        let node = fields.enter_node(0).unwrap();
+        let fields = match node.enter_field(FieldKey("x".into())) {
+            EitherCursor::Fields(f) => f,
+            EitherCursor::Nodes(_) => panic!(),
+        };
+        let node = fields.enter_node(0).unwrap();
+        assert_eq!(node.path().to_string(), "b[0]/x[0]");
+        assert_eq!(node.node_type().0, "x0");
+
+        // Navigating up ends at the root, which is alone in its field.
+        let node = node.exit_node().unwrap().exit_field().unwrap();
+        let node = match node.next_node() {
+            Ok(EitherCursor::Fields(f)) => f.exit_field().unwrap(),
+            _ => panic!(),
+        };
+        assert!(node.is_root());
+        let node = match node.seek_nodes(0) {
+            Ok(EitherCursor::Nodes(n)) => n,
+            _ => panic!(),
+        };
+        let failure = node.exit_node().err().unwrap();
+        assert_eq!(failure.error, CursorError::AtRoot);
+        assert!(failure.cursor.is_root());
+
+        // Cursors from a single field start in it.
+        let node = GenericNodesCursor::<&BasicNode>::new(a.as_slice());
+        let node = match node.next_node() {
+            Ok(EitherCursor::Nodes(n)) => n,
+            _ => panic!(),
+        };
+        assert_eq!(node.path(), Path::detached(FieldKey::root(), 1));
+        assert!(node.exit_node().unwrap().exit_field().unwrap().is_root());
+    }
+
+    #[test]
+    fn restore_position() {
+        let mut b = vec![leaf("b0")];
+        for key in ["x", "y", "z"] {
+            b[0].fields
+                .insert(FieldKey(key.into()), vec![leaf(key), leaf(key)]);
+        }
+        let a = vec![leaf("a0")];
+        let roots = || {
+            GenericNodesCursor::<&BasicNode>::from_roots([
+                (FieldKey("a".into()), a.as_slice()),
+                (FieldKey("b".into()), b.as_slice()),
+            ])
+        };
+        let fields = |cursor| match cursor {
+            EitherCursor::Fields(f) => f,
+            EitherCursor::Nodes(_) => panic!(),
+        };
+        let nodes = |cursor| match cursor {
+            EitherCursor::Nodes(n) => n,
+            EitherCursor::Fields(_) => panic!(),
+        };
+
+        // Iterate to b[0]/y[1].
+        let cursor = fields(roots().first_field());
+        let cursor = nodes(fields(cursor.next_field()).first_node());
+        let cursor = fields(fields(cursor.first_field()).next_field())
+            .enter_node(1)
+            .unwrap();
+        let position = cursor.position();
+        assert!(position.is_node());
+        let restored = nodes(roots().restore(&position).unwrap());
+        assert_eq!(restored.path().to_string(), "b[0]/y[1]");
+        assert_eq!(restored.position(), position);
+
+        // Iteration continues after restoring: from field y to z, then out of b[0] and on past b.
+        let field = restored.exit_node().unwrap();
+        let position = field.position();
+        assert!(!position.is_node());
+        let field = fields(roots().restore(&position).unwrap());
+        assert_eq!(field.position(), position);
+        let field = fields(field.next_field());
+        assert_eq!(field.field_key().0, "z");
+        let field = nodes(field.next_field()).exit_node().unwrap();
+        assert!(nodes(field.next_field()).is_root());
+
+        // Fields entered by key are not iterated.
+        let cursor = nodes(fields(roots().enter_field(FieldKey("b".into()))).first_node());
+        let field = fields(cursor.enter_field(FieldKey("x".into())));
+        let field = fields(roots().restore(&field.position()).unwrap());
+        assert!(!nodes(field.next_field()).is_root());
+
+        // Positions from another tree may not exist.
+        let missing =
+            nodes(fields(roots().enter_field(FieldKey("a".into()))).first_node()).position();
+        let other =
+            GenericNodesCursor::<&BasicNode>::from_roots([(FieldKey("b".into()), b.as_slice())]);
+        assert!(other.restore(&missing).is_none());
+    }
 }
@@ -1,12 +1,58 @@
-use crate::{EitherCursor, FieldKey, FieldsCursor, NodesCursor, TreeType, Value};
+use crate::{
+    forest::path::{Path, PathStep},
+    CursorError, CursorFailure, CursorResult, EitherCursor, FieldKey, FieldsCursor, NodesCursor,
+    TreeType, Value,
+};
 
-pub struct DummyNodes {}
+const VALUE: f64 = 42f64;
+
+fn child_key() -> FieldKey {
+    FieldKey("child".into())
+}
+
+/// Cursor over a generated tree which has no backing storage.
+///
+/// The detached field [FieldKey::root] has a single node, and every node less than `depth` levels below it
+/// has a "child" field of `width` nodes. All nodes (except the root) have type "Dummy" and value 42.
+pub struct DummyNodes {
+    width: u32,
+    depth: u32,
+    /// Index of each node from the root to the current node: empty at the root.
+    path: Vec<u32>,
+}
+
+pub struct DummyFields {
+    width: u32,
+    depth: u32,
+    /// Index of each node from the root to the node containing the current field.
+    path: Vec<u32>,
+    key: FieldKey,
+}
+
+impl DummyNodes {
+    pub fn new(width: u32, depth: u32) -> DummyNodes {
+        DummyNodes {
+            width,
+            depth,
+            path: vec![0],
+        }
+    }
+
+    fn field_length(&self) -> u32 {
+        // The root, and the node in the detached field, are alone in their fields.
+        if self.path.len() <= 1 {
+            1
+        } else {
+            self.width
+        }
+    }
+}
 
 impl NodesCursor for DummyNodes {
     type TFields = DummyFields;
 
     fn field_index(&self) -> u32 {
-        0
+        self.path.last().copied().unwrap_or(0)
     }
 
     fn chunk_start(&self) -> u32 {
@@ -17,61 +63,165 @@ impl NodesCursor for DummyNodes {
         1
     }
 
-    fn seek_nodes(self, _offset: i32) -> EitherCursor<Self, Self::TFields> {
-        EitherCursor::Nodes(self)
+    fn seek_nodes(mut self, offset: i32) -> CursorResult<EitherCursor<Self, Self::TFields>, Self> {
+        let index = self.field_index() as i64 + offset as i64;
+        if index < 0 || index >= self.field_length() as i64 {
+            Ok(EitherCursor::Fields(self.exit_node()?))
+        } else {
+            if let Some(last) = self.path.last_mut() {
+                *last = index as u32;
+            }
+            Ok(EitherCursor::Nodes(self))
+        }
     }
 
-    fn next_node(self) -> EitherCursor<Self, Self::TFields> {
+    fn next_node(self) -> CursorResult<EitherCursor<Self, Self::TFields>, Self> {
         self.seek_nodes(1)
     }
 
-    fn exit_node(self) -> Self::TFields {
-        DummyFields {}
+    fn exit_node(mut self) -> CursorResult<Self::TFields, Self> {
+        if self.path.pop().is_none() {
+            return Err(CursorFailure::new(self, CursorError::AtRoot));
+        }
+        Ok(DummyFields {
+            width: self.width,
+            depth: self.depth,
+            key: if self.path.is_empty() {
+                FieldKey::root()
+            } else {
+                child_key()
+            },
+            path: self.path,
+        })
+    }
+
+    fn is_root(&self) -> bool {
+        self.path.is_empty()
+    }
+
+    fn path(&self) -> Path {
+        Path(
+            self.path
+                .iter()
+                .enumerate()
+                .map(|(depth, &index)| PathStep {
+                    key: if depth == 0 {
+                        FieldKey::root()
+                    } else {
+                        child_key()
+                    },
+                    index,
+                })
+                .collect(),
+        )
     }
 
     fn value(&self) -> Value {
-        Value(Some(42f64))
+        Value(self.payload().map(|_| VALUE))
+    }
+
+    fn payload(&self) -> Option<Vec<u8>> {
+        if self.is_root() {
+            None
+        } else {
+            Some(VALUE.to_le_bytes().to_vec())
+        }
+    }
+
+    fn pending(&self) -> bool {
+        false
     }
 
     fn first_field(self) -> EitherCursor<Self, Self::TFields> {
-        EitherCursor::Fields(DummyFields {})
+        if self.is_root() {
+            self.enter_field(FieldKey::root())
+        } else if self.path.len() as u32 > self.depth {
+            EitherCursor::Nodes(self)
+        } else {
+            self.enter_field(child_key())
+        }
     }
 
-    fn enter_field(self, _key: FieldKey) -> EitherCursor<Self, Self::TFields> {
-        EitherCursor::Fields(DummyFields {})
+    fn enter_field(self, key: FieldKey) -> EitherCursor<Self, Self::TFields> {
+        EitherCursor::Fields(DummyFields {
+            width: self.width,
+            depth: self.depth,
+            path: self.path,
+            key,
+        })
     }
 
     fn node_type(&self) -> TreeType {
-        TreeType("TODO".into())
+        if self.is_root() {
+            TreeType(String::new())
+        } else {
+            TreeType("Dummy".into())
+        }
     }
 }
 
-pub struct DummyFields {}
-
 impl FieldsCursor for DummyFields {
     type TNodes = DummyNodes;
 
     fn next_field(self) -> EitherCursor<Self::TNodes, Self> {
-        EitherCursor::Nodes(DummyNodes {})
+        // Nodes have at most one field.
+        EitherCursor::Nodes(self.parent())
     }
 
-    fn exit_field(self) -> Self::TNodes {
-        DummyNodes {}
+    fn exit_field(self) -> CursorResult<Self::TNodes, Self> {
+        Ok(self.parent())
     }
 
     fn skip_pending_fields(self) -> EitherCursor<Self::TNodes, Self> {
         EitherCursor::Fields(self)
     }
 
+    fn field_key(&self) -> FieldKey {
+        self.key.clone()
+    }
+
     fn get_field_length(&self) -> u32 {
-        1
+        if self.path.is_empty() {
+            (self.key == FieldKey::root()) as u32
+        } else if self.key == child_key() && self.path.len() as u32 <= self.depth {
+            self.width
+        } else {
+            0
+        }
     }
 
     fn first_node(self) -> EitherCursor<Self::TNodes, Self> {
-        EitherCursor::Nodes(DummyNodes {})
+        if self.get_field_length() > 0 {
+            EitherCursor::Nodes(self.child(0))
+        } else {
+            EitherCursor::Fields(self)
+        }
     }
 
-    fn enter_node(self, _child_index: u32) -> Self::TNodes {
-        DummyNodes {}
+    fn enter_node(self, child_index: u32) -> CursorResult<Self::TNodes, Self> {
+        if child_index < self.get_field_length() {
+            Ok(self.child(child_index))
+        } else {
+            Err(CursorFailure::new(self, CursorError::OutOfRange))
+        }
+    }
+}
+
+impl DummyFields {
+    fn parent(self) -> DummyNodes {
+        DummyNodes {
+            width: self.width,
+            depth: self.depth,
+            path: self.path,
+        }
+    }
+
+    fn child(mut self, index: u32) -> DummyNodes {
+        self.path.push(index);
+        DummyNodes {
+            width: self.width,
+            depth: self.depth,
+            path: self.path,
+        }
     }
 }
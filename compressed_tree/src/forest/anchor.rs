@@ -0,0 +1,280 @@
+//! Anchors: stable references to nodes or positions in a [super::mixed::Forest] which are updated by edits.
+//!
+//! Anchors are tracked as logical paths, so how the content is split into chunks does not affect them:
+//! splitting and merging chunks does not require any updates.
+//!
+//! Every edit is described to the [AnchorSet] as a combination of a few primitives:
+//! inserting nodes at a position, detaching a range of nodes into a new detached field,
+//! attaching a detached field at a position, and deleting a detached field.
+//! A move is a detach followed by an attach.
+//!
+//! TODO: updates visit every anchor. A tree of anchors mirroring the paths would make this proportional to the anchors affected.
+
+use std::collections::HashMap;
+
+use crate::FieldKey;
+
+use super::path::{FieldPosition, FieldRange, Path, PathStep};
+
+/// Handle to an anchor in an [AnchorSet].
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Anchor(u64);
+
+/// Current location of an anchor.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum AnchorLocation {
+    Node(Path),
+    Position(FieldPosition),
+    /// The anchored node (or the field containing the anchored position) was deleted.
+    Deleted,
+}
+
+#[derive(Clone)]
+enum Location {
+    /// For positions, the last step's index is the position in its field.
+    Tracked {
+        steps: Vec<PathStep>,
+        position: bool,
+    },
+    Deleted,
+}
+
+/// Set of anchors into a forest.
+#[derive(Clone, Default)]
+pub struct AnchorSet {
+    next: u64,
+    anchors: HashMap<Anchor, Location, ahash::RandomState>,
+}
+
+impl AnchorSet {
+    fn add(&mut self, location: Location) -> Anchor {
+        let anchor = Anchor(self.next);
+        self.next += 1;
+        self.anchors.insert(anchor, location);
+        anchor
+    }
+
+    /// Creates an anchor to the node at `path`.
+    /// None for the root, which is not a node (its fields are the detached fields).
+    pub fn track_node(&mut self, path: Path) -> Option<Anchor> {
+        if path.is_root() {
+            return None;
+        }
+        Some(self.add(Location::Tracked {
+            steps: path.0,
+            position: false,
+        }))
+    }
+
+    /// Creates an anchor to a position in a field.
+    pub fn track_position(&mut self, position: FieldPosition) -> Anchor {
+        let mut steps = position.parent.0;
+        steps.push(PathStep {
+            key: position.key,
+            index: position.index,
+        });
+        self.add(Location::Tracked {
+            steps,
+            position: true,
+        })
+    }
+
+    /// Stops tracking `anchor`.
+    pub fn release(&mut self, anchor: Anchor) {
+        self.anchors.remove(&anchor);
+    }
+
+    /// Where `anchor` currently refers to. None if the anchor is unknown (for example released).
+    pub fn locate(&self, anchor: Anchor) -> Option<AnchorLocation> {
+        Some(match self.anchors.get(&anchor)? {
+            Location::Tracked {
+                steps,
+                position: false,
+            } => AnchorLocation::Node(Path(steps.clone())),
+            Location::Tracked {
+                steps,
+                position: true,
+            } => {
+                let (last, parent) = steps.split_last().unwrap();
+                AnchorLocation::Position(FieldPosition::new(
+                    Path(parent.to_vec()),
+                    last.key.clone(),
+                    last.index,
+                ))
+            }
+            Location::Deleted => AnchorLocation::Deleted,
+        })
+    }
+
+    /// Calls `f` with every tracked location which passes through field `key` of `parent`:
+    /// gives the steps, the depth of the step in that field, and if that step is a position rather than a node.
+    fn for_each_through(
+        &mut self,
+        parent: &Path,
+        key: &FieldKey,
+        mut f: impl FnMut(&mut Vec<PathStep>, usize, bool),
+    ) {
+        let depth = parent.0.len();
+        for location in self.anchors.values_mut() {
+            if let Location::Tracked { steps, position } = location {
+                if steps.len() > depth && steps[..depth] == parent.0[..] && steps[depth].key == *key
+                {
+                    let is_position = *position && depth == steps.len() - 1;
+                    f(steps, depth, is_position);
+                }
+            }
+        }
+    }
+
+    /// `count` nodes were inserted at `at`.
+    /// Nodes and positions at or after `at` are shifted after the inserted nodes.
+    pub fn on_insert(&mut self, at: &FieldPosition, count: u32) {
+        self.for_each_through(&at.parent, &at.key, |steps, depth, _| {
+            if steps[depth].index >= at.index {
+                steps[depth].index += count;
+            }
+        });
+    }
+
+    /// The nodes in `range` were moved into the new detached field `destination`.
+    /// Positions strictly inside the range move with the nodes.
+    pub fn on_detach(&mut self, range: &FieldRange, destination: &FieldKey) {
+        self.for_each_through(&range.parent, &range.key, |steps, depth, is_position| {
+            let index = steps[depth].index;
+            let inside = if is_position {
+                range.start < index && index < range.end
+            } else {
+                range.start <= index && index < range.end
+            };
+            if inside {
+                let mut moved = vec![PathStep {
+                    key: destination.clone(),
+                    index: index - range.start,
+                }];
+                moved.extend(steps.drain(depth + 1..));
+                *steps = moved;
+            } else if index >= range.end {
+                steps[depth].index -= range.len();
+            }
+        });
+    }
+
+    /// The content of the detached field `source`, which has `count` nodes, was inserted at `at`.
+    pub fn on_attach(&mut self, source: &FieldKey, count: u32, at: &FieldPosition) {
+        self.on_insert(at, count);
+        for location in self.anchors.values_mut() {
+            if let Location::Tracked { steps, .. } = location {
+                if steps[0].key == *source {
+                    let mut moved = at.parent.0.clone();
+                    moved.push(PathStep {
+                        key: at.key.clone(),
+                        index: at.index + steps[0].index,
+                    });
+                    moved.extend(steps.drain(1..));
+                    *steps = moved;
+                }
+            }
+        }
+    }
+
+    /// The detached field `key` and all its content was deleted.
+    pub fn on_delete_root(&mut self, key: &FieldKey) {
+        for location in self.anchors.values_mut() {
+            if let Location::Tracked { steps, .. } = location {
+                if steps[0].key == *key {
+                    *location = Location::Deleted;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forest::test_stuff::key;
+
+    #[test]
+    fn anchors_follow_edits() {
+        let mut set = AnchorSet::default();
+        let parent = Path::detached(key("root"), 0);
+        let node = set.track_node(parent.child(key("x"), 5)).unwrap();
+        let inner = set
+            .track_node(parent.child(key("x"), 2).child(key("y"), 0))
+            .unwrap();
+        let position = set.track_position(FieldPosition::new(parent.clone(), key("x"), 3));
+
+        set.on_insert(&FieldPosition::new(parent.clone(), key("x"), 0), 2);
+        assert_eq!(
+            set.locate(node),
+            Some(AnchorLocation::Node(parent.child(key("x"), 7)))
+        );
+
+        // Detach [3, 6): moves inner and the position, shifts node.
+        set.on_detach(&FieldRange::new(parent.clone(), key("x"), 3, 6), &key("d"));
+        assert_eq!(
+            set.locate(node),
+            Some(AnchorLocation::Node(parent.child(key("x"), 4)))
+        );
+        assert_eq!(
+            set.locate(inner),
+            Some(AnchorLocation::Node(
+                Path::detached(key("d"), 1).child(key("y"), 0)
+            ))
+        );
+        assert_eq!(
+            set.locate(position),
+            Some(AnchorLocation::Position(FieldPosition::new(
+                Path::root(),
+                key("d"),
+                2
+            )))
+        );
+
+        // Reattach in another field.
+        set.on_attach(
+            &key("d"),
+            3,
+            &FieldPosition::new(parent.clone(), key("z"), 0),
+        );
+        assert_eq!(
+            set.locate(inner),
+            Some(AnchorLocation::Node(
+                parent.child(key("z"), 1).child(key("y"), 0)
+            ))
+        );
+
+        set.on_detach(&FieldRange::new(parent.clone(), key("z"), 0, 3), &key("e"));
+        set.on_delete_root(&key("e"));
+        assert_eq!(set.locate(inner), Some(AnchorLocation::Deleted));
+        assert_eq!(
+            set.locate(node),
+            Some(AnchorLocation::Node(parent.child(key("x"), 4)))
+        );
+
+        set.release(node);
+        assert_eq!(set.locate(node), None);
+    }
+
+    #[test]
+    fn the_root_is_not_tracked() {
+        let mut set = AnchorSet::default();
+        assert_eq!(set.track_node(Path::root()), None);
+        assert!(set.anchors.is_empty());
+    }
+
+    #[test]
+    fn positions_at_range_boundaries_stay() {
+        let mut set = AnchorSet::default();
+        let before = set.track_position(FieldPosition::new(Path::root(), key("r"), 1));
+        let after = set.track_position(FieldPosition::new(Path::root(), key("r"), 3));
+        set.on_detach(&FieldRange::new(Path::root(), key("r"), 1, 3), &key("d"));
+        let expected = Some(AnchorLocation::Position(FieldPosition::new(
+            Path::root(),
+            key("r"),
+            1,
+        )));
+        assert_eq!(set.locate(before), expected);
+        assert_eq!(set.locate(after), expected);
+    }
+}
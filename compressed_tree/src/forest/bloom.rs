@@ -0,0 +1,238 @@
+//! Bloom filters over [NodeId]s, used by [super::id_index] to answer "might this id be here" without loading a subtree.
+//!
+//! Ids in a [super::uniform_chunk::UniformChunk] are often sequential ranges,
+//! so filters also hold truncated ids (the id with its low bits dropped):
+//! a range is inserted as a small number of aligned blocks instead of one entry per id.
+
+use super::{
+    node_id::NodeId,
+    serialize::{DecodeError, Reader, Writer},
+};
+
+/// Number of low bits dropped per level of truncation.
+const LEVEL_BITS: u32 = 4;
+/// Number of levels of truncation, including the untruncated ids at level 0.
+const LEVELS: u32 = 9;
+const BITS_PER_ENTRY: usize = 10;
+const HASH_COUNT: u32 = 7;
+
+/// Plain bloom filter over `u128` keys.
+#[derive(Clone)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+}
+
+impl BloomFilter {
+    /// Creates a filter sized for `expected_entries` with about a 1% false positive rate.
+    pub fn new(expected_entries: usize) -> BloomFilter {
+        let words = (expected_entries.max(1) * BITS_PER_ENTRY).div_ceil(64);
+        BloomFilter {
+            bits: vec![0; words],
+        }
+    }
+
+    pub fn insert(&mut self, key: u128, salt: u32) {
+        let bit_count = self.bits.len() as u64 * 64;
+        for bit in bit_indexes(key, salt, bit_count) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    pub fn contains(&self, key: u128, salt: u32) -> bool {
+        let bit_count = self.bits.len() as u64 * 64;
+        bit_indexes(key, salt, bit_count)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+}
+
+fn bit_indexes(key: u128, salt: u32, bit_count: u64) -> impl Iterator<Item = u64> {
+    let low = mix(mix(key as u64) ^ salt as u64);
+    let high = mix((key >> 64) as u64 ^ low);
+    // Double hashing. The bit count is only a multiple of 64, not a power of two, so the step is not always coprime with it:
+    // a key's probes can repeat a bit, which slightly raises the false positive rate for that key.
+    let step = high | 1;
+    (0..HASH_COUNT as u64).map(move |i| low.wrapping_add(i.wrapping_mul(step)) % bit_count)
+}
+
+/// splitmix64 finalizer.
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+/// Bloom filter over ids which supports efficient insertion of id ranges.
+///
+/// Each level `l` holds ids truncated by `l * LEVEL_BITS` bits:
+/// an entry at level `l` covers an aligned block of `2^(l * LEVEL_BITS)` ids.
+#[derive(Clone)]
+pub struct RangeBloomFilter {
+    filter: BloomFilter,
+    /// Bit `l` is set if level `l` has entries: only those levels are checked, which keeps the false positive rate down.
+    levels: u32,
+    entries: usize,
+    capacity: usize,
+}
+
+impl RangeBloomFilter {
+    pub fn new(expected_entries: usize) -> RangeBloomFilter {
+        RangeBloomFilter {
+            filter: BloomFilter::new(expected_entries),
+            levels: 0,
+            entries: 0,
+            capacity: expected_entries.max(1),
+        }
+    }
+
+    /// Builds a filter sized to hold exactly `ranges`, given as `(first, count)`.
+    pub fn from_ranges(ranges: &[(NodeId, u128)]) -> RangeBloomFilter {
+        let mut filter = RangeBloomFilter::new(RangeBloomFilter::entries_for(ranges));
+        for (first, count) in ranges {
+            filter.insert_range(*first, *count);
+        }
+        filter
+    }
+
+    /// Number of entries inserting `ranges` (given as `(first, count)`) adds.
+    pub fn entries_for(ranges: &[(NodeId, u128)]) -> usize {
+        ranges
+            .iter()
+            .map(|(first, count)| blocks(*first, *count).count())
+            .sum()
+    }
+
+    pub fn insert(&mut self, id: NodeId) {
+        self.insert_range(id, 1);
+    }
+
+    /// Inserts the `count` ids starting at `first`.
+    pub fn insert_range(&mut self, first: NodeId, count: u128) {
+        for (level, truncated) in blocks(first, count) {
+            self.filter.insert(truncated, level);
+            self.levels |= 1 << level;
+            self.entries += 1;
+        }
+    }
+
+    pub fn might_contain(&self, id: NodeId) -> bool {
+        (0..LEVELS).any(|level| {
+            self.levels & (1 << level) != 0
+                && self.filter.contains(id.0 >> (level * LEVEL_BITS), level)
+        })
+    }
+
+    /// Number of entries the filter was sized for.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of entries inserted.
+    pub fn len(&self) -> usize {
+        self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    /// True if more entries have been inserted than the filter was sized for,
+    /// which raises the false positive rate.
+    pub fn is_overfull(&self) -> bool {
+        self.entries > self.capacity
+    }
+
+    pub fn encode(&self, w: &mut Writer) {
+        w.varint(self.capacity as u64);
+        w.varint(self.levels as u64);
+        w.varint(self.entries as u64);
+        w.varint(self.filter.bits.len() as u64);
+        for word in self.filter.bits.iter() {
+            w.u64(*word);
+        }
+    }
+
+    pub fn decode(r: &mut Reader) -> Result<RangeBloomFilter, DecodeError> {
+        let capacity = r.varint()? as usize;
+        let levels = r.u32()?;
+        let entries = r.varint()? as usize;
+        let bits = (0..r.varint()?)
+            .map(|_| r.u64())
+            .collect::<Result<Vec<_>, _>>()?;
+        if bits.is_empty() {
+            return Err(DecodeError("empty bloom filter"));
+        }
+        Ok(RangeBloomFilter {
+            filter: BloomFilter { bits },
+            levels,
+            entries,
+            capacity,
+        })
+    }
+}
+
+/// Splits a range of ids into aligned blocks, each given as `(level, truncated id)`.
+fn blocks(first: NodeId, count: u128) -> impl Iterator<Item = (u32, u128)> {
+    let mut start = first.0;
+    let mut left = count;
+    std::iter::from_fn(move || {
+        if left == 0 {
+            return None;
+        }
+        let mut level = 0;
+        while level + 1 < LEVELS {
+            let size = 1u128 << ((level + 1) * LEVEL_BITS);
+            if start.is_multiple_of(size) && left >= size {
+                level += 1;
+            } else {
+                break;
+            }
+        }
+        let shift = level * LEVEL_BITS;
+        let block = (level, start >> shift);
+        // Wraps only after the last id, u128::MAX.
+        start = start.wrapping_add(1 << shift);
+        left -= 1 << shift;
+        Some(block)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_filter_has_no_false_negatives() {
+        let ranges = [
+            (NodeId(5), 1),
+            (NodeId(1000), 70_000),
+            (NodeId(u64::MAX as u128), 300),
+            (NodeId(u128::MAX), 1),
+        ];
+        let filter = RangeBloomFilter::from_ranges(&ranges);
+        for (first, count) in ranges {
+            for i in 0..count {
+                assert!(filter.might_contain(first.offset(i).unwrap()));
+            }
+        }
+        let false_positives = (200_000..210_000)
+            .filter(|i| filter.might_contain(NodeId(*i)))
+            .count();
+        assert!(false_positives < 500, "{}", false_positives);
+        assert!(!filter.is_overfull());
+
+        let mut w = Writer::default();
+        filter.encode(&mut w);
+        let decoded = RangeBloomFilter::decode(&mut Reader::new(&w.data)).unwrap();
+        assert!((0..250_000)
+            .all(|i| decoded.might_contain(NodeId(i)) == filter.might_contain(NodeId(i))));
+        assert!(!decoded.is_overfull());
+    }
+
+    #[test]
+    fn ranges_are_inserted_as_blocks() {
+        assert_eq!(blocks(NodeId(0), 1 << 20).count(), 1);
+        assert_eq!(blocks(NodeId(1), 1 << 20).count(), 15 * 5 + 1);
+        assert_eq!(blocks(NodeId(u128::MAX - 1), 2).count(), 2);
+    }
+}
@@ -0,0 +1,427 @@
+//! Changesets: serializable batches of edits to a [Forest], used to sync edits between clients.
+//!
+//! Each [Change] is addressed against the state of the forest after the changes before it in the changeset.
+//! Inserted content is kept as chunks, so [UniformChunk](super::uniform_chunk::UniformChunk)s stay compressed in the encoding.
+
+use std::rc::Rc;
+
+use crate::FieldKey;
+
+use super::{
+    edit::EditError,
+    mixed::{Field, Forest},
+    path::{FieldPosition, FieldRange, Path, PathStep},
+    serialize::{read_field, write_field, DecodeError, EncodeError, Reader, Writer},
+    store::ChunkStore,
+};
+
+const CHANGESET_VERSION: u8 = 0;
+
+const TAG_INSERT: u8 = 0;
+const TAG_REMOVE: u8 = 1;
+const TAG_SET_VALUE: u8 = 2;
+const TAG_MOVE: u8 = 3;
+
+/// A single edit.
+#[derive(Clone)]
+pub enum Change {
+    /// Inserts `content` at `at`.
+    Insert { at: FieldPosition, content: Field },
+    /// Detaches the nodes in `range` and deletes them.
+    Remove { range: FieldRange },
+    /// Sets the payload of the node at `path`.
+    SetValue { path: Path, value: Option<Vec<u8>> },
+    /// Moves the nodes in `range` to `to`.
+    /// `to` is addressed against the forest after the nodes have been detached.
+    Move {
+        range: FieldRange,
+        to: FieldPosition,
+    },
+}
+
+/// Sequence of changes, applied in order.
+#[derive(Clone, Default)]
+pub struct Changeset(pub Vec<Change>);
+
+impl Changeset {
+    /// Applies the changes to `forest`.
+    /// If any change fails, the changes before it are undone, so `forest` has the content (and anchors) it had before.
+    pub fn apply(&self, forest: &mut Forest) -> Result<(), EditError> {
+        self.apply_inverted(forest).map(|_| ())
+    }
+
+    /// Changeset which undoes this one when applied to the result of applying this one to `before`.
+    pub fn invert(&self, before: &Forest) -> Result<Changeset, EditError> {
+        self.apply_inverted(&mut before.clone())
+    }
+
+    /// [Changeset::apply], returning the inverse.
+    ///
+    /// Subscribers are notified of the whole changeset as one batch (See [super::observer]).
+    ///
+    /// The changes are applied to `forest` in place. If one fails, the ones before it are undone,
+    /// with removed nodes kept detached until then so they (and anchors to them) can be put back.
+    pub fn apply_inverted(&self, forest: &mut Forest) -> Result<Changeset, EditError> {
+        forest.batched(|forest| {
+            let mut applied: Vec<Applied> = vec![];
+            for change in self.0.iter() {
+                match apply_change(forest, change) {
+                    Ok(done) => applied.push(done),
+                    Err(e) => {
+                        for done in applied.into_iter().rev() {
+                            done.undo(forest);
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+            let mut inverse = vec![];
+            for done in applied.into_iter().rev() {
+                if let Some(removed) = done.removed {
+                    forest.delete_root(&removed);
+                }
+                inverse.push(done.inverse);
+            }
+            Ok(Changeset(inverse))
+        })
+    }
+
+    /// Serializes the changeset.
+    /// Content in [super::mixed::Chunk::Lazy] chunks is written as references to the store it comes from.
+    /// Fails if inserted content has a schema nested too deeply to encode.
+    pub fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+        let mut w = Writer::default();
+        w.u8(CHANGESET_VERSION);
+        w.varint(self.0.len() as u64);
+        for change in self.0.iter() {
+            match change {
+                Change::Insert { at, content } => {
+                    w.u8(TAG_INSERT);
+                    write_position(&mut w, at);
+                    write_field(&mut w, content)?;
+                }
+                Change::Remove { range } => {
+                    w.u8(TAG_REMOVE);
+                    write_range(&mut w, range);
+                }
+                Change::SetValue { path, value } => {
+                    w.u8(TAG_SET_VALUE);
+                    write_path(&mut w, path);
+                    w.optional_bytes(value.as_deref());
+                }
+                Change::Move { range, to } => {
+                    w.u8(TAG_MOVE);
+                    write_range(&mut w, range);
+                    write_position(&mut w, to);
+                }
+            }
+        }
+        Ok(w.data)
+    }
+
+    /// Deserializes a changeset written by [Changeset::encode].
+    /// Chunk references are only allowed if a `store` to load them from is provided.
+    pub fn decode(
+        data: &[u8],
+        store: Option<&Rc<dyn ChunkStore>>,
+    ) -> Result<Changeset, DecodeError> {
+        let mut r = Reader::new(data);
+        if r.u8()? != CHANGESET_VERSION {
+            return Err(DecodeError("unsupported changeset version"));
+        }
+        let changes = (0..r.varint()?)
+            .map(|_| {
+                Ok(match r.u8()? {
+                    TAG_INSERT => Change::Insert {
+                        at: read_position(&mut r)?,
+                        content: read_field(&mut r, store)?,
+                    },
+                    TAG_REMOVE => Change::Remove {
+                        range: read_range(&mut r)?,
+                    },
+                    TAG_SET_VALUE => Change::SetValue {
+                        path: read_path(&mut r)?,
+                        value: r.optional_bytes()?.map(|v| v.to_vec()),
+                    },
+                    TAG_MOVE => Change::Move {
+                        range: read_range(&mut r)?,
+                        to: read_position(&mut r)?,
+                    },
+                    _ => return Err(DecodeError("unknown change tag")),
+                })
+            })
+            .collect::<Result<_, DecodeError>>()?;
+        if !r.is_empty() {
+            return Err(DecodeError("trailing data"));
+        }
+        Ok(Changeset(changes))
+    }
+}
+
+/// A change applied as part of a changeset which has not finished applying.
+struct Applied {
+    inverse: Change,
+    /// For [Change::Remove], the detached field holding the removed nodes,
+    /// which is deleted once the changeset has been applied.
+    removed: Option<FieldKey>,
+}
+
+impl Applied {
+    /// Undoes the change, on the forest as it was right after applying it.
+    fn undo(self, forest: &mut Forest) {
+        let undone = match (&self.inverse, self.removed) {
+            // Put back the removed nodes themselves, so anchors to them are kept.
+            (Change::Insert { at, .. }, Some(removed)) => forest.insert(&removed, at),
+            (inverse, _) => apply_change(forest, inverse).map(|done| {
+                if let Some(removed) = done.removed {
+                    forest.delete_root(&removed);
+                }
+            }),
+        };
+        undone.expect("inverse of an applied change applies");
+    }
+}
+
+/// Applies `change`, and returns its inverse. If it fails, `forest` is left unmodified.
+fn apply_change(forest: &mut Forest, change: &Change) -> Result<Applied, EditError> {
+    let mut removed = None;
+    let inverse = match change {
+        Change::Insert { at, content } => {
+            let count: usize = content.iter().map(|c| c.len()).sum();
+            let detached = forest.build(content.clone());
+            if let Err(e) = forest.insert(&detached, at) {
+                forest.delete_root(&detached);
+                return Err(e);
+            }
+            Change::Remove {
+                range: FieldRange::new(
+                    at.parent.clone(),
+                    at.key.clone(),
+                    at.index,
+                    at.index + count as u32,
+                ),
+            }
+        }
+        Change::Remove { range } => {
+            let detached = forest.detach(range)?;
+            let content = forest.root_chunks(&detached).to_vec();
+            removed = Some(detached);
+            Change::Insert {
+                at: range.start_position(),
+                content,
+            }
+        }
+        Change::SetValue { path, value } => Change::SetValue {
+            path: path.clone(),
+            value: forest.set_value(path, value.clone())?,
+        },
+        Change::Move { range, to } => {
+            let detached = forest.detach(range)?;
+            if let Err(e) = forest.insert(&detached, to) {
+                forest
+                    .insert(&detached, &range.start_position())
+                    .expect("detached nodes can be put back");
+                return Err(e);
+            }
+            Change::Move {
+                range: FieldRange::new(
+                    to.parent.clone(),
+                    to.key.clone(),
+                    to.index,
+                    to.index + range.len(),
+                ),
+                to: range.start_position(),
+            }
+        }
+    };
+    Ok(Applied { inverse, removed })
+}
+
+fn write_path(w: &mut Writer, path: &Path) {
+    w.varint(path.0.len() as u64);
+    for step in path.0.iter() {
+        w.string(&step.key.0);
+        w.varint(step.index as u64);
+    }
+}
+
+fn read_path(r: &mut Reader) -> Result<Path, DecodeError> {
+    (0..r.varint()?)
+        .map(|_| {
+            Ok(PathStep {
+                key: FieldKey(r.string()?),
+                index: r.u32()?,
+            })
+        })
+        .collect::<Result<_, _>>()
+        .map(Path)
+}
+
+fn write_position(w: &mut Writer, position: &FieldPosition) {
+    write_path(w, &position.parent);
+    w.string(&position.key.0);
+    w.varint(position.index as u64);
+}
+
+fn read_position(r: &mut Reader) -> Result<FieldPosition, DecodeError> {
+    Ok(FieldPosition::new(
+        read_path(r)?,
+        FieldKey(r.string()?),
+        r.u32()?,
+    ))
+}
+
+fn write_range(w: &mut Writer, range: &FieldRange) {
+    write_path(w, &range.parent);
+    w.string(&range.key.0);
+    w.varint(range.start as u64);
+    w.varint(range.end as u64);
+}
+
+fn read_range(r: &mut Reader) -> Result<FieldRange, DecodeError> {
+    let parent = read_path(r)?;
+    let key = FieldKey(r.string()?);
+    let start = r.u32()?;
+    let end = r.u32()?;
+    if start > end {
+        return Err(DecodeError("invalid range"));
+    }
+    Ok(FieldRange::new(parent, key, start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::forest::{
+        anchor::AnchorLocation,
+        mixed::Chunk,
+        observer::Scope,
+        test_stuff::{big_tree, describe_root, key, leaf, test_forest},
+        tree::Indexable,
+    };
+
+    fn test_changeset() -> Changeset {
+        let root = Path::detached(key("root"), 0);
+        Changeset(vec![
+            Change::Insert {
+                at: FieldPosition::new(root.clone(), key("a"), 2),
+                content: vec![Chunk::Uniform(Rc::new(big_tree(3))), leaf(3)],
+            },
+            Change::SetValue {
+                path: root.child(key("a"), 1),
+                value: Some(vec![9]),
+            },
+            Change::Remove {
+                range: FieldRange::new(root.clone(), key("a"), 5, 7),
+            },
+            Change::Move {
+                range: FieldRange::new(root.clone(), key("b"), 0, 2),
+                to: FieldPosition::new(root.child(key("a"), 0), key("moved"), 0),
+            },
+        ])
+    }
+
+    #[test]
+    fn encoding_round_trip() {
+        let changeset = test_changeset();
+        let data = changeset.encode().unwrap();
+        let decoded = Changeset::decode(&data, None).unwrap();
+        assert_eq!(decoded.encode(), Ok(data.clone()));
+        match &decoded.0[0] {
+            Change::Insert { content, .. } => assert!(matches!(content[0], Chunk::Uniform(_))),
+            _ => unreachable!(),
+        }
+        for length in 0..data.len() {
+            assert!(Changeset::decode(&data[..length], None).is_err());
+        }
+    }
+
+    #[test]
+    fn apply_and_invert() {
+        let mut forest = test_forest();
+        let before = describe_root(&forest);
+        let changeset = test_changeset();
+        let inverse = changeset.invert(&forest).unwrap();
+
+        changeset.apply(&mut forest).unwrap();
+        let after = describe_root(&forest);
+        assert_ne!(after, before);
+        let root = Path::detached(key("root"), 0);
+        assert_eq!(forest.root(&key("root")).len(), 1);
+        assert!(forest.field_at(&root, &key("b")).unwrap().len() == 0);
+        assert_eq!(
+            forest
+                .field_at(&root.child(key("a"), 0), &key("moved"))
+                .unwrap()
+                .len(),
+            2
+        );
+
+        // The inverse of the inverse redoes the changes.
+        let redo = inverse.invert(&forest).unwrap();
+        inverse.apply(&mut forest).unwrap();
+        assert_eq!(describe_root(&forest), before);
+        redo.apply(&mut forest).unwrap();
+        assert_eq!(describe_root(&forest), after);
+    }
+
+    #[test]
+    fn failed_changes_are_atomic() {
+        let mut forest = test_forest();
+        let before = describe_root(&forest);
+        let root = Path::detached(key("root"), 0);
+        let changeset = Changeset(vec![
+            Change::SetValue {
+                path: root.child(key("b"), 0),
+                value: None,
+            },
+            Change::Remove {
+                range: FieldRange::new(root, key("b"), 0, 3),
+            },
+        ]);
+        assert_eq!(
+            changeset.apply(&mut forest).err(),
+            Some(EditError::OutOfRange)
+        );
+        assert_eq!(describe_root(&forest), before);
+    }
+
+    #[test]
+    fn failed_changesets_are_undone_in_place() {
+        let mut forest = test_forest();
+        let before = describe_root(&forest);
+        let root = Path::detached(key("root"), 0);
+        // Nodes which the changes remove and move.
+        let removed = forest.anchor_node(&root.child(key("a"), 2)).unwrap();
+        let moved = forest.anchor_node(&root.child(key("b"), 1)).unwrap();
+        let events = Rc::new(Cell::new(0));
+        let count = events.clone();
+        forest
+            .subscribe(Scope::Subtree(Path::root()), move |_| {
+                count.set(count.get() + 1)
+            })
+            .unwrap();
+
+        let mut changeset = test_changeset();
+        changeset.0.push(Change::Remove {
+            range: FieldRange::new(root.clone(), key("a"), 0, 100),
+        });
+        assert_eq!(
+            changeset.apply(&mut forest).err(),
+            Some(EditError::OutOfRange)
+        );
+        assert_eq!(describe_root(&forest), before);
+        assert_eq!(forest.roots().count(), 1);
+        assert_eq!(
+            forest.locate(removed),
+            Some(AnchorLocation::Node(root.child(key("a"), 2)))
+        );
+        assert_eq!(
+            forest.locate(moved),
+            Some(AnchorLocation::Node(root.child(key("b"), 1)))
+        );
+        assert_eq!(events.get(), 0);
+    }
+}
@@ -0,0 +1,608 @@
+//! Structural diff: computes a [Changeset] which turns one tree into another.
+//!
+//! Trees are compared through the [Node] trait, so they can be in different representations
+//! (for example a [BasicTree](super::example_node::BasicTree) and a [UniformChunk](super::uniform_chunk::UniformChunk)).
+//! Nodes with the same [NodeData::identity] (or equal [NodeData::subtree_hash]es) are known to be identical without reading them,
+//! and nodes at the same offset in stored chunks with the same [ChunkId](super::store::ChunkId) without even loading them (see [Indexable::stored_chunk]),
+//! so diffing two versions of a forest which share most of their chunks only visits the parts which were edited (and their siblings).
+//!
+//! Within each field, unchanged nodes at the start and end are skipped, and the rest are matched with a longest common subsequence (when small enough).
+//! Unmatched nodes of the same type are updated in place, others are removed and new ones inserted.
+//! Moves are not detected: a moved subtree is removed and inserted.
+//!
+//! Inserted content is copied into [MixedNode]s, one chunk per node.
+
+use std::{collections::BTreeSet, ops::Range, rc::Rc};
+
+use crate::{
+    visit::{walk, Flow, Visitor, WalkOptions},
+    FieldKey,
+};
+
+use super::{
+    changeset::{Change, Changeset},
+    edit::EditError,
+    mixed::{Chunk, Field, Forest, MixedNode, MixedNodeRef},
+    path::{FieldPosition, FieldRange, Path},
+    tree::{Indexable, Node, NodeNav},
+};
+
+/// Largest number of node pairs compared to match up the changed part of a field.
+/// Beyond this, nodes are paired up by index.
+const LCS_LIMIT: usize = 1 << 16;
+
+/// Changeset which turns `before` into `after`, addressed as the field `key` of the node at `parent`.
+/// Fails if content which needs to be compared is pending.
+pub fn diff_field<'a, 'b, A: Node<'a>, B: Node<'b>>(
+    parent: &Path,
+    key: &FieldKey,
+    before: &A::TField,
+    after: &B::TField,
+) -> Result<Changeset, EditError> {
+    let mut out = vec![];
+    changes::<A, B>(parent, key, before, after, &mut out)?;
+    Ok(Changeset(out))
+}
+
+impl Forest {
+    /// Changeset which turns this forest into `after`. See [diff_field].
+    pub fn diff(&self, after: &Forest) -> Result<Changeset, EditError> {
+        let keys: BTreeSet<&FieldKey> = self.roots.keys().chain(after.roots.keys()).collect();
+        let mut out = vec![];
+        for key in keys {
+            changes::<MixedNodeRef, MixedNodeRef>(
+                &Path::root(),
+                key,
+                &self.root(key),
+                &after.root(key),
+                &mut out,
+            )?;
+        }
+        Ok(Changeset(out))
+    }
+}
+
+/// Part of the work of diffing a field, in the order the changes are generated.
+enum Step<A, B, FA, FB> {
+    /// Diff the field `key` of the node at `parent`.
+    Field(Path, FieldKey, FA, FB),
+    /// Diff the node at the path with one of the same type.
+    Update(Path, A, B),
+    Change(Change),
+}
+
+/// Steps for the fields of `A` and `B`.
+type Steps<'a, 'b, A, B> = Vec<Step<A, B, <A as NodeNav<'a>>::TField, <B as NodeNav<'b>>::TField>>;
+
+/// Appends changes for a field to `out`.
+///
+/// Uses an explicit stack, so deep trees do not overflow the call stack.
+fn changes<'a, 'b, A: Node<'a>, B: Node<'b>>(
+    parent: &Path,
+    key: &FieldKey,
+    before: &A::TField,
+    after: &B::TField,
+    out: &mut Vec<Change>,
+) -> Result<(), EditError> {
+    let mut stack: Steps<A, B> = vec![];
+    field::<A, B>(parent, key, before, after, &mut stack)?;
+    stack.reverse();
+    while let Some(step) = stack.pop() {
+        let mut next = vec![];
+        match step {
+            Step::Field(parent, key, before, after) => {
+                field::<A, B>(&parent, &key, &before, &after, &mut next)?
+            }
+            Step::Update(path, x, y) => update(path, &x, &y, &mut next),
+            Step::Change(change) => out.push(change),
+        }
+        stack.extend(next.into_iter().rev());
+    }
+    Ok(())
+}
+
+/// Appends the steps to diff a field to `steps`.
+/// Changes are generated from the end of the field to the start, so the indexes of parts not visited yet stay valid.
+fn field<'a, 'b, A: Node<'a>, B: Node<'b>>(
+    parent: &Path,
+    key: &FieldKey,
+    before: &A::TField,
+    after: &B::TField,
+    steps: &mut Steps<'a, 'b, A, B>,
+) -> Result<(), EditError> {
+    let (n, m) = (before.len(), after.len());
+    let same = |i: usize, j: usize| {
+        if same_stored(before, i, after, j) {
+            return Ok(true);
+        }
+        equal(before.index(i).unwrap(), after.index(j).unwrap())
+    };
+    let mut start = 0;
+    while start < n.min(m) && same(start, start)? {
+        start += 1;
+    }
+    let mut end = 0;
+    while end < n.min(m) - start && same(n - 1 - end, m - 1 - end)? {
+        end += 1;
+    }
+
+    // The end of both fields is added as a match, so every gap is followed by one.
+    let mut matches = lcs(start..n - end, start..m - end, same)?;
+    matches.push((n - end, m - end));
+    let mut gaps = vec![];
+    let (mut i, mut j) = (start, start);
+    for (next_i, next_j) in matches {
+        if next_i > i || next_j > j {
+            gaps.push((i..next_i, j..next_j));
+        }
+        (i, j) = (next_i + 1, next_j + 1);
+    }
+    for (b, a) in gaps.into_iter().rev() {
+        gap::<A, B>(parent, key, before, after, b, a, steps)?;
+    }
+    Ok(())
+}
+/// Longest common subsequence of the nodes in `b` and `a`, as pairs of indexes.
+/// Empty if there are too many pairs to compare.
+fn lcs(
+    b: Range<usize>,
+    a: Range<usize>,
+    same: impl Fn(usize, usize) -> Result<bool, EditError>,
+) -> Result<Vec<(usize, usize)>, EditError> {
+    let (lb, la) = (b.len(), a.len());
+    if lb == 0 || la == 0 || lb * la > LCS_LIMIT {
+        return Ok(vec![]);
+    }
+    // lengths[i][j] is the length of the longest common subsequence of b[i..] and a[j..].
+    let width = la + 1;
+    let mut lengths = vec![0u32; (lb + 1) * width];
+    let mut equal = vec![false; lb * la];
+    for i in (0..lb).rev() {
+        for j in (0..la).rev() {
+            equal[i * la + j] = same(b.start + i, a.start + j)?;
+            lengths[i * width + j] = if equal[i * la + j] {
+                lengths[(i + 1) * width + j + 1] + 1
+            } else {
+                lengths[(i + 1) * width + j].max(lengths[i * width + j + 1])
+            };
+        }
+    }
+    let mut matches = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < lb && j < la {
+        if equal[i * la + j] {
+            matches.push((b.start + i, a.start + j));
+            (i, j) = (i + 1, j + 1);
+        } else if lengths[(i + 1) * width + j] >= lengths[i * width + j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    Ok(matches)
+}
+
+/// Appends steps turning the nodes `b` of `before` into the nodes `a` of `after`.
+/// Nodes are paired up by index: pairs of the same type are updated, and the rest are replaced.
+fn gap<'a, 'b, A: Node<'a>, B: Node<'b>>(
+    parent: &Path,
+    key: &FieldKey,
+    before: &A::TField,
+    after: &B::TField,
+    b: Range<usize>,
+    a: Range<usize>,
+    steps: &mut Steps<'a, 'b, A, B>,
+) -> Result<(), EditError> {
+    let common = b.len().min(a.len());
+    let range = |start: usize, end: usize| {
+        FieldRange::new(parent.clone(), key.clone(), start as u32, end as u32)
+    };
+    let position = |index: usize| FieldPosition::new(parent.clone(), key.clone(), index as u32);
+    if b.len() > common {
+        steps.push(Step::Change(Change::Remove {
+            range: range(b.start + common, b.end),
+        }));
+    }
+    if a.len() > common {
+        steps.push(Step::Change(Change::Insert {
+            at: position(b.start + common),
+            content: copy_range::<B>(after, a.start + common..a.end)?,
+        }));
+    }
+    for k in (0..common).rev() {
+        let (x, y) = (
+            node::<A>(before, b.start + k)?,
+            node::<B>(after, a.start + k)?,
+        );
+        if x.get_def() == y.get_def() {
+            let path = parent.child(key.clone(), (b.start + k) as u32);
+            steps.push(Step::Update(path, x, y));
+        } else {
+            steps.push(Step::Change(Change::Remove {
+                range: range(b.start + k, b.start + k + 1),
+            }));
+            steps.push(Step::Change(Change::Insert {
+                at: position(b.start + k),
+                content: copy_range::<B>(after, a.start + k..a.start + k + 1)?,
+            }));
+        }
+    }
+    Ok(())
+}
+
+/// Appends steps turning `x`, at `path`, into `y`, which has the same type.
+fn update<'a, 'b, A: Node<'a>, B: Node<'b>>(
+    path: Path,
+    x: &A,
+    y: &B,
+    steps: &mut Steps<'a, 'b, A, B>,
+) {
+    if known_equal(x, y) == Some(true) {
+        return;
+    }
+    if x.get_payload() != y.get_payload() {
+        steps.push(Step::Change(Change::SetValue {
+            path: path.clone(),
+            value: y.get_payload().map(|p| p.to_vec()),
+        }));
+    }
+    let mut keys = field_keys(x);
+    keys.extend(field_keys(y));
+    keys.sort();
+    keys.dedup();
+    for key in keys {
+        let (before, after) = (x.get_field(key.clone()), y.get_field(key.clone()));
+        steps.push(Step::Field(path.clone(), key, before, after));
+    }
+}
+
+/// True if node `i` of `a` and node `j` of `b` are the same node of the same stored chunk,
+/// so are identical without loading them.
+fn same_stored(a: &impl Indexable, i: usize, b: &impl Indexable, j: usize) -> bool {
+    match (a.stored_chunk(i), b.stored_chunk(j)) {
+        (Some(x), Some(y)) => x == y && i - a.chunk_range(i).0 == j - b.chunk_range(j).0,
+        _ => false,
+    }
+}
+
+/// Whether the subtrees are identical, if that is known without reading them:
+/// from their [NodeData::identity], or their [NodeData::subtree_hash].
+fn known_equal<'a, 'b, A: Node<'a>, B: Node<'b>>(x: &A, y: &B) -> Option<bool> {
+    if x.identity().is_some() && x.identity() == y.identity() {
+        return Some(true);
+    }
+    Some(x.subtree_hash()? == y.subtree_hash()?)
+}
+
+/// True if the subtrees are identical.
+///
+/// Uses an explicit stack, so deep trees do not overflow the call stack.
+fn equal<'a, 'b, A: Node<'a>, B: Node<'b>>(x: A, y: B) -> Result<bool, EditError> {
+    let mut stack = vec![(x, y)];
+    while let Some((x, y)) = stack.pop() {
+        match known_equal(&x, &y) {
+            Some(true) => continue,
+            Some(false) => return Ok(false),
+            None => {}
+        }
+        if x.is_pending() || y.is_pending() {
+            return Err(EditError::Pending(x.load_error().or(y.load_error())));
+        }
+        if x.get_def() != y.get_def() || x.get_payload() != y.get_payload() {
+            return Ok(false);
+        }
+        let keys = field_keys(&x);
+        if keys != field_keys(&y) {
+            return Ok(false);
+        }
+        // Pushed in reverse, so they are compared in order.
+        for key in keys.into_iter().rev() {
+            let (a, b) = (x.get_field(key.clone()), y.get_field(key));
+            if a.len() != b.len() {
+                return Ok(false);
+            }
+            for i in (0..a.len()).rev() {
+                if !same_stored(&a, i, &b, i) {
+                    stack.push((a.index(i).unwrap(), b.index(i).unwrap()));
+                }
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// Keys of the non-empty fields of `node`, in order.
+fn field_keys<'a, T: Node<'a>>(node: &T) -> Vec<FieldKey> {
+    node.get_fields()
+        .filter(|(_, field)| field.len() != 0)
+        .map(|(key, _)| key.clone())
+        .collect()
+}
+
+fn node<'a, T: Node<'a>>(field: &T::TField, index: usize) -> Result<T, EditError> {
+    match field.index(index) {
+        Some(n) if !n.is_pending() => Ok(n),
+        Some(n) => Err(EditError::Pending(n.load_error())),
+        None => Err(EditError::Pending(None)),
+    }
+}
+
+fn copy_range<'a, T: Node<'a>>(field: &T::TField, range: Range<usize>) -> Result<Field, EditError> {
+    range
+        .map(|i| Ok(Chunk::Node(Rc::new(copy(node::<T>(field, i)?)?))))
+        .collect()
+}
+
+/// [Visitor] which copies the subtrees it walks into [MixedNode]s.
+#[derive(Default)]
+struct Copier {
+    /// Nodes being copied, and the field of each being copied (which is in the next node).
+    nodes: Vec<(MixedNode, Option<(FieldKey, Field)>)>,
+    /// Copies of the nodes the walk started at.
+    copies: Vec<MixedNode>,
+    /// Set if the walk stopped at a pending node.
+    pending: Option<EditError>,
+}
+
+impl<'a, T: Node<'a>> Visitor<T> for Copier {
+    fn enter_node(&mut self, node: &T, _depth: usize) -> Flow {
+        if node.is_pending() {
+            self.pending = Some(EditError::Pending(node.load_error()));
+            return Flow::Stop;
+        }
+        let copy = MixedNode::new(node.get_def(), node.get_payload().map(|p| p.to_vec()));
+        self.nodes.push((copy, None));
+        Flow::Continue
+    }
+
+    fn exit_node(&mut self, _node: &T, _depth: usize) -> Flow {
+        let (copy, _) = self.nodes.pop().unwrap();
+        match self.nodes.last_mut() {
+            Some((_, Some((_, field)))) => field.push(Chunk::Node(Rc::new(copy))),
+            _ => self.copies.push(copy),
+        }
+        Flow::Continue
+    }
+
+    fn enter_field(&mut self, key: &FieldKey, _depth: usize) -> Flow {
+        self.nodes.last_mut().unwrap().1 = Some((key.clone(), vec![]));
+        Flow::Continue
+    }
+
+    fn exit_field(&mut self, _key: &FieldKey, _depth: usize) -> Flow {
+        let (copy, field) = self.nodes.last_mut().unwrap();
+        if let Some((key, field)) = field.take() {
+            if !field.is_empty() {
+                copy.fields_mut().insert(key, field);
+            }
+        }
+        Flow::Continue
+    }
+}
+
+/// Copies the subtree under `node`.
+///
+/// Walks the subtree without recursing, so it can copy trees of any depth.
+fn copy<'a, T: Node<'a>>(node: T) -> Result<MixedNode, EditError> {
+    let mut copier = Copier::default();
+    walk(node, &WalkOptions::default(), &mut copier);
+    match copier.pending {
+        Some(error) => Err(error),
+        None => Ok(copier.copies.pop().unwrap()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::{
+        forest::{
+            example_node::{BasicNode, BasicTree},
+            serialize::encode_chunk,
+            store::{ChunkId, ChunkStore, MemoryStore},
+            test_stuff::{
+                big_tree, deep_node, describe_field, describe_root, forest_with_root, key, leaf,
+                to_basic,
+            },
+            tree::Tree,
+            uniform_chunk::UniformChunkNode,
+        },
+        TreeType,
+    };
+
+    #[test]
+    fn diff_across_representations() {
+        let chunk = big_tree(5);
+        let mut basic = BasicTree(
+            (0..5)
+                .map(|i| to_basic(chunk.view().index(i).unwrap()))
+                .collect(),
+        );
+        let changes = diff_field::<UniformChunkNode, &BasicNode>(
+            &Path::root(),
+            &key("root"),
+            &chunk.view(),
+            &basic.view(),
+        )
+        .unwrap();
+        assert!(changes.0.is_empty());
+
+        basic.0.remove(1);
+        let (_, channel) = basic.0[2].fields.iter_mut().next().unwrap();
+        channel[0].payload = Some(vec![9]);
+        basic.0.push(BasicNode {
+            def: TreeType("new".into()),
+            payload: None,
+            fields: BTreeMap::new(),
+        });
+        let changes = diff_field::<UniformChunkNode, &BasicNode>(
+            &Path::root(),
+            &key("root"),
+            &chunk.view(),
+            &basic.view(),
+        )
+        .unwrap();
+        assert_eq!(changes.0.len(), 3);
+
+        let mut forest = Forest::new();
+        forest.set_root(key("root"), vec![Chunk::Uniform(Rc::new(chunk))]);
+        changes.apply(&mut forest).unwrap();
+        assert_eq!(
+            describe_root(&forest),
+            describe_field::<&BasicNode>(basic.view())
+        );
+    }
+
+    #[test]
+    fn diff_of_edits() {
+        let mut before = Forest::new();
+        before.set_root(
+            key("root"),
+            vec![
+                leaf(1),
+                Chunk::Uniform(Rc::new(big_tree(4))),
+                leaf(2),
+                leaf(3),
+            ],
+        );
+        let root = Path::root();
+        let mut after = before.clone();
+        Changeset(vec![
+            Change::Move {
+                range: FieldRange::new(root.clone(), key("root"), 0, 1),
+                to: FieldPosition::new(root.clone(), key("root"), 4),
+            },
+            Change::SetValue {
+                path: Path::detached(key("root"), 5),
+                value: None,
+            },
+            Change::Insert {
+                at: FieldPosition::new(root.clone(), key("root"), 2),
+                content: vec![leaf(4), leaf(5)],
+            },
+        ])
+        .apply(&mut after)
+        .unwrap();
+
+        let changes = before.diff(&after).unwrap();
+        changes.apply(&mut before).unwrap();
+        assert_eq!(describe_root(&before), describe_root(&after));
+        assert!(before.diff(&after).unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn shared_subtrees_are_skipped() {
+        // A subtree which is pending, so it can not be compared without being skipped.
+        let store: Rc<dyn ChunkStore> = Rc::new(MemoryStore::default());
+        let inner = |id: u128| {
+            let mut inner = MixedNode::new(TreeType("inner".into()), None);
+            inner.fields_mut().insert(
+                key("lazy"),
+                vec![Chunk::lazy(store.clone(), ChunkId(id), 3)],
+            );
+            inner
+        };
+        let forest = |value: u8, inner: MixedNode| {
+            let mut root = MixedNode::new(TreeType("root".into()), Some(vec![value]));
+            root.fields_mut()
+                .insert(key("inner"), vec![Chunk::Node(Rc::new(inner))]);
+            let mut forest = Forest::new();
+            forest.set_root(key("root"), vec![Chunk::Node(Rc::new(root))]);
+            forest
+        };
+
+        let before = forest(1, inner(0));
+        let mut after = before.clone();
+        after
+            .set_value(&Path::detached(key("root"), 0), Some(vec![2]))
+            .unwrap();
+        assert_eq!(before.diff(&after).unwrap().0.len(), 1);
+
+        // Stored chunks with the same id are equal, even if they are not shared.
+        assert_eq!(before.diff(&forest(2, inner(0))).unwrap().0.len(), 1);
+
+        // Other content has to be compared.
+        let other = forest(2, inner(1));
+        assert_eq!(before.diff(&other).err(), Some(EditError::Pending(None)));
+    }
+
+    #[test]
+    fn stored_chunks_are_compared_by_id() {
+        let store: Rc<dyn ChunkStore> = Rc::new(MemoryStore::default());
+        let stored: Vec<ChunkId> = [1, 2, 3, 9]
+            .into_iter()
+            .map(|value| store.store(encode_chunk(&leaf(value)).unwrap()).unwrap().0)
+            .collect();
+        // Each forest has its own lazy chunks, which start out unloaded.
+        let forest = |ids: [ChunkId; 3]| {
+            let mut forest = Forest::new();
+            let field = ids.map(|id| Chunk::lazy(store.clone(), id, 1));
+            forest.set_root(key("root"), field.to_vec());
+            forest
+        };
+        let loaded = |forest: &Forest| {
+            forest
+                .root_chunks(&key("root"))
+                .iter()
+                .map(|chunk| matches!(chunk, Chunk::Lazy(lazy) if lazy.is_loaded()))
+                .collect::<Vec<_>>()
+        };
+
+        let before = forest([stored[0], stored[1], stored[2]]);
+        let same = forest([stored[0], stored[1], stored[2]]);
+        assert!(before.diff(&same).unwrap().0.is_empty());
+        assert_eq!(loaded(&before), [false; 3]);
+        assert_eq!(loaded(&same), [false; 3]);
+
+        // Only the chunks which differ are loaded to compare them.
+        let after = forest([stored[0], stored[3], stored[2]]);
+        let changes = before.diff(&after).unwrap();
+        assert_eq!(changes.0.len(), 1);
+        assert!(matches!(
+            &changes.0[0],
+            Change::SetValue { path, value: Some(value) }
+                if *path == Path::detached(key("root"), 1) && value == &[9]
+        ));
+        assert_eq!(loaded(&before), [false, true, false]);
+        assert_eq!(loaded(&after), [false, true, false]);
+    }
+
+    #[test]
+    fn diff_deep_trees() {
+        const DEPTH: u32 = 200_000;
+        // Built separately, so the subtrees under the roots are only known to be equal from their hashes.
+        let before = forest_with_root(deep_node(DEPTH));
+        let mut root = deep_node(DEPTH);
+        root.set_payload(Some(vec![1]));
+        let after = forest_with_root(root);
+        assert_eq!(before.diff(&after).unwrap().0.len(), 1);
+
+        // Basic nodes have no hashes, so are compared node by node.
+        let node = |fields| BasicNode {
+            def: TreeType("node".into()),
+            payload: Some(vec![0]),
+            fields,
+        };
+        let mut basic = node(BTreeMap::new());
+        for _ in 0..DEPTH {
+            basic = node(BTreeMap::from([(key("child"), vec![basic])]));
+        }
+        basic.payload = Some(vec![1]);
+        let changes = diff_field::<MixedNodeRef, &BasicNode>(
+            &Path::root(),
+            &key("root"),
+            &before.root(&key("root")),
+            &BasicTree(vec![basic]).view(),
+        )
+        .unwrap();
+        assert_eq!(changes.0.len(), 1);
+
+        // Inserting the whole tree copies it.
+        let mut empty = Forest::new();
+        let changes = empty.diff(&after).unwrap();
+        changes.apply(&mut empty).unwrap();
+        assert_eq!(empty.hash(), after.hash());
+    }
+}
@@ -0,0 +1,428 @@
+//! Index based edits of a [Forest]: build, insert and detach, as well as setting values.
+//!
+//! Edits are addressed by the parent node's [Path], a [FieldKey] and indexes within that field.
+//! Content which is not in the tree lives in detached fields (the roots of the forest):
+//! [Forest::build] creates one from new content, [Forest::detach] moves a range of nodes into one,
+//! and [Forest::insert] moves one's content into a field.
+//!
+//! Edits work directly on the chunked storage: shared chunks along the path to the edited field are copied (copy on write),
+//! [Chunk::Lazy] chunks are replaced by their loaded content, and [UniformChunk]s are only split
+//! at the edges of the edited range, or to copy out the one node an edit goes through.
+
+use std::{collections::BTreeMap, ops::Range, rc::Rc};
+
+use crate::FieldKey;
+
+use super::{
+    mixed::{Chunk, Field, Forest, MixedNode, MixedNodeRef},
+    observer::Event,
+    path::{FieldPosition, FieldRange, Path},
+    store::StoreError,
+    tree::{Indexable, NodeNav, Tree},
+};
+
+/// Why an edit could not be applied. The forest is not modified when an edit fails.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EditError {
+    /// A node on the path does not exist.
+    NotFound,
+    /// An index is past the end of its field.
+    OutOfRange,
+    /// Content needed for the edit has not been loaded from the store yet,
+    /// with the error loading it failed with, if any.
+    Pending(Option<StoreError>),
+    /// A detached field can not be inserted into itself.
+    InvalidDestination,
+}
+
+impl Forest {
+    fn new_detached_key(&mut self) -> FieldKey {
+        loop {
+            let key = FieldKey(format!("detached-{}", self.next_detached));
+            self.next_detached += 1;
+            if !self.roots.contains_key(&key) {
+                return key;
+            }
+        }
+    }
+
+    /// Checks that the node at `parent` exists and all content needed to edit the field `key`
+    /// in the range `[start, end)` is available, without modifying anything.
+    /// Returns the length of the field.
+    fn check_field(
+        &self,
+        parent: &Path,
+        key: &FieldKey,
+        start: u32,
+        end: u32,
+    ) -> Result<usize, EditError> {
+        let mut node: Option<MixedNodeRef> = None;
+        for step in parent.0.iter() {
+            let field = match &node {
+                None => self.root(&step.key),
+                Some(n) => n.get_field(step.key.clone()),
+            };
+            node = match field.index(step.index as usize) {
+                None => return Err(EditError::NotFound),
+                Some(MixedNodeRef::Pending(l)) => return Err(EditError::Pending(l.error())),
+                Some(n) => Some(n),
+            };
+        }
+        let field = match &node {
+            None => self.root(key),
+            Some(n) => n.get_field(key.clone()),
+        };
+        let length = field.len();
+        if end as usize > length {
+            return Err(EditError::OutOfRange);
+        }
+        // Splitting a chunk at either end of the range needs it to be loaded.
+        for index in [start as usize, end as usize] {
+            if index < length && field.chunk_range(index).0 != index {
+                if let Some(MixedNodeRef::Pending(l)) = field.index(index) {
+                    return Err(EditError::Pending(l.error()));
+                }
+            }
+        }
+        Ok(length)
+    }
+
+    /// Adds `content` as a new detached field, and returns its key.
+    pub fn build(&mut self, content: Field) -> FieldKey {
+        let key = self.new_detached_key();
+        self.set_root(
+            key.clone(),
+            content.into_iter().filter(|c| !c.is_empty()).collect(),
+        );
+        key
+    }
+
+    /// Moves the nodes in `range` into a new detached field, and returns its key.
+    pub fn detach(&mut self, range: &FieldRange) -> Result<FieldKey, EditError> {
+        self.batched(|forest| forest.detach_in_batch(range))
+    }
+
+    fn detach_in_batch(&mut self, range: &FieldRange) -> Result<FieldKey, EditError> {
+        self.check_field(&range.parent, &range.key, range.start, range.end)?;
+        let key = self.new_detached_key();
+        if !range.is_empty() {
+            let fields = fields_mut(&mut self.roots, &range.parent)?;
+            let field = fields.get_mut(&range.key).ok_or(EditError::OutOfRange)?;
+            let chunks = isolate(field, range.start as usize, range.end as usize)?;
+            let detached: Field = field.drain(chunks).collect();
+            if field.is_empty() {
+                fields.remove(&range.key);
+            }
+            self.roots.insert(key.clone(), detached);
+            self.notify(Event::NodesRemoved {
+                range: range.clone(),
+            });
+        }
+        self.anchors.on_detach(range, &key);
+        Ok(key)
+    }
+
+    /// Moves all content of the detached field `source` to `at`.
+    /// A `source` which does not exist is treated as empty.
+    pub fn insert(&mut self, source: &FieldKey, at: &FieldPosition) -> Result<(), EditError> {
+        self.batched(|forest| forest.insert_in_batch(source, at))
+    }
+
+    fn insert_in_batch(&mut self, source: &FieldKey, at: &FieldPosition) -> Result<(), EditError> {
+        let into_source = match at.parent.0.first() {
+            Some(step) => step.key == *source,
+            None => at.key == *source,
+        };
+        if into_source {
+            return Err(EditError::InvalidDestination);
+        }
+        self.check_field(&at.parent, &at.key, at.index, at.index)?;
+        let content = self.roots.remove(source).unwrap_or_default();
+        let count: usize = content.iter().map(|c| c.len()).sum();
+        if count != 0 {
+            let fields = fields_mut(&mut self.roots, &at.parent)?;
+            let field = fields.entry(at.key.clone()).or_default();
+            let chunk = split_at(field, at.index as usize)?;
+            field.splice(chunk..chunk, content);
+        }
+        self.anchors.on_attach(source, count as u32, at);
+        if count != 0 {
+            self.notify(Event::NodesInserted {
+                at: at.clone(),
+                count: count as u32,
+            });
+        }
+        Ok(())
+    }
+
+    /// Replaces the payload of the node at `path`, and returns the previous payload.
+    pub fn set_value(
+        &mut self,
+        path: &Path,
+        value: Option<Vec<u8>>,
+    ) -> Result<Option<Vec<u8>>, EditError> {
+        self.batched(|forest| forest.set_value_in_batch(path, value))
+    }
+
+    fn set_value_in_batch(
+        &mut self,
+        path: &Path,
+        value: Option<Vec<u8>>,
+    ) -> Result<Option<Vec<u8>>, EditError> {
+        let (parent, last) = path.split_last().ok_or(EditError::NotFound)?;
+        self.check_field(&parent, &last.key, last.index, last.index + 1)
+            .map_err(|e| match e {
+                EditError::OutOfRange => EditError::NotFound,
+                e => e,
+            })?;
+        if let Some(MixedNodeRef::Pending(l)) = self.node_at(path) {
+            return Err(EditError::Pending(l.error()));
+        }
+        let fields = fields_mut(&mut self.roots, &parent)?;
+        let field = fields.get_mut(&last.key).ok_or(EditError::NotFound)?;
+        let node = node_mut(field, last.index as usize)?;
+        let previous = node.set_payload(value.clone());
+        self.ids
+            .set_payload(node.def(), node.payload(), previous.as_deref());
+        self.notify(Event::ValueChanged {
+            path: path.clone(),
+            value,
+        });
+        Ok(previous)
+    }
+}
+
+/// The fields of the node at `parent` (or the roots for the root path), ready to be modified.
+fn fields_mut<'a>(
+    roots: &'a mut BTreeMap<FieldKey, Field>,
+    parent: &Path,
+) -> Result<&'a mut BTreeMap<FieldKey, Field>, EditError> {
+    let mut fields = roots;
+    for step in parent.0.iter() {
+        let field = fields.get_mut(&step.key).ok_or(EditError::NotFound)?;
+        fields = node_mut(field, step.index as usize)?.fields_mut();
+    }
+    Ok(fields)
+}
+
+/// The node at `index` in `field`, split out into its own [Chunk::Node] which is not shared.
+fn node_mut(field: &mut Field, index: usize) -> Result<&mut MixedNode, EditError> {
+    let chunks = isolate(field, index, index + 1)?;
+    let chunk = &mut field[chunks.start];
+    load(chunk)?;
+    if let Chunk::Uniform(u) = chunk {
+        let node = MixedNode::from_uniform(&u.view().index(0).unwrap());
+        *chunk = Chunk::Node(Rc::new(node));
+    }
+    match chunk {
+        Chunk::Node(node) => Ok(Rc::make_mut(node)),
+        _ => unreachable!("loaded chunks are not lazy"),
+    }
+}
+
+/// Splits chunks so the nodes `[start, end)` are exactly the chunks in the returned range.
+fn isolate(field: &mut Field, start: usize, end: usize) -> Result<Range<usize>, EditError> {
+    let first = split_at(field, start)?;
+    let last = split_at(field, end)?;
+    Ok(first..last)
+}
+
+/// Splits chunks so a chunk starts at `index`, and returns that chunk's index in `field`
+/// (which is `field.len()` if `index` is the end of the field).
+fn split_at(field: &mut Field, index: usize) -> Result<usize, EditError> {
+    let mut start = 0;
+    for i in 0..field.len() {
+        if index == start {
+            return Ok(i);
+        }
+        let length = field[i].len();
+        if index < start + length {
+            load(&mut field[i])?;
+            let (before, after) = match &field[i] {
+                Chunk::Uniform(u) => (u.slice(0, index - start), u.slice(index - start, length)),
+                _ => unreachable!("only uniform chunks contain multiple nodes"),
+            };
+            field.splice(
+                i..=i,
+                [
+                    Chunk::Uniform(Rc::new(before)),
+                    Chunk::Uniform(Rc::new(after)),
+                ],
+            );
+            return Ok(i + 1);
+        }
+        start += length;
+    }
+    if index == start {
+        Ok(field.len())
+    } else {
+        Err(EditError::OutOfRange)
+    }
+}
+
+/// Replaces a [Chunk::Lazy] with its loaded content.
+fn load(chunk: &mut Chunk) -> Result<(), EditError> {
+    let loaded = match chunk {
+        Chunk::Lazy(lazy) => lazy
+            .get()
+            .ok_or_else(|| EditError::Pending(lazy.error()))?
+            .clone(),
+        _ => return Ok(()),
+    };
+    *chunk = loaded;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        forest::{
+            anchor::AnchorLocation,
+            store::{ChunkId, ChunkStore, MemoryStore},
+            test_stuff::{big_tree, forest_with_root, key, walk_all_field},
+            uniform_chunk::UniformChunk,
+        },
+        TreeType,
+    };
+
+    fn lengths(chunks: &[Chunk]) -> Vec<(bool, usize)> {
+        chunks
+            .iter()
+            .map(|c| (matches!(c, Chunk::Uniform(_)), c.len()))
+            .collect()
+    }
+
+    /// Root node with a field "children" holding a uniform chunk of `count` nodes.
+    fn edit_forest(chunk: &UniformChunk) -> (Forest, Path) {
+        let mut root = MixedNode::new(TreeType("root".into()), None);
+        root.fields_mut().insert(
+            key("children"),
+            vec![Chunk::Uniform(Rc::new(chunk.clone()))],
+        );
+        (forest_with_root(root), Path::detached(key("root"), 0))
+    }
+
+    fn children<'a>(forest: &'a Forest, root: &Path) -> &'a [Chunk] {
+        match &forest.root_chunks(&root.0[0].key)[0] {
+            Chunk::Node(n) => &n.fields()[&key("children")],
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn detach_and_insert() {
+        let (mut forest, root) = edit_forest(&big_tree(10));
+        let moved = forest.anchor_node(&root.child(key("children"), 6)).unwrap();
+
+        let detached = forest
+            .detach(&FieldRange::new(root.clone(), key("children"), 3, 5))
+            .unwrap();
+        assert_eq!(lengths(children(&forest, &root)), [(true, 3), (true, 5)]);
+        assert_eq!(lengths(forest.root_chunks(&detached)), [(true, 2)]);
+        assert_eq!(
+            forest.locate(moved),
+            Some(AnchorLocation::Node(root.child(key("children"), 4)))
+        );
+
+        // Put the detached nodes back at the start.
+        forest
+            .insert(
+                &detached,
+                &FieldPosition::new(root.clone(), key("children"), 0),
+            )
+            .unwrap();
+        assert!(forest.root_chunks(&detached).is_empty());
+        assert_eq!(
+            lengths(children(&forest, &root)),
+            [(true, 2), (true, 3), (true, 5)]
+        );
+        assert_eq!(
+            forest.locate(moved),
+            Some(AnchorLocation::Node(root.child(key("children"), 6)))
+        );
+        assert_eq!(
+            walk_all_field::<MixedNodeRef>(forest.root(&key("root"))),
+            1 + 10 * 5
+        );
+
+        // Build new content and insert it into a new field.
+        let built = forest.build(vec![Chunk::Node(Rc::new(MixedNode::new(
+            TreeType("leaf".into()),
+            None,
+        )))]);
+        forest
+            .insert(&built, &FieldPosition::new(root.clone(), key("other"), 0))
+            .unwrap();
+        assert!(forest.node_at(&root.child(key("other"), 0)).is_some());
+    }
+
+    #[test]
+    fn edits_inside_uniform_chunks_split_one_node() {
+        let chunk = big_tree(10);
+        let channel = chunk.schema().fields()[0].0.clone();
+        let (mut forest, root) = edit_forest(&chunk);
+        let node = root.child(key("children"), 4);
+
+        let detached = forest
+            .detach(&FieldRange::new(node.clone(), channel.clone(), 0, 1))
+            .unwrap();
+        assert_eq!(
+            lengths(children(&forest, &root)),
+            [(true, 4), (false, 1), (true, 5)]
+        );
+        assert_eq!(
+            walk_all_field::<MixedNodeRef>(forest.root(&key("root"))),
+            1 + 10 * 5 - 1
+        );
+        // The emptied field is removed.
+        assert_eq!(forest.node_at(&node).unwrap().get_fields().count(), 3);
+
+        forest
+            .insert(&detached, &FieldPosition::new(node.clone(), channel, 0))
+            .unwrap();
+        assert_eq!(forest.node_at(&node).unwrap().get_fields().count(), 4);
+    }
+
+    #[test]
+    fn invalid_edits_do_not_modify() {
+        let (mut forest, root) = edit_forest(&big_tree(4));
+        let children_range =
+            |start, end| FieldRange::new(root.clone(), key("children"), start, end);
+        assert_eq!(
+            forest.detach(&children_range(2, 5)),
+            Err(EditError::OutOfRange)
+        );
+        assert_eq!(
+            forest.detach(&FieldRange::new(
+                root.child(key("children"), 4),
+                key("x"),
+                0,
+                0
+            )),
+            Err(EditError::NotFound)
+        );
+        let inner = forest.detach(&children_range(0, 1)).unwrap();
+        assert_eq!(
+            forest.insert(
+                &inner,
+                &FieldPosition::new(Path::detached(inner.clone(), 0), key("x"), 0)
+            ),
+            Err(EditError::InvalidDestination)
+        );
+
+        // Chunks which are not in the store are pending.
+        let store: Rc<dyn ChunkStore> = Rc::new(MemoryStore::default());
+        forest.set_root(key("lazy"), vec![Chunk::lazy(store, ChunkId(1), 3)]);
+        assert_eq!(
+            forest.detach(&FieldRange::new(Path::root(), key("lazy"), 1, 2)),
+            Err(EditError::Pending(None))
+        );
+        // Whole chunks can be moved without loading them.
+        let whole = forest
+            .detach(&FieldRange::new(Path::root(), key("lazy"), 0, 3))
+            .unwrap();
+        assert_eq!(forest.root(&whole).len(), 3);
+        assert_eq!(lengths(children(&forest, &root)), [(true, 3)]);
+    }
+}
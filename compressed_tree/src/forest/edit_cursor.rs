@@ -0,0 +1,646 @@
+//! Cursors which can edit the [Forest] they navigate.
+//!
+//! [EditNodes] and [EditFields] implement the [NodesCursor] and [FieldsCursor] traits like [GenericNodesCursor](crate::cursor::GenericNodesCursor),
+//! but hold the forest mutably and track their position as a [Path],
+//! so they can set the current node's value and insert or delete nodes around it.
+//! They keep the fields along their path resolved between operations, and only resolve them again from the root after an edit.
+//! Edits go through [super::edit], so they copy shared chunks on write, update anchors and notify subscribers.
+
+use self_cell::self_cell;
+
+use crate::{
+    cursor::is_pending_field, CursorError, CursorFailure, CursorResult, EitherCursor, FieldKey,
+    FieldsCursor, NodesCursor, TreeType, Value,
+};
+
+use super::{
+    edit::EditError,
+    mixed::{Field, Forest, MixedField, MixedNodeRef},
+    path::{FieldPosition, FieldRange, Path, PathStep},
+    store::StoreError,
+    tree::{Indexable, NodeData, NodeNav},
+};
+
+/// Editing cursor at a node.
+pub struct EditNodes<'a> {
+    /// Has the fields containing each node of `path`.
+    live: Live<'a>,
+    /// Empty at the root, whose fields are the forest's detached fields.
+    path: Path,
+}
+
+/// Editing cursor at a field.
+pub struct EditFields<'a> {
+    /// Has the fields containing each node of `parent`, then the current field.
+    live: Live<'a>,
+    /// Node containing the field: the root path for detached fields.
+    parent: Path,
+    key: FieldKey,
+}
+
+/// Fields resolved in a forest, from a detached field down.
+type Fields<'b> = Vec<MixedField<'b>>;
+
+self_cell!(
+    /// A forest, and fields borrowed from it.
+    struct Resolved<'a> {
+        owner: &'a mut Forest,
+
+        #[covariant]
+        dependent: Fields,
+    }
+);
+
+/// The forest being edited, and the fields along a cursor's path.
+///
+/// The fields borrow the forest, so they are dropped to edit it, and resolved again after.
+struct Live<'a>(Option<Resolved<'a>>);
+
+const DROPPED: &str = "fields are only dropped during edits";
+
+impl<'a> Live<'a> {
+    /// Resolves the field containing each node of `steps`, then the field `key` of the last one (if any).
+    fn new(forest: &'a mut Forest, steps: &[PathStep], key: Option<&FieldKey>) -> Self {
+        Live(Some(Resolved::new(forest, |forest| {
+            let mut fields = Fields::new();
+            let keys = steps.iter().map(|step| &step.key).chain(key);
+            for (depth, key) in keys.enumerate() {
+                let field = field_in(forest, &fields, &steps[..depth], key);
+                fields.push(field);
+            }
+            fields
+        })))
+    }
+
+    fn resolved(&self) -> &Resolved<'a> {
+        self.0.as_ref().expect(DROPPED)
+    }
+
+    fn resolved_mut(&mut self) -> &mut Resolved<'a> {
+        self.0.as_mut().expect(DROPPED)
+    }
+
+    fn forest(&self) -> &Forest {
+        self.resolved().borrow_owner()
+    }
+
+    fn fields(&self) -> &[MixedField<'_>] {
+        self.resolved().borrow_dependent()
+    }
+
+    /// Adds the field `key` of the node at `steps`, which is the last node the fields contain.
+    fn push(&mut self, steps: &[PathStep], key: &FieldKey) {
+        self.resolved_mut().with_dependent_mut(|forest, fields| {
+            let field = field_in(forest, fields, steps, key);
+            fields.push(field);
+        });
+    }
+
+    fn pop(&mut self) {
+        self.resolved_mut()
+            .with_dependent_mut(|_, fields| fields.pop());
+    }
+
+    /// Edits the forest with `f`, then resolves the fields along `steps` and `key` again,
+    /// since the edit may have replaced the chunks they were in.
+    fn edit<R>(
+        &mut self,
+        steps: &[PathStep],
+        key: Option<&FieldKey>,
+        f: impl FnOnce(&mut Forest) -> R,
+    ) -> R {
+        let forest = self.0.take().expect(DROPPED).into_owner();
+        let result = f(&mut *forest);
+        *self = Live::new(forest, steps, key);
+        result
+    }
+}
+
+/// The node at `steps`, given the fields containing each of its nodes. None for the root.
+fn node_in<'b>(fields: &[MixedField<'b>], steps: &[PathStep]) -> Option<MixedNodeRef<'b>> {
+    let last = steps.last()?;
+    let node = fields[steps.len() - 1].index(last.index as usize);
+    Some(node.expect("cursor is at an existing node"))
+}
+
+/// The field `key` of the node at `steps` (a detached field, for the root).
+fn field_in<'b>(
+    forest: &'b Forest,
+    fields: &[MixedField<'b>],
+    steps: &[PathStep],
+    key: &FieldKey,
+) -> MixedField<'b> {
+    match node_in(fields, steps) {
+        Some(node) => node.get_field(key.clone()),
+        None => forest.root(key),
+    }
+}
+
+impl Forest {
+    /// An editing cursor at the node at `path` (or the root, for the root path), if it exists.
+    pub fn edit_cursor(&mut self, path: &Path) -> Option<EditNodes<'_>> {
+        if !path.is_root() {
+            self.node_at(path)?;
+        }
+        Some(EditNodes {
+            live: Live::new(self, &path.0, None),
+            path: path.clone(),
+        })
+    }
+
+    /// An editing cursor at the field `key` of the node at `parent`, if that node exists.
+    pub fn edit_field_cursor(&mut self, parent: &Path, key: FieldKey) -> Option<EditFields<'_>> {
+        self.field_at(parent, &key)?;
+        Some(EditFields {
+            live: Live::new(self, &parent.0, Some(&key)),
+            parent: parent.clone(),
+            key,
+        })
+    }
+}
+
+impl<'a> EditNodes<'a> {
+    /// Path to the current node.
+    pub fn path(&self) -> Path {
+        self.path.clone()
+    }
+
+    /// The field containing the current node, and its index in it. None for the root.
+    fn field(&self) -> Option<(MixedField<'_>, usize)> {
+        let last = self.path.0.last()?;
+        let field = self.live.fields().last().expect("cursor is in a field");
+        Some((field.clone(), last.index as usize))
+    }
+
+    /// The current node. None for the root.
+    fn node(&self) -> Option<MixedNodeRef<'_>> {
+        node_in(self.live.fields(), &self.path.0)
+    }
+
+    /// If the current node is `pending` because loading it failed, the error it failed with.
+    pub fn pending_error(&self) -> Option<StoreError> {
+        self.node().and_then(|node| node.load_error())
+    }
+
+    /// The position `offset` nodes after the current one. The root is not in a field, so has none.
+    fn position(&self, offset: u32) -> Result<FieldPosition, EditError> {
+        let (parent, last) = self.path.split_last().ok_or(EditError::NotFound)?;
+        Ok(FieldPosition::new(
+            parent,
+            last.key.clone(),
+            last.index + offset,
+        ))
+    }
+
+    /// Edits the forest with `f`. Edits keep the cursor's path, except the index of the current node.
+    fn edit<R>(&mut self, f: impl FnOnce(&mut Forest) -> R) -> R {
+        self.live.edit(&self.path.0, None, f)
+    }
+
+    /// Replaces the current node's payload, and returns the previous one.
+    pub fn set_value(&mut self, value: Option<Vec<u8>>) -> Result<Option<Vec<u8>>, EditError> {
+        let path = self.path.clone();
+        self.edit(|forest| forest.set_value(&path, value))
+    }
+
+    /// Inserts `content` before the current node. The cursor stays at the same node.
+    pub fn insert_before(&mut self, content: Field) -> Result<(), EditError> {
+        let at = self.position(0)?;
+        let count = self.edit(|forest| insert(forest, &at, content))?;
+        self.path.0.last_mut().unwrap().index += count;
+        Ok(())
+    }
+
+    /// Inserts `content` after the current node. The cursor stays at the same node.
+    pub fn insert_after(&mut self, content: Field) -> Result<(), EditError> {
+        let at = self.position(1)?;
+        self.edit(|forest| insert(forest, &at, content))?;
+        Ok(())
+    }
+
+    /// Deletes `count` nodes starting at the current one.
+    ///
+    /// Moves to the node after them, or to the field if there is none.
+    /// If the nodes can not be deleted, nothing is changed and the cursor is returned with the error.
+    pub fn delete(
+        mut self,
+        count: u32,
+    ) -> Result<EitherCursor<Self, EditFields<'a>>, (Self, EditError)> {
+        let range = match self.position(0) {
+            Ok(at) => FieldRange::new(at.parent, at.key, at.index, at.index + count),
+            Err(e) => return Err((self, e)),
+        };
+        let deleted = self.edit(|forest| match forest.detach(&range) {
+            Ok(detached) => {
+                forest.delete_root(&detached);
+                Ok(())
+            }
+            Err(e) => Err(e),
+        });
+        if let Err(e) = deleted {
+            return Err((self, e));
+        }
+        match self.field() {
+            Some((field, index)) if index < field.len() => Ok(EitherCursor::Nodes(self)),
+            _ => Ok(EitherCursor::Fields(self.field_cursor())),
+        }
+    }
+
+    /// The field containing this node. Only the root is not in a field.
+    fn field_cursor(mut self) -> EditFields<'a> {
+        let last = self.path.0.pop().expect("root is not in a field");
+        EditFields {
+            live: self.live,
+            parent: self.path,
+            key: last.key,
+        }
+    }
+}
+
+/// Inserts `content` at `at`, and returns the number of nodes inserted.
+fn insert(forest: &mut Forest, at: &FieldPosition, content: Field) -> Result<u32, EditError> {
+    let count = content.iter().map(|c| c.len() as u32).sum();
+    let source = forest.build(content);
+    if let Err(e) = forest.insert(&source, at) {
+        forest.delete_root(&source);
+        return Err(e);
+    }
+    Ok(count)
+}
+
+impl<'a> NodesCursor for EditNodes<'a> {
+    type TFields = EditFields<'a>;
+
+    fn field_index(&self) -> u32 {
+        self.path.0.last().map_or(0, |step| step.index)
+    }
+
+    fn chunk_start(&self) -> u32 {
+        self.field()
+            .map_or(0, |(field, index)| field.chunk_range(index).0 as u32)
+    }
+
+    fn chunk_length(&self) -> u32 {
+        self.field()
+            .map_or(1, |(field, index)| field.chunk_range(index).1 as u32)
+    }
+
+    fn seek_nodes(mut self, offset: i32) -> CursorResult<EitherCursor<Self, Self::TFields>, Self> {
+        let index = self.field_index() as i64 + offset as i64;
+        // The root is alone in its (virtual) field.
+        let len = self.field().map_or(1, |(field, _)| field.len());
+        if index < 0 || index >= len as i64 {
+            Ok(EitherCursor::Fields(self.exit_node()?))
+        } else {
+            if let Some(last) = self.path.0.last_mut() {
+                last.index = index as u32;
+            }
+            Ok(EitherCursor::Nodes(self))
+        }
+    }
+
+    fn next_node(self) -> CursorResult<EitherCursor<Self, Self::TFields>, Self> {
+        self.seek_nodes(1)
+    }
+
+    fn exit_node(self) -> CursorResult<Self::TFields, Self> {
+        if self.path.is_root() {
+            return Err(CursorFailure::new(self, CursorError::AtRoot));
+        }
+        Ok(self.field_cursor())
+    }
+
+    fn is_root(&self) -> bool {
+        self.path.is_root()
+    }
+
+    fn path(&self) -> Path {
+        self.path.clone()
+    }
+
+    fn first_field(self) -> EitherCursor<Self, Self::TFields> {
+        let first = match self.node() {
+            Some(node) => node.get_fields().next().map(|(key, _)| key.clone()),
+            None => self
+                .live
+                .forest()
+                .roots()
+                .next()
+                .map(|(key, _)| key.clone()),
+        };
+        match first {
+            Some(key) => self.enter_field(key),
+            None => EitherCursor::Nodes(self),
+        }
+    }
+
+    fn enter_field(mut self, key: FieldKey) -> EitherCursor<Self, Self::TFields> {
+        self.live.push(&self.path.0, &key);
+        EitherCursor::Fields(EditFields {
+            live: self.live,
+            parent: self.path,
+            key,
+        })
+    }
+
+    /// The root's type is the empty string.
+    fn node_type(&self) -> TreeType {
+        self.node()
+            .map_or(TreeType(String::new()), |node| node.get_def())
+    }
+
+    fn value(&self) -> Value {
+        let node = match self.node() {
+            Some(node) => node,
+            None => return Value(None),
+        };
+        Value::of(node.get_payload())
+    }
+
+    fn payload(&self) -> Option<Vec<u8>> {
+        self.node()?.get_payload().map(|p| p.to_vec())
+    }
+
+    fn pending(&self) -> bool {
+        self.node().is_some_and(|node| node.is_pending())
+    }
+}
+
+impl<'a> EditFields<'a> {
+    /// Path to the node containing the current field.
+    pub fn parent(&self) -> &Path {
+        &self.parent
+    }
+
+    /// Inserts `content` at `index` in the current field.
+    pub fn insert(&mut self, index: u32, content: Field) -> Result<(), EditError> {
+        let at = FieldPosition::new(self.parent.clone(), self.key.clone(), index);
+        self.edit(|forest| insert(forest, &at, content))?;
+        Ok(())
+    }
+
+    /// Edits the forest with `f`. Edits keep the cursor's path.
+    fn edit<R>(&mut self, f: impl FnOnce(&mut Forest) -> R) -> R {
+        self.live.edit(&self.parent.0, Some(&self.key), f)
+    }
+
+    /// The current field.
+    fn field(&self) -> &MixedField<'_> {
+        self.live.fields().last().expect("cursor is in a field")
+    }
+
+    fn child(self, index: u32) -> EditNodes<'a> {
+        EditNodes {
+            path: self.parent.child(self.key, index),
+            live: self.live,
+        }
+    }
+
+    fn parent_cursor(mut self) -> EditNodes<'a> {
+        self.live.pop();
+        EditNodes {
+            live: self.live,
+            path: self.parent,
+        }
+    }
+
+    /// Deletes the nodes in `[start, end)` of the current field.
+    pub fn delete_range(&mut self, start: u32, end: u32) -> Result<(), EditError> {
+        let range = FieldRange::new(self.parent.clone(), self.key.clone(), start, end);
+        self.edit(|forest| {
+            let detached = forest.detach(&range)?;
+            forest.delete_root(&detached);
+            Ok(())
+        })
+    }
+}
+
+impl<'a> FieldsCursor for EditFields<'a> {
+    type TNodes = EditNodes<'a>;
+
+    fn next_field(mut self) -> EitherCursor<Self::TNodes, Self> {
+        let next = match node_in(self.live.fields(), &self.parent.0) {
+            Some(node) => node
+                .get_fields()
+                .map(|(key, _)| key)
+                .find(|key| **key > self.key)
+                .cloned(),
+            None => self
+                .live
+                .forest()
+                .roots()
+                .map(|(key, _)| key)
+                .find(|key| **key > self.key)
+                .cloned(),
+        };
+        match next {
+            Some(key) => {
+                self.live.pop();
+                self.live.push(&self.parent.0, &key);
+                EitherCursor::Fields(EditFields { key, ..self })
+            }
+            None => EitherCursor::Nodes(self.parent_cursor()),
+        }
+    }
+
+    /// Detached fields exit to the root, so this never fails.
+    fn exit_field(self) -> CursorResult<Self::TNodes, Self> {
+        Ok(self.parent_cursor())
+    }
+
+    fn skip_pending_fields(self) -> EitherCursor<Self::TNodes, Self> {
+        let mut fields = self;
+        while is_pending_field::<MixedNodeRef>(fields.field()) {
+            fields = match fields.next_field() {
+                EitherCursor::Fields(f) => f,
+                nodes => return nodes,
+            };
+        }
+        EitherCursor::Fields(fields)
+    }
+
+    fn field_key(&self) -> FieldKey {
+        self.key.clone()
+    }
+
+    fn get_field_length(&self) -> u32 {
+        self.field().len() as u32
+    }
+
+    fn first_node(self) -> EitherCursor<Self::TNodes, Self> {
+        if self.get_field_length() > 0 {
+            EitherCursor::Nodes(self.child(0))
+        } else {
+            EitherCursor::Fields(self)
+        }
+    }
+
+    fn enter_node(self, child_index: u32) -> CursorResult<Self::TNodes, Self> {
+        if child_index < self.get_field_length() {
+            Ok(self.child(child_index))
+        } else {
+            Err(CursorFailure::new(self, CursorError::OutOfRange))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::forest::{
+        mixed::{Chunk, MixedNode},
+        observer::{Event, Scope},
+        test_stuff::{big_tree, describe_field, key},
+    };
+
+    /// A field holding one "leaf" node whose payload is `value`.
+    fn number(value: f64) -> Field {
+        vec![Chunk::Node(Rc::new(MixedNode::new(
+            TreeType("leaf".into()),
+            Some(value.to_le_bytes().to_vec()),
+        )))]
+    }
+
+    fn values(forest: &Forest) -> Vec<Option<f64>> {
+        let field = forest.root(&key("root"));
+        (0..field.len())
+            .map(|i| {
+                let payload = field.index(i).unwrap().get_payload().map(|p| p.to_vec());
+                payload.map(|p| f64::from_le_bytes(p.try_into().unwrap()))
+            })
+            .collect()
+    }
+
+    /// Counts the nodes in the subtree at `nodes`, and returns the cursor there.
+    fn count<N: NodesCursor>(nodes: N) -> (N, usize) {
+        let mut total = 1;
+        let mut fields = match nodes.first_field() {
+            EitherCursor::Fields(f) => f,
+            EitherCursor::Nodes(n) => return (n, total),
+        };
+        loop {
+            let mut next = fields.first_node();
+            while let EitherCursor::Nodes(child) = next {
+                let (child, n) = count(child);
+                total += n;
+                next = child.next_node().ok().expect("nodes are in a field");
+            }
+            let EitherCursor::Fields(done) = next else {
+                unreachable!()
+            };
+            fields = match done.next_field() {
+                EitherCursor::Fields(f) => f,
+                EitherCursor::Nodes(n) => return (n, total),
+            };
+        }
+    }
+
+    #[test]
+    fn navigation_and_edits_keep_fields_resolved() {
+        let mut forest = Forest::new();
+        forest.set_root(key("root"), vec![Chunk::Uniform(Rc::new(big_tree(5)))]);
+        forest.set_root(key("other"), [number(1.0), number(2.0)].concat());
+        let at = Path::detached(key("root"), 2);
+        let total = count(forest.cursor()).1;
+        let subtree = count(forest.cursor_at(&at).unwrap()).1;
+
+        let (root, n) = count(forest.edit_cursor(&Path::root()).unwrap());
+        assert_eq!(n, total);
+        let node = match root.enter_field(key("root")) {
+            EitherCursor::Fields(f) => f.enter_node(2).unwrap(),
+            EitherCursor::Nodes(_) => panic!(),
+        };
+        let mut child = match node.first_field() {
+            EitherCursor::Fields(f) => f.enter_node(0).unwrap(),
+            EitherCursor::Nodes(_) => panic!(),
+        };
+        // Edits copy the chunks the cursor's fields are in, so it has to read the copies after.
+        child.set_value(Some(vec![7])).unwrap();
+        child.insert_after(number(3.0)).unwrap();
+        assert_eq!(child.payload(), Some(vec![7]));
+        let node = child.exit_node().unwrap().exit_field().unwrap();
+        let (node, n) = count(node);
+        assert_eq!(n, subtree + 1);
+        assert_eq!(node.path(), at);
+        drop(node);
+        assert_eq!(count(forest.cursor()).1, total + 1);
+    }
+
+    #[test]
+    fn edit_values_and_structure() {
+        let mut forest = Forest::new();
+        forest.set_root(
+            key("root"),
+            [number(1.0), number(2.0), number(3.0)].concat(),
+        );
+
+        let mut cursor = forest.edit_cursor(&Path::detached(key("root"), 1)).unwrap();
+        assert_eq!(
+            cursor.set_value(Some(5f64.to_le_bytes().to_vec())),
+            Ok(Some(2f64.to_le_bytes().to_vec()))
+        );
+        cursor.insert_before(number(4.0)).unwrap();
+        assert_eq!(cursor.field_index(), 2);
+        assert_eq!(cursor.value().0, Some(5.0));
+        cursor.insert_after(number(6.0)).unwrap();
+
+        let cursor = match cursor.delete(1) {
+            Ok(EitherCursor::Nodes(n)) => n,
+            _ => panic!(),
+        };
+        assert_eq!(cursor.value().0, Some(6.0));
+        let (cursor, error) = cursor.delete(3).err().unwrap();
+        assert_eq!(error, EditError::OutOfRange);
+        let mut fields = match cursor.delete(2) {
+            Ok(EitherCursor::Fields(f)) => f,
+            _ => panic!(),
+        };
+        assert_eq!(fields.get_field_length(), 2);
+        fields.insert(2, number(7.0)).unwrap();
+        drop(fields);
+        assert_eq!(values(&forest), vec![Some(1.0), Some(4.0), Some(7.0)]);
+    }
+
+    #[test]
+    fn edits_copy_on_write_and_notify() {
+        let mut forest = Forest::new();
+        forest.set_root(key("root"), vec![Chunk::Uniform(Rc::new(big_tree(5)))]);
+        let original = forest.clone();
+        let events = Rc::new(RefCell::new(vec![]));
+        let log = events.clone();
+        forest
+            .subscribe(Scope::Subtree(Path::root()), move |e| {
+                log.borrow_mut().push(matches!(e, Event::BatchCompleted))
+            })
+            .unwrap();
+
+        let cursor = forest.edit_cursor(&Path::detached(key("root"), 2)).unwrap();
+        let fields = match cursor.first_field() {
+            EitherCursor::Fields(f) => f,
+            EitherCursor::Nodes(_) => panic!(),
+        };
+        let child = fields.field_key();
+        let mut node = fields.enter_node(0).unwrap();
+        node.set_value(None).unwrap();
+        assert_eq!(node.path(), Path::detached(key("root"), 2).child(child, 0));
+        let fields = node.exit_node().unwrap().exit_field().unwrap();
+        let fields = fields.exit_node().unwrap();
+        assert_eq!(fields.field_key(), key("root"));
+        let root = fields.exit_field().unwrap();
+        assert!(root.is_root());
+        let failure = root.exit_node().err().unwrap();
+        assert_eq!(failure.error, CursorError::AtRoot);
+        assert_eq!(failure.cursor.path(), Path::root());
+        drop(failure);
+
+        assert_eq!(*events.borrow(), vec![false, true]);
+        assert_eq!(forest.root_chunks(&key("root")).len(), 3);
+        assert_ne!(
+            describe_field::<MixedNodeRef>(forest.root(&key("root"))),
+            describe_field::<MixedNodeRef>(original.root(&key("root")))
+        );
+        assert_eq!(original.root_chunks(&key("root")).len(), 1);
+    }
+}
@@ -1,15 +1,16 @@
 //! Simple tree that owns its children.
 //! This serves as an example of the simplest way to implement Node, and is not actually used.
 
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
 use crate::{FieldKey, TreeType};
 
 use super::{
-    tree::{NodeData, NodeNav, Tree},
+    tree::{NodeData, NodeIdentity, NodeNav, Tree},
     util::ImSlice,
 };
 
+#[derive(Clone)]
 pub struct BasicTree(pub Vec<BasicNode>);
 
 impl Tree for BasicTree {
@@ -20,10 +21,24 @@ impl Tree for BasicTree {
     }
 }
 
+#[derive(Clone)]
 pub struct BasicNode {
     pub def: TreeType,
     pub payload: Option<Vec<u8>>,
-    pub fields: HashMap<FieldKey, Vec<BasicNode>>,
+    pub fields: BTreeMap<FieldKey, Vec<BasicNode>>,
+}
+
+/// Drops descendants using an explicit stack, so dropping deep trees does not overflow the call stack.
+impl Drop for BasicNode {
+    fn drop(&mut self) {
+        let mut stack: Vec<BasicNode> = std::mem::take(&mut self.fields)
+            .into_values()
+            .flatten()
+            .collect();
+        while let Some(mut node) = stack.pop() {
+            stack.extend(std::mem::take(&mut node.fields).into_values().flatten());
+        }
+    }
 }
 
 impl<'a> NodeNav<'a> for &'a BasicNode {
@@ -51,12 +66,16 @@ impl<'b> NodeData for &'b BasicNode {
     fn get_payload(&self) -> Option<ImSlice> {
         self.payload.as_ref().map(|p| p.as_slice())
     }
+
+    fn identity(&self) -> Option<NodeIdentity> {
+        Some(NodeIdentity(*self as *const BasicNode as usize, 0))
+    }
 }
 
 const EMPTY: &Vec<BasicNode> = &vec![];
 
 pub struct FieldIterator<'a> {
-    data: std::collections::hash_map::Iter<'a, FieldKey, Vec<BasicNode>>,
+    data: std::collections::btree_map::Iter<'a, FieldKey, Vec<BasicNode>>,
 }
 
 impl<'a> Iterator for FieldIterator<'a> {
@@ -0,0 +1,464 @@
+//! Merkle hashes of subtrees, for cheaply comparing and deduplicating content.
+//!
+//! A node's hash covers its type, its payload, and its non-empty fields in key order, each as its key and the hashes of its children.
+//! It depends only on the logical content, so it is the same however the subtree is stored
+//! (for example as a [BasicNode](super::example_node::BasicNode), a [MixedNode] or in a [UniformChunk]).
+//!
+//! [MixedNode]s, [UniformChunk]s and [LazyChunk]s cache their hashes. Since edits copy the chunks they modify (and their ancestors),
+//! hashing a forest after an edit only rehashes the edited path, and two snapshots sharing most of their chunks can be compared cheaply.
+//! Stored chunks' hashes are stored with them, so hashing a forest opened from a snapshot does not load its unchanged chunks.
+
+use std::fmt;
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    visit::{walk, Flow, Visitor, WalkOptions},
+    FieldKey, TreeType,
+};
+
+use super::{
+    mixed::{Chunk, Forest, LazyChunk, MixedNode, MixedNodeRef},
+    path::Path,
+    serialize::{DecodeError, Reader, Writer},
+    tree::{Indexable, Node, Tree},
+    uniform_chunk::UniformChunk,
+};
+
+/// SHA-256 based hash of a subtree.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SubtreeHash(pub [u8; 32]);
+
+impl fmt::Debug for SubtreeHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0.iter() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Combines a node's data and the hashes of its children.
+/// `fields` must be in key order, and not include empty fields.
+fn combine(
+    def: &TreeType,
+    payload: Option<&[u8]>,
+    fields: &[(FieldKey, Vec<SubtreeHash>)],
+) -> SubtreeHash {
+    fn bytes(hasher: &mut Sha256, data: &[u8]) {
+        hasher.update((data.len() as u64).to_le_bytes());
+        hasher.update(data);
+    }
+    let mut hasher = Sha256::new();
+    bytes(&mut hasher, def.0.as_bytes());
+    hasher.update([payload.is_some() as u8]);
+    if let Some(p) = payload {
+        bytes(&mut hasher, p);
+    }
+    hasher.update((fields.len() as u64).to_le_bytes());
+    for (key, children) in fields {
+        bytes(&mut hasher, key.0.as_bytes());
+        hasher.update((children.len() as u64).to_le_bytes());
+        for child in children {
+            hasher.update(child.0);
+        }
+    }
+    SubtreeHash(hasher.finalize().into())
+}
+
+/// Fields of a node, with the hashes of their children.
+type FieldHashes = Vec<(FieldKey, Vec<SubtreeHash>)>;
+
+/// [Visitor] which hashes the subtrees it walks, bottom up.
+#[derive(Default)]
+struct Hasher {
+    /// For each node being walked, its fields hashed so far.
+    nodes: Vec<FieldHashes>,
+    /// Hashes of the nodes the walk started at.
+    hashes: Vec<SubtreeHash>,
+    /// True if the walk stopped at a pending node.
+    pending: bool,
+}
+
+impl Hasher {
+    /// Adds the hash of a node to the field it is in.
+    fn add(&mut self, hash: SubtreeHash) {
+        match self.nodes.last_mut() {
+            Some(fields) => fields.last_mut().unwrap().1.push(hash),
+            None => self.hashes.push(hash),
+        }
+    }
+
+    /// Hashes of the nodes the walk started at. None if any of them is pending.
+    fn finish(self) -> Option<Vec<SubtreeHash>> {
+        (!self.pending).then_some(self.hashes)
+    }
+}
+
+impl<'a, T: Node<'a>> Visitor<T> for Hasher {
+    fn enter_node(&mut self, node: &T, _depth: usize) -> Flow {
+        if node.is_pending() {
+            self.pending = true;
+            return Flow::Stop;
+        }
+        self.nodes.push(vec![]);
+        Flow::Continue
+    }
+
+    fn exit_node(&mut self, node: &T, _depth: usize) -> Flow {
+        let mut fields = self.nodes.pop().unwrap();
+        fields.retain(|(_, children)| !children.is_empty());
+        self.add(combine(&node.get_def(), node.get_payload(), &fields));
+        Flow::Continue
+    }
+
+    fn enter_field(&mut self, key: &FieldKey, _depth: usize) -> Flow {
+        if let Some(fields) = self.nodes.last_mut() {
+            fields.push((key.clone(), vec![]));
+        }
+        Flow::Continue
+    }
+}
+
+/// Hash of the subtree under `node`, in any representation. None if any of it is pending.
+///
+/// Nothing is cached: prefer [MixedNode::hash] and [UniformChunk::hashes] when possible.
+/// Walks the subtree without recursing, so it can hash trees of any depth.
+pub fn hash_node<'a, T: Node<'a> + Clone>(node: &T) -> Option<SubtreeHash> {
+    let mut hasher = Hasher::default();
+    walk(node.clone(), &WalkOptions::default(), &mut hasher);
+    Some(hasher.finish()?[0])
+}
+
+/// Hashes of the top level nodes of `chunk`, if they are cached
+/// (or, for a [LazyChunk], stored with it so they are known without loading it).
+fn cached(chunk: &Chunk) -> Option<&[SubtreeHash]> {
+    match chunk {
+        Chunk::Node(n) => n.hash.get().map(std::slice::from_ref),
+        Chunk::Uniform(u) => Some(u.hashes()),
+        Chunk::Lazy(l) => l.known_hashes(),
+    }
+}
+
+/// Something whose hashes [fill] computes and caches: each is visited, then done once what is under it is cached.
+enum Item<'a> {
+    Node(&'a MixedNode, bool),
+    Lazy(&'a LazyChunk, bool),
+}
+
+/// Pushes `chunk` onto `stack` for [fill], unless its hashes are cached.
+fn push<'a>(stack: &mut Vec<Item<'a>>, chunk: &'a Chunk) {
+    match chunk {
+        _ if cached(chunk).is_some() => {}
+        Chunk::Node(n) => stack.push(Item::Node(n, false)),
+        Chunk::Lazy(l) => stack.push(Item::Lazy(l, false)),
+        // Uniform chunks hash themselves when asked.
+        Chunk::Uniform(_) => {}
+    }
+}
+
+/// Computes and caches the hashes of the items on `stack`, and everything under them which is not cached yet.
+/// None if any of it is pending.
+///
+/// Stored chunks whose hashes are known are not loaded.
+/// Uses an explicit stack, so it can hash trees of any depth.
+fn fill(mut stack: Vec<Item>) -> Option<()> {
+    while let Some(item) = stack.pop() {
+        match item {
+            Item::Node(n, _) if n.hash.get().is_some() => {}
+            Item::Node(n, false) => {
+                stack.push(Item::Node(n, true));
+                for chunk in n.fields().values().flatten() {
+                    push(&mut stack, chunk);
+                }
+            }
+            Item::Node(n, true) => {
+                let mut fields = FieldHashes::new();
+                for (key, field) in n.fields() {
+                    let children = field.iter().map(cached).collect::<Option<Vec<_>>>()?;
+                    if children.iter().any(|hashes| !hashes.is_empty()) {
+                        fields.push((key.clone(), children.concat()));
+                    }
+                }
+                n.hash
+                    .get_or_init(|| combine(n.def(), n.payload(), &fields));
+            }
+            Item::Lazy(l, _) if l.hashes.get().is_some() => {}
+            Item::Lazy(l, false) => {
+                stack.push(Item::Lazy(l, true));
+                push(&mut stack, l.get()?);
+            }
+            Item::Lazy(l, true) => {
+                let hashes = cached(l.get()?)?.to_vec();
+                l.hashes.get_or_init(|| hashes);
+            }
+        }
+    }
+    Some(())
+}
+
+/// Hashes of the nodes in a field. None if any of it is pending.
+///
+/// Uses and fills the caches of [MixedNode]s, [UniformChunk]s and [LazyChunk]s,
+/// so stored chunks whose hashes were stored with them (see [super::store::write_chunk]) are not loaded.
+pub fn field_hashes(field: &[Chunk]) -> Option<Vec<SubtreeHash>> {
+    let mut stack = vec![];
+    for chunk in field {
+        push(&mut stack, chunk);
+    }
+    fill(stack)?;
+    let hashes = field.iter().map(cached).collect::<Option<Vec<_>>>()?;
+    Some(hashes.concat())
+}
+
+const HASHES_VERSION: u8 = 0;
+
+/// Serializes the hashes of the top level nodes of a chunk, to store with it.
+pub fn encode_hashes(hashes: &[SubtreeHash]) -> Vec<u8> {
+    let mut w = Writer::default();
+    w.u8(HASHES_VERSION);
+    w.varint(hashes.len() as u64);
+    for hash in hashes {
+        w.bytes(&hash.0);
+    }
+    w.data
+}
+
+/// Deserializes hashes written by [encode_hashes], which must be for `length` nodes.
+pub fn decode_hashes(data: &[u8], length: u32) -> Result<Vec<SubtreeHash>, DecodeError> {
+    let mut r = Reader::new(data);
+    if r.u8()? != HASHES_VERSION {
+        return Err(DecodeError("unsupported hashes version"));
+    }
+    if r.varint()? != length as u64 {
+        return Err(DecodeError("hash count does not match chunk"));
+    }
+    let hashes = (0..length)
+        .map(|_| {
+            let bytes = r.bytes()?.try_into();
+            Ok(SubtreeHash(bytes.map_err(|_| DecodeError("invalid hash"))?))
+        })
+        .collect::<Result<_, _>>()?;
+    if !r.is_empty() {
+        return Err(DecodeError("trailing data"));
+    }
+    Ok(hashes)
+}
+
+impl LazyChunk {
+    /// Hashes of the top level nodes if they are known without loading the chunk:
+    /// cached, or stored with it (unless they can not be read).
+    pub fn known_hashes(&self) -> Option<&[SubtreeHash]> {
+        if let Some(hashes) = self.hashes.get() {
+            return Some(hashes);
+        }
+        let data = self.store().load_hashes(self.id).ok()??;
+        let hashes = decode_hashes(&data, self.length).ok()?;
+        Some(self.hashes.get_or_init(|| hashes))
+    }
+}
+
+impl UniformChunk {
+    /// Hashes of the top level nodes, computed on first use.
+    pub fn hashes(&self) -> &[SubtreeHash] {
+        self.hashes.get_or_init(|| {
+            let view = self.view();
+            (0..view.len())
+                .map(|i| hash_node(&view.index(i).unwrap()).expect("uniform chunks are loaded"))
+                .collect()
+        })
+    }
+}
+
+impl MixedNode {
+    /// Hash of this subtree, cached (along with the hashes of the nodes under it). None if any of it is pending.
+    pub fn hash(&self) -> Option<SubtreeHash> {
+        fill(vec![Item::Node(self, false)])?;
+        self.hash.get().copied()
+    }
+}
+
+impl MixedNodeRef<'_> {
+    /// Hash of this subtree, cached unless it is nested inside a [UniformChunk]. None if any of it is pending.
+    pub fn hash(&self) -> Option<SubtreeHash> {
+        match self {
+            MixedNodeRef::Node(n) => n.hash(),
+            MixedNodeRef::Uniform(u, Some(chunk)) => Some(chunk.hashes()[u.offset as usize]),
+            MixedNodeRef::Uniform(u, None) => hash_node(u),
+            MixedNodeRef::Pending(_) => None,
+        }
+    }
+}
+
+impl Forest {
+    /// Hash of the whole forest: the hash of the root as a node with an empty type and no payload, whose fields are the detached fields.
+    /// None if any of it is pending.
+    pub fn hash(&self) -> Option<SubtreeHash> {
+        let fields = self
+            .roots
+            .iter()
+            .filter(|(_, field)| field.iter().any(|c| !c.is_empty()))
+            .map(|(key, field)| Some((key.clone(), field_hashes(field)?)))
+            .collect::<Option<Vec<_>>>()?;
+        Some(combine(&TreeType("".into()), None, &fields))
+    }
+
+    /// Hash of the subtree at `path`. None if it does not exist or is pending.
+    pub fn hash_at(&self, path: &Path) -> Option<SubtreeHash> {
+        if path.is_root() {
+            self.hash()
+        } else {
+            self.node_at(path)?.hash()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use super::*;
+    use crate::forest::{
+        example_node::BasicNode,
+        snapshot::{read_snapshot, SnapshotWriter},
+        store::{ChunkId, ChunkStore, MemoryStore, StoreError},
+        test_stuff::{big_tree, deep_node, forest_with_root, key, leaf, to_basic},
+    };
+
+    /// Store which counts how many chunks are loaded from it.
+    #[derive(Default)]
+    struct CountLoads(MemoryStore, Cell<usize>);
+
+    impl ChunkStore for CountLoads {
+        fn load(&self, id: ChunkId) -> Result<Option<Rc<[u8]>>, StoreError> {
+            self.1.set(self.1.get() + 1);
+            self.0.load(id)
+        }
+
+        fn store(&self, data: Vec<u8>) -> Result<(ChunkId, bool), StoreError> {
+            self.0.store(data)
+        }
+
+        fn load_ids(&self, id: ChunkId) -> Result<Option<Rc<[u8]>>, StoreError> {
+            self.0.load_ids(id)
+        }
+
+        fn store_ids(&self, id: ChunkId, data: Vec<u8>) -> Result<(), StoreError> {
+            self.0.store_ids(id, data)
+        }
+
+        fn load_hashes(&self, id: ChunkId) -> Result<Option<Rc<[u8]>>, StoreError> {
+            self.0.load_hashes(id)
+        }
+
+        fn store_hashes(&self, id: ChunkId, data: Vec<u8>) -> Result<(), StoreError> {
+            self.0.store_hashes(id, data)
+        }
+    }
+
+    #[test]
+    fn hashes_match_across_representations() {
+        let chunk = big_tree(3);
+        let hashes = chunk.hashes();
+        assert_eq!(hashes.len(), 3);
+        // All the nodes have the same content.
+        assert_eq!(hashes[0], hashes[2]);
+
+        let node = chunk.view().index(1).unwrap();
+        let basic: BasicNode = to_basic(node.clone());
+        assert_eq!(hash_node(&&basic), Some(hashes[1]));
+        assert_eq!(MixedNode::from_basic(&basic).hash(), Some(hashes[1]));
+        assert_eq!(MixedNode::from_uniform(&node).hash(), Some(hashes[1]));
+
+        // Modifying a node after hashing it clears the cached hash.
+        let mut mixed = MixedNode::from_uniform(&node);
+        assert_eq!(mixed.hash(), Some(hashes[1]));
+        mixed.set_payload(Some(vec![0]));
+        assert_ne!(mixed.hash(), Some(hashes[1]));
+        mixed.fields_mut().clear();
+        assert_eq!(mixed.hash(), hash_node(&MixedNodeRef::Node(&mixed)));
+
+        let mut changed = to_basic(node);
+        changed.fields.values_mut().next().unwrap()[0].payload = Some(vec![0]);
+        assert_ne!(hash_node(&&changed), Some(hashes[1]));
+        let mut empty_field = to_basic(chunk.view().index(1).unwrap());
+        empty_field.fields.insert(key("empty"), vec![]);
+        assert_eq!(hash_node(&&empty_field), Some(hashes[1]));
+    }
+
+    #[test]
+    fn edits_update_cached_hashes() {
+        let mut root = MixedNode::new(TreeType("root".into()), None);
+        root.fields_mut()
+            .insert(key("a"), vec![Chunk::Uniform(Rc::new(big_tree(100)))]);
+        let forest = forest_with_root(root);
+        let original = forest.hash().unwrap();
+
+        let path = Path::detached(key("root"), 0).child(key("a"), 10);
+        // Top level nodes of uniform chunks use the chunk's cached hashes.
+        assert!(matches!(
+            forest.node_at(&path),
+            Some(MixedNodeRef::Uniform(_, Some(_)))
+        ));
+        assert_eq!(
+            forest.hash_at(&path),
+            hash_node(&forest.node_at(&path).unwrap())
+        );
+        let mut edited = forest.clone();
+        let previous = edited.set_value(&path, Some(vec![1])).unwrap();
+        let changed = edited.hash().unwrap();
+        assert_ne!(changed, original);
+        assert_eq!(forest.hash(), Some(original));
+
+        edited.set_value(&path, previous).unwrap();
+        assert_eq!(edited.hash(), Some(original));
+        assert_eq!(
+            edited.hash_at(&Path::detached(key("root"), 0)),
+            forest.hash_at(&Path::detached(key("root"), 0))
+        );
+    }
+
+    #[test]
+    fn hash_deep_tree() {
+        const DEPTH: u32 = 200_000;
+        let forest = forest_with_root(deep_node(DEPTH));
+        let root = Path::detached(key("root"), 0);
+        let hash = hash_node(&forest.node_at(&root).unwrap());
+        assert!(hash.is_some());
+        assert!(forest.hash().is_some());
+        assert_eq!(forest.hash_at(&root), hash);
+    }
+
+    #[test]
+    fn snapshots_are_hashed_without_loading_unchanged_chunks() {
+        let counter = Rc::new(CountLoads::default());
+        let store: Rc<dyn ChunkStore> = counter.clone();
+        let mut root = MixedNode::new(TreeType("root".into()), None);
+        root.fields_mut()
+            .insert(key("children"), (0..10).map(leaf).collect());
+        root.fields_mut()
+            .insert(key("uniform"), vec![Chunk::Uniform(Rc::new(big_tree(5)))]);
+        let mut forest = forest_with_root(root);
+        let original = forest.hash();
+        let snapshot = SnapshotWriter::new(store.clone())
+            .write(&mut forest)
+            .unwrap();
+
+        let before = read_snapshot(&snapshot.manifest, &store);
+        let mut after = read_snapshot(&snapshot.manifest, &store);
+        assert_eq!(before.hash(), original);
+        assert_eq!(counter.1.get(), 0);
+
+        // Editing loads the root and the edited child.
+        let path = Path::detached(key("root"), 0).child(key("children"), 3);
+        after.set_value(&path, Some(vec![9])).unwrap();
+        assert_eq!(counter.1.get(), 2);
+        assert_ne!(after.hash(), original);
+        assert_eq!(counter.1.get(), 2);
+
+        // Diffing only loads the same chunks of the other forest.
+        let changes = before.diff(&after).unwrap();
+        assert_eq!(changes.0.len(), 1);
+        assert_eq!(counter.1.get(), 4);
+    }
+}
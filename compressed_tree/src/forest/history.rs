@@ -0,0 +1,169 @@
+//! Undo and redo of edits to a [Forest].
+//!
+//! Each transaction is recorded as the changeset which undoes it.
+//! Content removed by an edit is kept by its inverse as the same reference counted chunks the forest held,
+//! so history shares its data with the forest (and with snapshots) instead of copying it.
+
+use std::collections::VecDeque;
+
+use super::{changeset::Changeset, edit::EditError, mixed::Forest};
+
+/// Undo and redo stacks for a forest.
+#[derive(Clone, Default)]
+pub struct History {
+    /// Inverses of applied transactions, most recent last.
+    undo: VecDeque<Changeset>,
+    /// Inverses of undone transactions, most recently undone last.
+    redo: Vec<Changeset>,
+    /// Maximum number of transactions which can be undone. None for unlimited.
+    limit: Option<usize>,
+}
+
+impl History {
+    /// History which only keeps the most recent `limit` transactions.
+    pub fn with_limit(limit: usize) -> History {
+        History {
+            limit: Some(limit),
+            ..History::default()
+        }
+    }
+
+    /// Applies `changeset` to `forest` as a transaction which can be undone.
+    ///
+    /// This starts a new branch of history, so transactions which were undone can no longer be redone.
+    pub fn apply(&mut self, forest: &mut Forest, changeset: &Changeset) -> Result<(), EditError> {
+        let inverse = changeset.apply_inverted(forest)?;
+        self.record(inverse);
+        Ok(())
+    }
+
+    /// Records a transaction which has already been applied, given the changeset which undoes it.
+    pub fn record(&mut self, inverse: Changeset) {
+        self.redo.clear();
+        self.undo.push_back(inverse);
+        if let Some(limit) = self.limit {
+            while self.undo.len() > limit {
+                self.undo.pop_front();
+            }
+        }
+    }
+
+    /// Undoes the most recent transaction. Returns false if there is nothing to undo.
+    ///
+    /// The forest must not have been modified other than through this history since the transaction.
+    /// This is not checked: otherwise the inverse is applied to whatever is now at the locations it edits,
+    /// which may fail or leave the forest with unspecified content.
+    pub fn undo(&mut self, forest: &mut Forest) -> Result<bool, EditError> {
+        let Some(inverse) = self.undo.back() else {
+            return Ok(false);
+        };
+        let redo = inverse.apply_inverted(forest)?;
+        self.undo.pop_back();
+        self.redo.push(redo);
+        Ok(true)
+    }
+
+    /// Redoes the most recently undone transaction. Returns false if there is nothing to redo.
+    ///
+    /// As for [History::undo], the forest must not have been modified other than through this history since.
+    pub fn redo(&mut self, forest: &mut Forest) -> Result<bool, EditError> {
+        let Some(changeset) = self.redo.last() else {
+            return Ok(false);
+        };
+        let undo = changeset.apply_inverted(forest)?;
+        self.redo.pop();
+        self.undo.push_back(undo);
+        Ok(true)
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Discards the undone transactions, so they can not be redone.
+    pub fn discard_redo(&mut self) {
+        self.redo.clear();
+    }
+
+    /// Discards all history.
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::forest::{
+        changeset::Change,
+        mixed::Chunk,
+        path::{FieldPosition, FieldRange, Path},
+        test_stuff::{big_tree, describe_root, key},
+    };
+
+    fn insert(index: u32) -> Changeset {
+        Changeset(vec![Change::Insert {
+            at: FieldPosition::new(Path::root(), key("root"), index),
+            content: vec![Chunk::Uniform(Rc::new(big_tree(2)))],
+        }])
+    }
+
+    #[test]
+    fn undo_and_redo() {
+        let mut forest = Forest::new();
+        let mut history = History::default();
+        let mut states = vec![describe_root(&forest)];
+        for i in 0..3 {
+            history.apply(&mut forest, &insert(i)).unwrap();
+            states.push(describe_root(&forest));
+        }
+
+        assert!(history.undo(&mut forest).unwrap());
+        assert!(history.undo(&mut forest).unwrap());
+        assert_eq!(describe_root(&forest), states[1]);
+        assert!(history.redo(&mut forest).unwrap());
+        assert_eq!(describe_root(&forest), states[2]);
+
+        // A new edit discards the redo branch.
+        history.apply(&mut forest, &insert(0)).unwrap();
+        assert!(!history.can_redo());
+        assert!(!history.redo(&mut forest).unwrap());
+
+        while history.undo(&mut forest).unwrap() {}
+        assert_eq!(describe_root(&forest), states[0]);
+        assert!(history.can_redo());
+        history.discard_redo();
+        assert!(!history.can_redo());
+    }
+
+    #[test]
+    fn history_shares_removed_chunks() {
+        let chunk = Rc::new(big_tree(1000));
+        let mut forest = Forest::new();
+        forest.set_root(key("root"), vec![Chunk::Uniform(chunk.clone())]);
+        let mut history = History::with_limit(1);
+        history
+            .apply(
+                &mut forest,
+                &Changeset(vec![Change::Remove {
+                    range: FieldRange::new(Path::root(), key("root"), 0, 1000),
+                }]),
+            )
+            .unwrap();
+        // The only other reference to the chunk is from the history.
+        assert_eq!(Rc::strong_count(&chunk), 2);
+
+        // Exceeding the limit drops the oldest transaction, and its data.
+        history.apply(&mut forest, &insert(0)).unwrap();
+        assert_eq!(Rc::strong_count(&chunk), 1);
+        assert!(history.undo(&mut forest).unwrap());
+        assert!(!history.can_undo());
+    }
+}
@@ -0,0 +1,657 @@
+//! Index of the [NodeId]s in a tree, so looking one up only needs to search (or load) the parts which might contain it.
+//!
+//! The index holds [RangeBloomFilter]s rather than the ids themselves, so it stays small enough to keep in memory
+//! when the content it covers is not loaded.
+//!
+//! Ids are added to the filters as content is inserted, into a new filter (twice as large) whenever the last one is full.
+//! Deletes are conservative: they leave the filters as is (which can only cause false positives) and mark the index stale.
+//! Stale indexes are rebuilt lazily, when the content is saved.
+//!
+//! Content stored in a [ChunkStore] is indexed by an entry stored next to each chunk (see [encode_entry]):
+//! a filter over the ids of the nodes in the chunk itself, and the ids of the chunks it references.
+//! Entries are keyed by [ChunkId] like the chunks, so they never go stale and are only written along with a new chunk.
+//! Looking up an id loads the entries of the stored chunks which might contain it (and never the chunks themselves).
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, HashSet},
+    rc::Rc,
+};
+
+use crate::{FieldKey, TreeType};
+
+use super::{
+    bloom::RangeBloomFilter,
+    edit::EditError,
+    mixed::{index_chunk, Chunk, Forest, MixedNodeRef},
+    node_id::NodeId,
+    path::{Path, PathStep},
+    serialize::{DecodeError, Reader, Writer},
+    store::{ChunkId, ChunkStore},
+    tree::{Indexable, Node, NodeNav, Tree},
+    uniform_chunk::UniformChunkNode,
+};
+
+const ENTRY_VERSION: u8 = 0;
+
+/// Capacity of the first filter for inserted ids.
+/// Each filter added when the last is full is twice as large (or as large as needed for what is being inserted).
+const INSERTED_CAPACITY: usize = 64;
+
+/// Entry for a stored chunk.
+struct ChunkIds {
+    /// Ids of the nodes stored in the chunk itself.
+    filter: RangeBloomFilter,
+    /// Stored chunks it references, which have their own entries.
+    children: Vec<ChunkId>,
+}
+
+impl ChunkIds {
+    fn decode(data: &[u8]) -> Result<ChunkIds, DecodeError> {
+        let mut r = Reader::new(data);
+        if r.u8()? != ENTRY_VERSION {
+            return Err(DecodeError("unsupported id index version"));
+        }
+        let filter = RangeBloomFilter::decode(&mut r)?;
+        let children = (0..r.varint()?)
+            .map(|_| r.u128().map(ChunkId))
+            .collect::<Result<Vec<_>, _>>()?;
+        if !r.is_empty() {
+            return Err(DecodeError("trailing data"));
+        }
+        Ok(ChunkIds { filter, children })
+    }
+}
+
+/// Encodes the entry in the index for `chunk`, which is about to be stored.
+/// Chunks nested in it must already be stored (as [Chunk::Lazy]): their entries are referenced, not repeated.
+pub fn encode_entry(chunk: &Chunk) -> Vec<u8> {
+    let mut ids = vec![];
+    let mut children = vec![];
+    content_ids(std::slice::from_ref(chunk), &mut ids, &mut children);
+    let mut w = Writer::default();
+    w.u8(ENTRY_VERSION);
+    RangeBloomFilter::from_ranges(&ranges(ids)).encode(&mut w);
+    w.varint(children.len() as u64);
+    for (child, _) in children {
+        w.u128(child.0);
+    }
+    w.data
+}
+
+/// Appends the ids of the nodes in `field` to `ids`, and the stored chunks it references to `stored`,
+/// without loading them.
+fn content_ids(
+    field: &[Chunk],
+    ids: &mut Vec<NodeId>,
+    stored: &mut Vec<(ChunkId, Rc<dyn ChunkStore>)>,
+) {
+    let mut stack: Vec<&Chunk> = field.iter().collect();
+    while let Some(chunk) = stack.pop() {
+        match chunk {
+            Chunk::Node(node) => {
+                ids.extend(NodeId::from_node(node.def(), node.payload()));
+                stack.extend(node.fields().values().flatten());
+            }
+            Chunk::Uniform(uniform) => ids.extend(field_ids::<UniformChunkNode>(uniform.view())),
+            Chunk::Lazy(lazy) => stored.push((lazy.id, lazy.store().clone())),
+        }
+    }
+}
+
+/// Which ids a tree might contain. See the module docs.
+#[derive(Clone, Default)]
+pub struct IdIndex {
+    /// Ids in inserted content.
+    inserted: Vec<RangeBloomFilter>,
+    /// Stored chunks in the tree, and the stores they are in: all ids in the tree are in these or [IdIndex::inserted].
+    stored: BTreeMap<ChunkId, Rc<dyn ChunkStore>>,
+    /// Entries of stored chunks which have been loaded, shared by clones.
+    entries: Rc<RefCell<HashMap<ChunkId, Rc<ChunkIds>>>>,
+    /// True if content has been removed since the index was last rebuilt.
+    stale: bool,
+}
+
+impl IdIndex {
+    /// True if the tree might contain `id`. There are no false negatives.
+    pub fn might_contain(&self, id: NodeId) -> bool {
+        self.inserted.iter().any(|f| f.might_contain(id))
+            || self.chunks_might_contain(
+                self.stored
+                    .iter()
+                    .map(|(chunk, store)| (*chunk, store.clone())),
+                id,
+            )
+    }
+
+    /// True if the subtrees in the stored `chunks` (each given with the store it is in) might contain `id`.
+    /// Chunks whose entry is not available (or fails to load) might contain anything.
+    ///
+    /// Uses an explicit stack, so deep trees do not overflow the call stack.
+    fn chunks_might_contain(
+        &self,
+        chunks: impl IntoIterator<Item = (ChunkId, Rc<dyn ChunkStore>)>,
+        id: NodeId,
+    ) -> bool {
+        let mut stack: Vec<(ChunkId, Rc<dyn ChunkStore>)> = chunks.into_iter().collect();
+        let mut visited = HashSet::new();
+        while let Some((chunk, store)) = stack.pop() {
+            if !visited.insert(chunk) {
+                continue;
+            }
+            let Some(entry) = self.entry(chunk, &store) else {
+                return true;
+            };
+            if entry.filter.might_contain(id) {
+                return true;
+            }
+            stack.extend(entry.children.iter().map(|child| (*child, store.clone())));
+        }
+        false
+    }
+
+    /// The entry for `chunk`, loading it from `store` if needed.
+    fn entry(&self, chunk: ChunkId, store: &Rc<dyn ChunkStore>) -> Option<Rc<ChunkIds>> {
+        if let Some(entry) = self.entries.borrow().get(&chunk) {
+            return Some(entry.clone());
+        }
+        let entry = Rc::new(ChunkIds::decode(&store.load_ids(chunk).ok()??).ok()?);
+        self.entries.borrow_mut().insert(chunk, entry.clone());
+        Some(entry)
+    }
+
+    /// True if content has been removed since the index was last rebuilt,
+    /// so [IdIndex::might_contain] may match ids which are no longer in the tree.
+    pub fn is_stale(&self) -> bool {
+        self.stale
+    }
+
+    /// Records the content of `field` being added to the tree.
+    pub fn add<'a, T: Node<'a>>(&mut self, field: T::TField) {
+        self.insert_ranges(&ranges(field_ids::<T>(field)));
+    }
+
+    /// Records the chunks in `field` being added to a [Forest].
+    /// Stored chunks are indexed by their entries, so are not loaded.
+    pub fn add_chunks(&mut self, field: &[Chunk]) {
+        let mut ids = vec![];
+        let mut stored = vec![];
+        content_ids(field, &mut ids, &mut stored);
+        self.stored.extend(stored);
+        self.insert_ranges(&ranges(ids));
+    }
+
+    /// Records the ids in `ranges` (given as `(first, count)`) being added to the tree.
+    pub fn insert_ranges(&mut self, ranges: &[(NodeId, u128)]) {
+        let entries = RangeBloomFilter::entries_for(ranges);
+        if entries == 0 {
+            return;
+        }
+        let last = self.inserted.last();
+        if last.is_none_or(|filter| filter.len() + entries > filter.capacity()) {
+            let capacity = last.map_or(INSERTED_CAPACITY, |filter| filter.capacity() * 2);
+            self.inserted
+                .push(RangeBloomFilter::new(capacity.max(entries)));
+        }
+        let filter = self.inserted.last_mut().unwrap();
+        for (first, count) in ranges {
+            filter.insert_range(*first, *count);
+        }
+    }
+
+    /// Records the payload of a node of type `def` being set to `payload`, replacing `previous`.
+    pub fn set_payload(&mut self, def: &TreeType, payload: Option<&[u8]>, previous: Option<&[u8]>) {
+        if let Some(id) = NodeId::from_node(def, payload) {
+            self.insert_ranges(&[(id, 1)]);
+        }
+        if NodeId::from_node(def, previous).is_some() {
+            self.remove();
+        }
+    }
+
+    /// Records content being removed from the tree.
+    pub fn remove(&mut self) {
+        self.stale = true;
+    }
+
+    /// Rebuilds the index once all of `fields` (the content of the tree) has been stored.
+    ///
+    /// Only the stored chunks in `fields` are referenced (their entries cover everything in them),
+    /// so nothing is loaded, and the cost is proportional to the number of chunks in `fields` rather than the size of the tree.
+    pub fn rebuild<'a>(&mut self, fields: impl IntoIterator<Item = &'a [Chunk]>) {
+        let mut index = IdIndex {
+            entries: self.entries.clone(),
+            ..IdIndex::default()
+        };
+        for field in fields {
+            index.add_chunks(field);
+        }
+        *self = index;
+    }
+}
+
+/// The ids held by the nodes in `field` and their descendants.
+///
+/// Uses an explicit stack, so deep trees do not overflow the call stack.
+pub fn field_ids<'a, T: Node<'a>>(field: T::TField) -> Vec<NodeId> {
+    let mut ids = vec![];
+    let mut stack: Vec<T> = (0..field.len())
+        .rev()
+        .filter_map(|i| field.index(i))
+        .collect();
+    while let Some(node) = stack.pop() {
+        ids.extend(NodeId::of(&node));
+        let fields: Vec<(&FieldKey, T::TField)> = node.get_fields().collect();
+        for (_, field) in fields.into_iter().rev() {
+            stack.extend((0..field.len()).rev().filter_map(|i| field.index(i)));
+        }
+    }
+    ids
+}
+
+/// Sorts `ids` into ranges of consecutive ids, given as `(first, count)`.
+pub fn ranges(mut ids: Vec<NodeId>) -> Vec<(NodeId, u128)> {
+    ids.sort_unstable();
+    ids.dedup();
+    let mut ranges: Vec<(NodeId, u128)> = vec![];
+    for id in ids {
+        match ranges.last_mut() {
+            Some((first, count)) if first.offset(*count) == Some(id) => *count += 1,
+            _ => ranges.push((id, 1)),
+        }
+    }
+    ranges
+}
+
+impl Forest {
+    /// Which ids this forest might contain.
+    pub fn id_index(&self) -> &IdIndex {
+        &self.ids
+    }
+
+    /// True if this forest might contain `id`. There are no false negatives.
+    ///
+    /// Stored chunks are not loaded: only their entries in the index are.
+    pub fn might_contain(&self, id: NodeId) -> bool {
+        self.ids.might_contain(id)
+    }
+
+    /// Rebuilds the id index after all content has been stored. See [IdIndex::rebuild].
+    pub(super) fn rebuild_id_index(&mut self) {
+        self.ids
+            .rebuild(self.roots.values().map(|field| field.as_slice()));
+    }
+
+    /// Path to the node holding `id` (a node of type [NodeId::TYPE]), if the forest has one.
+    ///
+    /// Chunks which are not loaded yet are skipped unless the index says they might contain `id`.
+    /// Fails if a chunk which might contain it is pending.
+    ///
+    /// Uses an explicit stack, so deep trees do not overflow the call stack.
+    pub fn find_id(&self, id: NodeId) -> Result<Option<Path>, EditError> {
+        enum Item<'a> {
+            /// A field, and the index in `steps` of the step to its parent (None for the root).
+            Field(Option<usize>, &'a FieldKey, &'a [Chunk]),
+            /// A node, and the index in `steps` of the step to it.
+            Node(usize, MixedNodeRef<'a>),
+        }
+
+        if !self.might_contain(id) {
+            return Ok(None);
+        }
+        // Steps to the nodes found so far, each with the index of the step to its parent.
+        let mut steps: Vec<(Option<usize>, PathStep)> = vec![];
+        let mut stack: Vec<Item> = self
+            .roots
+            .iter()
+            .map(|(key, field)| Item::Field(None, key, field.as_slice()))
+            .collect();
+        while let Some(item) = stack.pop() {
+            match item {
+                Item::Field(parent, key, field) => {
+                    let mut start = 0;
+                    for chunk in field {
+                        let first = start;
+                        let length = chunk.len();
+                        start += length;
+                        if let Chunk::Lazy(lazy) = chunk {
+                            if !lazy.is_loaded()
+                                && !self
+                                    .ids
+                                    .chunks_might_contain([(lazy.id, lazy.store().clone())], id)
+                            {
+                                continue;
+                            }
+                            if lazy.get().is_none() {
+                                return Err(EditError::Pending(lazy.error()));
+                            }
+                        }
+                        for i in 0..length {
+                            steps.push((
+                                parent,
+                                PathStep {
+                                    key: key.clone(),
+                                    index: (first + i) as u32,
+                                },
+                            ));
+                            stack.push(Item::Node(steps.len() - 1, index_chunk(chunk, i)));
+                        }
+                    }
+                }
+                Item::Node(at, node) => {
+                    if NodeId::of(&node) == Some(id) {
+                        return Ok(Some(path(&steps, at)));
+                    }
+                    if let MixedNodeRef::Node(node) = node {
+                        stack.extend(
+                            node.fields()
+                                .iter()
+                                .map(|(key, field)| Item::Field(Some(at), key, field.as_slice())),
+                        );
+                        continue;
+                    }
+                    for (key, field) in node.get_fields() {
+                        for i in 0..field.len() {
+                            steps.push((
+                                Some(at),
+                                PathStep {
+                                    key: key.clone(),
+                                    index: i as u32,
+                                },
+                            ));
+                            stack.push(Item::Node(steps.len() - 1, field.index(i).unwrap()));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// The path made of the step at `at` in `steps` and its ancestors.
+fn path(steps: &[(Option<usize>, PathStep)], mut at: usize) -> Path {
+    let mut path = vec![];
+    loop {
+        let (parent, step) = &steps[at];
+        path.push(step.clone());
+        match parent {
+            Some(parent) => at = *parent,
+            None => break,
+        }
+    }
+    path.reverse();
+    Path(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, rc::Rc};
+
+    use super::*;
+    use crate::{
+        forest::{
+            example_node::BasicNode,
+            mixed::{Field, MixedNode},
+            path::FieldRange,
+            serialize,
+            snapshot::{read_snapshot, SnapshotWriter},
+            store::{write_chunk, MemoryStore, StoreError},
+            test_stuff::{forest_with_root, key},
+            uniform_chunk::{ChunkSchema, UniformChunk},
+        },
+        TreeType,
+    };
+
+    /// A uniform chunk of `count` id nodes with sequential ids from `first`.
+    fn id_range(first: u128, count: u32) -> UniformChunk {
+        let schema = ChunkSchema::new_leaf(NodeId::tree_type(), count, Some(NodeId::SIZE as u16));
+        let data = (0..count as u128)
+            .flat_map(|i| NodeId(first + i).to_payload())
+            .collect();
+        UniformChunk::new(Rc::new(schema), data)
+    }
+
+    /// A node whose id is held by its "id" child, with `children`.
+    fn node(id: u128, children: Field) -> MixedNode {
+        let mut node = MixedNode::new(TreeType("node".into()), None);
+        let id = MixedNode::new(NodeId::tree_type(), Some(NodeId(id).to_payload()));
+        node.fields_mut()
+            .insert(key("id"), vec![Chunk::Node(Rc::new(id))]);
+        if !children.is_empty() {
+            node.fields_mut().insert(key("children"), children);
+        }
+        node
+    }
+
+    /// Forest with a root node with id 1, whose children are nodes with ids 10 to 13,
+    /// each with 100 children with sequential ids in a uniform chunk.
+    fn id_forest() -> Forest {
+        let children = (0..4)
+            .map(|i| {
+                let ids = Chunk::Uniform(Rc::new(id_range(1000 * (i + 1), 100)));
+                Chunk::Node(Rc::new(node(10 + i, vec![ids])))
+            })
+            .collect();
+        forest_with_root(node(1, children))
+    }
+
+    /// Keeps chunks in memory, but not their entries in the id index.
+    #[derive(Default)]
+    struct ChunksOnly(MemoryStore);
+
+    impl ChunkStore for ChunksOnly {
+        fn load(&self, id: ChunkId) -> Result<Option<Rc<[u8]>>, StoreError> {
+            self.0.load(id)
+        }
+
+        fn store(&self, data: Vec<u8>) -> Result<(ChunkId, bool), StoreError> {
+            self.0.store(data)
+        }
+    }
+
+    fn basic(def: TreeType, payload: Vec<u8>, children: Vec<BasicNode>) -> BasicNode {
+        let mut fields = BTreeMap::new();
+        fields.insert(key("children"), children);
+        BasicNode {
+            def,
+            payload: Some(payload),
+            fields,
+        }
+    }
+
+    #[test]
+    fn ids_are_only_held_by_id_nodes() {
+        let value = NodeId(7).to_payload();
+        let tree = [basic(
+            TreeType("node".into()),
+            value.clone(),
+            vec![
+                basic(NodeId::tree_type(), NodeId(8).to_payload(), vec![]),
+                basic(NodeId::tree_type(), vec![1, 2], vec![]),
+            ],
+        )];
+        // Only the child of the id type holds an id, though its parent's payload is the same size.
+        assert_eq!(field_ids::<&BasicNode>(&tree[..]), vec![NodeId(8)]);
+        assert_eq!(
+            NodeId::from_node(&TreeType("node".into()), Some(&value)),
+            None
+        );
+        assert_eq!(NodeId(u128::MAX).offset(1), None);
+    }
+
+    #[test]
+    fn inserted_ranges() {
+        let mut index = IdIndex::default();
+        assert!(!index.might_contain(NodeId(1)));
+        let chunk = id_range(1000, 5000);
+        index.add::<UniformChunkNode>(chunk.view());
+        assert!((1000..6000).all(|i| index.might_contain(NodeId(i))));
+        assert!(!index.might_contain(NodeId(10_000)));
+        assert_eq!(
+            ranges(vec![NodeId(3), NodeId(1), NodeId(2), NodeId(5), NodeId(2)]),
+            vec![(NodeId(1), 3), (NodeId(5), 1)]
+        );
+
+        // Ids inserted one at a time grow the filters instead of filling one up.
+        for i in 0..1000 {
+            index.insert_ranges(&[(NodeId(100_000 + 7 * i), 1)]);
+        }
+        assert!(index.inserted.len() > 2);
+        assert!(index.inserted.iter().all(|filter| !filter.is_overfull()));
+        assert!((0..1000).all(|i| index.might_contain(NodeId(100_000 + 7 * i))));
+
+        // Removing content leaves its ids.
+        assert!(!index.is_stale());
+        index.remove();
+        assert!(index.is_stale());
+        assert!(index.might_contain(NodeId(1000)));
+    }
+
+    #[test]
+    fn stored_chunks_are_indexed_without_loading() {
+        let store: Rc<dyn ChunkStore> = Rc::new(MemoryStore::default());
+        let child = node(2, vec![Chunk::Uniform(Rc::new(id_range(1000, 100)))]);
+        let root = node(1, vec![Chunk::Node(Rc::new(child))]);
+        let id = match write_chunk(&store, &Chunk::Node(Rc::new(root))).unwrap() {
+            Chunk::Lazy(l) => l.id,
+            _ => unreachable!(),
+        };
+
+        let root = Chunk::lazy(store.clone(), id, 1);
+        let mut forest = Forest::new();
+        forest.set_root(key("root"), vec![root.clone()]);
+        assert!(forest.might_contain(NodeId(1)));
+        assert!(forest.might_contain(NodeId(2)));
+        assert!((1000..1100).all(|i| forest.might_contain(NodeId(i))));
+        assert!(!forest.might_contain(NodeId(5000)));
+        match root {
+            Chunk::Lazy(l) => assert!(!l.is_loaded()),
+            _ => unreachable!(),
+        }
+
+        // A chunk stored without an entry might contain anything.
+        let data = serialize::encode_chunk(&Chunk::Node(Rc::new(node(3, vec![])))).unwrap();
+        let bare = store.store(data).unwrap().0;
+        forest.set_root(key("bare"), vec![Chunk::lazy(store, bare, 1)]);
+        assert!(forest.might_contain(NodeId(5000)));
+        assert!(!forest.id_index().is_stale());
+    }
+
+    #[test]
+    fn find_ids() {
+        let forest = id_forest();
+        let root = Path::detached(key("root"), 0);
+        assert_eq!(
+            forest.find_id(NodeId(1)),
+            Ok(Some(root.child(key("id"), 0)))
+        );
+        assert_eq!(
+            forest.find_id(NodeId(12)),
+            Ok(Some(root.child(key("children"), 2).child(key("id"), 0)))
+        );
+        assert_eq!(
+            forest.find_id(NodeId(3050)),
+            Ok(Some(
+                root.child(key("children"), 2).child(key("children"), 50)
+            ))
+        );
+        assert!(forest.might_contain(NodeId(4099)));
+        assert!(!forest.might_contain(NodeId(5000)));
+        assert_eq!(forest.find_id(NodeId(5000)), Ok(None));
+        assert_eq!(forest.find_id(NodeId(1100)), Ok(None));
+
+        // Values which become ids are added to the index.
+        let mut edited = forest.clone();
+        let path = root.child(key("children"), 1).child(key("id"), 0);
+        edited
+            .set_value(&path, Some(NodeId(5000).to_payload()))
+            .unwrap();
+        assert!(edited.id_index().is_stale());
+        assert_eq!(edited.find_id(NodeId(5000)), Ok(Some(path)));
+        assert_eq!(edited.find_id(NodeId(11)), Ok(None));
+        assert!(!forest.might_contain(NodeId(5000)));
+        // Other nodes' values are not ids.
+        edited
+            .set_value(
+                &root.child(key("children"), 0),
+                Some(NodeId(6000).to_payload()),
+            )
+            .unwrap();
+        assert!(!edited.might_contain(NodeId(6000)));
+    }
+
+    #[test]
+    fn lookup_only_loads_chunks_which_might_contain_the_id() {
+        let children_loaded = |forest: &Forest| -> Vec<bool> {
+            let Some(Chunk::Node(root)) = (match &forest.root_chunks(&key("root"))[0] {
+                Chunk::Lazy(lazy) if lazy.is_loaded() => lazy.get(),
+                _ => None,
+            }) else {
+                return vec![];
+            };
+            root.fields()[&key("children")]
+                .iter()
+                .map(|chunk| matches!(chunk, Chunk::Lazy(lazy) if lazy.is_loaded()))
+                .collect()
+        };
+        let path = Path::detached(key("root"), 0)
+            .child(key("children"), 2)
+            .child(key("children"), 50);
+
+        let store: Rc<dyn ChunkStore> = Rc::new(MemoryStore::default());
+        let mut forest = id_forest();
+        let snapshot = SnapshotWriter::new(store.clone())
+            .write(&mut forest)
+            .unwrap();
+        let loaded = read_snapshot(&snapshot.manifest, &store);
+        assert!(!loaded.might_contain(NodeId(5000)));
+        assert_eq!(loaded.find_id(NodeId(5000)), Ok(None));
+        assert_eq!(children_loaded(&loaded), vec![]);
+        assert_eq!(loaded.find_id(NodeId(3050)), Ok(Some(path.clone())));
+        assert_eq!(children_loaded(&loaded), vec![false, false, true, false]);
+
+        // Without entries any chunk might contain the id, so they are loaded until it is found.
+        let store: Rc<dyn ChunkStore> = Rc::new(ChunksOnly::default());
+        let mut forest = id_forest();
+        let snapshot = SnapshotWriter::new(store.clone())
+            .write(&mut forest)
+            .unwrap();
+        let unindexed = read_snapshot(&snapshot.manifest, &store);
+        assert!(unindexed.might_contain(NodeId(5000)));
+        assert_eq!(unindexed.find_id(NodeId(3050)), Ok(Some(path)));
+        assert_eq!(unindexed.find_id(NodeId(5000)), Ok(None));
+        assert_eq!(children_loaded(&unindexed), vec![true; 4]);
+    }
+
+    #[test]
+    fn deletes_are_conservative_until_saved() {
+        let store: Rc<dyn ChunkStore> = Rc::new(MemoryStore::default());
+        let writer = SnapshotWriter::new(store.clone());
+        let mut forest = id_forest();
+        writer.write(&mut forest).unwrap();
+        assert!(!forest.id_index().is_stale());
+
+        let root = Path::detached(key("root"), 0);
+        let detached = forest
+            .detach(&FieldRange::new(root.clone(), key("children"), 1, 2))
+            .unwrap();
+        forest.delete_root(&detached);
+        assert!(forest.id_index().is_stale());
+        assert!(forest.might_contain(NodeId(2050)));
+        assert_eq!(forest.find_id(NodeId(2050)), Ok(None));
+
+        let snapshot = writer.write(&mut forest).unwrap();
+        assert!(!forest.id_index().is_stale());
+        assert!(!forest.might_contain(NodeId(2050)));
+        assert_eq!(
+            forest.find_id(NodeId(3050)),
+            Ok(Some(
+                root.child(key("children"), 1).child(key("children"), 50)
+            ))
+        );
+        let loaded = read_snapshot(&snapshot.manifest, &store);
+        assert!(!loaded.might_contain(NodeId(2050)));
+        assert!(loaded.might_contain(NodeId(4050)));
+    }
+}
@@ -0,0 +1,753 @@
+//! Forest which mixes individually allocated nodes with [UniformChunk]s,
+//! and can lazily load chunks from a [ChunkStore].
+
+use std::{
+    cell::{Cell, OnceCell},
+    collections::BTreeMap,
+    rc::Rc,
+};
+
+use crate::{FieldKey, TreeType};
+
+use crate::cursor::GenericNodesCursor;
+use crate::{EitherCursor, FieldsCursor, NodesCursor};
+
+use super::{
+    anchor::{Anchor, AnchorLocation, AnchorSet},
+    example_node::BasicNode,
+    hash::SubtreeHash,
+    id_index::IdIndex,
+    observer::Events,
+    path::{FieldPosition, Path},
+    serialize,
+    store::{ChunkId, ChunkStore, StoreError},
+    tree::{Indexable, NodeData, NodeIdentity, NodeNav, Tree},
+    uniform_chunk::{ChunkFieldsIterator, ChunkInfo, UniformChunk, UniformChunkNode},
+    util::ImSlice,
+};
+
+/// Sequence of nodes within a field, split into chunks.
+pub type Field = Vec<Chunk>;
+
+/// Part of a field: one or more sibling nodes stored together.
+///
+/// Chunks are reference counted so forests (and parts of them) can be cheaply cloned and share data.
+#[derive(Clone)]
+pub enum Chunk {
+    /// A single node.
+    Node(Rc<MixedNode>),
+    /// A sequence of nodes with identical shape.
+    Uniform(Rc<UniformChunk>),
+    /// A chunk which is loaded from a [ChunkStore] on first access.
+    Lazy(Rc<LazyChunk>),
+}
+
+impl Chunk {
+    /// Number of top level nodes in this chunk.
+    pub fn len(&self) -> usize {
+        match self {
+            Chunk::Node(_) => 1,
+            Chunk::Uniform(u) => u.get_count(),
+            Chunk::Lazy(l) => l.length as usize,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// A chunk which will be loaded from `store` when first accessed.
+    /// `length` must be the number of top level nodes in the stored chunk.
+    pub fn lazy(store: Rc<dyn ChunkStore>, id: ChunkId, length: u32) -> Chunk {
+        Chunk::Lazy(Rc::new(LazyChunk {
+            id,
+            length,
+            store,
+            loaded: OnceCell::new(),
+            error: Cell::new(None),
+            hashes: OnceCell::new(),
+        }))
+    }
+
+    /// A [Chunk::Lazy] for `chunk`, which has already been stored in `store` as `id`.
+    pub fn lazy_loaded(store: Rc<dyn ChunkStore>, id: ChunkId, chunk: Chunk) -> Chunk {
+        let length = chunk.len() as u32;
+        Chunk::Lazy(Rc::new(LazyChunk {
+            id,
+            length,
+            store,
+            loaded: OnceCell::from(chunk),
+            error: Cell::new(None),
+            hashes: OnceCell::new(),
+        }))
+    }
+}
+
+/// Node which owns its fields.
+#[derive(Clone)]
+pub struct MixedNode {
+    def: TreeType,
+    payload: Option<Vec<u8>>,
+    /// Non-empty fields.
+    fields: BTreeMap<FieldKey, Field>,
+    /// Cached hash of this subtree. See [super::hash].
+    /// Cleared by everything which can modify `payload` or `fields`.
+    pub(super) hash: OnceCell<SubtreeHash>,
+}
+
+/// Drops descendants using an explicit stack, so dropping deep trees does not overflow the call stack.
+/// Only nodes which are not shared are visited.
+impl Drop for MixedNode {
+    fn drop(&mut self) {
+        // Loaded lazy chunks are unwrapped too, since snapshots nest nodes through them.
+        let mut stack: Vec<Chunk> = std::mem::take(&mut self.fields)
+            .into_values()
+            .flatten()
+            .collect();
+        while let Some(chunk) = stack.pop() {
+            match chunk {
+                Chunk::Node(node) => {
+                    if let Ok(mut node) = Rc::try_unwrap(node) {
+                        stack.extend(std::mem::take(&mut node.fields).into_values().flatten());
+                    }
+                }
+                Chunk::Lazy(lazy) => {
+                    if let Ok(mut lazy) = Rc::try_unwrap(lazy) {
+                        stack.extend(lazy.loaded.take());
+                    }
+                }
+                Chunk::Uniform(_) => {}
+            }
+        }
+    }
+}
+
+impl MixedNode {
+    pub fn new(def: TreeType, payload: Option<Vec<u8>>) -> MixedNode {
+        MixedNode {
+            def,
+            payload,
+            fields: BTreeMap::new(),
+            hash: OnceCell::new(),
+        }
+    }
+
+    pub fn def(&self) -> &TreeType {
+        &self.def
+    }
+
+    pub fn payload(&self) -> Option<&[u8]> {
+        self.payload.as_deref()
+    }
+
+    /// Replaces the payload, and returns the previous one.
+    pub fn set_payload(&mut self, payload: Option<Vec<u8>>) -> Option<Vec<u8>> {
+        self.hash.take();
+        std::mem::replace(&mut self.payload, payload)
+    }
+
+    /// Fields, which should all be non-empty.
+    pub fn fields(&self) -> &BTreeMap<FieldKey, Field> {
+        &self.fields
+    }
+
+    /// Fields, for modification. Clears the cached hash.
+    pub fn fields_mut(&mut self) -> &mut BTreeMap<FieldKey, Field> {
+        self.hash.take();
+        &mut self.fields
+    }
+
+    /// Copies a [BasicNode] subtree, storing each node as its own chunk.
+    pub fn from_basic(node: &BasicNode) -> MixedNode {
+        MixedNode {
+            def: node.def.clone(),
+            payload: node.payload.clone(),
+            fields: node
+                .fields
+                .iter()
+                .filter(|(_, children)| !children.is_empty())
+                .map(|(key, children)| {
+                    let field = children
+                        .iter()
+                        .map(|c| Chunk::Node(Rc::new(MixedNode::from_basic(c))))
+                        .collect();
+                    (key.clone(), field)
+                })
+                .collect(),
+            hash: OnceCell::new(),
+        }
+    }
+
+    /// Copies a node out of a [UniformChunk].
+    /// Its fields are copied into [UniformChunk]s of their own, so only this node is split out of the chunk.
+    pub fn from_uniform(node: &UniformChunkNode) -> MixedNode {
+        MixedNode {
+            def: node.get_def(),
+            payload: node.get_payload().map(|p| p.to_vec()),
+            fields: node
+                .get_fields()
+                .filter(|(_, field)| field.len() != 0)
+                .map(|(key, field)| (key.clone(), vec![Chunk::Uniform(Rc::new(field.to_chunk()))]))
+                .collect(),
+            hash: OnceCell::new(),
+        }
+    }
+}
+
+/// Reference to a chunk in a [ChunkStore].
+pub struct LazyChunk {
+    pub id: ChunkId,
+    /// Number of top level nodes in the chunk, known without loading it.
+    pub length: u32,
+    store: Rc<dyn ChunkStore>,
+    loaded: OnceCell<Chunk>,
+    /// Why the last attempt to load failed, if it was an error rather than the chunk being unavailable.
+    error: Cell<Option<StoreError>>,
+    /// Cached hashes of the top level nodes. See [super::hash].
+    pub(super) hashes: OnceCell<Vec<SubtreeHash>>,
+}
+
+impl LazyChunk {
+    /// The loaded chunk, loading it if needed.
+    ///
+    /// Returns None if the chunk is not available from the store yet, or could not be loaded (it is pending).
+    /// Loading will be retried on the next access, and [LazyChunk::error] reports why the last attempt failed.
+    pub fn get(&self) -> Option<&Chunk> {
+        if let Some(chunk) = self.loaded.get() {
+            return Some(chunk);
+        }
+        match self.load() {
+            Ok(chunk) => {
+                self.error.set(None);
+                let chunk = chunk?;
+                Some(self.loaded.get_or_init(|| chunk))
+            }
+            Err(e) => {
+                self.error.set(Some(e));
+                None
+            }
+        }
+    }
+
+    fn load(&self) -> Result<Option<Chunk>, StoreError> {
+        let Some(data) = self.store.load(self.id)? else {
+            return Ok(None);
+        };
+        let chunk = serialize::decode_chunk(&data, &self.store).map_err(StoreError::Corrupt)?;
+        if chunk.len() != self.length as usize {
+            return Err(StoreError::Corrupt(serialize::DecodeError(
+                "chunk length does not match reference",
+            )));
+        }
+        Ok(Some(chunk))
+    }
+
+    /// The error from the last attempt to load this chunk, if it failed with one.
+    /// None if the chunk is loaded, or was just not available.
+    pub fn error(&self) -> Option<StoreError> {
+        self.error.get()
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.loaded.get().is_some()
+    }
+
+    /// The store the chunk is loaded from.
+    pub fn store(&self) -> &Rc<dyn ChunkStore> {
+        &self.store
+    }
+}
+
+/// A collection of trees, each stored in a detached field identified by a [FieldKey].
+///
+/// Clones share their chunks, but not their subscribers (see [super::observer]).
+#[derive(Clone, Default)]
+pub struct Forest {
+    pub(super) roots: BTreeMap<FieldKey, Field>,
+    pub(super) ids: IdIndex,
+    pub(super) anchors: AnchorSet,
+    /// Used to generate keys for new detached fields.
+    pub(super) next_detached: u64,
+    pub(super) events: Events,
+}
+
+impl Forest {
+    pub fn new() -> Forest {
+        Forest::default()
+    }
+
+    /// The detached field `key`, which is empty if it does not exist.
+    pub fn root(&self, key: &FieldKey) -> MixedField<'_> {
+        MixedField::Chunks(self.roots.get(key).map_or(&[], |f| f.as_slice()))
+    }
+
+    /// The chunks in the detached field `key`.
+    pub fn root_chunks(&self, key: &FieldKey) -> &[Chunk] {
+        self.roots.get(key).map_or(&[], |f| f.as_slice())
+    }
+
+    /// True if the forest has content which has not been written to a [ChunkStore].
+    ///
+    /// Edits replace the chunks they modify (and their ancestors) with chunks which are not [Chunk::Lazy],
+    /// so this only needs to check the roots. See [super::snapshot].
+    pub fn is_dirty(&self) -> bool {
+        self.roots
+            .values()
+            .flatten()
+            .any(|chunk| !matches!(chunk, Chunk::Lazy(_)))
+    }
+
+    pub fn roots(&self) -> impl Iterator<Item = (&FieldKey, MixedField<'_>)> {
+        self.roots
+            .iter()
+            .map(|(k, f)| (k, MixedField::Chunks(f.as_slice())))
+    }
+
+    /// Replaces the content of the detached field `key`.
+    ///
+    /// Anchors into the field are not updated,
+    /// so this is intended for building forests or replacing a field with equivalent content.
+    pub fn set_root(&mut self, key: FieldKey, field: Field) {
+        self.ids.add_chunks(&field);
+        let previous = if field.is_empty() {
+            self.roots.remove(&key)
+        } else {
+            self.roots.insert(key, field)
+        };
+        if previous.is_some() {
+            self.ids.remove();
+        }
+    }
+
+    /// Deletes the detached field `key` and its content.
+    pub fn delete_root(&mut self, key: &FieldKey) {
+        if self.roots.remove(key).is_some() {
+            self.ids.remove();
+        }
+        self.anchors.on_delete_root(key);
+    }
+
+    /// The field `key` of the node at `parent`, if that node exists.
+    pub fn field_at(&self, parent: &Path, key: &FieldKey) -> Option<MixedField<'_>> {
+        if parent.is_root() {
+            Some(self.root(key))
+        } else {
+            Some(self.node_at(parent)?.get_field(key.clone()))
+        }
+    }
+
+    /// The node at `path`, if it exists. None for the root path.
+    pub fn node_at(&self, path: &Path) -> Option<MixedNodeRef<'_>> {
+        let (parent, last) = path.split_last()?;
+        self.field_at(&parent, &last.key)?
+            .index(last.index as usize)
+    }
+
+    /// A cursor at the root, whose fields are the forest's detached fields.
+    pub fn cursor(&self) -> GenericNodesCursor<'_, MixedNodeRef<'_>> {
+        GenericNodesCursor::from_roots(self.roots().map(|(key, field)| (key.clone(), field)))
+    }
+
+    /// A cursor at the node at `path` (or the root, for the root path), if it exists.
+    /// The cursor can navigate the whole forest.
+    pub fn cursor_at(&self, path: &Path) -> Option<GenericNodesCursor<'_, MixedNodeRef<'_>>> {
+        let mut cursor = self.cursor();
+        for step in path.0.iter() {
+            cursor = match cursor.enter_field(step.key.clone()) {
+                EitherCursor::Fields(f) => f.enter_node(step.index).ok()?,
+                EitherCursor::Nodes(_) => return None,
+            };
+        }
+        Some(cursor)
+    }
+
+    /// Creates an anchor to the node at `path`, if it exists.
+    pub fn anchor_node(&mut self, path: &Path) -> Option<Anchor> {
+        self.node_at(path)?;
+        self.anchors.track_node(path.clone())
+    }
+
+    /// Creates an anchor to `position`, if it exists.
+    pub fn anchor_position(&mut self, position: &FieldPosition) -> Option<Anchor> {
+        let field = self.field_at(&position.parent, &position.key)?;
+        if position.index as usize > field.len() {
+            return None;
+        }
+        Some(self.anchors.track_position(position.clone()))
+    }
+
+    /// Where `anchor` currently refers to. None if the anchor is unknown (for example released).
+    pub fn locate(&self, anchor: Anchor) -> Option<AnchorLocation> {
+        self.anchors.locate(anchor)
+    }
+
+    /// Cursor at the node `anchor` refers to.
+    /// None if the anchor is to a position, or its node has been deleted.
+    pub fn cursor_at_anchor(
+        &self,
+        anchor: Anchor,
+    ) -> Option<GenericNodesCursor<'_, MixedNodeRef<'_>>> {
+        match self.locate(anchor)? {
+            AnchorLocation::Node(path) => self.cursor_at(&path),
+            _ => None,
+        }
+    }
+
+    pub fn release_anchor(&mut self, anchor: Anchor) {
+        self.anchors.release(anchor);
+    }
+}
+
+// Views
+
+/// Node within a [Forest].
+#[derive(Clone)]
+pub enum MixedNodeRef<'a> {
+    Node(&'a MixedNode),
+    /// Node in a [UniformChunk], and the chunk if it is one of the chunk's top level nodes (so its hash is cached there).
+    Uniform(UniformChunkNode<'a>, Option<&'a UniformChunk>),
+    /// Node in a chunk which has not been loaded yet.
+    Pending(&'a LazyChunk),
+}
+
+/// Field within a [Forest].
+#[derive(Clone)]
+pub enum MixedField<'a> {
+    Chunks(&'a [Chunk]),
+    /// Field nested inside a [UniformChunk].
+    Uniform(ChunkInfo<'a>),
+}
+
+impl<'a> MixedField<'a> {
+    /// Finds the chunk containing `index`, and the index of its first node.
+    fn find_chunk(chunks: &'a [Chunk], index: usize) -> Option<(&'a Chunk, usize)> {
+        let mut start = 0;
+        for chunk in chunks {
+            let end = start + chunk.len();
+            if index < end {
+                return Some((chunk, start));
+            }
+            start = end;
+        }
+        None
+    }
+}
+
+/// The data of `chunk`, if it is (or has loaded) a uniform chunk.
+fn uniform_chunk(chunk: &Chunk) -> Option<ChunkInfo<'_>> {
+    match chunk {
+        Chunk::Node(_) => None,
+        Chunk::Uniform(u) => Some(u.view()),
+        Chunk::Lazy(l) => uniform_chunk(l.get()?),
+    }
+}
+
+pub(super) fn index_chunk(chunk: &Chunk, index: usize) -> MixedNodeRef<'_> {
+    match chunk {
+        Chunk::Node(n) => MixedNodeRef::Node(n),
+        Chunk::Uniform(u) => MixedNodeRef::Uniform(u.view().index(index).unwrap(), Some(u)),
+        Chunk::Lazy(l) => match l.get() {
+            Some(loaded) => index_chunk(loaded, index),
+            None => MixedNodeRef::Pending(l),
+        },
+    }
+}
+
+impl<'a> Indexable for MixedField<'a> {
+    type Item = Option<MixedNodeRef<'a>>;
+
+    fn index(&self, index: usize) -> Self::Item {
+        match self {
+            MixedField::Chunks(chunks) => {
+                let (chunk, start) = MixedField::find_chunk(chunks, index)?;
+                Some(index_chunk(chunk, index - start))
+            }
+            MixedField::Uniform(info) => info.index(index).map(|u| MixedNodeRef::Uniform(u, None)),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            MixedField::Chunks(chunks) => chunks.iter().map(|c| c.len()).sum(),
+            MixedField::Uniform(info) => info.len(),
+        }
+    }
+
+    fn chunk_range(&self, index: usize) -> (usize, usize) {
+        match self {
+            MixedField::Chunks(chunks) => match MixedField::find_chunk(chunks, index) {
+                Some((chunk, start)) => (start, chunk.len()),
+                None => (index, 1),
+            },
+            MixedField::Uniform(info) => (0, info.len()),
+        }
+    }
+
+    fn uniform_chunk(&self, index: usize) -> Option<ChunkInfo<'_>> {
+        match self {
+            MixedField::Chunks(chunks) => uniform_chunk(MixedField::find_chunk(chunks, index)?.0),
+            MixedField::Uniform(info) => Some(info.clone()),
+        }
+    }
+
+    fn stored_chunk(&self, index: usize) -> Option<ChunkId> {
+        match self {
+            MixedField::Chunks(chunks) => match MixedField::find_chunk(chunks, index)?.0 {
+                Chunk::Lazy(l) => Some(l.id),
+                _ => None,
+            },
+            MixedField::Uniform(_) => None,
+        }
+    }
+}
+
+pub enum MixedFieldsIterator<'a> {
+    Node(std::collections::btree_map::Iter<'a, FieldKey, Field>),
+    Uniform(ChunkFieldsIterator<'a>),
+    Empty,
+}
+
+impl<'a> Iterator for MixedFieldsIterator<'a> {
+    type Item = (&'a FieldKey, MixedField<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            MixedFieldsIterator::Node(iter) => {
+                let (key, field) = iter.next()?;
+                Some((key, MixedField::Chunks(field)))
+            }
+            MixedFieldsIterator::Uniform(iter) => {
+                let (key, info) = iter.next()?;
+                Some((key, MixedField::Uniform(info)))
+            }
+            MixedFieldsIterator::Empty => None,
+        }
+    }
+}
+
+impl<'a> NodeNav<'a> for MixedNodeRef<'a> {
+    type TField = MixedField<'a>;
+    type TFields = MixedFieldsIterator<'a>;
+
+    fn get_field(&self, label: FieldKey) -> Self::TField {
+        match self {
+            MixedNodeRef::Node(n) => {
+                MixedField::Chunks(n.fields.get(&label).map_or(&[], |f| f.as_slice()))
+            }
+            MixedNodeRef::Uniform(u, _) => MixedField::Uniform(u.get_field(label)),
+            MixedNodeRef::Pending(_) => MixedField::Chunks(&[]),
+        }
+    }
+
+    fn get_fields(&self) -> Self::TFields {
+        match self {
+            MixedNodeRef::Node(n) => MixedFieldsIterator::Node(n.fields.iter()),
+            MixedNodeRef::Uniform(u, _) => MixedFieldsIterator::Uniform(u.get_fields()),
+            MixedNodeRef::Pending(_) => MixedFieldsIterator::Empty,
+        }
+    }
+
+    fn is_leaf(&self) -> bool {
+        match self {
+            MixedNodeRef::Node(n) => n.fields.is_empty(),
+            MixedNodeRef::Uniform(u, _) => u.is_leaf(),
+            MixedNodeRef::Pending(_) => true,
+        }
+    }
+
+    fn is_pending(&self) -> bool {
+        matches!(self, MixedNodeRef::Pending(_))
+    }
+
+    fn load_error(&self) -> Option<StoreError> {
+        match self {
+            MixedNodeRef::Pending(l) => l.error(),
+            _ => None,
+        }
+    }
+}
+
+impl NodeData for MixedNodeRef<'_> {
+    fn get_def(&self) -> TreeType {
+        match self {
+            MixedNodeRef::Node(n) => n.def.clone(),
+            MixedNodeRef::Uniform(u, _) => u.get_def(),
+            MixedNodeRef::Pending(_) => TreeType("".into()),
+        }
+    }
+
+    fn get_payload(&self) -> Option<ImSlice<'_>> {
+        match self {
+            MixedNodeRef::Node(n) => n.payload.as_deref(),
+            MixedNodeRef::Uniform(u, _) => u.get_payload(),
+            MixedNodeRef::Pending(_) => None,
+        }
+    }
+
+    fn identity(&self) -> Option<NodeIdentity> {
+        match self {
+            MixedNodeRef::Node(n) => Some(NodeIdentity(*n as *const MixedNode as usize, 0)),
+            MixedNodeRef::Uniform(u, _) => u.identity(),
+            MixedNodeRef::Pending(_) => None,
+        }
+    }
+
+    fn subtree_hash(&self) -> Option<SubtreeHash> {
+        match self {
+            MixedNodeRef::Node(_) | MixedNodeRef::Uniform(_, Some(_)) => self.hash(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forest::{
+        edit::EditError,
+        store::{write_chunk, MemoryStore},
+        test_stuff::{big_tree, walk_all_field},
+    };
+
+    fn basic(children: usize) -> BasicNode {
+        let mut fields = BTreeMap::default();
+        fields.insert(
+            FieldKey("child".into()),
+            (0..children)
+                .map(|_| BasicNode {
+                    def: TreeType("leaf".into()),
+                    payload: Some(vec![1, 2]),
+                    fields: BTreeMap::default(),
+                })
+                .collect(),
+        );
+        BasicNode {
+            def: TreeType("root".into()),
+            payload: None,
+            fields,
+        }
+    }
+
+    #[test]
+    fn walk_mixed() {
+        let mut root = MixedNode::from_basic(&basic(3));
+        root.fields.insert(
+            FieldKey("chunk".into()),
+            vec![Chunk::Uniform(Rc::new(big_tree(10)))],
+        );
+        let mut forest = Forest::new();
+        forest.set_root(FieldKey("root".into()), vec![Chunk::Node(Rc::new(root))]);
+        let count = walk_all_field::<MixedNodeRef>(forest.root(&FieldKey("root".into())));
+        assert_eq!(count, 1 + 3 + 10 * 5);
+    }
+
+    #[test]
+    fn anchors_resolve_to_cursors() {
+        let mut forest = Forest::new();
+        let key = FieldKey("root".into());
+        forest.set_root(
+            key.clone(),
+            vec![Chunk::Node(Rc::new(MixedNode::from_basic(&basic(3))))],
+        );
+        let path = Path::detached(key.clone(), 0).child(FieldKey("child".into()), 2);
+        let anchor = forest.anchor_node(&path).unwrap();
+        let cursor = forest.cursor_at_anchor(anchor).unwrap();
+        assert_eq!(cursor.field_index(), 2);
+        assert_eq!(cursor.path(), path);
+        assert!(forest
+            .anchor_node(&Path::detached(key.clone(), 1))
+            .is_none());
+
+        forest.delete_root(&key);
+        assert_eq!(forest.locate(anchor), Some(AnchorLocation::Deleted));
+        assert!(forest.cursor_at_anchor(anchor).is_none());
+    }
+
+    #[test]
+    fn lazy_chunks_are_pending_until_available() {
+        let source = Rc::new(MemoryStore::default());
+        let chunk = Chunk::Node(Rc::new(MixedNode::from_basic(&basic(3))));
+        let id = match write_chunk(&(source.clone() as Rc<dyn ChunkStore>), &chunk).unwrap() {
+            Chunk::Lazy(l) => l.id,
+            _ => unreachable!(),
+        };
+
+        // A store which does not have the data yet.
+        let target = Rc::new(MemoryStore::default());
+        let mut forest = Forest::new();
+        let key = FieldKey("root".into());
+        forest.set_root(key.clone(), vec![Chunk::lazy(target.clone(), id, 1)]);
+
+        let root = forest.root(&key);
+        assert!(root.index(0).unwrap().is_pending());
+        assert_eq!(root.chunk_range(0), (0, 1));
+
+        target.copy_from(&source);
+        assert!(!root.index(0).unwrap().is_pending());
+        assert_eq!(walk_all_field::<MixedNodeRef>(root), 4);
+    }
+
+    #[test]
+    fn corrupt_chunks_stay_pending() {
+        let store = Rc::new(MemoryStore::default());
+        let (id, _) = store.store(vec![0, 99]).unwrap();
+        let mut forest = Forest::new();
+        let key = FieldKey("root".into());
+        forest.set_root(key.clone(), vec![Chunk::lazy(store, id, 1)]);
+
+        let error = Some(StoreError::Corrupt(serialize::DecodeError(
+            "unknown chunk tag",
+        )));
+        let node = forest.root(&key).index(0).unwrap();
+        assert!(node.is_pending());
+        assert_eq!(node.load_error(), error);
+        let path = Path::detached(key, 0);
+        assert_eq!(forest.cursor_at(&path).unwrap().pending_error(), error);
+        assert_eq!(
+            forest.set_value(&path, None),
+            Err(EditError::Pending(error))
+        );
+    }
+
+    #[test]
+    fn skip_pending_fields() {
+        let store: Rc<dyn ChunkStore> = Rc::new(MemoryStore::default());
+        let mut forest = Forest::new();
+        for name in ["a", "b"] {
+            let missing = Chunk::lazy(store.clone(), ChunkId(name.len() as u128), 1);
+            forest.set_root(FieldKey(name.into()), vec![missing.clone(), missing]);
+        }
+        forest.set_root(
+            FieldKey("c".into()),
+            vec![Chunk::Node(Rc::new(MixedNode::from_basic(&basic(0))))],
+        );
+
+        let fields = match forest.cursor().first_field() {
+            EitherCursor::Fields(f) => f,
+            EitherCursor::Nodes(_) => panic!(),
+        };
+        match fields.skip_pending_fields() {
+            EitherCursor::Fields(f) => assert_eq!(f.field_key().0, "c"),
+            EitherCursor::Nodes(_) => panic!(),
+        }
+
+        let fields = forest
+            .edit_field_cursor(&Path::root(), FieldKey("a".into()))
+            .unwrap();
+        match fields.skip_pending_fields() {
+            EitherCursor::Fields(f) => assert_eq!(f.field_key().0, "c"),
+            EitherCursor::Nodes(_) => panic!(),
+        }
+
+        // Skipping past the last field exits to the parent.
+        forest.delete_root(&FieldKey("c".into()));
+        let fields = match forest.cursor().first_field() {
+            EitherCursor::Fields(f) => f,
+            EitherCursor::Nodes(_) => panic!(),
+        };
+        assert!(matches!(
+            fields.skip_pending_fields(),
+            EitherCursor::Nodes(n) if n.is_root()
+        ));
+    }
+}
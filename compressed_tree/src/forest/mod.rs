@@ -4,7 +4,14 @@ Tree in the style of [Fluid's experimental tree](https://github.com/microsoft/Fl
 This prototypes a forest using [im_rc::OrdMap] which allows with compressed sequences via [uniform_chunk],
 as well as a general architectural pattern for all of this with low coupling and a nice API in (See [nav]).
 
-This design was done with virtualization (only loading a subset of the tree on demand) in mind, however none has been implemented yet.
+This design was done with virtualization (only loading a subset of the tree on demand) in mind.
+[mixed::Forest] can load chunks on demand from a [store::ChunkStore], reporting their nodes as pending until they are available.
+[snapshot] saves a forest incrementally, only writing the chunks which changed since the last snapshot.
+Forests are edited with index based [edit]s (or by navigating to the content with an [edit_cursor]), which are batched into serializable [changeset]s (which [history] uses for undo and redo).
+A [transaction] stages several edits and applies them atomically, and [observer] notifies subscribers about them.
+A [diff] computes the changeset between two trees, even in different representations.
+Subtrees have Merkle [hash]es which are independent of their representation, and cached in chunks.
+Changesets can be [rebase]d over concurrent changesets, which [sequencer] uses to keep several clients in sync.
 The ability to load data on demand based on [node_id::NodeId], as well as efficiently look up parents is required.
 The two main approaches for this would be to either virtualize the [forest]'s B Tree directly,
 or to virtualize the logical tree, and load chunks of it into the Forest.
@@ -17,7 +24,7 @@ or to virtualize the logical tree, and load chunks of it into the Forest.
         - Compressed sequences, and other cluster based allocation schemes help, but might not be enough after lots of edits in large documents.
 - Persist the logical tree in snapshots (Current approach in Fluid's experimental tree, but does not support virtualization):
     - When virtualized, hard to determine if a given Id exists, and if so load it's data.
-        - Can track set of unloaded trees: use bloom filters to determine when they might be needed for Id lookup.
+        - Can track set of unloaded trees: use bloom filters to determine when they might be needed for Id lookup (See [id_index] and [bloom]).
             - This has issues with sequence compression, but that can be solved with extra multiple bloom filters for truncated ids to emulate efficient batch insertion.
             - Higher code complexity, and possibly runtime cost (Update them on every edit?)
                 - Maybe can use lazy update approach? (Dirty on change, recompute when saving)
@@ -27,7 +34,26 @@ or to virtualize the logical tree, and load chunks of it into the Forest.
 extern crate derive_more;
 extern crate num_integer;
 
+pub mod anchor;
+pub mod bloom;
+pub mod changeset;
+pub mod diff;
+pub mod edit;
+pub mod edit_cursor;
 pub mod example_node;
+pub mod hash;
+pub mod history;
+pub mod id_index;
+pub mod mixed;
+pub mod node_id;
+pub mod observer;
+pub mod path;
+pub mod rebase;
+pub mod sequencer;
+pub mod serialize;
+pub mod snapshot;
+pub mod store;
+pub mod transaction;
 pub mod tree;
 pub mod uniform_chunk;
 pub mod util;
@@ -120,11 +146,8 @@ pub mod test_stuff;
 //     // }
 // }
 /*
-Works index based:
-Build
-Insert
-Detach
+Edits are index based (See [edit]): Build, Insert, Detach.
 
-with anchors, how do you do replace when src is internally anchored? Rely on tombstones? Dummy node?
+With anchors, how do you do replace when src is internally anchored? Rely on tombstones? Dummy node?
 
 */
@@ -0,0 +1,56 @@
+//! Identifiers for nodes.
+
+use crate::TreeType;
+
+use super::tree::NodeData;
+
+/// Unique identifier for a node.
+///
+/// Ids are held by nodes of the reserved type [NodeId::TYPE], whose payload is the id ([NodeId::SIZE] bytes, little endian).
+/// Nodes of any other type have no id, whatever their payload,
+/// so content which needs to be looked up by id holds one of these nodes (typically as a child of the node it identifies).
+/// Ids within a [super::uniform_chunk::UniformChunk] are usually sequential (depth first pre-order),
+/// so ranges of ids are common and are handled in bulk where possible.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct NodeId(pub u128);
+
+impl NodeId {
+    /// Size of the payload of a node which holds an id.
+    pub const SIZE: usize = 16;
+
+    /// Name of the type of nodes which hold an id.
+    pub const TYPE: &'static str = "NodeId";
+
+    /// The type of nodes which hold an id.
+    pub fn tree_type() -> TreeType {
+        TreeType(NodeId::TYPE.into())
+    }
+
+    /// Returns the id `offset` after this one, or None if that is past the last id.
+    pub fn offset(self, offset: u128) -> Option<NodeId> {
+        self.0.checked_add(offset).map(NodeId)
+    }
+
+    /// The id held by a node of type `def` with `payload`, if it holds one.
+    pub fn from_node(def: &TreeType, payload: Option<&[u8]>) -> Option<NodeId> {
+        if def.0 != NodeId::TYPE {
+            return None;
+        }
+        Some(NodeId(u128::from_le_bytes(payload?.try_into().ok()?)))
+    }
+
+    /// The id held by `node`, if it holds one.
+    pub fn of(node: &impl NodeData) -> Option<NodeId> {
+        // Checking the payload size first avoids copying the type of most nodes.
+        let payload = node.get_payload()?;
+        if payload.len() != NodeId::SIZE {
+            return None;
+        }
+        NodeId::from_node(&node.get_def(), Some(payload))
+    }
+
+    /// The payload of a node which holds this id.
+    pub fn to_payload(self) -> Vec<u8> {
+        self.0.to_le_bytes().to_vec()
+    }
+}
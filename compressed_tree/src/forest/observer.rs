@@ -0,0 +1,452 @@
+//! Change notifications: callbacks subscribed to part of a [Forest], which are told about edits to it.
+//!
+//! Subscriptions are scoped to a subtree or a field, which are tracked with anchors, so they follow the content as it is edited.
+//! Events are matched against subscriptions as each edit is applied (so their paths are relative to the forest at that point),
+//! and are delivered when the batch the edit is part of completes, followed by [Event::BatchCompleted].
+//! Hosts which can not run callbacks while the forest is in use can defer delivery (see [Forest::defer_notifications]).
+//! A [Changeset](super::changeset::Changeset) is one batch, as is a whole [Transaction](super::transaction::Transaction),
+//! and edits made directly on the forest are each their own batch.
+//! Edits which fail part way through deliver nothing.
+//!
+//! Cloning a forest does not copy its subscribers: they are only notified about edits to the forest they subscribed to.
+//! Building a forest with [Forest::set_root] does not notify.
+//!
+//! Detaching nodes is reported as removing them, and inserting detached content is reported as inserting it.
+//! Content in detached fields is outside of every scope other than the root, so building content before inserting it is not reported.
+
+use std::{cell::RefCell, rc::Rc};
+
+use crate::FieldKey;
+
+use super::{
+    anchor::{Anchor, AnchorLocation},
+    edit::EditError,
+    mixed::Forest,
+    path::{FieldPosition, FieldRange, Path},
+};
+
+/// Change to a forest, delivered to subscribers.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Event {
+    /// `count` nodes were inserted at `at`.
+    NodesInserted { at: FieldPosition, count: u32 },
+    /// The nodes in `range` were removed (or moved elsewhere).
+    NodesRemoved { range: FieldRange },
+    /// The payload of the node at `path` was set to `value`.
+    ValueChanged { path: Path, value: Option<Vec<u8>> },
+    /// All events from a batch of edits have been delivered.
+    BatchCompleted,
+}
+
+/// Part of a forest to be notified about.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Scope {
+    /// The node at the path and everything under it. The root path covers the whole forest.
+    Subtree(Path),
+    /// A field of the node at the path, and everything under it.
+    Field(Path, FieldKey),
+}
+
+/// Handle to a subscription, used to unsubscribe.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Subscription(pub u32);
+
+type Callback = Rc<dyn Fn(&Event)>;
+
+struct Subscriber {
+    subscription: Subscription,
+    /// Anchor to the node the scope is relative to. None for the root.
+    node: Option<Anchor>,
+    /// For [Scope::Field], the key of the field.
+    key: Option<FieldKey>,
+    callback: Callback,
+}
+
+#[derive(Default)]
+struct Subscribers {
+    next: u32,
+    list: Vec<Subscriber>,
+}
+
+/// Subscribers to a forest, and the events waiting to be delivered to them.
+#[derive(Default)]
+pub(super) struct Events {
+    subscribers: Rc<RefCell<Subscribers>>,
+    pending: Vec<(Subscription, Event)>,
+    /// Number of batches currently open. Events are delivered when this returns to 0.
+    batch_depth: u32,
+    /// If set, events from completed batches are queued in `ready` instead of being delivered.
+    deferred: bool,
+    ready: Vec<(Callback, Event)>,
+}
+
+/// Events from completed batches, taken from a forest with [Forest::take_notifications].
+#[must_use]
+pub struct Notifications(Vec<(Callback, Event)>);
+
+impl Notifications {
+    /// Calls the subscribers' callbacks.
+    pub fn deliver(self) {
+        for (callback, event) in self.0 {
+            callback(&event);
+        }
+    }
+}
+
+/// Clones of a forest are unobserved, so cloning gives no subscribers.
+impl Clone for Events {
+    fn clone(&self) -> Events {
+        Events::default()
+    }
+}
+
+impl Forest {
+    /// Calls `callback` with events for the edits within `scope`.
+    /// Returns None if the node `scope` is relative to does not exist.
+    ///
+    /// The callback is called after each batch of edits, so it can not observe a partially applied batch.
+    pub fn subscribe(
+        &mut self,
+        scope: Scope,
+        callback: impl Fn(&Event) + 'static,
+    ) -> Option<Subscription> {
+        let (path, key) = match scope {
+            Scope::Subtree(path) => (path, None),
+            Scope::Field(path, key) => (path, Some(key)),
+        };
+        let node = if path.is_root() {
+            None
+        } else {
+            Some(self.anchor_node(&path)?)
+        };
+        let mut subscribers = self.events.subscribers.borrow_mut();
+        let subscription = Subscription(subscribers.next);
+        subscribers.next += 1;
+        subscribers.list.push(Subscriber {
+            subscription,
+            node,
+            key,
+            callback: Rc::new(callback),
+        });
+        Some(subscription)
+    }
+
+    /// Stops delivering events to `subscription`, including events from the batch in progress.
+    pub fn unsubscribe(&mut self, subscription: Subscription) {
+        let mut subscribers = self.events.subscribers.borrow_mut();
+        if let Some(i) = subscribers
+            .list
+            .iter()
+            .position(|s| s.subscription == subscription)
+        {
+            if let Some(anchor) = subscribers.list.remove(i).node {
+                self.anchors.release(anchor);
+            }
+        }
+    }
+
+    /// Copy of this forest which keeps its subscribers, and the events waiting to be delivered to them,
+    /// for staging edits which replace this forest if they succeed.
+    pub(super) fn observed_copy(&self) -> Forest {
+        let mut forest = self.clone();
+        forest.events = Events {
+            subscribers: self.events.subscribers.clone(),
+            pending: self.events.pending.clone(),
+            batch_depth: self.events.batch_depth,
+            deferred: self.events.deferred,
+            ready: self.events.ready.clone(),
+        };
+        forest
+    }
+
+    /// Queues events from completed batches instead of calling the subscribers during the edit,
+    /// until they are taken with [Forest::take_notifications].
+    ///
+    /// This lets hosts which hold the forest while editing it (like a `RefCell` borrow)
+    /// deliver events after releasing it, so the callbacks can use the forest.
+    pub fn defer_notifications(&mut self) {
+        self.events.deferred = true;
+    }
+
+    /// Takes the events queued since [Forest::defer_notifications] (or the last call to this).
+    pub fn take_notifications(&mut self) -> Notifications {
+        Notifications(std::mem::take(&mut self.events.ready))
+    }
+
+    /// Starts a batch: events are held until the matching [Forest::end_batch].
+    pub(super) fn start_batch(&mut self) {
+        self.events.batch_depth += 1;
+    }
+
+    /// Ends a batch started with [Forest::start_batch], delivering the events if it was the outermost one.
+    pub(super) fn end_batch(&mut self) {
+        self.events.batch_depth -= 1;
+        if self.events.batch_depth == 0 {
+            self.deliver();
+        }
+    }
+
+    /// Runs `edit` as a batch (nested in the current one, if any).
+    /// If it fails, the events it reported are dropped, so subscribers are only told about edits which succeeded.
+    pub(super) fn batched<R>(
+        &mut self,
+        edit: impl FnOnce(&mut Forest) -> Result<R, EditError>,
+    ) -> Result<R, EditError> {
+        let reported = self.events.pending.len();
+        self.start_batch();
+        let result = edit(self);
+        if result.is_err() {
+            self.events.pending.truncate(reported);
+        }
+        self.end_batch();
+        result
+    }
+
+    /// Reports an edit to the subscribers whose scope it is in.
+    /// Must be called before the edit updates the anchors for removals, and after for insertions.
+    pub(super) fn notify(&mut self, event: Event) {
+        let subscribers = self.events.subscribers.clone();
+        for subscriber in subscribers.borrow().list.iter() {
+            let node = match subscriber.node {
+                None => Path::root(),
+                Some(anchor) => match self.anchors.locate(anchor) {
+                    Some(AnchorLocation::Node(path)) => path,
+                    _ => continue,
+                },
+            };
+            if in_scope(&node, subscriber.key.as_ref(), &event) {
+                self.events
+                    .pending
+                    .push((subscriber.subscription, event.clone()));
+            }
+        }
+        if self.events.batch_depth == 0 {
+            self.deliver();
+        }
+    }
+
+    fn deliver(&mut self) {
+        let pending = std::mem::take(&mut self.events.pending);
+        if pending.is_empty() {
+            return;
+        }
+        // Callbacks are collected first so the subscribers are not borrowed while they run.
+        let callbacks: Vec<(Subscription, Callback)> = self
+            .events
+            .subscribers
+            .borrow()
+            .list
+            .iter()
+            .map(|s| (s.subscription, s.callback.clone()))
+            .collect();
+        let mut ready = vec![];
+        for (subscription, callback) in callbacks {
+            let mut any = false;
+            for (_, event) in pending.iter().filter(|(s, _)| *s == subscription) {
+                ready.push((callback.clone(), event.clone()));
+                any = true;
+            }
+            if any {
+                ready.push((callback, Event::BatchCompleted));
+            }
+        }
+        if self.events.deferred {
+            self.events.ready.extend(ready);
+        } else {
+            Notifications(ready).deliver();
+        }
+    }
+}
+
+/// True if `node` (the path of a node, not the root) is in field `key` of the node at `parent`.
+fn in_field(parent: &Path, key: &FieldKey, node: &Path) -> bool {
+    node.0.len() > parent.0.len() && parent.is_prefix_of(node) && node.0[parent.0.len()].key == *key
+}
+
+/// True if `event` changes anything in the scope given by the node at `node`, and the field `key` of it for field scopes.
+fn in_scope(node: &Path, key: Option<&FieldKey>, event: &Event) -> bool {
+    let contains = |changed: &Path| match key {
+        None => node.is_prefix_of(changed),
+        Some(key) => in_field(node, key, changed),
+    };
+    match event {
+        Event::NodesInserted { at, .. } => contains(&at.parent.child(at.key.clone(), at.index)),
+        Event::NodesRemoved { range } => {
+            let removes_scope = in_field(&range.parent, &range.key, node) && {
+                let index = node.0[range.parent.0.len()].index;
+                range.start <= index && index < range.end
+            };
+            removes_scope || contains(&range.parent.child(range.key.clone(), range.start))
+        }
+        Event::ValueChanged { path, .. } => contains(path),
+        Event::BatchCompleted => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forest::{
+        changeset::{Change, Changeset},
+        mixed::Chunk,
+        store::{ChunkId, ChunkStore, MemoryStore},
+        test_stuff::{key, leaf, test_forest},
+    };
+
+    fn record(forest: &mut Forest, scope: Scope) -> Rc<RefCell<Vec<Event>>> {
+        let events: Rc<RefCell<Vec<Event>>> = Rc::default();
+        let log = events.clone();
+        forest
+            .subscribe(scope, move |e| log.borrow_mut().push(e.clone()))
+            .unwrap();
+        events
+    }
+
+    #[test]
+    fn events_are_scoped() {
+        let mut forest = test_forest();
+        let root = Path::detached(key("root"), 0);
+        let all = record(&mut forest, Scope::Subtree(Path::root()));
+        let b = record(&mut forest, Scope::Field(root.clone(), key("b")));
+        let node = record(&mut forest, Scope::Subtree(root.child(key("b"), 1)));
+
+        forest
+            .set_value(&root.child(key("a"), 0), Some(vec![5]))
+            .unwrap();
+        assert_eq!(all.borrow().len(), 2);
+        assert!(b.borrow().is_empty());
+
+        // Inserting before the subscribed node moves its scope.
+        let content = forest.build(vec![leaf(3)]);
+        forest
+            .insert(&content, &FieldPosition::new(root.clone(), key("b"), 0))
+            .unwrap();
+        let inserted = Event::NodesInserted {
+            at: FieldPosition::new(root.clone(), key("b"), 0),
+            count: 1,
+        };
+        assert_eq!(*b.borrow(), [inserted.clone(), Event::BatchCompleted]);
+        assert!(node.borrow().is_empty());
+        forest
+            .set_value(&root.child(key("b"), 2), Some(vec![7]))
+            .unwrap();
+        assert_eq!(
+            node.borrow()[0],
+            Event::ValueChanged {
+                path: root.child(key("b"), 2),
+                value: Some(vec![7])
+            }
+        );
+
+        // Removing the subscribed node is reported to it, and then it gets nothing more.
+        let removed = FieldRange::new(root.clone(), key("b"), 1, 3);
+        forest.detach(&removed).unwrap();
+        assert_eq!(
+            node.borrow()[2..],
+            [
+                Event::NodesRemoved { range: removed },
+                Event::BatchCompleted
+            ]
+        );
+        assert_eq!(b.borrow().len(), 6);
+        assert_eq!(all.borrow().len(), 8);
+    }
+
+    #[test]
+    fn failed_edits_deliver_nothing() {
+        let mut forest = test_forest();
+        let store: Rc<dyn ChunkStore> = Rc::new(MemoryStore::default());
+        forest.set_root(key("lazy"), vec![Chunk::lazy(store, ChunkId(1), 3)]);
+        let events = record(&mut forest, Scope::Subtree(Path::root()));
+
+        let pending = FieldRange::new(Path::root(), key("lazy"), 1, 2);
+        assert_eq!(forest.detach(&pending), Err(EditError::Pending(None)));
+        assert_eq!(
+            forest.set_value(&Path::detached(key("lazy"), 0), None),
+            Err(EditError::Pending(None))
+        );
+        let at = FieldPosition::new(Path::detached(key("lazy"), 0), key("x"), 0);
+        assert_eq!(
+            forest.insert(&key("root"), &at),
+            Err(EditError::Pending(None))
+        );
+        assert!(events.borrow().is_empty());
+    }
+
+    #[test]
+    fn clones_are_unobserved() {
+        let mut forest = test_forest();
+        let events = record(&mut forest, Scope::Subtree(Path::root()));
+        let path = Path::detached(key("root"), 0).child(key("b"), 0);
+
+        let mut copy = forest.clone();
+        copy.set_value(&path, None).unwrap();
+        assert!(events.borrow().is_empty());
+
+        forest.set_value(&path, None).unwrap();
+        assert_eq!(events.borrow().len(), 2);
+    }
+
+    #[test]
+    fn batches_are_delivered_together() {
+        let mut forest = test_forest();
+        let root = Path::detached(key("root"), 0);
+        let events = record(&mut forest, Scope::Field(root.clone(), key("a")));
+        let changeset = Changeset(vec![
+            Change::SetValue {
+                path: root.child(key("a"), 0),
+                value: None,
+            },
+            Change::Move {
+                range: FieldRange::new(root.clone(), key("a"), 0, 2),
+                to: FieldPosition::new(root.clone(), key("b"), 0),
+            },
+        ]);
+
+        // Inverting applies the changeset to a copy, which must not notify.
+        changeset.invert(&forest).unwrap();
+        assert!(events.borrow().is_empty());
+
+        // A failed changeset delivers nothing.
+        let mut failing = changeset.clone();
+        failing.0.push(Change::Remove {
+            range: FieldRange::new(root.clone(), key("a"), 0, 10),
+        });
+        assert!(failing.apply(&mut forest).is_err());
+        assert!(events.borrow().is_empty());
+
+        let mut transaction = forest.transaction();
+        transaction.apply(&changeset).unwrap();
+        transaction
+            .set_value(root.child(key("a"), 0), Some(vec![1]))
+            .unwrap();
+        assert!(events.borrow().is_empty());
+        transaction.commit();
+        assert_eq!(
+            *events.borrow(),
+            [
+                Event::ValueChanged {
+                    path: root.child(key("a"), 0),
+                    value: None
+                },
+                Event::NodesRemoved {
+                    range: FieldRange::new(root.clone(), key("a"), 0, 2)
+                },
+                Event::ValueChanged {
+                    path: root.child(key("a"), 0),
+                    value: Some(vec![1])
+                },
+                Event::BatchCompleted,
+            ]
+        );
+
+        // Rolled back transactions deliver nothing.
+        events.borrow_mut().clear();
+        let mut transaction = forest.transaction();
+        transaction
+            .set_value(root.child(key("a"), 0), None)
+            .unwrap();
+        transaction.rollback();
+        assert!(events.borrow().is_empty());
+    }
+}
@@ -0,0 +1,160 @@
+//! Absolute paths to nodes and positions within a [super::mixed::Forest].
+
+use std::{fmt, str::FromStr};
+
+use crate::FieldKey;
+
+/// Navigation from a node into one of its fields, then to the node at `index` in that field.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct PathStep {
+    pub key: FieldKey,
+    pub index: u32,
+}
+
+/// Path to a node from the root of the forest.
+///
+/// The root is a virtual node whose fields are the forest's detached fields,
+/// so the first step's key identifies a detached field.
+/// The empty path refers to the root itself.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct Path(pub Vec<PathStep>);
+
+/// Position between nodes (or at either end) of a field.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct FieldPosition {
+    /// Node containing the field.
+    pub parent: Path,
+    pub key: FieldKey,
+    /// Number of nodes in the field before the position.
+    pub index: u32,
+}
+
+/// Range of consecutive nodes in a field.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct FieldRange {
+    /// Node containing the field.
+    pub parent: Path,
+    pub key: FieldKey,
+    pub start: u32,
+    pub end: u32,
+}
+
+impl Path {
+    pub fn root() -> Path {
+        Path(vec![])
+    }
+
+    /// Path to the node at `index` in the detached field `key`.
+    pub fn detached(key: FieldKey, index: u32) -> Path {
+        Path(vec![PathStep { key, index }])
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Path to the child at `index` in field `key` of this node.
+    pub fn child(&self, key: FieldKey, index: u32) -> Path {
+        let mut steps = self.0.clone();
+        steps.push(PathStep { key, index });
+        Path(steps)
+    }
+
+    /// The parent node, and the step from it to this node. None for the root.
+    pub fn split_last(&self) -> Option<(Path, &PathStep)> {
+        let (last, parent) = self.0.split_last()?;
+        Some((Path(parent.to_vec()), last))
+    }
+
+    /// True if `self` is `other` or one of its ancestors.
+    pub fn is_prefix_of(&self, other: &Path) -> bool {
+        other.0.starts_with(&self.0)
+    }
+}
+
+impl FieldPosition {
+    pub fn new(parent: Path, key: FieldKey, index: u32) -> FieldPosition {
+        FieldPosition { parent, key, index }
+    }
+}
+
+impl FieldRange {
+    pub fn new(parent: Path, key: FieldKey, start: u32, end: u32) -> FieldRange {
+        debug_assert!(start <= end);
+        FieldRange {
+            parent,
+            key,
+            start,
+            end,
+        }
+    }
+
+    pub fn len(&self) -> u32 {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Position of the start of the range.
+    pub fn start_position(&self) -> FieldPosition {
+        FieldPosition::new(self.parent.clone(), self.key.clone(), self.start)
+    }
+}
+
+/// Formats as `key[index]` steps separated by `/`, for example `root[0]/child[2]`.
+/// The root path is formatted as an empty string.
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, step) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str("/")?;
+            }
+            write!(f, "{}[{}]", step.key.0, step.index)?;
+        }
+        Ok(())
+    }
+}
+
+/// Error from parsing a [Path].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ParsePathError(pub String);
+
+/// Parses the format written by [Path]'s Display implementation.
+/// Keys containing `/` or `[` can not be parsed.
+impl FromStr for Path {
+    type Err = ParsePathError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Ok(Path::root());
+        }
+        s.split('/')
+            .map(|step| {
+                let error = || ParsePathError(format!("invalid path step: {:?}", step));
+                let (key, index) = step.split_once('[').ok_or_else(error)?;
+                let index = index.strip_suffix(']').ok_or_else(error)?;
+                Ok(PathStep {
+                    key: FieldKey(key.into()),
+                    index: index.parse().map_err(|_| error())?,
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map(Path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_round_trip() {
+        let path = Path::detached(FieldKey("root".into()), 0).child(FieldKey("x".into()), 12);
+        assert_eq!(path.to_string(), "root[0]/x[12]");
+        assert_eq!("root[0]/x[12]".parse(), Ok(path));
+        assert_eq!("".parse(), Ok(Path::root()));
+        assert!("root[0]/x".parse::<Path>().is_err());
+    }
+}
@@ -0,0 +1,557 @@
+//! Rebasing changesets over concurrent changesets which were sequenced first.
+//!
+//! Each change which is rebased over is described by its effect on the structure of the tree
+//! (the same primitives as [super::anchor]: insert, detach, attach and delete of a detached field).
+//! The paths and positions in the rebased change are mapped through those effects:
+//! - Concurrent inserts at the same position are ordered with the change which was sequenced first going first.
+//! - Changes to nodes which were deleted (including changes inside deleted subtrees) are dropped.
+//!   Nodes concurrently moved out of a deleted subtree are deleted too.
+//! - Changes to nodes which were moved follow the nodes.
+//!   Of two concurrent moves of the same node, the one sequenced last wins.
+//!   Nodes moved into a subtree which was concurrently deleted are deleted.
+//! - Removes and moves of ranges apply to the nodes which were in the range,
+//!   so they are split if the range was split by concurrent inserts or moves.
+//!   If concurrent moves would create a cycle, the one sequenced first wins.
+//! - Of two concurrent value sets of the same node, the one sequenced last wins.
+//!
+//! Ranges are always tracked node by node, so rebasing is proportional to the size of the ranges involved.
+
+use crate::FieldKey;
+
+use super::{
+    changeset::{Change, Changeset},
+    path::{FieldPosition, FieldRange, Path, PathStep},
+};
+
+/// Key used for the detached field nodes are moved into while describing an effect.
+fn scratch_key() -> FieldKey {
+    FieldKey("\0rebase".into())
+}
+
+fn in_scratch(steps: &[PathStep]) -> bool {
+    steps.first().is_some_and(|s| s.key == scratch_key())
+}
+
+/// Effect of a change on the structure of the tree.
+enum Effect {
+    Insert {
+        at: FieldPosition,
+        count: u32,
+    },
+    /// Moves `range` into the scratch field.
+    Detach {
+        range: FieldRange,
+    },
+    /// Moves the `count` nodes in the scratch field to `at`.
+    Attach {
+        at: FieldPosition,
+        count: u32,
+    },
+    /// Deletes the scratch field.
+    Delete,
+}
+
+fn effects(change: &Change) -> Vec<Effect> {
+    match change {
+        Change::Insert { at, content } => vec![Effect::Insert {
+            at: at.clone(),
+            count: content.iter().map(|c| c.len() as u32).sum(),
+        }],
+        Change::Remove { range } => vec![
+            Effect::Detach {
+                range: range.clone(),
+            },
+            Effect::Delete,
+        ],
+        Change::SetValue { .. } => vec![],
+        Change::Move { range, to } => vec![
+            Effect::Detach {
+                range: range.clone(),
+            },
+            Effect::Attach {
+                at: to.clone(),
+                count: range.len(),
+            },
+        ],
+    }
+}
+
+/// Depth of the step in `steps` which is in field `key` of `parent`, if there is one.
+fn through(steps: &[PathStep], parent: &Path, key: &FieldKey) -> Option<usize> {
+    let depth = parent.0.len();
+    (steps.len() > depth && steps[..depth] == parent.0[..] && steps[depth].key == *key)
+        .then_some(depth)
+}
+
+/// Maps a path to a node through `effect`. None if the node was deleted.
+fn map_steps(mut steps: Vec<PathStep>, effect: &Effect) -> Option<Vec<PathStep>> {
+    match effect {
+        Effect::Insert { at, count } => {
+            if let Some(depth) = through(&steps, &at.parent, &at.key) {
+                if steps[depth].index >= at.index {
+                    steps[depth].index += count;
+                }
+            }
+        }
+        Effect::Detach { range } => {
+            if let Some(depth) = through(&steps, &range.parent, &range.key) {
+                let index = steps[depth].index;
+                if index >= range.end {
+                    steps[depth].index -= range.len();
+                } else if index >= range.start {
+                    let mut moved = vec![PathStep {
+                        key: scratch_key(),
+                        index: index - range.start,
+                    }];
+                    moved.extend(steps.drain(depth + 1..));
+                    steps = moved;
+                }
+            }
+        }
+        Effect::Attach { at, count } => {
+            if in_scratch(&steps) {
+                let mut moved = at.parent.0.clone();
+                moved.push(PathStep {
+                    key: at.key.clone(),
+                    index: at.index + steps[0].index,
+                });
+                moved.extend(steps.drain(1..));
+                steps = moved;
+            } else if let Some(depth) = through(&steps, &at.parent, &at.key) {
+                if steps[depth].index >= at.index {
+                    steps[depth].index += count;
+                }
+            }
+        }
+        Effect::Delete => {
+            if in_scratch(&steps) {
+                return None;
+            }
+        }
+    }
+    Some(steps)
+}
+
+fn map_path(path: &Path, effects: &[Effect]) -> Option<Path> {
+    let mut steps = path.0.clone();
+    for effect in effects {
+        steps = map_steps(steps, effect)?;
+    }
+    Some(Path(steps))
+}
+
+/// Maps a position through `effects`. None if the node containing it was deleted.
+///
+/// Positions inside detached ranges collapse to where the range was.
+/// `after_inserts` selects which side of nodes inserted exactly at the position it ends up on.
+fn map_position(
+    position: &FieldPosition,
+    effects: &[Effect],
+    after_inserts: bool,
+) -> Option<FieldPosition> {
+    let mut parent = position.parent.0.clone();
+    let mut index = position.index;
+    for effect in effects {
+        match effect {
+            Effect::Insert { at, count } | Effect::Attach { at, count } => {
+                if parent == at.parent.0
+                    && position.key == at.key
+                    && (index > at.index || (index == at.index && after_inserts))
+                {
+                    index += count;
+                }
+            }
+            Effect::Detach { range } => {
+                if parent == range.parent.0 && position.key == range.key {
+                    if index >= range.end {
+                        index -= range.len();
+                    } else if index > range.start {
+                        index = range.start;
+                    }
+                }
+            }
+            Effect::Delete => {}
+        }
+        parent = map_steps(parent, effect)?;
+    }
+    Some(FieldPosition::new(
+        Path(parent),
+        position.key.clone(),
+        index,
+    ))
+}
+
+/// The nodes in `range`.
+fn nodes(range: &FieldRange) -> Vec<Path> {
+    (range.start..range.end)
+        .map(|i| range.parent.child(range.key.clone(), i))
+        .collect()
+}
+
+/// The longest run of `nodes` which starts with the first node and are consecutive siblings, as a range.
+fn first_run(nodes: &[Path]) -> FieldRange {
+    let (parent, step) = nodes[0].split_last().unwrap();
+    let mut end = step.index + 1;
+    for node in nodes[1..].iter() {
+        match node.split_last() {
+            Some((p, s)) if p == parent && s.key == step.key && s.index == end => end += 1,
+            _ => break,
+        }
+    }
+    FieldRange::new(parent, step.key.clone(), step.index, end)
+}
+
+/// Changes which remove `nodes`, which may be anywhere in the tree.
+fn remove_nodes(mut nodes: Vec<Path>) -> Vec<Change> {
+    let mut changes = vec![];
+    while !nodes.is_empty() {
+        // Group nodes which have become siblings, regardless of their order.
+        let (parent, step) = nodes[0].split_last().unwrap();
+        let mut indexes: Vec<u32> = nodes
+            .iter()
+            .filter_map(|n| match n.split_last() {
+                Some((p, s)) if p == parent && s.key == step.key => Some(s.index),
+                _ => None,
+            })
+            .collect();
+        indexes.sort_unstable();
+        let mut end = indexes[0] + 1;
+        while indexes.get((end - indexes[0]) as usize) == Some(&end) {
+            end += 1;
+        }
+        let range = FieldRange::new(parent, step.key.clone(), indexes[0], end);
+        let change = Change::Remove { range };
+        let effects = effects(&change);
+        nodes = nodes.iter().filter_map(|n| map_path(n, &effects)).collect();
+        changes.push(change);
+    }
+    changes
+}
+
+/// Changes which move `nodes`, which may be anywhere in the tree, to `to`, keeping their order.
+/// None if `to` is inside the nodes, so moving them would create a cycle.
+fn move_nodes(mut nodes: Vec<Path>, mut to: FieldPosition) -> Option<Vec<Change>> {
+    let mut changes = vec![];
+    while !nodes.is_empty() {
+        let range = first_run(&nodes);
+        let count = range.len();
+        let detach = [Effect::Detach {
+            range: range.clone(),
+        }];
+        let destination = map_position(&to, &detach, false)?;
+        if in_scratch(&destination.parent.0) {
+            return None;
+        }
+        let change = Change::Move {
+            range,
+            to: destination.clone(),
+        };
+        let effects = effects(&change);
+        nodes = nodes[count as usize..]
+            .iter()
+            .filter_map(|n| map_path(n, &effects))
+            .collect();
+        to = FieldPosition::new(
+            destination.parent,
+            destination.key,
+            destination.index + count,
+        );
+        changes.push(change);
+    }
+    Some(changes)
+}
+
+/// Converts the destination of a move from being relative to the tree after `range` is detached
+/// to being relative to the tree before.
+fn before_detach(to: &FieldPosition, range: &FieldRange) -> FieldPosition {
+    let mut to = to.clone();
+    if to.parent == range.parent && to.key == range.key && to.index > range.start {
+        to.index += range.len();
+    }
+    if let Some(depth) = through(&to.parent.0, &range.parent, &range.key) {
+        if to.parent.0[depth].index >= range.start {
+            to.parent.0[depth].index += range.len();
+        }
+    }
+    to
+}
+
+/// Moving nodes to where they already are does nothing.
+fn is_noop(change: &Change) -> bool {
+    matches!(change, Change::Move { range, to } if *to == range.start_position())
+}
+
+/// Rebases `change` over `over`, which was applied to the same tree.
+/// `later` is true if `change` was sequenced after `over`.
+fn rebase_change(change: &Change, over: &Change, later: bool) -> Vec<Change> {
+    if is_noop(change) {
+        return vec![];
+    }
+    if is_noop(over) {
+        return vec![change.clone()];
+    }
+    let effects = effects(over);
+    match change {
+        Change::Insert { at, content } => map_position(at, &effects, later)
+            .map(|at| Change::Insert {
+                at,
+                content: content.clone(),
+            })
+            .into_iter()
+            .collect(),
+        Change::Remove { range } => {
+            let mut removed = nodes(range);
+            if let Change::Move { range: other, .. } = over {
+                // Nodes concurrently moved out of the removed subtrees are removed too,
+                // since the move is dropped when rebased over this.
+                let moved_out: Vec<Path> = nodes(other)
+                    .into_iter()
+                    .filter(|n| removed.iter().any(|r| r != n && r.is_prefix_of(n)))
+                    .collect();
+                removed.extend(moved_out);
+            }
+            remove_nodes(
+                removed
+                    .iter()
+                    .filter_map(|n| map_path(n, &effects))
+                    .collect(),
+            )
+        }
+        Change::SetValue { path, value } => {
+            if let Change::SetValue { path: other, .. } = over {
+                if !later && other == path {
+                    return vec![];
+                }
+            }
+            map_path(path, &effects)
+                .map(|path| Change::SetValue {
+                    path,
+                    value: value.clone(),
+                })
+                .into_iter()
+                .collect()
+        }
+        Change::Move { range, to } => {
+            if let Change::Move {
+                range: other,
+                to: other_to,
+            } = over
+            {
+                if !later && rebase_move(other, other_to, change, true).is_none() {
+                    // The later move would create a cycle so it is dropped: undo it before this move.
+                    let undo = Change::Move {
+                        range: FieldRange::new(
+                            other_to.parent.clone(),
+                            other_to.key.clone(),
+                            other_to.index,
+                            other_to.index + other.len(),
+                        ),
+                        to: other.start_position(),
+                    };
+                    return vec![undo, change.clone()];
+                }
+            }
+            rebase_move(range, to, over, later).unwrap_or_default()
+        }
+    }
+}
+
+/// Rebases a move of `range` to `to` over `over`. None if the move would create a cycle.
+fn rebase_move(
+    range: &FieldRange,
+    to: &FieldPosition,
+    over: &Change,
+    later: bool,
+) -> Option<Vec<Change>> {
+    let effects = effects(over);
+    let nodes = nodes(range)
+        .into_iter()
+        .filter(|n| match over {
+            // Nodes moved by both: the move sequenced last wins.
+            Change::Move { range: other, .. } if !later => {
+                let (parent, step) = n.split_last().unwrap();
+                !(parent == other.parent
+                    && step.key == other.key
+                    && other.start <= step.index
+                    && step.index < other.end)
+            }
+            _ => true,
+        })
+        .filter_map(|n| map_path(&n, &effects))
+        .collect();
+    match map_position(&before_detach(to, range), &effects, later) {
+        Some(to) => move_nodes(nodes, to),
+        // The destination was deleted, which would have deleted the moved nodes too.
+        None => Some(remove_nodes(nodes)),
+    }
+}
+
+/// Rebases `changes` over `over`, where both apply to the same tree and `over` was sequenced first.
+/// Returns `changes` rebased over `over`, and `over` rebased over `changes`.
+pub fn rebase_both(changes: &[Change], over: &[Change]) -> (Vec<Change>, Vec<Change>) {
+    match (changes.len(), over.len()) {
+        (0, _) => (vec![], over.to_vec()),
+        (_, 0) => (changes.to_vec(), vec![]),
+        (1, 1) => (
+            rebase_change(&changes[0], &over[0], true),
+            rebase_change(&over[0], &changes[0], false),
+        ),
+        (1, _) => {
+            let (changes, mut first) = rebase_both(changes, &over[..1]);
+            let (changes, rest) = rebase_both(&changes, &over[1..]);
+            first.extend(rest);
+            (changes, first)
+        }
+        _ => {
+            let (mut first, over) = rebase_both(&changes[..1], over);
+            let (rest, over) = rebase_both(&changes[1..], &over);
+            first.extend(rest);
+            (first, over)
+        }
+    }
+}
+
+impl Changeset {
+    /// Rebases this changeset over `over`, which applies to the same tree and was sequenced first.
+    /// The result applies to the tree after `over`.
+    pub fn rebase(&self, over: &Changeset) -> Changeset {
+        Changeset(rebase_both(&self.0, &over.0).0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        forest::{
+            mixed::{Forest, MixedNode},
+            test_stuff::{describe_root, forest_with_root, key, leaf},
+        },
+        TreeType,
+    };
+
+    fn root() -> Path {
+        Path::detached(key("root"), 0)
+    }
+
+    /// Root node with `count` leaves in field "x".
+    fn rebase_forest(count: u8) -> Forest {
+        let mut root = MixedNode::new(TreeType("root".into()), None);
+        root.fields_mut()
+            .insert(key("x"), (0..count).map(leaf).collect());
+        forest_with_root(root)
+    }
+
+    fn insert(index: u32, value: u8) -> Change {
+        Change::Insert {
+            at: FieldPosition::new(root(), key("x"), index),
+            content: vec![leaf(value)],
+        }
+    }
+
+    fn range(start: u32, end: u32) -> FieldRange {
+        FieldRange::new(root(), key("x"), start, end)
+    }
+
+    /// Applies `first` then `second` rebased over it, and checks that applying `second`
+    /// then `first` rebased over it gives the same result.
+    fn check(count: u8, first: Vec<Change>, second: Vec<Change>) -> String {
+        let (first, second) = (Changeset(first), Changeset(second));
+        let mut a = rebase_forest(count);
+        first.apply(&mut a).unwrap();
+        second.rebase(&first).apply(&mut a).unwrap();
+
+        let mut b = rebase_forest(count);
+        let (second_rebased, first_rebased) = rebase_both(&second.0, &first.0);
+        assert_eq!(second_rebased.len(), second.rebase(&first).0.len());
+        second.apply(&mut b).unwrap();
+        Changeset(first_rebased).apply(&mut b).unwrap();
+        assert_eq!(describe_root(&a), describe_root(&b));
+        describe_root(&a)
+    }
+
+    #[test]
+    fn concurrent_inserts_order_by_sequence() {
+        let result = check(2, vec![insert(1, 10)], vec![insert(1, 20)]);
+        let expected = rebase_forest(0);
+        let mut expected = expected;
+        Changeset(vec![
+            insert(0, 0),
+            insert(1, 10),
+            insert(2, 20),
+            insert(3, 1),
+        ])
+        .apply(&mut expected)
+        .unwrap();
+        assert_eq!(result, describe_root(&expected));
+    }
+
+    #[test]
+    fn removes_keep_concurrent_inserts() {
+        // The insert inside the removed range survives, and splits the remove.
+        let result = check(
+            4,
+            vec![insert(2, 10)],
+            vec![Change::Remove { range: range(1, 4) }],
+        );
+        let mut expected = rebase_forest(0);
+        Changeset(vec![insert(0, 0), insert(1, 10)])
+            .apply(&mut expected)
+            .unwrap();
+        assert_eq!(result, describe_root(&expected));
+    }
+
+    #[test]
+    fn changes_under_removed_ancestors_are_dropped() {
+        let child = root().child(key("x"), 1);
+        let local = Changeset(vec![
+            Change::SetValue {
+                path: child.clone(),
+                value: None,
+            },
+            Change::Insert {
+                at: FieldPosition::new(child, key("y"), 0),
+                content: vec![leaf(5)],
+            },
+        ]);
+        let over = Changeset(vec![Change::Remove { range: range(0, 2) }]);
+        assert!(local.rebase(&over).0.is_empty());
+    }
+
+    #[test]
+    fn changes_follow_moved_nodes() {
+        let local = Changeset(vec![Change::SetValue {
+            path: root().child(key("x"), 0),
+            value: Some(vec![7]),
+        }]);
+        let over = Changeset(vec![Change::Move {
+            range: range(0, 1),
+            to: FieldPosition::new(root().child(key("x"), 1), key("y"), 0),
+        }]);
+        match &local.rebase(&over).0[..] {
+            [Change::SetValue { path, .. }] => {
+                assert_eq!(path, &root().child(key("x"), 1).child(key("y"), 0))
+            }
+            _ => panic!("expected one value set"),
+        }
+        check(3, over.0, local.0);
+    }
+
+    #[test]
+    fn moves_into_moved_nodes_are_dropped() {
+        // Concurrently move 0 under 1, and 1 under 0: the second move would create a cycle so is dropped.
+        let first = Changeset(vec![Change::Move {
+            range: range(0, 1),
+            to: FieldPosition::new(root().child(key("x"), 0), key("y"), 0),
+        }]);
+        let second = Changeset(vec![Change::Move {
+            range: range(1, 2),
+            to: FieldPosition::new(root().child(key("x"), 0), key("y"), 0),
+        }]);
+        assert!(second.rebase(&first).0.is_empty());
+        // Rebasing the first move over the second undoes the second.
+        let (_, first_rebased) = rebase_both(&second.0, &first.0);
+        assert_eq!(first_rebased.len(), 2);
+        check(2, first.0, second.0);
+    }
+}
@@ -0,0 +1,365 @@
+//! Local stand-in for a service which sequences changesets from several clients, for testing [Changeset::rebase].
+//!
+//! Clients have at most one changeset in flight (sent but not sequenced yet) at a time.
+//! Each sequenced changeset records how many sequenced changesets its client had received when sending it,
+//! and every client rebases it over the changesets sequenced since then before applying it.
+//! Since every client does this in the same order with the same inputs, they all converge.
+
+use super::{
+    changeset::{Change, Changeset},
+    mixed::Forest,
+    rebase::rebase_both,
+};
+use crate::forest::edit::EditError;
+
+/// Changeset sent by a client.
+#[derive(Clone)]
+pub struct Sequenced {
+    pub client: usize,
+    /// Number of sequenced changesets the client had received when sending this.
+    pub reference: usize,
+    pub changeset: Changeset,
+}
+
+/// Orders changesets from all clients.
+#[derive(Default)]
+pub struct Sequencer {
+    log: Vec<Sequenced>,
+}
+
+impl Sequencer {
+    /// Sequences `changeset` after all previously sequenced changesets, and returns its sequence number.
+    pub fn submit(&mut self, changeset: Sequenced) -> usize {
+        self.log.push(changeset);
+        self.log.len() - 1
+    }
+
+    /// All sequenced changesets, in order.
+    pub fn log(&self) -> &[Sequenced] {
+        &self.log
+    }
+}
+
+/// Client which edits a forest locally, and receives sequenced changesets.
+pub struct Client {
+    id: usize,
+    /// Forest with all received sequenced changesets applied.
+    base: Forest,
+    /// Received sequenced changesets, as they were applied to `base`.
+    history: Vec<Changeset>,
+    /// Sent changeset which has not been received back yet, rebased to apply to `base`.
+    in_flight: Option<Changeset>,
+    /// Local changes which have not been sent, which apply after `in_flight`.
+    unsent: Changeset,
+    /// `base` with the local changes applied.
+    view: Forest,
+    /// Number of sequenced changesets which failed to apply after rebasing, and were dropped.
+    dropped: usize,
+}
+
+impl Client {
+    pub fn new(id: usize, forest: Forest) -> Client {
+        Client {
+            id,
+            base: forest.clone(),
+            history: vec![],
+            in_flight: None,
+            unsent: Changeset::default(),
+            view: forest,
+            dropped: 0,
+        }
+    }
+
+    /// The forest including local changes.
+    pub fn view(&self) -> &Forest {
+        &self.view
+    }
+
+    /// The forest with only sequenced changes.
+    pub fn base(&self) -> &Forest {
+        &self.base
+    }
+
+    /// Number of sequenced changesets received.
+    pub fn received(&self) -> usize {
+        self.history.len()
+    }
+
+    /// Number of sequenced changesets which failed to apply after being rebased, and were dropped (see [Client::receive]).
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// True if all local changes have been sequenced and received.
+    pub fn is_synchronized(&self) -> bool {
+        self.in_flight.is_none() && self.unsent.0.is_empty()
+    }
+
+    /// Applies a local change to [Client::view].
+    pub fn edit(&mut self, changeset: Changeset) -> Result<(), EditError> {
+        changeset.apply(&mut self.view)?;
+        self.unsent.0.extend(changeset.0);
+        Ok(())
+    }
+
+    /// Takes the unsent changes to send to the sequencer, unless there is already a changeset in flight.
+    pub fn flush(&mut self) -> Option<Sequenced> {
+        if self.in_flight.is_some() || self.unsent.0.is_empty() {
+            return None;
+        }
+        let changeset = std::mem::take(&mut self.unsent);
+        self.in_flight = Some(changeset.clone());
+        Some(Sequenced {
+            client: self.id,
+            reference: self.received(),
+            changeset,
+        })
+    }
+
+    /// Processes the next sequenced changeset. Must be called for each sequenced changeset, in order.
+    ///
+    /// If the rebased changeset fails to apply, it is dropped (recorded as empty) and counted in [Client::dropped].
+    /// Rebasing is meant to always produce changesets which apply, so this indicates a bug in [Changeset::rebase].
+    /// Every client fails the same way so they stay consistent, but the sender's edit is lost:
+    /// a real host should report the failure (and the sequence number) so it can be diagnosed,
+    /// and may want to resynchronize from a snapshot rather than keep going.
+    pub fn receive(&mut self, sequenced: &Sequenced) {
+        let concurrent: Vec<Change> = self.history[sequenced.reference..]
+            .iter()
+            .flat_map(|c| c.0.iter().cloned())
+            .collect();
+        let mut applied = sequenced.changeset.rebase(&Changeset(concurrent));
+        // Every client fails the same way, so dropping the changeset keeps them consistent.
+        if applied.apply(&mut self.base).is_err() {
+            applied = Changeset::default();
+            self.dropped += 1;
+        }
+
+        if sequenced.client == self.id {
+            self.in_flight = None;
+        } else {
+            let mut over = applied.0.clone();
+            if let Some(in_flight) = self.in_flight.as_mut() {
+                let (rebased, rest) = rebase_both(&in_flight.0, &over);
+                in_flight.0 = rebased;
+                over = rest;
+            }
+            self.unsent = Changeset(rebase_both(&self.unsent.0, &over).0);
+        }
+        self.history.push(applied);
+
+        // Local changes which no longer apply are left out of the view until they are sequenced.
+        self.view = self.base.clone();
+        for local in self.in_flight.iter().chain([&self.unsent]) {
+            let _ = local.apply(&mut self.view);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::{
+        forest::{
+            mixed::{Chunk, MixedNode, MixedNodeRef},
+            path::{FieldPosition, FieldRange, Path},
+            test_stuff::{describe_root, forest_with_root, key},
+            tree::{Indexable, NodeNav},
+            uniform_chunk::{ChunkSchema, UniformChunk},
+        },
+        TreeType,
+    };
+
+    fn initial() -> Forest {
+        forest_with_root(MixedNode::new(TreeType("root".into()), None))
+    }
+
+    /// Paths to all nodes under the "root" field.
+    fn node_paths(forest: &Forest) -> Vec<Path> {
+        fn visit(node: MixedNodeRef, path: Path, out: &mut Vec<Path>) {
+            out.push(path.clone());
+            for (key, field) in node.get_fields() {
+                for i in 0..field.len() {
+                    visit(
+                        field.index(i).unwrap(),
+                        path.child(key.clone(), i as u32),
+                        out,
+                    );
+                }
+            }
+        }
+        let mut out = vec![];
+        visit(
+            forest.node_at(&Path::detached(key("root"), 0)).unwrap(),
+            Path::detached(key("root"), 0),
+            &mut out,
+        );
+        out
+    }
+
+    fn random_position(rng: &mut impl Rng, forest: &Forest) -> FieldPosition {
+        let nodes = node_paths(forest);
+        let parent = nodes[rng.gen_range(0..nodes.len())].clone();
+        let key = key(["a", "b"][rng.gen_range(0..2)]);
+        let length = forest.field_at(&parent, &key).unwrap().len();
+        FieldPosition::new(parent, key, rng.gen_range(0..=length) as u32)
+    }
+
+    /// A range of one or two nodes, excluding the root node. None if there are no such nodes.
+    fn random_range(rng: &mut impl Rng, forest: &Forest) -> Option<FieldRange> {
+        let nodes = node_paths(forest);
+        if nodes.len() < 2 {
+            return None;
+        }
+        let node = &nodes[rng.gen_range(1..nodes.len())];
+        let (parent, step) = node.split_last().unwrap();
+        let length = forest.field_at(&parent, &step.key).unwrap().len() as u32;
+        let end = rng.gen_range(step.index + 1..=length.min(step.index + 2));
+        Some(FieldRange::new(parent, step.key.clone(), step.index, end))
+    }
+
+    fn random_content(rng: &mut impl Rng) -> Chunk {
+        let value: u8 = rng.gen();
+        if rng.gen_bool(0.5) {
+            Chunk::Node(Rc::new(MixedNode::new(
+                TreeType("leaf".into()),
+                Some(vec![value]),
+            )))
+        } else {
+            let count = rng.gen_range(1..4);
+            let schema = ChunkSchema::new_leaf(TreeType("leaf".into()), count, Some(1));
+            Chunk::Uniform(Rc::new(UniformChunk::new(
+                Rc::new(schema),
+                vec![value; count as usize],
+            )))
+        }
+    }
+
+    fn random_change(rng: &mut impl Rng, forest: &Forest) -> Change {
+        loop {
+            match rng.gen_range(0..4) {
+                0 => {
+                    return Change::Insert {
+                        at: random_position(rng, forest),
+                        content: vec![random_content(rng)],
+                    }
+                }
+                1 => {
+                    if let Some(range) = random_range(rng, forest) {
+                        return Change::Remove { range };
+                    }
+                }
+                2 => {
+                    let nodes = node_paths(forest);
+                    return Change::SetValue {
+                        path: nodes[rng.gen_range(0..nodes.len())].clone(),
+                        value: Some(vec![rng.gen()]),
+                    };
+                }
+                _ => {
+                    if let Some(range) = random_range(rng, forest) {
+                        let mut detached = forest.clone();
+                        detached.detach(&range).unwrap();
+                        let to = random_position(rng, &detached);
+                        return Change::Move { range, to };
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn random_interleavings_converge() {
+        for seed in 0..50 {
+            let rng = &mut rand_pcg::Pcg64::seed_from_u64(seed);
+            let mut sequencer = Sequencer::default();
+            let mut clients: Vec<Client> = (0..3).map(|id| Client::new(id, initial())).collect();
+            for _ in 0..60 {
+                let client = &mut clients[rng.gen_range(0..3)];
+                match rng.gen_range(0..3) {
+                    0 => {
+                        let change = random_change(rng, client.view());
+                        client.edit(Changeset(vec![change])).unwrap();
+                    }
+                    1 => {
+                        if let Some(sent) = client.flush() {
+                            sequencer.submit(sent);
+                        }
+                    }
+                    _ => {
+                        if let Some(next) = sequencer.log().get(client.received()) {
+                            client.receive(next);
+                        }
+                    }
+                }
+            }
+
+            // Deliver everything.
+            while !clients
+                .iter()
+                .all(|c| c.is_synchronized() && c.received() == sequencer.log().len())
+            {
+                for client in clients.iter_mut() {
+                    if let Some(sent) = client.flush() {
+                        sequencer.submit(sent);
+                    }
+                    while let Some(next) = sequencer.log().get(client.received()) {
+                        client.receive(next);
+                    }
+                }
+            }
+
+            let expected = describe_root(clients[0].base());
+            for client in clients.iter() {
+                assert_eq!(client.dropped(), 0, "seed {}", seed);
+                assert_eq!(describe_root(client.base()), expected, "seed {}", seed);
+                assert_eq!(describe_root(client.view()), expected, "seed {}", seed);
+            }
+        }
+    }
+
+    /// Random changes to `forest`, and the forest after applying them.
+    fn random_changes(rng: &mut impl Rng, forest: &Forest) -> (Vec<Change>, Forest) {
+        let mut forest = forest.clone();
+        let changes = (0..rng.gen_range(1..3))
+            .map(|_| {
+                let change = random_change(rng, &forest);
+                Changeset(vec![change.clone()]).apply(&mut forest).unwrap();
+                change
+            })
+            .collect();
+        (changes, forest)
+    }
+
+    #[test]
+    fn concurrent_changesets_commute() {
+        for seed in 0..1000 {
+            let rng = &mut rand_pcg::Pcg64::seed_from_u64(seed);
+            let mut base = initial();
+            for _ in 0..rng.gen_range(0..8) {
+                let change = random_change(rng, &base);
+                Changeset(vec![change]).apply(&mut base).unwrap();
+            }
+            let (first, mut after_first) = random_changes(rng, &base);
+            let (second, mut after_second) = random_changes(rng, &base);
+
+            let (second_rebased, first_rebased) = rebase_both(&second, &first);
+            Changeset(second_rebased)
+                .apply(&mut after_first)
+                .unwrap_or_else(|e| panic!("seed {}: {:?}", seed, e));
+            Changeset(first_rebased)
+                .apply(&mut after_second)
+                .unwrap_or_else(|e| panic!("seed {}: {:?}", seed, e));
+            assert_eq!(
+                describe_root(&after_first),
+                describe_root(&after_second),
+                "seed {}",
+                seed
+            );
+        }
+    }
+}
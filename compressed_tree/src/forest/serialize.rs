@@ -0,0 +1,598 @@
+//! Binary encoding of [Chunk]s.
+//!
+//! [UniformChunk]s are stored as their schema followed by their data blob, so they stay compressed.
+//! Chunks nested within a [MixedNode] can be stored inline or as a reference to another stored chunk,
+//! which is loaded lazily.
+
+use std::rc::Rc;
+
+use crate::{FieldKey, TreeType};
+
+use super::{
+    mixed::{Chunk, Field, MixedNode},
+    store::{ChunkId, ChunkStore},
+    uniform_chunk::{ChunkSchema, OffsetSchema, UniformChunk},
+};
+
+const FORMAT_VERSION: u8 = 0;
+
+const TAG_NODE: u8 = 0;
+const TAG_UNIFORM: u8 = 1;
+const TAG_REFERENCE: u8 = 2;
+
+/// Error from decoding invalid data.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DecodeError(pub &'static str);
+
+/// Error from encoding a chunk which cannot be serialized.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct EncodeError(pub &'static str);
+
+/// Appends primitives to a buffer.
+#[derive(Default)]
+pub struct Writer {
+    pub data: Vec<u8>,
+}
+
+impl Writer {
+    pub fn u8(&mut self, v: u8) {
+        self.data.push(v);
+    }
+
+    /// LEB128 encoded integer.
+    pub fn varint(&mut self, mut v: u64) {
+        loop {
+            let byte = (v & 0x7f) as u8;
+            v >>= 7;
+            if v == 0 {
+                self.data.push(byte);
+                return;
+            }
+            self.data.push(byte | 0x80);
+        }
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u128(&mut self, v: u128) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    /// Length prefixed bytes.
+    pub fn bytes(&mut self, v: &[u8]) {
+        self.varint(v.len() as u64);
+        self.data.extend_from_slice(v);
+    }
+
+    pub fn string(&mut self, v: &str) {
+        self.bytes(v.as_bytes());
+    }
+
+    pub fn optional_bytes(&mut self, v: Option<&[u8]>) {
+        match v {
+            Some(bytes) => {
+                self.u8(1);
+                self.bytes(bytes);
+            }
+            None => self.u8(0),
+        }
+    }
+}
+
+/// Reads primitives written by [Writer].
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], DecodeError> {
+        if length > self.data.len() {
+            return Err(DecodeError("unexpected end of data"));
+        }
+        let (taken, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn varint(&mut self) -> Result<u64, DecodeError> {
+        let mut v = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            v |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err(DecodeError("varint too long"))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, DecodeError> {
+        u32::try_from(self.varint()?).map_err(|_| DecodeError("integer out of range"))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, DecodeError> {
+        let bytes = self.take(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn u128(&mut self) -> Result<u128, DecodeError> {
+        let bytes = self.take(16)?;
+        Ok(u128::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+        let length = self.varint()? as usize;
+        self.take(length)
+    }
+
+    pub fn string(&mut self) -> Result<String, DecodeError> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| DecodeError("invalid utf-8"))
+    }
+
+    pub fn optional_bytes(&mut self) -> Result<Option<&'a [u8]>, DecodeError> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.bytes()?)),
+            _ => Err(DecodeError("invalid option tag")),
+        }
+    }
+}
+
+/// Serializes a chunk. Nested [Chunk::Lazy] chunks are written as references.
+///
+/// Fails for a [Chunk::Lazy], which is already stored:
+/// a blob which is only a reference would load as a lazy chunk nested directly in another.
+/// Also fails for schemas nested deeper than [read_schema] accepts.
+pub fn encode_chunk(chunk: &Chunk) -> Result<Vec<u8>, EncodeError> {
+    if let Chunk::Lazy(_) = chunk {
+        return Err(EncodeError("top level chunk is a reference"));
+    }
+    let mut w = Writer::default();
+    w.u8(FORMAT_VERSION);
+    write_chunk_body(&mut w, chunk)?;
+    Ok(w.data)
+}
+
+/// Deserializes a chunk written by [encode_chunk].
+/// References to other chunks are loaded lazily from `store`.
+pub fn decode_chunk(data: &[u8], store: &Rc<dyn ChunkStore>) -> Result<Chunk, DecodeError> {
+    let mut r = Reader::new(data);
+    if r.u8()? != FORMAT_VERSION {
+        return Err(DecodeError("unsupported format version"));
+    }
+    let chunk = read_chunk_body(&mut r, Some(store))?;
+    if let Chunk::Lazy(_) = chunk {
+        return Err(DecodeError("top level chunk is a reference"));
+    }
+    if !r.is_empty() {
+        return Err(DecodeError("trailing data"));
+    }
+    Ok(chunk)
+}
+
+pub fn write_chunk_body(w: &mut Writer, chunk: &Chunk) -> Result<(), EncodeError> {
+    write_chunks(w, std::slice::from_ref(chunk))
+}
+
+/// Writes each of `chunks` as by [write_chunk_body].
+///
+/// Uses an explicit stack, so deep trees do not overflow the call stack.
+fn write_chunks(w: &mut Writer, chunks: &[Chunk]) -> Result<(), EncodeError> {
+    enum Item<'a> {
+        Chunk(&'a Chunk),
+        Field(&'a FieldKey, &'a [Chunk]),
+    }
+    // Pushed in reverse, so they are popped in order.
+    let mut stack: Vec<Item> = chunks.iter().rev().map(Item::Chunk).collect();
+    while let Some(item) = stack.pop() {
+        match item {
+            Item::Field(key, field) => {
+                w.string(&key.0);
+                w.varint(field.len() as u64);
+                stack.extend(field.iter().rev().map(Item::Chunk));
+            }
+            Item::Chunk(Chunk::Node(node)) => {
+                w.u8(TAG_NODE);
+                w.string(&node.def().0);
+                w.optional_bytes(node.payload());
+                w.varint(node.fields().len() as u64);
+                stack.extend(
+                    node.fields()
+                        .iter()
+                        .rev()
+                        .map(|(key, field)| Item::Field(key, field)),
+                );
+            }
+            Item::Chunk(Chunk::Uniform(uniform)) => {
+                w.u8(TAG_UNIFORM);
+                write_schema(w, uniform.schema(), 0)?;
+                w.bytes(uniform.data());
+            }
+            Item::Chunk(Chunk::Lazy(lazy)) => {
+                w.u8(TAG_REFERENCE);
+                w.u128(lazy.id.0);
+                w.varint(lazy.length as u64);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads a chunk written by [write_chunk_body].
+/// References are only allowed if a `store` to load them from is provided.
+pub fn read_chunk_body(
+    r: &mut Reader,
+    store: Option<&Rc<dyn ChunkStore>>,
+) -> Result<Chunk, DecodeError> {
+    Ok(read_chunks(r, store, 1)?.pop().unwrap())
+}
+
+pub fn write_field(w: &mut Writer, field: &[Chunk]) -> Result<(), EncodeError> {
+    w.varint(field.len() as u64);
+    write_chunks(w, field)
+}
+
+pub fn read_field(
+    r: &mut Reader,
+    store: Option<&Rc<dyn ChunkStore>>,
+) -> Result<Vec<Chunk>, DecodeError> {
+    let count = r.varint()?;
+    read_chunks(r, store, count)
+}
+
+/// A node being read by [read_chunks].
+struct NodeFrame {
+    node: MixedNode,
+    /// Number of fields after the one being read.
+    fields_left: u64,
+    /// The field being read, and the number of chunks left to read in it.
+    field: Option<(FieldKey, Field, u64)>,
+}
+
+/// Reads `count` chunks written by [write_chunks].
+///
+/// Uses an explicit stack, so deeply nested data does not overflow the call stack.
+fn read_chunks(
+    r: &mut Reader,
+    store: Option<&Rc<dyn ChunkStore>>,
+    count: u64,
+) -> Result<Vec<Chunk>, DecodeError> {
+    let mut chunks = vec![];
+    let mut left = count;
+    // Nodes being read, each in the field being read in the one before it.
+    let mut stack: Vec<NodeFrame> = vec![];
+    loop {
+        // Find where the next chunk goes, completing fields and nodes which have all their content.
+        let remaining = match stack.last_mut() {
+            None => &mut left,
+            Some(frame) => match &mut frame.field {
+                Some((_, _, remaining)) if *remaining > 0 => remaining,
+                field => {
+                    if let Some((key, content, _)) = field.take() {
+                        frame.node.fields_mut().insert(key, content);
+                    }
+                    if frame.fields_left > 0 {
+                        frame.fields_left -= 1;
+                        let key = FieldKey(r.string()?);
+                        frame.field = Some((key, vec![], r.varint()?));
+                    } else {
+                        let done = stack.pop().unwrap();
+                        add_chunk(&mut stack, &mut chunks, Chunk::Node(Rc::new(done.node)));
+                    }
+                    continue;
+                }
+            },
+        };
+        if *remaining == 0 {
+            return Ok(chunks);
+        }
+        *remaining -= 1;
+        let chunk = match r.u8()? {
+            TAG_NODE => {
+                let def = TreeType(r.string()?);
+                let payload = r.optional_bytes()?.map(|p| p.to_vec());
+                stack.push(NodeFrame {
+                    node: MixedNode::new(def, payload),
+                    fields_left: r.varint()?,
+                    field: None,
+                });
+                continue;
+            }
+            TAG_UNIFORM => {
+                let schema = read_schema(r, 0)?;
+                let data = r.bytes()?;
+                if schema.bytes_per_top_level_node as usize * schema.top_level_length as usize
+                    != data.len()
+                {
+                    return Err(DecodeError("uniform chunk data does not match schema"));
+                }
+                Chunk::Uniform(Rc::new(UniformChunk::new(Rc::new(schema), data.to_vec())))
+            }
+            TAG_REFERENCE => {
+                let id = ChunkId(r.u128()?);
+                let length = r.u32()?;
+                let store = store.ok_or(DecodeError("unexpected chunk reference"))?;
+                Chunk::lazy(store.clone(), id, length)
+            }
+            _ => return Err(DecodeError("unknown chunk tag")),
+        };
+        add_chunk(&mut stack, &mut chunks, chunk);
+    }
+}
+
+/// Adds a chunk read by [read_chunks] to the field being read in the innermost node, or to the top level `chunks`.
+fn add_chunk(stack: &mut [NodeFrame], chunks: &mut Vec<Chunk>, chunk: Chunk) {
+    match stack.last_mut() {
+        Some(NodeFrame {
+            field: Some((_, content, _)),
+            ..
+        }) => content.push(chunk),
+        _ => chunks.push(chunk),
+    }
+}
+
+/// Writes a schema nested `depth` levels deep in another.
+/// Fails for schemas [read_schema] would reject as nested too deeply.
+fn write_schema(w: &mut Writer, schema: &ChunkSchema, depth: u32) -> Result<(), EncodeError> {
+    if depth >= MAX_SCHEMA_DEPTH {
+        return Err(EncodeError("schema nested too deeply"));
+    }
+    w.string(&schema.tree_type.0);
+    w.varint(schema.top_level_length as u64);
+    w.varint(schema.bytes_per_top_level_node as u64);
+    w.varint(schema.payload_size.map_or(0, |p| p as u64 + 1));
+    w.varint(schema.fields().len() as u64);
+    for (key, field) in schema.fields() {
+        w.string(&key.0);
+        w.varint(field.byte_offset as u64);
+        write_schema(w, &field.schema, depth + 1)?;
+    }
+    Ok(())
+}
+
+/// Deepest nesting of schemas [write_schema] and [read_schema] accept.
+/// Each [ChunkSchema] holds two copies of its fields' schemas, so their size doubles with each level of nesting.
+const MAX_SCHEMA_DEPTH: u32 = 16;
+
+/// Reads a schema nested `depth` levels deep in another.
+fn read_schema(r: &mut Reader, depth: u32) -> Result<ChunkSchema, DecodeError> {
+    if depth >= MAX_SCHEMA_DEPTH {
+        return Err(DecodeError("schema nested too deeply"));
+    }
+    let tree_type = TreeType(r.string()?);
+    let top_level_length = r.u32()?;
+    let bytes_per_top_level_node = r.u32()?;
+    let payload_size = match r.varint()? {
+        0 => None,
+        p => Some(u16::try_from(p - 1).map_err(|_| DecodeError("payload size out of range"))?),
+    };
+    let payload_end = payload_size.unwrap_or(0) as u32;
+    if payload_end > bytes_per_top_level_node {
+        return Err(DecodeError("payload outside of node"));
+    }
+    let field_count = r.varint()?;
+    let mut fields = vec![];
+    for _ in 0..field_count {
+        let key = FieldKey(r.string()?);
+        let byte_offset = r.u32()?;
+        let schema = read_schema(r, depth + 1)?;
+        if byte_offset as u64 + schema.byte_length() as u64 > bytes_per_top_level_node as u64 {
+            return Err(DecodeError("field outside of parent node"));
+        }
+        // The payload is at the start of each node.
+        if schema.byte_length() > 0 && byte_offset < payload_end {
+            return Err(DecodeError("field overlaps payload"));
+        }
+        fields.push((
+            key,
+            OffsetSchema {
+                schema,
+                byte_offset,
+            },
+        ));
+    }
+    Ok(ChunkSchema::new(
+        tree_type,
+        top_level_length,
+        bytes_per_top_level_node,
+        payload_size,
+        &fields,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forest::{
+        edit::EditError,
+        mixed::{Forest, MixedNodeRef},
+        path::{FieldRange, Path},
+        store::{write_chunk, MemoryStore, StoreError},
+        test_stuff::{big_tree, deep_node, key, walk_all_field},
+        tree::{Indexable, NodeData},
+    };
+
+    #[test]
+    fn round_trip() {
+        let mut root = MixedNode::new(TreeType("root".into()), Some(vec![1, 2, 3]));
+        root.fields_mut().insert(
+            FieldKey("a".into()),
+            vec![
+                Chunk::Uniform(Rc::new(big_tree(4))),
+                Chunk::Node(Rc::new(MixedNode::new(TreeType("leaf".into()), None))),
+            ],
+        );
+        let data = encode_chunk(&Chunk::Node(Rc::new(root))).unwrap();
+        let store: Rc<dyn ChunkStore> = Rc::new(MemoryStore::default());
+        let chunk = decode_chunk(&data, &store).unwrap();
+        assert_eq!(encode_chunk(&chunk), Ok(data));
+
+        let mut forest = Forest::new();
+        forest.set_root(FieldKey("x".into()), vec![chunk]);
+        let field = forest.root(&FieldKey("x".into()));
+        assert_eq!(walk_all_field::<MixedNodeRef>(field.clone()), 1 + 4 * 5 + 1);
+        assert_eq!(
+            field.index(0).unwrap().get_payload(),
+            Some(&[1u8, 2, 3][..])
+        );
+    }
+
+    #[test]
+    fn deep_round_trip() {
+        const DEPTH: u32 = 200_000;
+        let data = encode_chunk(&Chunk::Node(Rc::new(deep_node(DEPTH)))).unwrap();
+        let store: Rc<dyn ChunkStore> = Rc::new(MemoryStore::default());
+        let chunk = decode_chunk(&data, &store).unwrap();
+        let mut forest = Forest::new();
+        forest.set_root(key("x"), vec![chunk]);
+        assert_eq!(
+            walk_all_field::<MixedNodeRef>(forest.root(&key("x"))),
+            DEPTH as usize + 1
+        );
+    }
+
+    #[test]
+    fn deep_schemas_are_an_error() {
+        // A chain of `depth` schemas, each the only field of the one before it.
+        let read = |depth: u32| {
+            let mut w = Writer::default();
+            for i in 0..depth {
+                w.string("node");
+                w.varint(1);
+                w.varint(0);
+                w.varint(0);
+                let last = i + 1 == depth;
+                w.varint(!last as u64);
+                if !last {
+                    w.string("x");
+                    w.varint(0);
+                }
+            }
+            read_schema(&mut Reader::new(&w.data), 0).map(|_| ())
+        };
+        assert_eq!(read(MAX_SCHEMA_DEPTH), Ok(()));
+        assert_eq!(
+            read(MAX_SCHEMA_DEPTH + 1),
+            Err(DecodeError("schema nested too deeply"))
+        );
+    }
+
+    #[test]
+    fn deep_schemas_are_not_written() {
+        // A uniform chunk of one node whose schema is a chain of `depth` schemas.
+        let chunk = |depth: u32| {
+            let mut schema = ChunkSchema::new_leaf(TreeType("leaf".into()), 1, Some(1));
+            for _ in 1..depth {
+                let field = OffsetSchema {
+                    schema,
+                    byte_offset: 0,
+                };
+                schema =
+                    ChunkSchema::new(TreeType("node".into()), 1, 1, None, &[(key("x"), field)]);
+            }
+            Chunk::Uniform(Rc::new(UniformChunk::new(Rc::new(schema), vec![7])))
+        };
+        let store: Rc<dyn ChunkStore> = Rc::new(MemoryStore::default());
+        let data = encode_chunk(&chunk(MAX_SCHEMA_DEPTH)).unwrap();
+        assert!(decode_chunk(&data, &store).is_ok());
+        assert_eq!(
+            encode_chunk(&chunk(MAX_SCHEMA_DEPTH + 1)),
+            Err(EncodeError("schema nested too deeply"))
+        );
+        assert_eq!(
+            write_chunk(&store, &chunk(MAX_SCHEMA_DEPTH + 1)).err(),
+            Some(StoreError::Unencodable(EncodeError(
+                "schema nested too deeply"
+            )))
+        );
+    }
+
+    #[test]
+    fn truncated_data_is_an_error() {
+        let data = encode_chunk(&Chunk::Uniform(Rc::new(big_tree(4)))).unwrap();
+        let store: Rc<dyn ChunkStore> = Rc::new(MemoryStore::default());
+        for length in 0..data.len() {
+            assert!(decode_chunk(&data[..length], &store).is_err());
+        }
+    }
+
+    #[test]
+    fn top_level_references_are_rejected() {
+        let memory = Rc::new(MemoryStore::default());
+        let store: Rc<dyn ChunkStore> = memory.clone();
+        let (target, _) = store
+            .store(encode_chunk(&Chunk::Uniform(Rc::new(big_tree(2)))).unwrap())
+            .unwrap();
+        let reference = Chunk::lazy(store.clone(), target, 2);
+        assert_eq!(
+            encode_chunk(&reference),
+            Err(EncodeError("top level chunk is a reference"))
+        );
+
+        // A blob which is only a reference, with the right length, as an older writer could produce.
+        let mut w = Writer::default();
+        w.u8(FORMAT_VERSION);
+        write_chunk_body(&mut w, &reference).unwrap();
+        assert_eq!(
+            decode_chunk(&w.data, &store).err(),
+            Some(DecodeError("top level chunk is a reference"))
+        );
+        let (id, _) = store.store(w.data).unwrap();
+        let mut forest = Forest::new();
+        forest.set_root(key("x"), vec![Chunk::lazy(store, id, 2)]);
+        assert_eq!(
+            forest.detach(&FieldRange::new(Path::root(), key("x"), 1, 2)),
+            Err(EditError::Pending(Some(StoreError::Corrupt(DecodeError(
+                "top level chunk is a reference"
+            )))))
+        );
+    }
+
+    #[test]
+    fn payload_must_fit_in_node() {
+        let schema = |payload_size: u16, field_offset: u32| {
+            let field = ChunkSchema::new_leaf(TreeType("leaf".into()), 1, Some(2));
+            ChunkSchema::new(
+                TreeType("node".into()),
+                1,
+                4,
+                Some(payload_size),
+                &[(
+                    FieldKey("x".into()),
+                    OffsetSchema {
+                        schema: field,
+                        byte_offset: field_offset,
+                    },
+                )],
+            )
+        };
+        let decode = |schema: ChunkSchema| {
+            let mut w = Writer::default();
+            write_schema(&mut w, &schema, 0).unwrap();
+            read_schema(&mut Reader::new(&w.data), 0).map(|_| ())
+        };
+        assert_eq!(decode(schema(2, 2)), Ok(()));
+        assert_eq!(
+            decode(schema(5, 4)),
+            Err(DecodeError("payload outside of node"))
+        );
+        assert_eq!(
+            decode(schema(3, 2)),
+            Err(DecodeError("field overlaps payload"))
+        );
+    }
+}
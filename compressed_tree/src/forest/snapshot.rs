@@ -0,0 +1,325 @@
+//! Incremental snapshots of a [Forest].
+//!
+//! Every chunk in the forest which has been written to a [ChunkStore] is a [Chunk::Lazy] referencing its blob,
+//! and edits replace the chunks they modify (and their ancestors) with ones that are not [Chunk::Lazy].
+//! Thus the chunks which are not [Chunk::Lazy] are exactly the dirty ones,
+//! and writing a snapshot only needs to visit and write those:
+//! everything else is reused by its content hash ([ChunkId]).
+//!
+//! Each written chunk's entry in the id index is stored next to it (see [super::id_index]),
+//! so the manifest only references the root chunks and the forest's index is rebuilt from those.
+//!
+//! Blobs are keyed by [ChunkId] rather than the [SubtreeHash](super::hash::SubtreeHash) of their content:
+//! a [ChunkId] covers exactly the encoded bytes, so stores can check a blob against its id without decoding it
+//! (or loading the chunks it references), and differently chunked copies of the same subtree are distinct blobs.
+//! Identically chunked dirty subtrees encode identically, so they are still only written once.
+//! The [SubtreeHash](super::hash::SubtreeHash)es of each written chunk's top level nodes are stored next to it too,
+//! so forests opened from a snapshot can be hashed and diffed without loading their unchanged chunks.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    rc::Rc,
+};
+
+use crate::FieldKey;
+
+use super::{
+    mixed::{Chunk, Forest},
+    serialize::{DecodeError, Reader, Writer},
+    store::{write_chunk_with, ChunkId, ChunkStore, StoreError},
+};
+
+const MANIFEST_VERSION: u8 = 0;
+
+/// Root of a snapshot: the chunks in each detached field of the forest.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Manifest {
+    /// Chunk ids, with the number of top level nodes in each chunk.
+    pub roots: BTreeMap<FieldKey, Vec<(ChunkId, u32)>>,
+}
+
+impl Manifest {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::default();
+        w.u8(MANIFEST_VERSION);
+        w.varint(self.roots.len() as u64);
+        for (key, chunks) in self.roots.iter() {
+            w.string(&key.0);
+            w.varint(chunks.len() as u64);
+            for (id, length) in chunks {
+                w.u128(id.0);
+                w.varint(*length as u64);
+            }
+        }
+        w.data
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Manifest, DecodeError> {
+        let mut r = Reader::new(data);
+        if r.u8()? != MANIFEST_VERSION {
+            return Err(DecodeError("unsupported manifest version"));
+        }
+        let mut roots = BTreeMap::new();
+        for _ in 0..r.varint()? {
+            let key = FieldKey(r.string()?);
+            let chunks = (0..r.varint()?)
+                .map(|_| Ok((ChunkId(r.u128()?), r.u32()?)))
+                .collect::<Result<_, DecodeError>>()?;
+            roots.insert(key, chunks);
+        }
+        if !r.is_empty() {
+            return Err(DecodeError("trailing data"));
+        }
+        Ok(Manifest { roots })
+    }
+}
+
+/// Result of writing a snapshot.
+pub struct Snapshot {
+    pub manifest: Manifest,
+    /// Chunks written by this snapshot.
+    pub written: BTreeSet<ChunkId>,
+    /// Chunks from previous snapshots which are directly referenced by new chunks or the manifest,
+    /// or which were re-encoded with content the store already had.
+    /// Their content (including chunks they reference) was not revisited.
+    pub reused: BTreeSet<ChunkId>,
+}
+
+/// Writes snapshots of forests into a [ChunkStore].
+pub struct SnapshotWriter {
+    store: Rc<dyn ChunkStore>,
+}
+
+impl SnapshotWriter {
+    pub fn new(store: Rc<dyn ChunkStore>) -> SnapshotWriter {
+        SnapshotWriter { store }
+    }
+
+    /// Writes the dirty chunks of `forest`, and a manifest referencing its content.
+    ///
+    /// Afterwards all chunks in `forest` are clean (the written ones are replaced with already loaded [Chunk::Lazy]s)
+    /// so the next snapshot only writes what has been edited since this one.
+    /// Its id index is rebuilt from the stored chunks.
+    ///
+    /// Fails with [StoreError::ForeignChunk] if `forest` has [Chunk::Lazy]s from a different store
+    /// (opened from another store's snapshot, for example), since the manifest would reference blobs this store does not have.
+    ///
+    /// If the store fails, fields written before the failure are already clean; the rest are left dirty.
+    pub fn write(&self, forest: &mut Forest) -> Result<Snapshot, StoreError> {
+        let mut written = BTreeSet::new();
+        let mut reused = BTreeSet::new();
+        let mut manifest = Manifest::default();
+        let keys: Vec<FieldKey> = forest.roots().map(|(k, _)| k.clone()).collect();
+        for key in keys {
+            let field: Vec<Chunk> = forest
+                .root_chunks(&key)
+                .iter()
+                .map(|chunk| {
+                    write_chunk_with(&self.store, chunk, &mut |id, new| {
+                        // Identical chunks written earlier in this snapshot are not new, but are not reused either.
+                        if new {
+                            written.insert(id);
+                        } else if !written.contains(&id) {
+                            reused.insert(id);
+                        }
+                    })
+                })
+                .collect::<Result<_, _>>()?;
+            manifest.roots.insert(
+                key.clone(),
+                field
+                    .iter()
+                    .map(|chunk| match chunk {
+                        Chunk::Lazy(lazy) => (lazy.id, lazy.length),
+                        _ => unreachable!("written chunks are lazy"),
+                    })
+                    .collect(),
+            );
+            forest.set_root(key, field);
+        }
+        forest.rebuild_id_index();
+        Ok(Snapshot {
+            manifest,
+            written,
+            reused,
+        })
+    }
+}
+
+/// Opens a snapshot. Chunks are loaded from `store` on demand.
+pub fn read_snapshot(manifest: &Manifest, store: &Rc<dyn ChunkStore>) -> Forest {
+    let mut forest = Forest::new();
+    for (key, chunks) in manifest.roots.iter() {
+        let field = chunks
+            .iter()
+            .map(|(id, length)| Chunk::lazy(store.clone(), *id, *length))
+            .collect();
+        forest.set_root(key.clone(), field);
+    }
+    forest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        forest::{
+            mixed::{MixedNode, MixedNodeRef},
+            node_id::NodeId,
+            store::MemoryStore,
+            test_stuff::{big_tree, deep_node, forest_with_root, key, walk_all_field},
+        },
+        TreeType,
+    };
+
+    /// Forest with a root node with `children` copies of the same uniform chunk.
+    fn snapshot_forest(children: usize) -> Forest {
+        let mut root = MixedNode::new(TreeType("root".into()), None);
+        let child = Rc::new(big_tree(10));
+        root.fields_mut().insert(
+            key("children"),
+            (0..children)
+                .map(|_| Chunk::Uniform(child.clone()))
+                .collect(),
+        );
+        forest_with_root(root)
+    }
+
+    #[test]
+    fn snapshots_reuse_unchanged_chunks() {
+        let memory = Rc::new(MemoryStore::default());
+        let store: Rc<dyn ChunkStore> = memory.clone();
+        let writer = SnapshotWriter::new(store.clone());
+        let mut forest = snapshot_forest(100);
+        assert!(forest.is_dirty());
+
+        let first = writer.write(&mut forest).unwrap();
+        assert!(!forest.is_dirty());
+        // Uniform chunks have identical content so are deduplicated.
+        assert_eq!(memory.len(), 2);
+        assert_eq!(first.written.len(), 2);
+        assert!(first.reused.is_empty());
+
+        // Nothing changed: the only work is referencing the existing root chunk.
+        let second = writer.write(&mut forest).unwrap();
+        assert!(second.written.is_empty());
+        assert_eq!(second.reused.len(), 1);
+        assert_eq!(first.manifest, second.manifest);
+
+        // Replace the root with a new node referencing the existing children.
+        let root = forest.root_chunks(&key("root"))[0].clone();
+        let mut node = match root {
+            Chunk::Lazy(lazy) => match lazy.get().unwrap() {
+                Chunk::Node(n) => MixedNode::clone(n),
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+        node.set_payload(Some(vec![1]));
+        forest.set_root(key("root"), vec![Chunk::Node(Rc::new(node))]);
+        let third = writer.write(&mut forest).unwrap();
+        assert_eq!(third.written.len(), 1);
+        assert_eq!(third.reused.len(), 1);
+
+        let loaded = read_snapshot(&Manifest::decode(&third.manifest.encode()).unwrap(), &store);
+        assert_eq!(
+            walk_all_field::<MixedNodeRef>(loaded.root(&key("root"))),
+            1 + 100 * 10 * 5
+        );
+    }
+
+    #[test]
+    fn snapshots_index_ids_without_loading() {
+        let store: Rc<dyn ChunkStore> = Rc::new(MemoryStore::default());
+        let writer = SnapshotWriter::new(store.clone());
+        let mut forest = snapshot_forest(10);
+        let id_node = |id: u128| {
+            let node = MixedNode::new(NodeId::tree_type(), Some(NodeId(id).to_payload()));
+            vec![Chunk::Node(Rc::new(node))]
+        };
+        forest.set_root(key("other"), id_node(1));
+        forest.set_root(key("other"), id_node(2));
+        assert!(forest.id_index().is_stale());
+
+        let snapshot = writer.write(&mut forest).unwrap();
+        assert!(!forest.id_index().is_stale());
+        assert!(!forest.might_contain(NodeId(1)));
+        assert!(forest.might_contain(NodeId(2)));
+
+        let loaded = read_snapshot(&snapshot.manifest, &store);
+        assert!(loaded.might_contain(NodeId(2)));
+        assert!(!loaded.might_contain(NodeId(3)));
+        for (key, _) in loaded.roots() {
+            match &loaded.root_chunks(key)[0] {
+                Chunk::Lazy(lazy) => assert!(!lazy.is_loaded()),
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[test]
+    fn identical_dirty_subtrees_are_written_once() {
+        let memory = Rc::new(MemoryStore::default());
+        let writer = SnapshotWriter::new(memory.clone());
+        let mut forest = Forest::new();
+        let mut root = MixedNode::new(TreeType("root".into()), None);
+        let child = MixedNode::new(TreeType("child".into()), Some(vec![1]));
+        root.fields_mut().insert(
+            key("children"),
+            vec![
+                Chunk::Node(Rc::new(child.clone())),
+                Chunk::Node(Rc::new(child)),
+            ],
+        );
+        forest.set_root(key("root"), vec![Chunk::Node(Rc::new(root))]);
+
+        let snapshot = writer.write(&mut forest).unwrap();
+        assert_eq!(memory.len(), 2);
+        assert_eq!(snapshot.written.len(), 2);
+        assert!(snapshot.reused.is_empty());
+    }
+
+    #[test]
+    fn chunks_from_other_stores_are_not_referenced() {
+        let first: Rc<dyn ChunkStore> = Rc::new(MemoryStore::default());
+        let mut forest = snapshot_forest(2);
+        let snapshot = SnapshotWriter::new(first.clone())
+            .write(&mut forest)
+            .unwrap();
+        let mut opened = read_snapshot(&snapshot.manifest, &first);
+        // Edit the root, so the snapshot writes it and references its (lazy) children.
+        let root = match &opened.root_chunks(&key("root"))[0] {
+            Chunk::Lazy(lazy) => match lazy.get().unwrap() {
+                Chunk::Node(n) => MixedNode::clone(n),
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+        opened.set_root(key("root"), vec![Chunk::Node(Rc::new(root))]);
+
+        let second = Rc::new(MemoryStore::default());
+        let result = SnapshotWriter::new(second.clone()).write(&mut opened);
+        assert!(matches!(result, Err(StoreError::ForeignChunk(_))));
+        assert_eq!(second.len(), 0);
+        assert!(opened.is_dirty());
+        // The store the chunks are from can still write it.
+        SnapshotWriter::new(first).write(&mut opened).unwrap();
+    }
+
+    #[test]
+    fn deep_snapshot_round_trip() {
+        const DEPTH: u32 = 200_000;
+        let store: Rc<dyn ChunkStore> = Rc::new(MemoryStore::default());
+        let writer = SnapshotWriter::new(store.clone());
+        let mut forest = forest_with_root(deep_node(DEPTH));
+        let snapshot = writer.write(&mut forest).unwrap();
+        // Every node has a distinct subtree, so is its own chunk.
+        assert_eq!(snapshot.written.len(), DEPTH as usize + 1);
+
+        let loaded = read_snapshot(&snapshot.manifest, &store);
+        assert_eq!(
+            walk_all_field::<MixedNodeRef>(loaded.root(&key("root"))),
+            DEPTH as usize + 1
+        );
+    }
+}
@@ -0,0 +1,433 @@
+//! Storage for serialized chunks, which allows [super::mixed::Forest]s to load chunks on demand.
+
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use crate::FieldKey;
+
+use sha2::{Digest, Sha256};
+
+use super::{
+    hash, id_index,
+    mixed::{Chunk, MixedNode},
+    serialize::{self, DecodeError, EncodeError},
+};
+
+/// Identifies a serialized chunk by a hash of its content.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct ChunkId(pub u128);
+
+impl ChunkId {
+    /// Computes the id for the serialized chunk `data`.
+    pub fn of(data: &[u8]) -> ChunkId {
+        let hash = Sha256::digest(data);
+        let mut bytes = [0u8; 16];
+        bytes.copy_from_slice(&hash[..16]);
+        ChunkId(u128::from_le_bytes(bytes))
+    }
+}
+
+/// Why a [ChunkStore] could not load or store a chunk.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StoreError {
+    /// The underlying storage failed.
+    Io(std::io::ErrorKind),
+    /// The stored data could not be decoded.
+    Corrupt(DecodeError),
+    /// A chunk being written could not be encoded.
+    Unencodable(EncodeError),
+    /// A chunk being written references a [Chunk::Lazy] from a different store.
+    ForeignChunk(ChunkId),
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::Io(kind) => write!(f, "chunk store IO error: {}", kind),
+            StoreError::Corrupt(e) => write!(f, "corrupt chunk: {}", e.0),
+            StoreError::Unencodable(e) => write!(f, "chunk cannot be encoded: {}", e.0),
+            StoreError::ForeignChunk(id) => write!(f, "chunk {:x} is from a different store", id.0),
+        }
+    }
+}
+
+/// Source of serialized chunks.
+pub trait ChunkStore {
+    /// Fetches the serialized chunk `id`.
+    ///
+    /// Returns None if the chunk is not available (yet): callers should treat its content as pending.
+    fn load(&self, id: ChunkId) -> Result<Option<Rc<[u8]>>, StoreError>;
+
+    /// Stores a serialized chunk and returns its id,
+    /// and true if the chunk was new (false if the store already had it).
+    fn store(&self, data: Vec<u8>) -> Result<(ChunkId, bool), StoreError>;
+
+    /// Fetches the entry in the id index for chunk `id` (see [super::id_index]).
+    ///
+    /// Returns None if it is not available, in which case the chunk might contain any id.
+    fn load_ids(&self, _id: ChunkId) -> Result<Option<Rc<[u8]>>, StoreError> {
+        Ok(None)
+    }
+
+    /// Stores the entry in the id index for chunk `id`, next to the chunk.
+    /// Stores which do not keep these make looking up ids load the chunks instead.
+    fn store_ids(&self, _id: ChunkId, _data: Vec<u8>) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    /// Fetches the [SubtreeHash](super::hash::SubtreeHash)es of the top level nodes of chunk `id`.
+    ///
+    /// Returns None if they are not available, in which case hashing the chunk loads it.
+    fn load_hashes(&self, _id: ChunkId) -> Result<Option<Rc<[u8]>>, StoreError> {
+        Ok(None)
+    }
+
+    /// Stores the hashes of the top level nodes of chunk `id`, next to the chunk.
+    /// Stores which do not keep these make hashing and diffing load the chunks instead.
+    fn store_hashes(&self, _id: ChunkId, _data: Vec<u8>) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+/// Keeps chunks in memory, and shares them with what loads them instead of copying them.
+#[derive(Default)]
+pub struct MemoryStore {
+    chunks: RefCell<HashMap<ChunkId, Rc<[u8]>>>,
+    ids: RefCell<HashMap<ChunkId, Rc<[u8]>>>,
+    hashes: RefCell<HashMap<ChunkId, Rc<[u8]>>>,
+}
+
+impl MemoryStore {
+    /// Copies all chunks (and their id index entries and hashes) from `other` into this store.
+    pub fn copy_from(&self, other: &MemoryStore) {
+        for (from, to) in [
+            (&other.chunks, &self.chunks),
+            (&other.ids, &self.ids),
+            (&other.hashes, &self.hashes),
+        ] {
+            let from = from.borrow();
+            let mut to = to.borrow_mut();
+            for (id, data) in from.iter() {
+                to.insert(*id, data.clone());
+            }
+        }
+    }
+
+    /// Number of chunks in the store.
+    pub fn len(&self) -> usize {
+        self.chunks.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl ChunkStore for MemoryStore {
+    fn load(&self, id: ChunkId) -> Result<Option<Rc<[u8]>>, StoreError> {
+        Ok(self.chunks.borrow().get(&id).cloned())
+    }
+
+    fn store(&self, data: Vec<u8>) -> Result<(ChunkId, bool), StoreError> {
+        let id = ChunkId::of(&data);
+        let mut chunks = self.chunks.borrow_mut();
+        let new = !chunks.contains_key(&id);
+        if new {
+            chunks.insert(id, data.into());
+        }
+        Ok((id, new))
+    }
+
+    fn load_ids(&self, id: ChunkId) -> Result<Option<Rc<[u8]>>, StoreError> {
+        Ok(self.ids.borrow().get(&id).cloned())
+    }
+
+    fn store_ids(&self, id: ChunkId, data: Vec<u8>) -> Result<(), StoreError> {
+        self.ids.borrow_mut().insert(id, data.into());
+        Ok(())
+    }
+
+    fn load_hashes(&self, id: ChunkId) -> Result<Option<Rc<[u8]>>, StoreError> {
+        Ok(self.hashes.borrow().get(&id).cloned())
+    }
+
+    fn store_hashes(&self, id: ChunkId, data: Vec<u8>) -> Result<(), StoreError> {
+        self.hashes.borrow_mut().insert(id, data.into());
+        Ok(())
+    }
+}
+
+/// Keeps each chunk in its own file in a local directory.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct DirectoryStore {
+    path: std::path::PathBuf,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl DirectoryStore {
+    /// Uses the directory at `path`, creating it if needed.
+    pub fn new(path: impl Into<std::path::PathBuf>) -> std::io::Result<DirectoryStore> {
+        let path = path.into();
+        std::fs::create_dir_all(&path)?;
+        Ok(DirectoryStore { path })
+    }
+
+    fn file(&self, id: ChunkId) -> std::path::PathBuf {
+        self.path.join(format!("{:032x}.chunk", id.0))
+    }
+
+    /// File holding the id index entry for chunk `id`, next to the chunk's file.
+    fn ids_file(&self, id: ChunkId) -> std::path::PathBuf {
+        self.path.join(format!("{:032x}.ids", id.0))
+    }
+
+    /// File holding the hashes of the top level nodes of chunk `id`, next to the chunk's file.
+    fn hashes_file(&self, id: ChunkId) -> std::path::PathBuf {
+        self.path.join(format!("{:032x}.hashes", id.0))
+    }
+
+    /// Reads `file`, or returns None if it does not exist.
+    fn read_optional(file: std::path::PathBuf) -> Result<Option<Rc<[u8]>>, StoreError> {
+        match std::fs::read(file) {
+            Ok(data) => Ok(Some(data.into())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(StoreError::Io(e.kind())),
+        }
+    }
+
+    /// Where `file` is written before it is renamed to it, so `file` is never partially written.
+    fn temp_file(file: &std::path::Path) -> std::path::PathBuf {
+        let mut temp = file.as_os_str().to_owned();
+        temp.push(format!(".{}.tmp", std::process::id()));
+        temp.into()
+    }
+
+    /// Writes `data` to `file` unless it already exists, returning whether it was written.
+    fn write_new(file: std::path::PathBuf, data: Vec<u8>) -> Result<bool, StoreError> {
+        if file.exists() {
+            return Ok(false);
+        }
+        let temp = DirectoryStore::temp_file(&file);
+        std::fs::write(&temp, data)
+            .and_then(|_| std::fs::rename(&temp, file))
+            .map_err(|e| {
+                let _ = std::fs::remove_file(&temp);
+                StoreError::Io(e.kind())
+            })?;
+        Ok(true)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl ChunkStore for DirectoryStore {
+    /// Missing files are reported as pending, and files whose content does not match their id as corrupt.
+    fn load(&self, id: ChunkId) -> Result<Option<Rc<[u8]>>, StoreError> {
+        match std::fs::read(self.file(id)) {
+            Ok(data) if ChunkId::of(&data) != id => Err(StoreError::Corrupt(DecodeError(
+                "chunk content does not match its id",
+            ))),
+            Ok(data) => Ok(Some(data.into())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(StoreError::Io(e.kind())),
+        }
+    }
+
+    fn store(&self, data: Vec<u8>) -> Result<(ChunkId, bool), StoreError> {
+        let id = ChunkId::of(&data);
+        let new = DirectoryStore::write_new(self.file(id), data)?;
+        Ok((id, new))
+    }
+
+    fn load_ids(&self, id: ChunkId) -> Result<Option<Rc<[u8]>>, StoreError> {
+        DirectoryStore::read_optional(self.ids_file(id))
+    }
+
+    fn store_ids(&self, id: ChunkId, data: Vec<u8>) -> Result<(), StoreError> {
+        DirectoryStore::write_new(self.ids_file(id), data)?;
+        Ok(())
+    }
+
+    fn load_hashes(&self, id: ChunkId) -> Result<Option<Rc<[u8]>>, StoreError> {
+        DirectoryStore::read_optional(self.hashes_file(id))
+    }
+
+    fn store_hashes(&self, id: ChunkId, data: Vec<u8>) -> Result<(), StoreError> {
+        DirectoryStore::write_new(self.hashes_file(id), data)?;
+        Ok(())
+    }
+}
+
+/// Writes `chunk` to `store`, storing each chunk nested in a node's fields as its own blob
+/// so they can be loaded independently.
+///
+/// Each new chunk's entry in the id index and the hashes of its top level nodes are stored next to it,
+/// so ids can be looked up and the chunk hashed without loading it.
+///
+/// Returns a [Chunk::Lazy] referencing the stored chunk, which is already loaded.
+/// [Chunk::Lazy] chunks are already in their store, so are not written again:
+/// if one is from a different store than `store`, this fails with [StoreError::ForeignChunk].
+pub fn write_chunk(store: &Rc<dyn ChunkStore>, chunk: &Chunk) -> Result<Chunk, StoreError> {
+    write_chunk_with(store, chunk, &mut |_, _| {})
+}
+
+/// [write_chunk], but calls `on_chunk` for each chunk referenced by the output,
+/// with whether it was newly written, or was already in the store (from a previous write, or identical to another chunk).
+pub fn write_chunk_with(
+    store: &Rc<dyn ChunkStore>,
+    chunk: &Chunk,
+    on_chunk: &mut dyn FnMut(ChunkId, bool),
+) -> Result<Chunk, StoreError> {
+    /// A copy of a node whose children are being written, replacing them.
+    struct Frame {
+        node: MixedNode,
+        /// Children left to write, last first.
+        left: Vec<(FieldKey, usize)>,
+        /// Where the node goes in its parent's node, or None for `chunk`.
+        at: Option<(FieldKey, usize)>,
+    }
+    fn frame(node: &MixedNode, at: Option<(FieldKey, usize)>) -> Frame {
+        let left = node
+            .fields()
+            .iter()
+            .rev()
+            .flat_map(|(key, field)| (0..field.len()).rev().map(|i| (key.clone(), i)))
+            .collect();
+        Frame {
+            node: node.clone(),
+            left,
+            at,
+        }
+    }
+
+    let mut stack = match chunk {
+        Chunk::Node(node) => vec![frame(node, None)],
+        _ => return write_leaf_chunk(store, chunk, on_chunk),
+    };
+    // Uses an explicit stack, so deep trees do not overflow the call stack.
+    loop {
+        let top = stack.last_mut().unwrap();
+        if let Some((key, i)) = top.left.pop() {
+            let child = &top.node.fields()[&key][i];
+            if let Chunk::Node(node) = child {
+                let child = frame(node, Some((key, i)));
+                stack.push(child);
+            } else {
+                let written = write_leaf_chunk(store, child, on_chunk)?;
+                top.node.fields_mut().get_mut(&key).unwrap()[i] = written;
+            }
+            continue;
+        }
+        let done = stack.pop().unwrap();
+        let written = store_loaded(store, Chunk::Node(Rc::new(done.node)), on_chunk)?;
+        match (done.at, stack.last_mut()) {
+            (Some((key, i)), Some(parent)) => {
+                parent.node.fields_mut().get_mut(&key).unwrap()[i] = written;
+            }
+            _ => return Ok(written),
+        }
+    }
+}
+
+/// [write_chunk_with] for a chunk which is not a [Chunk::Node].
+fn write_leaf_chunk(
+    store: &Rc<dyn ChunkStore>,
+    chunk: &Chunk,
+    on_chunk: &mut dyn FnMut(ChunkId, bool),
+) -> Result<Chunk, StoreError> {
+    match chunk {
+        Chunk::Lazy(lazy) if !Rc::ptr_eq(lazy.store(), store) => {
+            Err(StoreError::ForeignChunk(lazy.id))
+        }
+        Chunk::Lazy(lazy) => {
+            on_chunk(lazy.id, false);
+            Ok(chunk.clone())
+        }
+        _ => store_loaded(store, chunk.clone(), on_chunk),
+    }
+}
+
+/// Stores `stored`, whose nested chunks are already in `store`, along with its id index entry and hashes if it is new.
+fn store_loaded(
+    store: &Rc<dyn ChunkStore>,
+    stored: Chunk,
+    on_chunk: &mut dyn FnMut(ChunkId, bool),
+) -> Result<Chunk, StoreError> {
+    let data = serialize::encode_chunk(&stored).map_err(StoreError::Unencodable)?;
+    let (id, new) = store.store(data)?;
+    // Chunks referencing pending chunks without stored hashes can not be hashed.
+    let hashes = hash::field_hashes(std::slice::from_ref(&stored));
+    if new {
+        store.store_ids(id, id_index::encode_entry(&stored))?;
+        if let Some(hashes) = &hashes {
+            store.store_hashes(id, hash::encode_hashes(hashes))?;
+        }
+    }
+    on_chunk(id, new);
+    let written = Chunk::lazy_loaded(store.clone(), id, stored);
+    if let (Chunk::Lazy(lazy), Some(hashes)) = (&written, hashes) {
+        lazy.hashes.get_or_init(|| hashes);
+    }
+    Ok(written)
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::{
+        forest::{
+            mixed::{Forest, MixedNodeRef},
+            test_stuff::{big_tree, walk_all_field},
+        },
+        FieldKey, TreeType,
+    };
+
+    #[test]
+    fn directory_store_round_trip() {
+        let dir = std::env::temp_dir().join(format!("chunk_store_test_{}", std::process::id()));
+        let store: Rc<dyn ChunkStore> = Rc::new(DirectoryStore::new(&dir).unwrap());
+
+        let mut root = MixedNode::new(TreeType("root".into()), Some(vec![7]));
+        root.fields_mut().insert(
+            FieldKey("chunk".into()),
+            vec![Chunk::Uniform(Rc::new(big_tree(3)))],
+        );
+        let id = match write_chunk(&store, &Chunk::Node(Rc::new(root))).unwrap() {
+            Chunk::Lazy(l) => l.id,
+            _ => unreachable!(),
+        };
+
+        // Open from a fresh store so nothing is loaded.
+        let store: Rc<dyn ChunkStore> = Rc::new(DirectoryStore::new(&dir).unwrap());
+        let mut forest = Forest::new();
+        let key = FieldKey("root".into());
+        forest.set_root(key.clone(), vec![Chunk::lazy(store, id, 1)]);
+        assert_eq!(walk_all_field::<MixedNodeRef>(forest.root(&key)), 1 + 3 * 5);
+        // No temporary files are left behind.
+        assert!(std::fs::read_dir(&dir).unwrap().all(|entry| {
+            let path = entry.unwrap().path();
+            let extension = path.extension().unwrap();
+            extension == "chunk" || extension == "ids" || extension == "hashes"
+        }));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn directory_store_detects_corruption() {
+        let dir = std::env::temp_dir().join(format!("chunk_store_corrupt_{}", std::process::id()));
+        let store = DirectoryStore::new(&dir).unwrap();
+        let (id, new) = store.store(vec![1, 2, 3]).unwrap();
+        assert!(new);
+        assert_eq!(store.load(id).unwrap().as_deref(), Some(&[1u8, 2, 3][..]));
+
+        // As if a crash (before chunks were written atomically) left the file truncated.
+        std::fs::write(store.file(id), [1]).unwrap();
+        assert_eq!(
+            store.load(id),
+            Err(StoreError::Corrupt(DecodeError(
+                "chunk content does not match its id"
+            )))
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,6 +1,11 @@
-use crate::{FieldKey, TreeType};
+use crate::{
+    visit::{walk, walk_field, NodeCounter, WalkOptions},
+    FieldKey, TreeType,
+};
 
 use super::{
+    example_node::BasicNode,
+    mixed::{Chunk, Forest, MixedNode, MixedNodeRef},
     tree::{Indexable, Node},
     uniform_chunk::{ChunkSchema, OffsetSchema, UniformChunk},
 };
@@ -63,32 +68,107 @@ pub fn big_tree(chunk_size: usize) -> UniformChunk {
     UniformChunk::new(Rc::new(schema), data)
 }
 
-pub fn walk_all<'a, T: Node<'a>>(n: T) -> usize {
-    let mut count = 1;
-    for (_, t) in n.get_fields() {
-        for c in 0..t.len() {
-            let child = t.index(c).unwrap();
-            count += walk_all(child);
-        }
+pub fn key(s: &str) -> FieldKey {
+    FieldKey(s.into())
+}
+
+/// A "leaf" node with a one byte payload.
+pub fn leaf(value: u8) -> Chunk {
+    Chunk::Node(Rc::new(MixedNode::new(
+        TreeType("leaf".into()),
+        Some(vec![value]),
+    )))
+}
+
+/// Forest with `root` as the only node, in the detached field "root".
+pub fn forest_with_root(root: MixedNode) -> Forest {
+    let mut forest = Forest::new();
+    forest.set_root(key("root"), vec![Chunk::Node(Rc::new(root))]);
+    forest
+}
+
+/// A chain of `depth + 1` "node" nodes, each the only child of the one above it in field "child".
+pub fn deep_node(depth: u32) -> MixedNode {
+    let mut node = MixedNode::new(TreeType("node".into()), Some(vec![0]));
+    for _ in 0..depth {
+        let mut parent = MixedNode::new(TreeType("node".into()), Some(vec![0]));
+        parent
+            .fields_mut()
+            .insert(key("child"), vec![Chunk::Node(Rc::new(node))]);
+        node = parent;
     }
-    count
+    node
+}
+
+/// Forest with a root node with a uniform chunk of 5 nodes in field "a", and two leaves in field "b".
+pub fn test_forest() -> Forest {
+    let mut root = MixedNode::new(TreeType("root".into()), None);
+    root.fields_mut()
+        .insert(key("a"), vec![Chunk::Uniform(Rc::new(big_tree(5)))]);
+    root.fields_mut().insert(key("b"), vec![leaf(1), leaf(2)]);
+    forest_with_root(root)
+}
+
+/// [describe_field] of the detached field "root".
+pub fn describe_root(forest: &Forest) -> String {
+    describe_field::<MixedNodeRef>(forest.root(&key("root")))
+}
+
+pub fn walk_all<'a, T: Node<'a>>(n: T) -> usize {
+    let mut counter = NodeCounter::default();
+    walk(n, &WalkOptions::default(), &mut counter);
+    counter.count
 }
 
 pub fn walk_all_field<'a, T: Node<'a>>(t: T::TField) -> usize {
-    let mut count = 0;
+    let mut counter = NodeCounter::default();
+    walk_field::<T>(t, &WalkOptions::default(), &mut counter);
+    counter.count
+}
+
+/// Describes the content of a field (types, payloads, and fields), independent of how it is chunked.
+/// Used to compare trees in tests.
+pub fn describe_field<'a, T: Node<'a>>(t: T::TField) -> String {
+    let mut out = String::new();
     for c in 0..t.len() {
         let child = t.index(c).unwrap();
-        count += walk_all(child);
+        out += &format!("{}{:?}", child.get_def().0, child.get_payload());
+        for (key, field) in child.get_fields() {
+            out += &format!("[{}:{}]", key.0, describe_field::<T>(field));
+        }
+        out += ",";
+    }
+    out
+}
+
+/// Copies a subtree into a [BasicNode].
+pub fn to_basic<'a, T: Node<'a>>(node: T) -> BasicNode {
+    BasicNode {
+        def: node.get_def(),
+        payload: node.get_payload().map(|p| p.to_vec()),
+        fields: node
+            .get_fields()
+            .map(|(key, field)| {
+                let children = (0..field.len())
+                    .map(|i| to_basic(field.index(i).unwrap()))
+                    .collect();
+                (key.clone(), children)
+            })
+            .collect(),
     }
-    count
 }
 
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
+    use std::collections::BTreeMap;
 
     use crate::{
-        forest::{example_node::BasicNode, tree::Tree, uniform_chunk::UniformChunkNode},
+        forest::{
+            example_node::BasicNode,
+            mixed::{MixedNode, MixedNodeRef},
+            tree::Tree,
+            uniform_chunk::UniformChunkNode,
+        },
         TreeType,
     };
 
@@ -108,12 +188,34 @@ mod tests {
         let n: &BasicNode = &BasicNode {
             def: TreeType("".into()),
             payload: None,
-            fields: HashMap::default(),
+            fields: BTreeMap::default(),
         };
 
         assert_eq!(walk_all(n), 1);
     }
 
+    fn keys<'a, T: Node<'a>>(node: T) -> Vec<FieldKey> {
+        node.get_fields().map(|(key, _)| key.clone()).collect()
+    }
+
+    #[test]
+    fn field_order_is_canonical() {
+        let chunk = big_tree(1);
+        let node = chunk.view().index(0).unwrap();
+        let basic = to_basic(node.clone());
+        let mixed = MixedNode::from_basic(&basic);
+
+        let expected = keys(node.clone());
+        assert_eq!(expected.len(), 4);
+        assert!(expected.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(keys(&basic), expected);
+        assert_eq!(keys(MixedNodeRef::Node(&mixed)), expected);
+        assert_eq!(
+            keys(MixedNodeRef::Node(&MixedNode::from_uniform(&node))),
+            expected
+        );
+    }
+
     #[test]
     fn print_sizes() {
         println!("UniformChunk:{}", std::mem::size_of::<UniformChunk>(),);
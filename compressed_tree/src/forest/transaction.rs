@@ -0,0 +1,172 @@
+//! Transactions: groups of edits to a [Forest] which are applied atomically.
+//!
+//! Edits are staged on a copy of the forest. Since chunks are reference counted and copied on write,
+//! the copy only duplicates the parts of the forest which are edited.
+//! The forest itself is not modified until the transaction is committed, so a failed edit can not leave it partially edited.
+//! Subscribers are notified of the whole transaction as one batch when it is committed.
+
+use super::{
+    changeset::{Change, Changeset},
+    edit::EditError,
+    mixed::{Field, Forest},
+    path::{FieldPosition, FieldRange, Path},
+};
+
+/// Edits staged against a forest. Dropping the transaction without committing it rolls it back.
+pub struct Transaction<'a> {
+    forest: &'a mut Forest,
+    staged: Forest,
+    changes: Vec<Change>,
+    /// Inverses of `changes`, in the order they were applied.
+    inverses: Vec<Change>,
+}
+
+/// Result of committing a [Transaction].
+pub struct Commit {
+    /// All the changes made by the transaction.
+    pub changeset: Changeset,
+    /// Changeset which undoes the transaction.
+    pub inverse: Changeset,
+}
+
+impl Forest {
+    /// Starts a transaction which edits this forest when committed.
+    pub fn transaction(&mut self) -> Transaction<'_> {
+        let mut staged = self.observed_copy();
+        staged.start_batch();
+        Transaction {
+            staged,
+            forest: self,
+            changes: vec![],
+            inverses: vec![],
+        }
+    }
+}
+
+impl<'a> Transaction<'a> {
+    /// The forest with the staged edits applied.
+    pub fn view(&self) -> &Forest {
+        &self.staged
+    }
+
+    /// Stages `changeset`. If it fails, none of it is staged, but previously staged edits are kept.
+    pub fn apply(&mut self, changeset: &Changeset) -> Result<(), EditError> {
+        let mut inverse = changeset.apply_inverted(&mut self.staged)?;
+        inverse.0.reverse();
+        self.changes.extend(changeset.0.iter().cloned());
+        self.inverses.extend(inverse.0);
+        Ok(())
+    }
+
+    pub fn insert(&mut self, at: FieldPosition, content: Field) -> Result<(), EditError> {
+        self.apply(&Changeset(vec![Change::Insert { at, content }]))
+    }
+
+    pub fn remove(&mut self, range: FieldRange) -> Result<(), EditError> {
+        self.apply(&Changeset(vec![Change::Remove { range }]))
+    }
+
+    pub fn set_value(&mut self, path: Path, value: Option<Vec<u8>>) -> Result<(), EditError> {
+        self.apply(&Changeset(vec![Change::SetValue { path, value }]))
+    }
+
+    pub fn move_range(&mut self, range: FieldRange, to: FieldPosition) -> Result<(), EditError> {
+        self.apply(&Changeset(vec![Change::Move { range, to }]))
+    }
+
+    /// Applies the staged edits to the forest.
+    pub fn commit(self) -> Commit {
+        let Transaction {
+            forest,
+            staged,
+            changes,
+            mut inverses,
+        } = self;
+        *forest = staged;
+        forest.end_batch();
+        inverses.reverse();
+        Commit {
+            changeset: Changeset(changes),
+            inverse: Changeset(inverses),
+        }
+    }
+
+    /// Discards the staged edits.
+    pub fn rollback(self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::forest::{
+        history::History,
+        mixed::Chunk,
+        store::{ChunkId, ChunkStore, MemoryStore},
+        test_stuff::{big_tree, describe_root, key},
+    };
+
+    /// Forest with a uniform chunk of 10 nodes in "root", and 4 nodes which are not in the store in "lazy".
+    fn transaction_forest() -> Forest {
+        let mut forest = Forest::new();
+        forest.set_root(key("root"), vec![Chunk::Uniform(Rc::new(big_tree(10)))]);
+        let store: Rc<dyn ChunkStore> = Rc::new(MemoryStore::default());
+        forest.set_root(key("lazy"), vec![Chunk::lazy(store, ChunkId(0), 4)]);
+        forest
+    }
+
+    fn root_range(start: u32, end: u32) -> FieldRange {
+        FieldRange::new(Path::root(), key("root"), start, end)
+    }
+
+    #[test]
+    fn commit_applies_all_edits() {
+        let mut forest = transaction_forest();
+        let before = describe_root(&forest);
+        let mut history = History::default();
+
+        let mut transaction = forest.transaction();
+        transaction.remove(root_range(0, 2)).unwrap();
+        transaction
+            .move_range(
+                root_range(0, 3),
+                FieldPosition::new(Path::root(), key("root"), 5),
+            )
+            .unwrap();
+        transaction
+            .set_value(Path::detached(key("root"), 1), Some(vec![1]))
+            .unwrap();
+        let staged = describe_root(transaction.view());
+        let commit = transaction.commit();
+        assert_eq!(commit.changeset.0.len(), 3);
+        assert_eq!(describe_root(&forest), staged);
+
+        history.record(commit.inverse);
+        history.undo(&mut forest).unwrap();
+        assert_eq!(describe_root(&forest), before);
+    }
+
+    #[test]
+    fn failed_edits_leave_the_forest_unchanged() {
+        let mut forest = transaction_forest();
+        let before = describe_root(&forest);
+        let chunks = forest.root_chunks(&key("root")).len();
+
+        let mut transaction = forest.transaction();
+        transaction.remove(root_range(3, 5)).unwrap();
+        // Splitting the pending chunk fails.
+        assert_eq!(
+            transaction.remove(FieldRange::new(Path::root(), key("lazy"), 1, 2)),
+            Err(EditError::Pending(None))
+        );
+        assert_eq!(
+            transaction.remove(root_range(0, 20)),
+            Err(EditError::OutOfRange)
+        );
+        transaction.rollback();
+
+        assert_eq!(describe_root(&forest), before);
+        assert_eq!(forest.root_chunks(&key("root")).len(), chunks);
+    }
+}
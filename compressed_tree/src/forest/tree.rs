@@ -1,6 +1,14 @@
 //! Core types of the tree abstraction.
 
-use crate::{forest::util::ImSlice, FieldKey, TreeType};
+use crate::{
+    forest::{
+        hash::SubtreeHash,
+        store::{ChunkId, StoreError},
+        uniform_chunk::ChunkInfo,
+        util::ImSlice,
+    },
+    FieldKey, TreeType,
+};
 
 /// Generic indexing trait.
 /// Based on https://www.reddit.com/r/rust/comments/qce86d/generalizing_with_gat_whats_going_to_happen_to/
@@ -9,6 +17,26 @@ pub trait Indexable {
 
     fn index(&self, index: usize) -> Self::Item;
     fn len(&self) -> usize;
+
+    /// Start and length of the chunk containing `index`.
+    /// Defaults to treating each item as its own chunk.
+    fn chunk_range(&self, index: usize) -> (usize, usize) {
+        (index, 1)
+    }
+
+    /// The uniform chunk holding the nodes in `chunk_range(index)`, if they are stored in one,
+    /// so readers can read its data directly instead of node by node.
+    /// Defaults to None.
+    fn uniform_chunk(&self, _index: usize) -> Option<ChunkInfo<'_>> {
+        None
+    }
+
+    /// Id of the stored chunk holding the nodes in `chunk_range(index)`, if they are stored in one.
+    /// Nodes at the same offset in chunks with the same id are identical, so comparisons can skip them without loading them.
+    /// Defaults to None.
+    fn stored_chunk(&self, _index: usize) -> Option<ChunkId> {
+        None
+    }
 }
 
 impl<'a, T> Indexable for &'a [T] {
@@ -36,6 +64,7 @@ pub trait NodeNav<'a>: Sized {
     type TField: Indexable<Item = Option<Self>>;
 
     /// For iterating the set of field labels for non-empty fields.
+    /// Fields are iterated in [FieldKey] order, so equal trees iterate their fields in the same order in any representation.
     type TFields: Iterator<Item = (&'a FieldKey, Self::TField)>;
 
     fn get_field(&self, label: FieldKey) -> Self::TField;
@@ -43,6 +72,18 @@ pub trait NodeNav<'a>: Sized {
 
     // Used to optimize checking for fields.
     fn is_leaf(&self) -> bool;
+
+    /// True if this node's data has not been loaded yet.
+    /// Pending nodes have no fields, and their def and payload are not meaningful.
+    fn is_pending(&self) -> bool {
+        false
+    }
+
+    /// For pending nodes, why their data could not be loaded if it failed with an error
+    /// rather than just not being available yet.
+    fn load_error(&self) -> Option<StoreError> {
+        None
+    }
 }
 
 /// Tree Node.
@@ -50,8 +91,28 @@ pub trait NodeNav<'a>: Sized {
 pub trait NodeData {
     fn get_def(&self) -> TreeType;
     fn get_payload(&self) -> Option<ImSlice>;
+
+    /// Identifies the storage this node's subtree is read from.
+    /// Nodes with the same identity have identical subtrees, so comparisons can skip them without reading them.
+    /// Defaults to None (unknown).
+    fn identity(&self) -> Option<NodeIdentity> {
+        None
+    }
+
+    /// [SubtreeHash] of this node's subtree, if it is cached (or is cached by computing it).
+    /// Subtrees with hashes are identical exactly when their hashes are, so comparisons can use them instead of reading the subtrees.
+    /// Defaults to None.
+    fn subtree_hash(&self) -> Option<SubtreeHash> {
+        None
+    }
 }
 
+/// Address of the data a node is read from. See [NodeData::identity].
+///
+/// Only meaningful while the nodes being compared are borrowed, since memory can be reused once freed.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct NodeIdentity(pub usize, pub usize);
+
 pub trait Node<'a>: NodeNav<'a> + NodeData {}
 
 impl<'a, TNode: NodeData + NodeNav<'a>> Node<'a> for TNode {}
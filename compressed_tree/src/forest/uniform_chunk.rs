@@ -1,9 +1,10 @@
-use std::{collections::HashMap, rc::Rc, usize};
+use std::{cell::OnceCell, collections::HashMap, rc::Rc, usize};
 
 use crate::{FieldKey, TreeType};
 
 use super::{
-    tree::{Indexable, NodeData, NodeNav, Tree},
+    hash::SubtreeHash,
+    tree::{Indexable, NodeData, NodeIdentity, NodeNav, Tree},
     util::{slice_with_length, ImSlice},
 };
 
@@ -13,6 +14,8 @@ use super::{
 pub struct UniformChunk {
     data: Vec<u8>,
     schema: Rc<ChunkSchema>,
+    /// Cached hashes of the top level nodes. See [super::hash].
+    pub(super) hashes: OnceCell<Vec<SubtreeHash>>,
 }
 
 impl PartialEq for UniformChunk {
@@ -55,7 +58,7 @@ impl ChunkSchema {
         fields: &[(FieldKey, OffsetSchema)],
     ) -> ChunkSchema {
         let mut field_list: Vec<(FieldKey, OffsetSchema)> = fields.into();
-        field_list.sort_by_key(|f| f.1.byte_offset);
+        field_list.sort_by(|a, b| a.0.cmp(&b.0));
         let field_map = fields.iter().cloned().collect();
         ChunkSchema {
             tree_type,
@@ -66,6 +69,16 @@ impl ChunkSchema {
             field_list,
         }
     }
+
+    /// Fields, in [FieldKey] order.
+    pub fn fields(&self) -> &[(FieldKey, OffsetSchema)] {
+        &self.field_list
+    }
+
+    /// Size of the data for all the top level nodes.
+    pub fn byte_length(&self) -> usize {
+        self.bytes_per_top_level_node as usize * self.top_level_length as usize
+    }
 }
 
 /// Offsets are for the first iteration (of a possible schema.node_count iterations)
@@ -102,12 +115,36 @@ impl UniformChunk {
             schema.bytes_per_top_level_node as usize * schema.top_level_length as usize,
             data.len()
         );
-        UniformChunk { schema, data }
+        UniformChunk {
+            schema,
+            data,
+            hashes: OnceCell::new(),
+        }
     }
 
     pub fn get_count(&self) -> usize {
         self.schema.top_level_length as usize
     }
+
+    pub fn schema(&self) -> &Rc<ChunkSchema> {
+        &self.schema
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Copies the top level nodes in `[start, end)` into a new chunk.
+    pub fn slice(&self, start: usize, end: usize) -> UniformChunk {
+        debug_assert!(start <= end && end <= self.get_count());
+        let stride = self.schema.bytes_per_top_level_node as usize;
+        let mut schema = ChunkSchema::clone(&self.schema);
+        schema.top_level_length = (end - start) as u32;
+        UniformChunk::new(
+            Rc::new(schema),
+            self.data[start * stride..end * stride].to_vec(),
+        )
+    }
 }
 
 impl Tree for UniformChunk {
@@ -138,11 +175,8 @@ impl<'a> NodeNav<'a> for UniformChunkNode<'a> {
         match self.view.schema.field_map.get(&label) {
             Some(x) => {
                 let node_data = self.data();
-                let field_data = slice_with_length(
-                    node_data,
-                    x.byte_offset as usize,
-                    x.schema.bytes_per_top_level_node as usize,
-                );
+                let field_data =
+                    slice_with_length(node_data, x.byte_offset as usize, x.schema.byte_length());
                 ChunkInfo {
                     schema: &x.schema,
                     data: field_data,
@@ -182,6 +216,50 @@ impl NodeData for UniformChunkNode<'_> {
             None => None,
         }
     }
+
+    /// The schema and the node's data determine its whole subtree.
+    fn identity(&self) -> Option<NodeIdentity> {
+        Some(NodeIdentity(
+            self.view.schema as *const ChunkSchema as usize,
+            self.data().as_ptr() as usize,
+        ))
+    }
+}
+
+impl<'a> ChunkInfo<'a> {
+    /// Copies this part of a chunk into its own chunk.
+    pub fn to_chunk(&self) -> UniformChunk {
+        UniformChunk::new(Rc::new(self.schema.clone()), self.data.to_vec())
+    }
+
+    /// Payload of each top level node, read with the chunk's fixed stride.
+    pub fn payloads(&self) -> impl Iterator<Item = Option<ImSlice<'a>>> + 'a {
+        let stride = self.schema.bytes_per_top_level_node as usize;
+        let size = self.schema.payload_size.map(usize::from);
+        let data = self.data;
+        (0..self.len()).map(move |i| size.map(|size| slice_with_length(data, i * stride, size)))
+    }
+
+    /// The field `key` of each top level node, in order. Empty if there is no such field.
+    pub fn field_of_each(&self, key: &FieldKey) -> impl Iterator<Item = ChunkInfo<'a>> + 'a {
+        let stride = self.schema.bytes_per_top_level_node as usize;
+        let len = self.len();
+        let data = self.data;
+        self.schema
+            .field_map
+            .get(key)
+            .into_iter()
+            .flat_map(move |field| {
+                (0..len).map(move |i| ChunkInfo {
+                    schema: &field.schema,
+                    data: slice_with_length(
+                        data,
+                        i * stride + field.byte_offset as usize,
+                        field.schema.byte_length(),
+                    ),
+                })
+            })
+    }
 }
 
 impl<'a> Indexable for ChunkInfo<'a> {
@@ -201,6 +279,14 @@ impl<'a> Indexable for ChunkInfo<'a> {
     fn len(&self) -> usize {
         self.schema.top_level_length as usize
     }
+
+    fn chunk_range(&self, _index: usize) -> (usize, usize) {
+        (0, self.len())
+    }
+
+    fn uniform_chunk(&self, _index: usize) -> Option<ChunkInfo<'_>> {
+        Some(self.clone())
+    }
 }
 
 pub struct ChunkFieldsIterator<'a> {
@@ -216,7 +302,7 @@ impl<'a> Iterator for ChunkFieldsIterator<'a> {
         let data = slice_with_length(
             self.data,
             schema.byte_offset as usize,
-            schema.schema.bytes_per_top_level_node as usize,
+            schema.schema.byte_length(),
         );
         let info: ChunkInfo = ChunkInfo {
             schema: &schema.schema,
@@ -230,3 +316,41 @@ impl<'a> Iterator for ChunkFieldsIterator<'a> {
 lazy_static! {
     static ref EMPTY_SCHEMA: ChunkSchema = ChunkSchema::new_leaf(TreeType("".into()), 0, None,);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields_with_multiple_nodes() {
+        // Each top level node has a one byte payload followed by a field of three one byte leaves.
+        let key = FieldKey("values".into());
+        let leaves = ChunkSchema::new_leaf(TreeType("leaf".into()), 3, Some(1));
+        let schema = ChunkSchema::new(
+            TreeType("node".into()),
+            2,
+            4,
+            Some(1),
+            &[(
+                key.clone(),
+                OffsetSchema {
+                    byte_offset: 1,
+                    schema: leaves,
+                },
+            )],
+        );
+        let chunk = UniformChunk::new(Rc::new(schema), vec![0, 1, 2, 3, 10, 11, 12, 13]);
+        let node = chunk.view().index(1).unwrap();
+
+        let payloads = |field: ChunkInfo| -> Vec<Vec<u8>> {
+            (0..field.len())
+                .map(|i| field.index(i).unwrap().get_payload().unwrap().to_vec())
+                .collect()
+        };
+        let expected = vec![vec![11], vec![12], vec![13]];
+        assert_eq!(payloads(node.get_field(key.clone())), expected);
+        let (label, field) = node.get_fields().next().unwrap();
+        assert_eq!(label, &key);
+        assert_eq!(payloads(field), expected);
+    }
+}
@@ -1,35 +1,70 @@
 #[macro_use]
 extern crate lazy_static;
 
-trait UpPath {}
-struct Value(Option<f64>); // TODO: more value types
+use std::fmt;
 
-#[derive(Clone, PartialEq, Eq, Hash)]
-pub struct FieldKey(String);
-#[derive(Clone, PartialEq, Eq, Hash)]
-pub struct TreeType(String);
+use forest::path::Path;
 
+/// Value of a node: its payload, if it is an 8 byte little endian f64.
+pub struct Value(pub Option<f64>); // TODO: more value types
+
+impl Value {
+    /// Value of a node with `payload`.
+    pub fn of(payload: Option<&[u8]>) -> Value {
+        Value(
+            payload
+                .and_then(|p| <[u8; 8]>::try_from(p).ok())
+                .map(f64::from_le_bytes),
+        )
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct FieldKey(pub String);
+
+impl FieldKey {
+    /// Key of the detached field a cursor created from a single field (like [cursor::GenericNodesCursor::new]) is in.
+    pub fn root() -> FieldKey {
+        FieldKey("root".into())
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct TreeType(pub String);
+
+pub mod build;
 pub mod cursor;
 pub mod dummy_cursor;
 pub mod forest;
+pub mod query;
+pub mod visit;
 pub mod wasm;
 
-trait NodesCursor: Sized {
+/**
+ * Cursor in `Nodes` mode.
+ *
+ * Cursors navigate trees under a virtual root node, whose field keys are actually detached sequences.
+ * The root has no type, value or payload, and is alone (at index 0) in a field which has no parent,
+ * so navigating up from it fails with `AtRoot`.
+ */
+pub trait NodesCursor: Sized {
     type TFields: FieldsCursor<TNodes = Self>;
     // ********** APIs for when mode = Nodes ********** //
 
     /**
-     * @returns a path to the current node.
+     * True if the current node is the root.
+     *
+     * Only valid when `mode` is `Nodes`.
+     */
+    fn is_root(&self) -> bool;
+
+    /**
+     * @returns a path to the current node from the root.
+     * The first step's key identifies the detached sequence the node is in, and the root's path is empty.
      *
      * Only valid when `mode` is `Nodes`.
-     * Assumes this cursor has a root node where its field keys are actually detached sequences.
-     * If the cursor is not rooted at such a node,
-     * calling this function is invalid, and the returned UpPath (if any) may not be meaningful.
-     * This requirement exists because {@link UpPath}s are absolute paths
-     * and thus must be rooted in a detached sequence.
-     * TODO: consider adding an optional base path to append to remove/clarify this restriction.
      */
-    // fn getPath(&self) -> impl UpPath; //  | undefined
+    fn path(&self) -> Path;
 
     /**
      * Index (within its parent field) of the current node.
@@ -64,14 +99,16 @@ trait NodesCursor: Sized {
      * If seeking to exactly past either end,
      * returns false and navigates up to the parent field (setting mode to `Fields`).
      *
+     * Fails with `AtRoot` if that would navigate up from the root.
+     *
      * Allowed if mode is `Nodes`.
      */
-    fn seek_nodes(self, offset: i32) -> EitherCursor<Self, Self::TFields>;
+    fn seek_nodes(self, offset: i32) -> CursorResult<EitherCursor<Self, Self::TFields>, Self>;
 
     /**
      * The same as `seek_nodes(1)`, but might be faster.
      */
-    fn next_node(self) -> EitherCursor<Self, Self::TFields>;
+    fn next_node(self) -> CursorResult<EitherCursor<Self, Self::TFields>, Self>;
 
     /**
      * Navigate up to parent field.
@@ -79,10 +116,10 @@ trait NodesCursor: Sized {
      *
      * Same as seek number.POSITIVE_INFINITY, but only valid when `mode` is `Nodes`.
      *
-     * TODO: what to do if at root?
+     * Fails with `AtRoot` at the root, which is not in a field.
      * TODO: Maybe merge with upToNode to make a single "Up"?
      */
-    fn exit_node(self) -> Self::TFields;
+    fn exit_node(self) -> CursorResult<Self::TFields, Self>;
 
     // ********** APIs for when mode = Nodes and not pending ********** //
 
@@ -116,9 +153,23 @@ trait NodesCursor: Sized {
      * Only valid when `mode` is `Nodes`, and not `pending`.
      */
     fn value(&self) -> Value;
+
+    /**
+     * The payload of the currently selected node.
+     *
+     * Only valid when `mode` is `Nodes`, and not `pending`.
+     */
+    fn payload(&self) -> Option<Vec<u8>>;
+
+    /**
+     * True if the current node is in a chunk which has not been loaded yet.
+     *
+     * Only valid when `mode` is `Nodes`.
+     */
+    fn pending(&self) -> bool;
 }
 
-trait FieldsCursor: Sized {
+pub trait FieldsCursor: Sized {
     type TNodes: NodesCursor<TFields = Self>;
     // ********** APIs for when mode = Fields ********** //
 
@@ -143,12 +194,14 @@ trait FieldsCursor: Sized {
      *
      * Only valid when `mode` is `Fields`.
      *
-     * TODO: what to do if at root?
+     * Detached sequences are fields of the root, so they exit to it.
+     * Fails with `AtRoot` if the field has no parent node.
      */
-    fn exit_field(self) -> Self::TNodes;
+    fn exit_field(self) -> CursorResult<Self::TNodes, Self>;
 
     /**
      * Moves the "current field" forward until `pending` is `false`.
+     * A field is pending if it is not empty, and all of its nodes are `pending`.
      *
      * If there are no remaining field to iterate to,
      * returns false and navigates up to the parent setting the mode to `Nodes`.
@@ -166,7 +219,7 @@ trait FieldsCursor: Sized {
      *
      * Allowed when `mode` is `Fields`, and not `pending`.
      */
-    // fn getFieldKey() -> FieldKey;
+    fn field_key(&self) -> FieldKey;
 
     /**
      * @returns the number of immediate children in the current field.
@@ -187,13 +240,64 @@ trait FieldsCursor: Sized {
     /**
      * Sets current node to the node at the provided `index` of the current field.
      *
+     * Fails with `OutOfRange` if there is no node at `index`.
+     *
      * Allowed when `mode` is `Fields`, and not `pending`.
      * Sets mode to `Nodes`.
      */
-    fn enter_node(self, child_index: u32) -> Self::TNodes;
+    fn enter_node(self, child_index: u32) -> CursorResult<Self::TNodes, Self>;
 }
 
-enum EitherCursor<TNodes, TFields: FieldsCursor<TNodes = TNodes>> {
+pub enum EitherCursor<TNodes, TFields: FieldsCursor<TNodes = TNodes>> {
     Nodes(TNodes),
     Fields(TFields),
 }
+
+/// Why a cursor operation could not be done.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CursorError {
+    /// The operation is not allowed in the cursor's current mode.
+    WrongMode,
+    /// There is no node at the requested index.
+    OutOfRange,
+    /// The operation would navigate up from the root.
+    AtRoot,
+    /// The operation needs the content of nodes which are pending (see [NodesCursor::pending]).
+    Pending,
+    /// Payloads read together have different sizes, so can not be split between their nodes.
+    MixedPayloadSizes,
+}
+
+impl fmt::Display for CursorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CursorError::WrongMode => "operation not allowed in the cursor's current mode",
+            CursorError::OutOfRange => "no node at that index",
+            CursorError::AtRoot => "can not navigate up from the root",
+            CursorError::Pending => "content is pending",
+            CursorError::MixedPayloadSizes => "payloads have different sizes",
+        })
+    }
+}
+
+/// A cursor operation which could not be done, and the cursor, still where it was before the operation.
+pub struct CursorFailure<C> {
+    pub cursor: C,
+    pub error: CursorError,
+}
+
+impl<C> CursorFailure<C> {
+    pub fn new(cursor: C, error: CursorError) -> Self {
+        CursorFailure { cursor, error }
+    }
+}
+
+/// Only shows the error, so results can be unwrapped for any cursor type.
+impl<C> fmt::Debug for CursorFailure<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CursorFailure({:?})", self.error)
+    }
+}
+
+/// Result of a cursor operation which can fail, returning cursor `C` on failure.
+pub type CursorResult<T, C> = Result<T, CursorFailure<C>>;
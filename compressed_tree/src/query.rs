@@ -0,0 +1,462 @@
+//! Small query language for finding nodes, evaluated with the cursor traits.
+//!
+//! A query is a sequence of steps separated by `/`, each selecting nodes relative to the nodes selected by the previous step
+//! (starting from the cursor's current node):
+//! - `key` selects the children in field `key`, and `*` the children in all fields.
+//! - `[2]` after the key selects only the child at that index, and `[1..3]`, `[1..]` or `[..3]` a range of them.
+//! - `{Type}` at the end selects only nodes of that type.
+//! - `//` before a step (instead of `/`) applies it at any depth: it selects matching descendants instead of children.
+//!
+//! For example, `//Y{X}/color` selects the `color` children of nodes of type `X` in fields `Y` anywhere in the tree.
+
+use std::{collections::HashSet, fmt, ops::Range, str::FromStr};
+
+use crate::{
+    forest::path::{Path, PathStep},
+    visit::{walk_cursor, Flow, Visitor, WalkOptions},
+    EitherCursor, FieldKey, FieldsCursor, NodesCursor, TreeType,
+};
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Query(pub Vec<QueryStep>);
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct QueryStep {
+    /// Select matching descendants at any depth, instead of only children.
+    pub recursive: bool,
+    /// Field to select nodes from. None selects from all fields.
+    pub key: Option<FieldKey>,
+    /// Indexes within the field to select.
+    pub range: Range<u32>,
+    /// Type of nodes to select. None selects all types.
+    pub def: Option<TreeType>,
+}
+
+impl QueryStep {
+    fn matches_field(&self, key: &FieldKey) -> bool {
+        self.key.as_ref().is_none_or(|k| k == key)
+    }
+
+    fn matches<C: NodesCursor>(&self, key: &FieldKey, node: &C) -> bool {
+        self.matches_field(key)
+            && self.range.contains(&node.field_index())
+            && !node.pending()
+            && self.def.as_ref().is_none_or(|def| *def == node.node_type())
+    }
+}
+
+impl Query {
+    /// Paths (relative to the cursor's current node) to the nodes the query selects, in document order (fields in key order) for each step.
+    /// Returns the cursor at the same node.
+    ///
+    /// The empty query selects the current node itself.
+    ///
+    /// Pending nodes are never selected, and their subtrees are silently skipped:
+    /// nothing is loaded, and nodes which could match under them are not reported.
+    pub fn evaluate<C: NodesCursor>(&self, cursor: C) -> (C, Vec<Path>) {
+        let mut cursor = cursor;
+        let mut selected = vec![Path::root()];
+        for step in self.0.iter() {
+            (cursor, selected) = if step.recursive {
+                descendants(cursor, step, &selected)
+            } else {
+                children(cursor, step, &selected)
+            };
+        }
+        (cursor, selected)
+    }
+}
+
+/// The children `step` selects of each of the `selected` nodes.
+fn children<C: NodesCursor>(mut cursor: C, step: &QueryStep, selected: &[Path]) -> (C, Vec<Path>) {
+    let mut next = vec![];
+    for base in selected {
+        cursor = descend(cursor, base);
+        let mut visitor = StepVisitor {
+            step,
+            keys: vec![],
+            path: vec![],
+            found: vec![],
+        };
+        let options = WalkOptions { max_depth: Some(1) };
+        cursor = walk_cursor(cursor, &options, &mut visitor).0;
+        cursor = ascend(cursor, base.0.len());
+        // Distinct nodes have distinct children, so these are not selected twice.
+        for found in visitor.found {
+            let mut path = base.clone();
+            path.0.extend(found);
+            next.push(path);
+        }
+    }
+    (cursor, next)
+}
+
+/// The descendants `step` selects of any of the `selected` nodes.
+///
+/// Selected nodes can be nested, so instead of walking each one's subtree,
+/// this walks the tree once, only entering the selected nodes' subtrees and the paths to them.
+fn descendants<C: NodesCursor>(cursor: C, step: &QueryStep, selected: &[Path]) -> (C, Vec<Path>) {
+    let mut ancestors = HashSet::new();
+    for path in selected {
+        ancestors.extend((0..path.0.len()).map(|depth| &path.0[..depth]));
+    }
+    let mut visitor = DescendantVisitor {
+        step,
+        selected: selected.iter().map(|path| path.0.as_slice()).collect(),
+        ancestors,
+        keys: vec![],
+        path: vec![],
+        under: vec![],
+        found: vec![],
+    };
+    let cursor = walk_cursor(cursor, &WalkOptions::default(), &mut visitor).0;
+    (cursor, visitor.found)
+}
+
+const FOUND: &str = "path was found by walking the tree";
+
+/// Navigates from the cursor's current node to the node at `path` relative to it.
+fn descend<C: NodesCursor>(mut cursor: C, path: &Path) -> C {
+    for step in path.0.iter() {
+        cursor = match cursor.enter_field(step.key.clone()) {
+            EitherCursor::Fields(f) => f.enter_node(step.index).expect(FOUND),
+            EitherCursor::Nodes(_) => unreachable!("{}", FOUND),
+        };
+    }
+    cursor
+}
+
+/// Navigates up `levels` levels.
+fn ascend<C: NodesCursor>(mut cursor: C, levels: usize) -> C {
+    for _ in 0..levels {
+        cursor = cursor.exit_node().expect(FOUND).exit_field().expect(FOUND);
+    }
+    cursor
+}
+
+/// Finds the children a (non-recursive) step selects of the node a traversal starts at.
+struct StepVisitor<'a> {
+    step: &'a QueryStep,
+    /// Key of the field entered at each depth.
+    keys: Vec<FieldKey>,
+    /// Path from where the traversal started to the current node.
+    path: Vec<PathStep>,
+    found: Vec<Vec<PathStep>>,
+}
+
+impl<C: NodesCursor> Visitor<C> for StepVisitor<'_> {
+    fn enter_node(&mut self, node: &C, depth: usize) -> Flow {
+        if depth == 0 {
+            return Flow::Continue;
+        }
+        let key = &self.keys[depth - 1];
+        self.path.truncate(depth - 1);
+        self.path.push(PathStep {
+            key: key.clone(),
+            index: node.field_index(),
+        });
+        if self.step.matches(key, node) {
+            self.found.push(self.path.clone());
+        }
+        Flow::Continue
+    }
+
+    fn enter_field(&mut self, key: &FieldKey, depth: usize) -> Flow {
+        if !self.step.matches_field(key) {
+            return Flow::SkipSubtree;
+        }
+        self.keys.truncate(depth - 1);
+        self.keys.push(key.clone());
+        Flow::Continue
+    }
+}
+
+/// Finds the nodes a recursive step selects under any of the selected nodes, in one traversal.
+struct DescendantVisitor<'a> {
+    step: &'a QueryStep,
+    selected: HashSet<&'a [PathStep]>,
+    /// Paths to the proper ancestors of the selected nodes.
+    ancestors: HashSet<&'a [PathStep]>,
+    /// Key of the field entered at each depth.
+    keys: Vec<FieldKey>,
+    /// Path from where the traversal started to the current node.
+    path: Vec<PathStep>,
+    /// For each node on `path` (and where the traversal started), if it or one of its ancestors is selected.
+    under: Vec<bool>,
+    found: Vec<Path>,
+}
+
+impl<C: NodesCursor> Visitor<C> for DescendantVisitor<'_> {
+    fn enter_node(&mut self, node: &C, depth: usize) -> Flow {
+        self.under.truncate(depth);
+        let mut under = false;
+        if depth > 0 {
+            let key = &self.keys[depth - 1];
+            self.path.truncate(depth - 1);
+            self.path.push(PathStep {
+                key: key.clone(),
+                index: node.field_index(),
+            });
+            under = self.under[depth - 1];
+            if under && self.step.matches(key, node) {
+                self.found.push(Path(self.path.clone()));
+            }
+        }
+        under = under || self.selected.contains(self.path.as_slice());
+        self.under.push(under);
+        if under || self.ancestors.contains(self.path.as_slice()) {
+            Flow::Continue
+        } else {
+            Flow::SkipSubtree
+        }
+    }
+
+    fn enter_field(&mut self, key: &FieldKey, depth: usize) -> Flow {
+        self.keys.truncate(depth - 1);
+        self.keys.push(key.clone());
+        Flow::Continue
+    }
+}
+
+/// Error from parsing a [Query].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ParseQueryError(pub String);
+
+impl FromStr for Query {
+    type Err = ParseQueryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Ok(Query(vec![]));
+        }
+        let mut steps = vec![];
+        let mut recursive = false;
+        let segments: Vec<&str> = s.split('/').collect();
+        for (i, segment) in segments.iter().enumerate() {
+            if segment.is_empty() {
+                // A leading `/` is optional, and `//` marks the next step as recursive.
+                if i == 0 && segments.len() > 1 {
+                    continue;
+                }
+                if recursive || i + 1 == segments.len() {
+                    return Err(ParseQueryError(format!("empty step in query {:?}", s)));
+                }
+                recursive = true;
+                continue;
+            }
+            let mut step = parse_step(segment)?;
+            step.recursive = recursive;
+            recursive = false;
+            steps.push(step);
+        }
+        Ok(Query(steps))
+    }
+}
+
+fn parse_step(segment: &str) -> Result<QueryStep, ParseQueryError> {
+    let error = || ParseQueryError(format!("invalid query step: {:?}", segment));
+    let (rest, def) = match segment.strip_suffix('}') {
+        Some(rest) => {
+            let (rest, def) = rest.split_once('{').ok_or_else(error)?;
+            (rest, Some(TreeType(def.into())))
+        }
+        None => (segment, None),
+    };
+    let (key, range) = match rest.strip_suffix(']') {
+        Some(rest) => {
+            let (key, range) = rest.split_once('[').ok_or_else(error)?;
+            (key, parse_range(range).ok_or_else(error)?)
+        }
+        None => (rest, 0..u32::MAX),
+    };
+    if key.is_empty() || key.contains(['[', ']', '{', '}']) {
+        return Err(error());
+    }
+    Ok(QueryStep {
+        recursive: false,
+        key: if key == "*" {
+            None
+        } else {
+            Some(FieldKey(key.into()))
+        },
+        range,
+        def,
+    })
+}
+
+fn parse_range(range: &str) -> Option<Range<u32>> {
+    match range.split_once("..") {
+        Some((start, end)) => {
+            let start = if start.is_empty() {
+                0
+            } else {
+                start.parse().ok()?
+            };
+            let end = if end.is_empty() {
+                u32::MAX
+            } else {
+                end.parse().ok()?
+            };
+            Some(start..end)
+        }
+        None => {
+            let index: u32 = range.parse().ok()?;
+            Some(index..index.checked_add(1)?)
+        }
+    }
+}
+
+/// Formats in the syntax parsed by [Query]'s FromStr implementation.
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, step) in self.0.iter().enumerate() {
+            if step.recursive {
+                f.write_str("//")?;
+            } else if i > 0 {
+                f.write_str("/")?;
+            }
+            match &step.key {
+                Some(key) => f.write_str(&key.0)?,
+                None => f.write_str("*")?,
+            }
+            let Range { start, end } = step.range;
+            if end == start + 1 {
+                write!(f, "[{}]", start)?;
+            } else if step.range != (0..u32::MAX) {
+                f.write_str("[")?;
+                if start != 0 {
+                    write!(f, "{}", start)?;
+                }
+                f.write_str("..")?;
+                if end != u32::MAX {
+                    write!(f, "{}", end)?;
+                }
+                f.write_str("]")?;
+            }
+            if let Some(def) = &step.def {
+                write!(f, "{{{}}}", def.0)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        build::{BasicTreeBuilder, TreeSink},
+        cursor::GenericNodesCursor,
+        forest::{
+            example_node::BasicNode,
+            mixed::{Chunk, MixedNode},
+            store::{ChunkId, ChunkStore, MemoryStore},
+            test_stuff::{deep_node, forest_with_root, key, leaf},
+        },
+    };
+    use std::rc::Rc;
+
+    /// A "root" node with a "shapes" field of circles and squares, each with a "color" child,
+    /// and a "group" field holding another node like the root.
+    fn tree() -> Vec<BasicNode> {
+        fn shapes(sink: &mut BasicTreeBuilder, depth: u32) {
+            sink.start_node(TreeType("Root".into()), None);
+            sink.start_field(key("shapes"));
+            for def in ["Circle", "Square", "Circle"] {
+                sink.start_node(TreeType(def.into()), None);
+                sink.start_field(key("color"));
+                sink.start_node(TreeType("Color".into()), Some(&[depth as u8]));
+                sink.end_node();
+                sink.end_field();
+                sink.end_node();
+            }
+            sink.end_field();
+            if depth > 0 {
+                sink.start_field(key("group"));
+                shapes(sink, depth - 1);
+                sink.end_field();
+            }
+            sink.end_node();
+        }
+        let mut builder = BasicTreeBuilder::default();
+        shapes(&mut builder, 1);
+        builder.finish().0
+    }
+
+    fn run(query: &str) -> Vec<String> {
+        let nodes = tree();
+        let cursor = GenericNodesCursor::<&BasicNode>::new(&nodes);
+        let query: Query = query.parse().unwrap();
+        let (cursor, paths) = query.evaluate(cursor);
+        assert_eq!(cursor.field_index(), 0);
+        paths.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn parse() {
+        for query in [
+            "",
+            "a",
+            "a/b",
+            "//a{T}",
+            "*[2]/b[1..3]//c[..4]{T}",
+            "a[5..]",
+        ] {
+            assert_eq!(query.parse::<Query>().unwrap().to_string(), query);
+        }
+        assert_eq!("/a".parse::<Query>().unwrap().to_string(), "a");
+        for query in ["a/", "a///b", "a[x]", "a{T", "[1]", "a[1]b"] {
+            assert!(query.parse::<Query>().is_err(), "{}", query);
+        }
+    }
+
+    #[test]
+    fn evaluate() {
+        assert_eq!(run(""), vec![""]);
+        assert_eq!(
+            run("shapes{Circle}/color"),
+            vec!["shapes[0]/color[0]", "shapes[2]/color[0]"]
+        );
+        assert_eq!(run("shapes[1..]"), vec!["shapes[1]", "shapes[2]"]);
+        assert_eq!(run("*{Root}"), vec!["group[0]"]);
+        assert_eq!(
+            run("//shapes[1]{Square}/*"),
+            vec!["group[0]/shapes[1]/color[0]", "shapes[1]/color[0]"]
+        );
+        assert_eq!(run("//color").len(), 6);
+        // Nodes selected through several paths are only reported once.
+        assert_eq!(run("//*//color").len(), 6);
+        assert!(run("group/group").is_empty());
+    }
+
+    #[test]
+    fn evaluate_nested_selections() {
+        const DEPTH: u32 = 100;
+        let forest = forest_with_root(deep_node(DEPTH));
+        let cursor = forest.cursor_at(&Path::detached(key("root"), 0)).unwrap();
+        let query: Query = "//child//child".parse().unwrap();
+        let (_, paths) = query.evaluate(cursor);
+        // Every node at least two levels down, each once, from the top.
+        assert_eq!(paths.len(), DEPTH as usize - 1);
+        for (i, path) in paths.iter().enumerate() {
+            assert_eq!(path.0.len(), i + 2);
+        }
+    }
+
+    #[test]
+    fn pending_subtrees_are_skipped() {
+        // A chunk which is not in the store, so is pending.
+        let store: Rc<dyn ChunkStore> = Rc::new(MemoryStore::default());
+        let mut root = MixedNode::new(TreeType("root".into()), None);
+        root.fields_mut().insert(
+            key("children"),
+            vec![leaf(1), Chunk::lazy(store, ChunkId(0), 1)],
+        );
+        let forest = forest_with_root(root);
+        for (query, expected) in [("children", 1), ("//*", 1), ("children[1]", 0)] {
+            let cursor = forest.cursor_at(&Path::detached(key("root"), 0)).unwrap();
+            let query: Query = query.parse().unwrap();
+            assert_eq!(query.evaluate(cursor).1.len(), expected, "{}", query);
+        }
+    }
+}
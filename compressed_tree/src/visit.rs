@@ -0,0 +1,458 @@
+//! Depth first traversal of trees, calling a [Visitor] as nodes and fields are entered and exited.
+//!
+//! [walk] and [walk_field] traverse any [Node] implementation, and [walk_cursor] traverses using the cursor traits.
+//! The visitor's hooks control the traversal by returning a [Flow], and [WalkOptions] limits its depth.
+//!
+//! Traversals do not recurse, so they can walk trees of any depth.
+
+use crate::{
+    forest::tree::{Indexable, Node},
+    EitherCursor, FieldKey, FieldsCursor, NodesCursor,
+};
+
+/// How a traversal continues after a [Visitor] hook.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Flow {
+    Continue,
+    /// Do not visit the content of the node or field being entered. Its exit hook is still called.
+    /// Treated as [Flow::Continue] when returned from an exit hook.
+    SkipSubtree,
+    /// End the traversal without calling any more hooks.
+    Stop,
+}
+
+/// Hooks called during a traversal, with the depth of the node or field:
+/// the nodes the traversal starts at have depth 0, and their fields and children depth 1.
+///
+/// `N` is the type the traversal reads nodes through: a [Node] for [walk], or a [NodesCursor] for [walk_cursor].
+/// All hooks default to doing nothing.
+pub trait Visitor<N> {
+    fn enter_node(&mut self, _node: &N, _depth: usize) -> Flow {
+        Flow::Continue
+    }
+
+    fn exit_node(&mut self, _node: &N, _depth: usize) -> Flow {
+        Flow::Continue
+    }
+
+    fn enter_field(&mut self, _key: &FieldKey, _depth: usize) -> Flow {
+        Flow::Continue
+    }
+
+    fn exit_field(&mut self, _key: &FieldKey, _depth: usize) -> Flow {
+        Flow::Continue
+    }
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct WalkOptions {
+    /// Nodes deeper than this, and the fields containing them, are not visited.
+    pub max_depth: Option<usize>,
+}
+
+impl WalkOptions {
+    fn enter_fields(&self, depth: usize) -> bool {
+        self.max_depth.is_none_or(|max| depth < max)
+    }
+}
+
+/// When an [OrderedVisitor] calls its function.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Order {
+    /// Before the node's descendants.
+    Pre,
+    /// After the node's descendants.
+    Post,
+}
+
+/// [Visitor] which calls a function for each node, in pre or post order.
+pub struct OrderedVisitor<F> {
+    order: Order,
+    f: F,
+}
+
+impl<F> OrderedVisitor<F> {
+    pub fn new(order: Order, f: F) -> Self {
+        OrderedVisitor { order, f }
+    }
+}
+
+impl<N, F: FnMut(&N, usize) -> Flow> Visitor<N> for OrderedVisitor<F> {
+    fn enter_node(&mut self, node: &N, depth: usize) -> Flow {
+        match self.order {
+            Order::Pre => (self.f)(node, depth),
+            Order::Post => Flow::Continue,
+        }
+    }
+
+    fn exit_node(&mut self, node: &N, depth: usize) -> Flow {
+        match self.order {
+            Order::Pre => Flow::Continue,
+            Order::Post => (self.f)(node, depth),
+        }
+    }
+}
+
+/// [Visitor] which counts the nodes visited.
+#[derive(Default)]
+pub struct NodeCounter {
+    pub count: usize,
+}
+
+impl<N> Visitor<N> for NodeCounter {
+    fn enter_node(&mut self, _node: &N, _depth: usize) -> Flow {
+        self.count += 1;
+        Flow::Continue
+    }
+}
+
+/// Walks the subtree under `node`.
+/// Returns [Flow::Stop] if the visitor stopped the traversal, and [Flow::Continue] otherwise.
+pub fn walk<'a, T: Node<'a>>(
+    node: T,
+    options: &WalkOptions,
+    visitor: &mut impl Visitor<T>,
+) -> Flow {
+    let mut stack = vec![];
+    if enter_node(node, 0, options, visitor, &mut stack) == Flow::Stop {
+        return Flow::Stop;
+    }
+    walk_stack(stack, options, visitor)
+}
+
+/// Walks the subtrees under each node in `field`, in order.
+/// Returns [Flow::Stop] if the visitor stopped the traversal, and [Flow::Continue] otherwise.
+pub fn walk_field<'a, T: Node<'a>>(
+    field: T::TField,
+    options: &WalkOptions,
+    visitor: &mut impl Visitor<T>,
+) -> Flow {
+    walk_stack(vec![Frame::Field(None, field, 0, 0)], options, visitor)
+}
+
+/// Part of the path from where a traversal started to the node it is visiting.
+/// Traversals keep these on an explicit stack instead of recursing, so deep trees do not overflow the call stack.
+enum Frame<'a, T: Node<'a>> {
+    /// Node and its depth, and its fields which have not been visited yet (None if they are skipped).
+    Node(T, usize, Option<T::TFields>),
+    /// Field, the index of the next node to visit in it, and its depth.
+    /// The key is None for the field a traversal starts in, which is not reported to the visitor.
+    Field(Option<&'a FieldKey>, T::TField, usize, usize),
+}
+
+/// Calls `enter_node`, then pushes the node so its fields are visited next.
+fn enter_node<'a, T: Node<'a>>(
+    node: T,
+    depth: usize,
+    options: &WalkOptions,
+    visitor: &mut impl Visitor<T>,
+    stack: &mut Vec<Frame<'a, T>>,
+) -> Flow {
+    let fields = match visitor.enter_node(&node, depth) {
+        Flow::Stop => return Flow::Stop,
+        Flow::SkipSubtree => None,
+        Flow::Continue => options.enter_fields(depth).then(|| node.get_fields()),
+    };
+    stack.push(Frame::Node(node, depth, fields));
+    Flow::Continue
+}
+
+fn walk_stack<'a, T: Node<'a>>(
+    mut stack: Vec<Frame<'a, T>>,
+    options: &WalkOptions,
+    visitor: &mut impl Visitor<T>,
+) -> Flow {
+    while let Some(frame) = stack.last_mut() {
+        match frame {
+            Frame::Node(_, depth, fields) => {
+                let depth = *depth;
+                match fields.as_mut().and_then(|f| f.next()) {
+                    Some((key, field)) => match visitor.enter_field(key, depth + 1) {
+                        Flow::Stop => return Flow::Stop,
+                        Flow::Continue => stack.push(Frame::Field(Some(key), field, 0, depth + 1)),
+                        Flow::SkipSubtree => {
+                            if visitor.exit_field(key, depth + 1) == Flow::Stop {
+                                return Flow::Stop;
+                            }
+                        }
+                    },
+                    None => {
+                        if let Some(Frame::Node(node, ..)) = stack.pop() {
+                            if visitor.exit_node(&node, depth) == Flow::Stop {
+                                return Flow::Stop;
+                            }
+                        }
+                    }
+                }
+            }
+            Frame::Field(key, field, index, depth) => {
+                let (key, depth) = (*key, *depth);
+                if *index < field.len() {
+                    let node = field.index(*index).unwrap();
+                    *index += 1;
+                    if enter_node(node, depth, options, visitor, &mut stack) == Flow::Stop {
+                        return Flow::Stop;
+                    }
+                } else {
+                    stack.pop();
+                    if let Some(key) = key {
+                        if visitor.exit_field(key, depth) == Flow::Stop {
+                            return Flow::Stop;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Flow::Continue
+}
+
+/// Next step of a cursor traversal.
+enum Step<C: NodesCursor> {
+    EnterNode(C),
+    ExitNode(C),
+    EnterField(C::TFields),
+    ExitField(C::TFields),
+}
+
+/// Walks the subtree under the cursor's current node, and returns the cursor at the same node.
+/// The returned [Flow] is [Flow::Stop] if the visitor stopped the traversal, and [Flow::Continue] otherwise.
+///
+/// Pending nodes are visited, but their content is not.
+/// The cursor tracks the path to the current node, so this does not recurse.
+pub fn walk_cursor<C: NodesCursor>(
+    cursor: C,
+    options: &WalkOptions,
+    visitor: &mut impl Visitor<C>,
+) -> (C, Flow) {
+    // Depth of the current node, or of the current field's nodes.
+    let mut depth = 0;
+    let mut step = Step::EnterNode(cursor);
+    loop {
+        step = match step {
+            Step::EnterNode(c) => match visitor.enter_node(&c, depth) {
+                Flow::Stop => return (unwind(EitherCursor::Nodes(c), depth), Flow::Stop),
+                Flow::Continue if options.enter_fields(depth) && !c.pending() => {
+                    match c.first_field() {
+                        EitherCursor::Nodes(n) => Step::ExitNode(n),
+                        EitherCursor::Fields(f) => {
+                            depth += 1;
+                            Step::EnterField(f)
+                        }
+                    }
+                }
+                _ => Step::ExitNode(c),
+            },
+            Step::ExitNode(c) => {
+                if visitor.exit_node(&c, depth) == Flow::Stop {
+                    return (unwind(EitherCursor::Nodes(c), depth), Flow::Stop);
+                }
+                if depth == 0 {
+                    return (c, Flow::Continue);
+                }
+                match c.next_node().expect(BELOW_START) {
+                    EitherCursor::Nodes(n) => Step::EnterNode(n),
+                    EitherCursor::Fields(f) => Step::ExitField(f),
+                }
+            }
+            Step::EnterField(f) => match visitor.enter_field(&f.field_key(), depth) {
+                Flow::Stop => return (unwind(EitherCursor::Fields(f), depth), Flow::Stop),
+                Flow::Continue => match f.first_node() {
+                    EitherCursor::Nodes(n) => Step::EnterNode(n),
+                    EitherCursor::Fields(f) => Step::ExitField(f),
+                },
+                Flow::SkipSubtree => Step::ExitField(f),
+            },
+            Step::ExitField(f) => {
+                if visitor.exit_field(&f.field_key(), depth) == Flow::Stop {
+                    return (unwind(EitherCursor::Fields(f), depth), Flow::Stop);
+                }
+                match f.next_field() {
+                    EitherCursor::Fields(f) => Step::EnterField(f),
+                    EitherCursor::Nodes(n) => {
+                        depth -= 1;
+                        Step::ExitNode(n)
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Cursors below the node a traversal started at always have a parent to exit to.
+const BELOW_START: &str = "cursor is below the start node";
+
+/// Navigates from `depth` back up to the node a traversal started at.
+fn unwind<C: NodesCursor>(cursor: EitherCursor<C, C::TFields>, mut depth: usize) -> C {
+    let mut fields = match cursor {
+        EitherCursor::Nodes(n) if depth == 0 => return n,
+        EitherCursor::Nodes(n) => n.exit_node().expect(BELOW_START),
+        EitherCursor::Fields(f) => f,
+    };
+    loop {
+        let nodes = fields.exit_field().expect(BELOW_START);
+        depth -= 1;
+        if depth == 0 {
+            return nodes;
+        }
+        fields = nodes.exit_node().expect(BELOW_START);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cursor::GenericNodesCursor,
+        dummy_cursor::DummyNodes,
+        forest::{
+            example_node::{BasicNode, BasicTree},
+            test_stuff::big_tree,
+            tree::{NodeData, Tree},
+            uniform_chunk::UniformChunkNode,
+        },
+        TreeType,
+    };
+
+    /// Records hook calls as strings.
+    #[derive(Default)]
+    struct Log {
+        calls: Vec<String>,
+        /// Returned from enter_node at this depth.
+        at_depth: Option<(usize, Flow)>,
+    }
+
+    impl<N> Visitor<N> for Log {
+        fn enter_node(&mut self, _node: &N, depth: usize) -> Flow {
+            self.calls.push(format!("n{}", depth));
+            match self.at_depth {
+                Some((d, flow)) if d == depth => flow,
+                _ => Flow::Continue,
+            }
+        }
+
+        fn exit_node(&mut self, _node: &N, depth: usize) -> Flow {
+            self.calls.push(format!("/n{}", depth));
+            Flow::Continue
+        }
+
+        fn enter_field(&mut self, _key: &FieldKey, depth: usize) -> Flow {
+            self.calls.push(format!("f{}", depth));
+            Flow::Continue
+        }
+
+        fn exit_field(&mut self, _key: &FieldKey, depth: usize) -> Flow {
+            self.calls.push(format!("/f{}", depth));
+            Flow::Continue
+        }
+    }
+
+    #[test]
+    fn hooks_are_balanced() {
+        let mut log = Log::default();
+        let (cursor, flow) = walk_cursor(DummyNodes::new(2, 1), &WalkOptions::default(), &mut log);
+        assert_eq!(flow, Flow::Continue);
+        assert_eq!(cursor.field_index(), 0);
+        assert_eq!(log.calls.join(" "), "n0 f1 n1 /n1 n1 /n1 /f1 /n0");
+
+        let mut log = Log {
+            at_depth: Some((1, Flow::SkipSubtree)),
+            ..Default::default()
+        };
+        walk_cursor(DummyNodes::new(1, 2), &WalkOptions::default(), &mut log);
+        assert_eq!(log.calls.join(" "), "n0 f1 n1 /n1 /f1 /n0");
+
+        let mut log = Log::default();
+        let options = WalkOptions { max_depth: Some(0) };
+        walk_cursor(DummyNodes::new(1, 2), &options, &mut log);
+        assert_eq!(log.calls.join(" "), "n0 /n0");
+    }
+
+    #[test]
+    fn stop_early() {
+        let chunk = big_tree(10);
+        let mut count = 0;
+        let mut visitor = OrderedVisitor::new(Order::Pre, |_: &UniformChunkNode, _| {
+            count += 1;
+            if count == 7 {
+                Flow::Stop
+            } else {
+                Flow::Continue
+            }
+        });
+        let flow = walk_field(chunk.view(), &WalkOptions::default(), &mut visitor);
+        assert_eq!(flow, Flow::Stop);
+        assert_eq!(count, 7);
+
+        // The cursor is returned at the node it started at.
+        let cursor = GenericNodesCursor::<UniformChunkNode>::new(chunk.view());
+        let mut log = Log {
+            at_depth: Some((1, Flow::Stop)),
+            ..Default::default()
+        };
+        let (cursor, flow) = walk_cursor(cursor, &WalkOptions::default(), &mut log);
+        assert_eq!(flow, Flow::Stop);
+        assert_eq!(log.calls.join(" "), "n0 f1 n1");
+        assert!(matches!(cursor.next_node(), Ok(EitherCursor::Nodes(n)) if n.field_index() == 1));
+    }
+
+    #[test]
+    fn deep_trees() {
+        const DEPTH: usize = 1_000_000;
+        let mut node = BasicNode {
+            def: TreeType("leaf".into()),
+            payload: None,
+            fields: Default::default(),
+        };
+        for _ in 0..DEPTH {
+            node = BasicNode {
+                def: TreeType("link".into()),
+                payload: None,
+                fields: [(FieldKey("next".into()), vec![node])].into(),
+            };
+        }
+        let mut counter = NodeCounter::default();
+        walk(&node, &WalkOptions::default(), &mut counter);
+        assert_eq!(counter.count, DEPTH + 1);
+
+        let tree = BasicTree(vec![node]);
+        let mut counter = NodeCounter::default();
+        let cursor = GenericNodesCursor::<&BasicNode>::new(tree.view());
+        walk_cursor(cursor, &WalkOptions::default(), &mut counter);
+        assert_eq!(counter.count, DEPTH + 1);
+
+        // Stopping at the bottom navigates all the way back up.
+        let mut visitor = OrderedVisitor::new(Order::Pre, |_: &DummyNodes, depth| {
+            if depth == DEPTH {
+                Flow::Stop
+            } else {
+                Flow::Continue
+            }
+        });
+        let (cursor, flow) = walk_cursor(
+            DummyNodes::new(1, DEPTH as u32),
+            &WalkOptions::default(),
+            &mut visitor,
+        );
+        assert_eq!(flow, Flow::Stop);
+        assert_eq!(cursor.exit_node().unwrap().field_key(), FieldKey::root());
+    }
+
+    #[test]
+    fn post_order() {
+        let chunk = big_tree(2);
+        let mut types = vec![];
+        let mut visitor = OrderedVisitor::new(Order::Post, |n: &UniformChunkNode, depth| {
+            types.push((n.get_def(), depth));
+            Flow::Continue
+        });
+        walk(
+            chunk.view().index(0).unwrap(),
+            &WalkOptions::default(),
+            &mut visitor,
+        );
+        assert_eq!(types.len(), 5);
+        assert_eq!(types[4], (chunk.view().index(0).unwrap().get_def(), 0));
+        assert!(types[..4].iter().all(|(_, depth)| *depth == 1));
+    }
+}
@@ -1,86 +1,698 @@
-use std::{collections::HashMap, mem::replace, ops::DerefMut, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fmt,
+    mem::{replace, take},
+    rc::Rc,
+};
 
-use owning_ref::OwningHandle;
+use js_sys::{Array, Function, Object, Reflect, Uint8Array};
+use self_cell::self_cell;
 use wasm_bindgen::prelude::*;
 
 use crate::{
-    cursor::{GenericFieldsCursor, GenericNodesCursor},
+    build::{copy_nodes, read_stream, ChunkBuilder, TreeSink},
+    cursor::{CursorPosition, GenericFieldsCursor, GenericNodesCursor},
     forest::{
+        changeset::Changeset,
+        edit::EditError,
+        edit_cursor::{EditFields, EditNodes},
         example_node::{BasicNode, BasicTree},
-        test_stuff::{walk_all, walk_all_field},
-        tree::{self, Node, NodeNav, Tree},
+        mixed::{Chunk, Forest, MixedNodeRef},
+        observer::{Event, Scope, Subscription},
+        path::Path,
+        store::StoreError,
+        test_stuff::walk_all_field,
+        tree::{Indexable, Node, Tree},
         uniform_chunk::{ChunkSchema, OffsetSchema, UniformChunk, UniformChunkNode},
+        util::ImSlice,
     },
-    EitherCursor, FieldKey, FieldsCursor, NodesCursor, TreeType,
+    query::{ParseQueryError, Query},
+    visit::{walk_cursor, NodeCounter, WalkOptions},
+    CursorError, CursorResult, EitherCursor, FieldKey, FieldsCursor, NodesCursor, TreeType, Value,
 };
 
-type TTree = UniformChunk;
-const BUILD_TEST_TREE: fn(usize, usize) -> TTree = chunked_test_tree;
+/// Tree representations a [WasmTree] can use.
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Backend {
+    /// A [UniformChunk]: every node has the same shape.
+    Uniform,
+    /// A [BasicTree] of individually allocated nodes.
+    Basic,
+    /// A [Forest] of mixed chunks, whose detached fields are the root's fields.
+    Forest,
+}
+
+/// A tree in one of the [Backend] representations.
+#[derive(Clone)]
+enum TreeData {
+    Uniform(UniformChunk),
+    Basic(BasicTree),
+    Forest(Forest),
+}
+
+impl TreeData {
+    fn backend(&self) -> Backend {
+        match self {
+            TreeData::Uniform(_) => Backend::Uniform,
+            TreeData::Basic(_) => Backend::Basic,
+            TreeData::Forest(_) => Backend::Forest,
+        }
+    }
+
+    /// A cursor at the root, whose only field is [FieldKey::root] (the detached fields, for forests).
+    fn root_cursor(&self) -> Box<dyn DynCursor + '_> {
+        match self {
+            TreeData::Uniform(t) => Box::new(Cursor::Nodes(
+                GenericNodesCursor::<UniformChunkNode>::from_roots([(FieldKey::root(), t.view())]),
+            )),
+            TreeData::Basic(t) => Box::new(Cursor::Nodes(
+                GenericNodesCursor::<&BasicNode>::from_roots([(FieldKey::root(), t.view())]),
+            )),
+            TreeData::Forest(f) => Box::new(Cursor::Nodes(f.cursor())),
+        }
+    }
+
+    /// A cursor at the first node of the tree (of the first detached field, for forests).
+    fn first_cursor(&self) -> Box<dyn DynCursor + '_> {
+        let mut cursor = self.root_cursor();
+        // Detached fields of a root cursor are never empty.
+        if cursor.first_field() == Ok(true) {
+            cursor.first_node().unwrap();
+        }
+        cursor
+    }
+
+    /// A cursor at `position`, which must be in this tree.
+    fn cursor_at(&self, position: &CursorPosition) -> Box<dyn DynCursor + '_> {
+        let mut cursor = self.root_cursor();
+        cursor.restore(position);
+        cursor
+    }
+}
+
+/// Immutable tree which JS can create cursors over, in a representation chosen at runtime.
+///
+/// Any number of cursors can read the tree at once, each moving independently.
+/// The tree is reference counted: each cursor keeps it alive, so cursors stay valid after the `WasmTree` is freed,
+/// and its memory is released once it and all its cursors have been freed (with `free()`).
+#[wasm_bindgen]
+pub struct WasmTree {
+    tree: Rc<TreeData>,
+}
+
+#[wasm_bindgen]
+impl WasmTree {
+    /// Create a tree of test data in the `backend` representation:
+    /// a root with `fields` fields of `per_field` leaves each.
+    /// For [Backend::Forest], the tree is the forest's detached field "root".
+    /// Use [WasmTree::from_objects] or [WasmTreeBuilder] to create trees of other content.
+    #[wasm_bindgen(js_name = fromTestData)]
+    pub fn from_test_data(backend: Backend, fields: usize, per_field: usize) -> WasmTree {
+        let tree = match backend {
+            Backend::Uniform => TreeData::Uniform(chunked_test_tree(fields, per_field)),
+            Backend::Basic => TreeData::Basic(basic_test_tree(fields, per_field)),
+            Backend::Forest => {
+                let mut forest = Forest::new();
+                forest.set_root(
+                    FieldKey::root(),
+                    vec![Chunk::Uniform(Rc::new(chunked_test_tree(
+                        fields, per_field,
+                    )))],
+                );
+                TreeData::Forest(forest)
+            }
+        };
+        WasmTree {
+            tree: Rc::new(tree),
+        }
+    }
+
+    /// A snapshot of `forest`'s current content.
+    #[wasm_bindgen(js_name = fromForest)]
+    pub fn from_forest(forest: &WasmForest) -> WasmTree {
+        WasmTree {
+            tree: Rc::new(TreeData::Forest(forest.forest.borrow().clone())),
+        }
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn backend(&self) -> Backend {
+        self.tree.backend()
+    }
+
+    /// A new cursor over this tree, at its first node (of the first detached field, for forests).
+    pub fn cursor(&self) -> WasmCursor {
+        WasmCursor::new(self.tree.clone())
+    }
+
+    /// Create a tree from plain objects shaped like `{type: string, value?: number | Uint8Array, fields?: {[key]: node[]}}`.
+    /// `nodes` is a node object, or an array of them, which become the tree's detached field "root".
+    ///
+    /// The tree is compressed like [WasmTreeBuilder::finish] does.
+    #[wasm_bindgen(js_name = fromObjects)]
+    pub fn from_objects(nodes: JsValue) -> Result<WasmTree, JsValue> {
+        let mut builder = WasmTreeBuilder::default();
+        build_objects(nodes, &mut builder)?;
+        Ok(builder.finish()?)
+    }
+}
+
+/// Builds a tree from a stream of calls, like a [TreeSink]:
+/// each node's fields go between its `beginNode` and `endNode`, and each field's nodes between its `beginField` and `endField`.
+/// Nodes outside of any field make up the tree's detached field "root".
+///
+/// Calls in the wrong place throw a "BuildError", and are ignored.
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct WasmTreeBuilder {
+    builder: ChunkBuilder,
+    /// For each node being built, if one of its fields is being built.
+    open: Vec<bool>,
+}
+
+/// Why a [WasmTreeBuilder] call is not allowed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BuildError {
+    /// Nodes can only be in a node's field, or at the top level.
+    NodeOutsideField,
+    /// Fields can only be in a node.
+    FieldOutsideNode,
+    /// There is no node (or field) to end, or it has a field (or node) which must be ended first.
+    NothingToEnd,
+    /// Nodes have to be ended before finishing.
+    Unfinished,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BuildError::NodeOutsideField => "node must be in a field",
+            BuildError::FieldOutsideNode => "field must be in a node",
+            BuildError::NothingToEnd => "nothing to end",
+            BuildError::Unfinished => "unfinished node",
+        })
+    }
+}
+
+/// Thrown to JS as an `Error` named "BuildError".
+impl From<BuildError> for JsValue {
+    fn from(e: BuildError) -> Self {
+        let error = js_sys::Error::new(&e.to_string());
+        error.set_name("BuildError");
+        error.into()
+    }
+}
+
+#[wasm_bindgen]
+impl WasmTreeBuilder {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        WasmTreeBuilder::default()
+    }
+
+    /// Starts a node, whose value (if any) is stored as an 8 byte little endian f64.
+    #[wasm_bindgen(js_name = beginNode)]
+    pub fn begin_node(&mut self, node_type: String, value: Option<f64>) -> Result<(), BuildError> {
+        self.begin_node_with_payload(node_type, value.map(|v| v.to_le_bytes().to_vec()))
+    }
+
+    /// Starts a node with an arbitrary payload.
+    #[wasm_bindgen(js_name = beginNodeWithPayload)]
+    pub fn begin_node_with_payload(
+        &mut self,
+        node_type: String,
+        payload: Option<Vec<u8>>,
+    ) -> Result<(), BuildError> {
+        if self.open.last() == Some(&false) {
+            return Err(BuildError::NodeOutsideField);
+        }
+        self.builder
+            .start_node(TreeType(node_type), payload.as_deref());
+        self.open.push(false);
+        Ok(())
+    }
+
+    #[wasm_bindgen(js_name = endNode)]
+    pub fn end_node(&mut self) -> Result<(), BuildError> {
+        if self.open.last() != Some(&false) {
+            return Err(BuildError::NothingToEnd);
+        }
+        self.open.pop();
+        self.builder.end_node();
+        Ok(())
+    }
+
+    #[wasm_bindgen(js_name = beginField)]
+    pub fn begin_field(&mut self, key: String) -> Result<(), BuildError> {
+        match self.open.last_mut() {
+            Some(open @ false) => *open = true,
+            _ => return Err(BuildError::FieldOutsideNode),
+        }
+        self.builder.start_field(FieldKey(key));
+        Ok(())
+    }
+
+    #[wasm_bindgen(js_name = endField)]
+    pub fn end_field(&mut self) -> Result<(), BuildError> {
+        match self.open.last_mut() {
+            Some(open @ true) => *open = false,
+            _ => return Err(BuildError::NothingToEnd),
+        }
+        self.builder.end_field();
+        Ok(())
+    }
+
+    /// The tree built so far, leaving this builder empty.
+    ///
+    /// Runs of nodes with the same shape are stored together as uniform chunks,
+    /// so the tree is a [Backend::Forest].
+    pub fn finish(&mut self) -> Result<WasmTree, BuildError> {
+        if !self.open.is_empty() {
+            return Err(BuildError::Unfinished);
+        }
+        let mut forest = Forest::new();
+        forest.set_root(FieldKey::root(), take(&mut self.builder).finish());
+        Ok(WasmTree {
+            tree: Rc::new(TreeData::Forest(forest)),
+        })
+    }
+}
+
+/// Reports the nodes in `nodes` (a node object or array of them, see [WasmTree::from_objects]) to `builder`.
+///
+/// Uses a stack instead of recursing, so deep trees do not overflow the stack.
+fn build_objects(nodes: JsValue, builder: &mut WasmTreeBuilder) -> Result<(), JsValue> {
+    enum Item {
+        Node(JsValue),
+        Field(String, Vec<JsValue>),
+        EndNode,
+        EndField,
+    }
+    let get = |object: &JsValue, key: &str| Reflect::get(object, &JsValue::from_str(key));
+    // Pushed in reverse, so they are popped in order.
+    let mut stack: Vec<Item> = node_list(nodes).into_iter().rev().map(Item::Node).collect();
+    while let Some(item) = stack.pop() {
+        match item {
+            Item::Node(object) => {
+                let node_type = get(&object, "type")?
+                    .as_string()
+                    .ok_or_else(|| JsValue::from_str("node type must be a string"))?;
+                builder.begin_node_with_payload(node_type, payload(get(&object, "value")?)?)?;
+                stack.push(Item::EndNode);
+                let fields = get(&object, "fields")?;
+                if fields.is_undefined() || fields.is_null() {
+                    continue;
+                }
+                let fields: Object = fields
+                    .dyn_into()
+                    .map_err(|_| JsValue::from_str("node fields must be an object"))?;
+                for entry in Object::entries(&fields)
+                    .iter()
+                    .collect::<Vec<_>>()
+                    .into_iter()
+                    .rev()
+                {
+                    let entry: Array = entry.into();
+                    let key = entry.get(0).as_string().unwrap_or_default();
+                    stack.push(Item::Field(key, node_list(entry.get(1))));
+                }
+            }
+            Item::Field(key, nodes) => {
+                builder.begin_field(key)?;
+                stack.push(Item::EndField);
+                stack.extend(nodes.into_iter().rev().map(Item::Node));
+            }
+            Item::EndNode => builder.end_node()?,
+            Item::EndField => builder.end_field()?,
+        }
+    }
+    Ok(())
+}
 
-// type TTree = BasicTree;
-// const BUILD_TEST_TREE: fn(usize, usize) -> TTree = basic_test_tree;
+/// The items of `nodes` if it is an array, otherwise just `nodes`.
+fn node_list(nodes: JsValue) -> Vec<JsValue> {
+    if Array::is_array(&nodes) {
+        Array::from(&nodes).iter().collect()
+    } else {
+        vec![nodes]
+    }
+}
 
-type InnerNode<'a> = <TTree as tree::Tree>::TNode<'a>;
-type StaticNode = InnerNode<'static>;
-type Nodes<'a> = <InnerNode<'a> as NodeNav<'a>>::TField;
+/// Payload for a node object's `value`.
+fn payload(value: JsValue) -> Result<Option<Vec<u8>>, JsValue> {
+    if value.is_undefined() || value.is_null() {
+        Ok(None)
+    } else if let Some(v) = value.as_f64() {
+        Ok(Some(v.to_le_bytes().to_vec()))
+    } else if let Some(bytes) = value.dyn_ref::<Uint8Array>() {
+        Ok(Some(bytes.to_vec()))
+    } else {
+        Err(JsValue::from_str(
+            "node value must be a number or Uint8Array",
+        ))
+    }
+}
 
+/// Owns (a reference to) its tree along with a cursor borrowing it,
+/// so the cursor persists between operations.
 #[wasm_bindgen]
 pub struct WasmCursor {
-    data: Handle<StaticNode>,
+    live: LiveCursor,
 }
 
-struct CursorWrap<'a, T: Node<'a>>(Cursor<'a, T>);
+type BoxedCursor<'a> = Box<dyn DynCursor + 'a>;
+
+self_cell!(
+    /// A cursor which stays at its position between JS calls, with the tree it borrows from.
+    /// Trees are immutable, so the cursor never needs to re-navigate from the root.
+    struct LiveCursor {
+        owner: Rc<TreeData>,
+
+        #[not_covariant]
+        dependent: BoxedCursor,
+    }
+);
+
+impl Clone for WasmCursor {
+    fn clone(&self) -> Self {
+        let position = self.with_cursor(|c| c.position());
+        WasmCursor {
+            live: LiveCursor::new(self.tree_data().clone(), |tree| tree.cursor_at(&position)),
+        }
+    }
+}
 
+/// A cursor in either mode. Only `Empty` while moving between them.
 enum Cursor<'a, T: Node<'a>> {
     Nodes(GenericNodesCursor<'a, T>),
     Fields(GenericFieldsCursor<'a, T>),
     Empty,
 }
 
-type Handle<T> = OwningHandle<Box<TTree>, CursorWrap<'static, T>>;
+impl WasmCursor {
+    fn new(tree: Rc<TreeData>) -> Self {
+        WasmCursor {
+            live: LiveCursor::new(tree, |tree| tree.first_cursor()),
+        }
+    }
 
-fn owning_handle(v: TTree) -> Handle<StaticNode> {
-    let cell_ref = Box::new(v);
-    let handle = OwningHandle::new_with_fn(cell_ref, |x| {
-        let x = unsafe { x.as_ref() }.unwrap();
-        let y: Nodes = x.view();
-        let root = GenericNodesCursor::new(y);
-        let cursor = CursorWrap(Cursor::Nodes(root));
-        cursor
-    });
-    handle
-}
+    fn tree_data(&self) -> &Rc<TreeData> {
+        self.live.borrow_owner()
+    }
 
-// Not sure why OwningHandle requires this.
-impl<'a, T: Node<'a>> core::ops::Deref for CursorWrap<'a, T> {
-    type Target = Cursor<'a, T>;
+    /// Runs `f` on the cursor.
+    fn with_cursor<R>(&self, f: impl FnOnce(&dyn DynCursor) -> R) -> R {
+        self.live.with_dependent(|_, cursor| f(&**cursor))
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+    /// Runs `f` on the cursor, which stays where `f` leaves it.
+    fn move_cursor<R>(&mut self, f: impl FnOnce(&mut dyn DynCursor) -> R) -> R {
+        self.live.with_dependent_mut(|_, cursor| f(&mut **cursor))
     }
 }
 
-// Not sure why OwningHandle requires this.
-impl<'a, T: Node<'a>> core::ops::DerefMut for CursorWrap<'a, T> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+/// The operations of [WasmCursor], for a [Cursor] over any node type.
+/// Operations fail (leaving the cursor where it was) if not allowed in the cursor's current mode.
+trait DynCursor {
+    fn is_node(&self) -> bool;
+    fn position(&self) -> CursorPosition;
+    /// Moves a cursor at the root to `position`, which must be in its tree.
+    fn restore(&mut self, position: &CursorPosition);
+    fn pending(&self) -> bool;
+    fn pending_error(&self) -> Option<StoreError>;
+    fn is_root(&self) -> Result<bool, CursorError>;
+    fn path(&self) -> Result<Path, CursorError>;
+    fn field_index(&self) -> Result<u32, CursorError>;
+    fn chunk_start(&self) -> Result<u32, CursorError>;
+    fn chunk_length(&self) -> Result<u32, CursorError>;
+    fn value(&self) -> Result<Option<f64>, CursorError>;
+    fn node_type(&self) -> Result<TreeType, CursorError>;
+    fn field_length(&self) -> Result<u32, CursorError>;
+
+    /// Returns true if the cursor is still at a node.
+    fn seek_nodes(&mut self, offset: i32) -> Result<bool, CursorError>;
+    /// Returns true if the cursor is still at a node.
+    fn next_node(&mut self) -> Result<bool, CursorError>;
+    fn exit_node(&mut self) -> Result<(), CursorError>;
+    /// Returns true if the cursor moved to a field.
+    fn first_field(&mut self) -> Result<bool, CursorError>;
+    /// Returns true if the cursor moved to a field.
+    fn enter_field(&mut self, key: FieldKey) -> Result<bool, CursorError>;
+    /// Returns true if the cursor is still at a field.
+    fn next_field(&mut self) -> Result<bool, CursorError>;
+    fn exit_field(&mut self) -> Result<(), CursorError>;
+    /// Returns true if the cursor is still at a field.
+    fn skip_pending_fields(&mut self) -> Result<bool, CursorError>;
+    /// Returns true if the cursor moved to a node.
+    fn first_node(&mut self) -> Result<bool, CursorError>;
+    fn enter_node(&mut self, child_index: u32) -> Result<(), CursorError>;
+
+    /// Calls `f` with the payload of each node in the current field (`Fields` mode),
+    /// or with `key`, of each node in field `key` of each node in the current chunk (`Nodes` mode).
+    fn for_each_payload(
+        &self,
+        key: Option<&FieldKey>,
+        f: &mut dyn FnMut(Option<ImSlice>),
+    ) -> Result<(), CursorError>;
+
+    /// Number of nodes in the subtree under the current node (limited by `options`), counted with [walk_cursor].
+    fn count_nodes(&mut self, options: &WalkOptions) -> Result<usize, CursorError>;
+    /// Paths (relative to the current node) to the nodes `query` selects.
+    fn query(&mut self, query: &Query) -> Result<Vec<Path>, CursorError>;
+}
+
+impl<'a, T: Node<'a>> Cursor<'a, T> {
+    fn nodes(&self) -> Result<&GenericNodesCursor<'a, T>, CursorError> {
+        match self {
+            Cursor::Nodes(n) => Ok(n),
+            _ => Err(CursorError::WrongMode),
+        }
+    }
+
+    fn fields(&self) -> Result<&GenericFieldsCursor<'a, T>, CursorError> {
+        match self {
+            Cursor::Fields(f) => Ok(f),
+            _ => Err(CursorError::WrongMode),
+        }
+    }
+
+    /// Takes the cursor in `Nodes` mode, to move it and put it back.
+    fn take_nodes(&mut self) -> Result<GenericNodesCursor<'a, T>, CursorError> {
+        self.nodes()?;
+        match replace(self, Cursor::Empty) {
+            Cursor::Nodes(n) => Ok(n),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Takes the cursor in `Fields` mode, to move it and put it back.
+    fn take_fields(&mut self) -> Result<GenericFieldsCursor<'a, T>, CursorError> {
+        self.fields()?;
+        match replace(self, Cursor::Empty) {
+            Cursor::Fields(f) => Ok(f),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Puts a taken cursor back where it was if `result` is a failure.
+    fn restore_nodes<R>(
+        &mut self,
+        result: CursorResult<R, GenericNodesCursor<'a, T>>,
+    ) -> Result<R, CursorError> {
+        result.map_err(|failure| {
+            *self = Cursor::Nodes(failure.cursor);
+            failure.error
+        })
+    }
+
+    /// Puts a taken cursor back where it was if `result` is a failure.
+    fn restore_fields<R>(
+        &mut self,
+        result: CursorResult<R, GenericFieldsCursor<'a, T>>,
+    ) -> Result<R, CursorError> {
+        result.map_err(|failure| {
+            *self = Cursor::Fields(failure.cursor);
+            failure.error
+        })
+    }
+
+    /// Moves to `cursor`, and returns true if it is at a node.
+    fn set_either(
+        &mut self,
+        cursor: EitherCursor<GenericNodesCursor<'a, T>, GenericFieldsCursor<'a, T>>,
+    ) -> bool {
+        match cursor {
+            EitherCursor::Nodes(n) => {
+                *self = Cursor::Nodes(n);
+                true
+            }
+            EitherCursor::Fields(f) => {
+                *self = Cursor::Fields(f);
+                false
+            }
+        }
     }
 }
 
-impl WasmCursor {
-    fn cursor_mut(&mut self) -> &mut Cursor<'static, StaticNode> {
-        self.data.deref_mut()
+impl<'a, T: Node<'a>> DynCursor for Cursor<'a, T> {
+    fn is_node(&self) -> bool {
+        matches!(self, Cursor::Nodes(_))
     }
 
-    fn cursor(&self) -> &Cursor<'static, StaticNode> {
-        &self.data
+    fn position(&self) -> CursorPosition {
+        match self {
+            Cursor::Nodes(n) => n.position(),
+            Cursor::Fields(f) => f.position(),
+            Cursor::Empty => unreachable!("cursor is only empty while moving"),
+        }
     }
 
-    fn new(v: TTree) -> Self {
-        WasmCursor {
-            data: owning_handle(v),
+    fn restore(&mut self, position: &CursorPosition) {
+        let n = self.take_nodes().expect("restoring a cursor at the root");
+        let restored = n
+            .restore(position)
+            .expect("position is in the cursor's tree");
+        self.set_either(restored);
+    }
+
+    fn pending(&self) -> bool {
+        match self {
+            Cursor::Nodes(n) => n.pending(),
+            _ => false,
+        }
+    }
+
+    fn pending_error(&self) -> Option<StoreError> {
+        match self {
+            Cursor::Nodes(n) => n.pending_error(),
+            _ => None,
+        }
+    }
+
+    fn is_root(&self) -> Result<bool, CursorError> {
+        Ok(self.nodes()?.is_root())
+    }
+
+    fn path(&self) -> Result<Path, CursorError> {
+        Ok(self.nodes()?.path())
+    }
+
+    fn field_index(&self) -> Result<u32, CursorError> {
+        Ok(self.nodes()?.field_index())
+    }
+
+    fn chunk_start(&self) -> Result<u32, CursorError> {
+        Ok(self.nodes()?.chunk_start())
+    }
+
+    fn chunk_length(&self) -> Result<u32, CursorError> {
+        Ok(self.nodes()?.chunk_length())
+    }
+
+    fn value(&self) -> Result<Option<f64>, CursorError> {
+        Ok(self.nodes()?.value().0)
+    }
+
+    fn node_type(&self) -> Result<TreeType, CursorError> {
+        Ok(self.nodes()?.node_type())
+    }
+
+    fn field_length(&self) -> Result<u32, CursorError> {
+        Ok(self.fields()?.get_field_length())
+    }
+
+    fn seek_nodes(&mut self, offset: i32) -> Result<bool, CursorError> {
+        let n = self.take_nodes()?;
+        let moved = self.restore_nodes(n.seek_nodes(offset))?;
+        Ok(self.set_either(moved))
+    }
+
+    fn next_node(&mut self) -> Result<bool, CursorError> {
+        let n = self.take_nodes()?;
+        let moved = self.restore_nodes(n.next_node())?;
+        Ok(self.set_either(moved))
+    }
+
+    fn exit_node(&mut self) -> Result<(), CursorError> {
+        let n = self.take_nodes()?;
+        *self = Cursor::Fields(self.restore_nodes(n.exit_node())?);
+        Ok(())
+    }
+
+    fn first_field(&mut self) -> Result<bool, CursorError> {
+        if self.nodes()?.is_leaf() {
+            return Ok(false);
+        }
+        let n = self.take_nodes()?;
+        Ok(!self.set_either(n.first_field()))
+    }
+
+    fn enter_field(&mut self, key: FieldKey) -> Result<bool, CursorError> {
+        let n = self.take_nodes()?;
+        Ok(!self.set_either(n.enter_field(key)))
+    }
+
+    fn next_field(&mut self) -> Result<bool, CursorError> {
+        let f = self.take_fields()?;
+        Ok(!self.set_either(f.next_field()))
+    }
+
+    fn exit_field(&mut self) -> Result<(), CursorError> {
+        let f = self.take_fields()?;
+        *self = Cursor::Nodes(self.restore_fields(f.exit_field())?);
+        Ok(())
+    }
+
+    fn skip_pending_fields(&mut self) -> Result<bool, CursorError> {
+        let f = self.take_fields()?;
+        Ok(!self.set_either(f.skip_pending_fields()))
+    }
+
+    fn first_node(&mut self) -> Result<bool, CursorError> {
+        let f = self.take_fields()?;
+        Ok(self.set_either(f.first_node()))
+    }
+
+    fn enter_node(&mut self, child_index: u32) -> Result<(), CursorError> {
+        let f = self.take_fields()?;
+        *self = Cursor::Nodes(self.restore_fields(f.enter_node(child_index))?);
+        Ok(())
+    }
+
+    fn for_each_payload(
+        &self,
+        key: Option<&FieldKey>,
+        mut f: &mut dyn FnMut(Option<ImSlice>),
+    ) -> Result<(), CursorError> {
+        match key {
+            Some(key) => self.nodes()?.for_each_field_payload(key, &mut f),
+            None => self.fields()?.for_each_payload(&mut f),
         }
     }
+
+    fn count_nodes(&mut self, options: &WalkOptions) -> Result<usize, CursorError> {
+        let mut counter = NodeCounter::default();
+        let (cursor, _) = walk_cursor(self.take_nodes()?, options, &mut counter);
+        *self = Cursor::Nodes(cursor);
+        Ok(counter.count)
+    }
+
+    fn query(&mut self, query: &Query) -> Result<Vec<Path>, CursorError> {
+        let (cursor, paths) = query.evaluate(self.take_nodes()?);
+        *self = Cursor::Nodes(cursor);
+        Ok(paths)
+    }
+}
+
+/// Thrown to JS as an `Error` named "CursorError", so misuse of a cursor can be caught.
+impl From<CursorError> for JsValue {
+    fn from(e: CursorError) -> Self {
+        let error = js_sys::Error::new(&e.to_string());
+        error.set_name("CursorError");
+        error.into()
+    }
 }
 
 fn basic_test_tree(fields: usize, per_field: usize) -> BasicTree {
@@ -88,7 +700,7 @@ fn basic_test_tree(fields: usize, per_field: usize) -> BasicTree {
         let tree: BasicNode = BasicNode {
             def: TreeType("".into()),
             payload: None,
-            fields: HashMap::default(),
+            fields: BTreeMap::default(),
         };
         tree
     }
@@ -125,295 +737,244 @@ fn chunked_test_tree(fields: usize, per_field: usize) -> UniformChunk {
     UniformChunk::new(Rc::new(root), data)
 }
 
+/// Cursor over a tree.
+///
+/// Keeps its tree alive (see [WasmTree]) until it is freed.
+/// Methods throw a "CursorError" if used in the wrong mode or navigating out of the tree,
+/// and leave the cursor where it was.
 #[wasm_bindgen]
 impl WasmCursor {
-    /// Create a new tree of test data and a cursor over it.
-    /// TODO: Public API for creating trees.
+    /// Create a new tree of test data (as a [UniformChunk]) and a cursor over it.
+    /// Use [WasmTree] for other representations and content.
     #[wasm_bindgen(constructor)]
     pub fn new_from_test_data(fields: usize, per_field: usize) -> Self {
-        WasmCursor::new(BUILD_TEST_TREE(fields, per_field))
+        WasmTree::from_test_data(Backend::Uniform, fields, per_field).cursor()
+    }
+
+    /// Representation of the tree this cursor is over.
+    #[wasm_bindgen(getter)]
+    pub fn backend(&self) -> Backend {
+        self.tree_data().backend()
+    }
+
+    /// The tree this cursor is over, shared with it.
+    #[wasm_bindgen(getter)]
+    pub fn tree(&self) -> WasmTree {
+        WasmTree {
+            tree: self.tree_data().clone(),
+        }
+    }
+
+    /// A new cursor over the same tree, at the same node or field.
+    /// The two move independently.
+    #[wasm_bindgen(js_name = clone)]
+    pub fn clone_cursor(&self) -> WasmCursor {
+        self.clone()
     }
 
     #[wasm_bindgen(getter)]
     pub fn mode(&self) -> i32 {
-        match self.cursor() {
-            Cursor::Nodes(_) => 0,
-            Cursor::Fields(_) => 1,
-            Cursor::Empty => panic!(),
+        if self.with_cursor(|c| c.is_node()) {
+            0
+        } else {
+            1
         }
     }
 
     #[wasm_bindgen(getter)]
     pub fn pending(&self) -> bool {
-        // TODO
-        false
+        self.with_cursor(|c| c.pending())
+    }
+
+    /// If the current node is `pending` because loading it failed, a description of the error.
+    #[wasm_bindgen(getter, js_name = pendingError)]
+    pub fn pending_error(&self) -> Option<String> {
+        self.with_cursor(|c| c.pending_error())
+            .map(|e| e.to_string())
+    }
+
+    /// True at the root, whose fields are the tree's detached fields ("root" for trees which are not forests).
+    #[wasm_bindgen(getter, js_name = isRoot)]
+    pub fn is_root(&self) -> Result<bool, CursorError> {
+        self.with_cursor(|c| c.is_root())
+    }
+
+    /// Path from the root to the current node, formatted like `root[0]/child[2]`.
+    #[wasm_bindgen(getter)]
+    pub fn path(&self) -> Result<String, CursorError> {
+        Ok(self.with_cursor(|c| c.path())?.to_string())
     }
 
     #[wasm_bindgen(getter, js_name = fieldIndex)]
-    pub fn field_index(&self) -> u32 {
-        match &self.cursor() {
-            Cursor::Nodes(n) => n.field_index(),
-            _ => panic!(),
-        }
+    pub fn field_index(&self) -> Result<u32, CursorError> {
+        self.with_cursor(|c| c.field_index())
     }
 
     #[wasm_bindgen(getter, js_name = chunkStart)]
-    pub fn chunk_start(&self) -> u32 {
-        match &self.cursor() {
-            Cursor::Nodes(n) => n.chunk_start(),
-            _ => panic!(),
-        }
+    pub fn chunk_start(&self) -> Result<u32, CursorError> {
+        self.with_cursor(|c| c.chunk_start())
     }
 
     #[wasm_bindgen(getter, js_name = chunkLength)]
-    pub fn chunk_length(&self) -> u32 {
-        match &self.cursor() {
-            Cursor::Nodes(n) => n.chunk_length(),
-            _ => panic!(),
-        }
+    pub fn chunk_length(&self) -> Result<u32, CursorError> {
+        self.with_cursor(|c| c.chunk_length())
     }
 
     #[wasm_bindgen(js_name = seekNodes)]
-    pub fn seek_nodes(&mut self, offset: i32) -> bool {
-        let cursor = self.cursor_mut();
-        let old = replace(cursor, Cursor::Empty);
-        match old {
-            Cursor::Nodes(n) => match n.seek_nodes(offset) {
-                EitherCursor::Nodes(n) => {
-                    *cursor = Cursor::Nodes(n);
-                    true
-                }
-                EitherCursor::Fields(f) => {
-                    *cursor = Cursor::Fields(f);
-                    false
-                }
-            },
-            _ => panic!(),
-        }
+    pub fn seek_nodes(&mut self, offset: i32) -> Result<bool, CursorError> {
+        self.move_cursor(|c| c.seek_nodes(offset))
     }
 
     #[wasm_bindgen(js_name = nextNode)]
-    pub fn next_node(&mut self) -> bool {
-        let cursor = self.cursor_mut();
-        let old = replace(cursor, Cursor::Empty);
-        match old {
-            Cursor::Nodes(n) => match n.next_node() {
-                EitherCursor::Nodes(n) => {
-                    *cursor = Cursor::Nodes(n);
-                    true
-                }
-                EitherCursor::Fields(f) => {
-                    *cursor = Cursor::Fields(f);
-                    false
-                }
-            },
-            _ => panic!(),
-        }
+    pub fn next_node(&mut self) -> Result<bool, CursorError> {
+        self.move_cursor(|c| c.next_node())
     }
 
     #[wasm_bindgen(js_name = exitNode)]
-    pub fn exit_node(&mut self) {
-        let cursor = self.cursor_mut();
-        let old = replace(cursor, Cursor::Empty);
-        match old {
-            Cursor::Nodes(n) => {
-                *cursor = Cursor::Fields(n.exit_node());
-            }
-            _ => panic!(),
-        }
+    pub fn exit_node(&mut self) -> Result<(), CursorError> {
+        self.move_cursor(|c| c.exit_node())
     }
 
     #[wasm_bindgen(getter)]
-    pub fn value(&self) -> Option<f64> {
-        match &self.cursor() {
-            Cursor::Nodes(n) => n.value().0,
-            _ => panic!(),
-        }
+    pub fn value(&self) -> Result<Option<f64>, CursorError> {
+        self.with_cursor(|c| c.value())
     }
 
     #[wasm_bindgen(js_name = firstField)]
-    pub fn first_field(&mut self) -> bool {
-        let cursor = self.cursor_mut();
-        match cursor {
-            Cursor::Nodes(n) => {
-                if n.is_leaf() {
-                    return false;
-                }
-            }
-            _ => panic!(),
-        }
-        let old = replace(cursor, Cursor::Empty);
-        match old {
-            Cursor::Nodes(n) => match n.first_field() {
-                EitherCursor::Nodes(n) => {
-                    *cursor = Cursor::Nodes(n);
-                    false
-                }
-                EitherCursor::Fields(f) => {
-                    *cursor = Cursor::Fields(f);
-                    true
-                }
-            },
-            _ => panic!(),
-        }
+    pub fn first_field(&mut self) -> Result<bool, CursorError> {
+        self.move_cursor(|c| c.first_field())
     }
 
     #[wasm_bindgen(js_name = enterField)]
-    pub fn enter_field(&mut self, key: String) -> bool {
-        let cursor = self.cursor_mut();
-        let old = replace(cursor, Cursor::Empty);
-        match old {
-            Cursor::Nodes(n) => match n.enter_field(FieldKey(key)) {
-                EitherCursor::Nodes(n) => {
-                    *cursor = Cursor::Nodes(n);
-                    false
-                }
-                EitherCursor::Fields(f) => {
-                    *cursor = Cursor::Fields(f);
-                    true
-                }
-            },
-            _ => panic!(),
-        }
+    pub fn enter_field(&mut self, key: String) -> Result<bool, CursorError> {
+        self.move_cursor(|c| c.enter_field(FieldKey(key)))
     }
 
     #[wasm_bindgen(getter, js_name = type)]
-    pub fn node_type(&self) -> String {
-        match &self.cursor() {
-            Cursor::Nodes(n) => n.node_type().0,
-            _ => panic!(),
-        }
+    pub fn node_type(&self) -> Result<String, CursorError> {
+        Ok(self.with_cursor(|c| c.node_type())?.0)
     }
 
     // ///////////////////////////
 
     #[wasm_bindgen(js_name = nextField)]
-    pub fn next_field(&mut self) -> bool {
-        let cursor = self.cursor_mut();
-        let old = replace(cursor, Cursor::Empty);
-        match old {
-            Cursor::Fields(f) => match f.next_field() {
-                EitherCursor::Nodes(n) => {
-                    *cursor = Cursor::Nodes(n);
-                    false
-                }
-                EitherCursor::Fields(f) => {
-                    *cursor = Cursor::Fields(f);
-                    true
-                }
-            },
-            _ => panic!(),
-        }
+    pub fn next_field(&mut self) -> Result<bool, CursorError> {
+        self.move_cursor(|c| c.next_field())
     }
 
     #[wasm_bindgen(js_name = exitField)]
-    pub fn exit_field(&mut self) {
-        let cursor = self.cursor_mut();
-        let old = replace(cursor, Cursor::Empty);
-        match old {
-            Cursor::Fields(f) => {
-                *cursor = Cursor::Nodes(f.exit_field());
-            }
-            _ => panic!(),
-        }
+    pub fn exit_field(&mut self) -> Result<(), CursorError> {
+        self.move_cursor(|c| c.exit_field())
     }
 
     #[wasm_bindgen(js_name = skipPendingFields)]
-    pub fn skip_pending_fields(&mut self) -> bool {
-        let cursor = self.cursor_mut();
-        let old = replace(cursor, Cursor::Empty);
-        match old {
-            Cursor::Fields(f) => match f.skip_pending_fields() {
-                EitherCursor::Nodes(n) => {
-                    *cursor = Cursor::Nodes(n);
-                    false
-                }
-                EitherCursor::Fields(f) => {
-                    *cursor = Cursor::Fields(f);
-                    true
-                }
-            },
-            _ => panic!(),
-        }
+    pub fn skip_pending_fields(&mut self) -> Result<bool, CursorError> {
+        self.move_cursor(|c| c.skip_pending_fields())
     }
 
     #[wasm_bindgen(js_name = getFieldLength)]
-    pub fn get_field_length(&self) -> u32 {
-        let cursor = self.cursor();
-        match cursor {
-            Cursor::Fields(f) => f.get_field_length(),
-            _ => panic!(),
-        }
+    pub fn get_field_length(&self) -> Result<u32, CursorError> {
+        self.with_cursor(|c| c.field_length())
     }
 
     #[wasm_bindgen(js_name = firstNode)]
-    pub fn first_node(&mut self) -> bool {
-        let cursor = self.cursor_mut();
-        let old = replace(cursor, Cursor::Empty);
-        match old {
-            Cursor::Fields(f) => match f.first_node() {
-                EitherCursor::Nodes(n) => {
-                    *cursor = Cursor::Nodes(n);
-                    true
-                }
-                EitherCursor::Fields(f) => {
-                    *cursor = Cursor::Fields(f);
-                    false
-                }
-            },
-            _ => panic!(),
-        }
+    pub fn first_node(&mut self) -> Result<bool, CursorError> {
+        self.move_cursor(|c| c.first_node())
     }
 
     #[wasm_bindgen(js_name = enterNode)]
-    pub fn enter_node(&mut self, child_index: u32) {
-        let cursor = self.cursor_mut();
-        let old = replace(cursor, Cursor::Empty);
-        match old {
-            Cursor::Fields(f) => {
-                *cursor = Cursor::Nodes(f.enter_node(child_index));
-            }
-            _ => panic!(),
+    pub fn enter_node(&mut self, child_index: u32) -> Result<(), CursorError> {
+        self.move_cursor(|c| c.enter_node(child_index))
+    }
+
+    // ********** Bulk reads ********** //
+    // Uniform chunks are read with their fixed stride, so these are much faster than reading node by node.
+
+    /// Values of the nodes in the current field, as a `Float64Array`, with NaN for nodes without a value.
+    /// Throws a "CursorError" if any of the nodes are pending.
+    ///
+    /// Only allowed in `Fields` mode.
+    #[wasm_bindgen(js_name = readValues)]
+    pub fn read_values(&self) -> Result<Vec<f64>, CursorError> {
+        self.values(None)
+    }
+
+    /// Payloads of the nodes in the current field, concatenated into a `Uint8Array`.
+    /// The payloads must all be the same size (with nodes without a payload counting as empty),
+    /// so the data can be split evenly between the nodes.
+    /// Throws a "CursorError" if they are not (`MixedPayloadSizes`), or if any of the nodes are pending (`Pending`).
+    ///
+    /// Only allowed in `Fields` mode.
+    #[wasm_bindgen(js_name = readPayloads)]
+    pub fn read_payloads(&self) -> Result<Vec<u8>, CursorError> {
+        self.payloads(None)
+    }
+
+    /// Like [WasmCursor::read_values], for the field `key` of every node in the current chunk
+    /// (from `chunkStart`, for `chunkLength` nodes), one after the other.
+    ///
+    /// Only allowed in `Nodes` mode.
+    #[wasm_bindgen(js_name = readFieldValues)]
+    pub fn read_field_values(&self, key: String) -> Result<Vec<f64>, CursorError> {
+        self.values(Some(&FieldKey(key)))
+    }
+
+    /// Like [WasmCursor::read_payloads], for the field `key` of every node in the current chunk
+    /// (from `chunkStart`, for `chunkLength` nodes), one after the other.
+    ///
+    /// Only allowed in `Nodes` mode.
+    #[wasm_bindgen(js_name = readFieldPayloads)]
+    pub fn read_field_payloads(&self, key: String) -> Result<Vec<u8>, CursorError> {
+        self.payloads(Some(&FieldKey(key)))
+    }
+}
+
+impl WasmCursor {
+    fn values(&self, key: Option<&FieldKey>) -> Result<Vec<f64>, CursorError> {
+        let mut values = vec![];
+        self.with_cursor(|c| {
+            c.for_each_payload(key, &mut |p| {
+                values.push(Value::of(p).0.unwrap_or(f64::NAN))
+            })
+        })?;
+        Ok(values)
+    }
+
+    fn payloads(&self, key: Option<&FieldKey>) -> Result<Vec<u8>, CursorError> {
+        let mut payloads = vec![];
+        let mut size = None;
+        let mut mixed_sizes = false;
+        self.with_cursor(|c| {
+            c.for_each_payload(key, &mut |p| {
+                let p = p.unwrap_or_default();
+                mixed_sizes |= *size.get_or_insert(p.len()) != p.len();
+                payloads.extend_from_slice(p)
+            })
+        })?;
+        if mixed_sizes {
+            return Err(CursorError::MixedPayloadSizes);
         }
+        Ok(payloads)
     }
 }
 
-/// Walks the subtree under the cursor's current node.
+/// Walks the subtree under the cursor's current node, leaving the cursor there.
 ///
 /// Returns the number of nodes in the subtree, including its root.
 #[wasm_bindgen(js_name = walkSubtree)]
-pub fn walk_subtree(n: &mut WasmCursor) -> usize {
-    let mut count = 1;
-    let mut in_fields = n.first_field();
-    while in_fields {
-        let mut in_nodes = n.first_node();
-        while in_nodes {
-            count += walk_subtree(n);
-            in_nodes = n.next_node();
-        }
-        in_fields = n.next_field();
-    }
-    count
+pub fn walk_subtree(n: &mut WasmCursor) -> Result<usize, CursorError> {
+    n.move_cursor(|c| c.count_nodes(&WalkOptions::default()))
 }
 
-/// Walks the subtree under the cursor's current node.
+/// Walks the subtree under the cursor's current node, leaving the cursor there.
 ///
-/// Returns the number of nodes in the subtree, including its root.
+/// Returns the number of nodes in the subtree down to `depth` levels below its root, including the root.
 #[wasm_bindgen(js_name = walkSubtreeDepth)]
-pub fn walk_subtree_depth(n: &mut WasmCursor, depth: usize) -> usize {
-    let mut count = 1;
-    if depth > 0 {
-        let mut in_fields = n.first_field();
-        while in_fields {
-            let mut in_nodes = n.first_node();
-            while in_nodes {
-                count += walk_subtree_depth(n, depth - 1);
-                in_nodes = n.next_node();
-            }
-            in_fields = n.next_field();
-        }
-    }
-    count
+pub fn walk_subtree_depth(n: &mut WasmCursor, depth: usize) -> Result<usize, CursorError> {
+    let options = WalkOptions {
+        max_depth: Some(depth),
+    };
+    n.move_cursor(|c| c.count_nodes(&options))
 }
 
 /// Walks the subtree under the cursor's current node.
@@ -424,74 +985,987 @@ pub fn walk_subtree_depth(n: &mut WasmCursor, depth: usize) -> usize {
 ///
 /// Returns the number of nodes in the subtree, including its root.
 #[wasm_bindgen(js_name = walkSubtreeInternal)]
-pub fn walk_subtree_internal(n: &mut WasmCursor) -> usize {
-    let cursor = n.cursor_mut();
-    let old = replace(cursor, Cursor::Empty);
-    let cursor_inner = match old {
-        Cursor::Nodes(c) => c,
-        _ => panic!(),
+pub fn walk_subtree_internal(n: &mut WasmCursor) -> Result<usize, CursorError> {
+    n.move_cursor(|c| c.count_nodes(&WalkOptions::default()))
+}
+
+/// Finds the nodes selected by `query` (see [crate::query]) under the cursor's current node.
+///
+/// Returns an array of paths relative to the current node, formatted like `shapes[0]/color[0]`.
+/// Pending nodes are not selected, and nodes under them are not searched.
+#[wasm_bindgen]
+pub fn query(n: &mut WasmCursor, query: &str) -> Result<Array, JsValue> {
+    let query: Query = query
+        .parse()
+        .map_err(|e: ParseQueryError| JsValue::from_str(&e.0))?;
+    let paths = query_paths(n, &query)?;
+    Ok(paths
+        .iter()
+        .map(|p| JsValue::from_str(&p.to_string()))
+        .collect())
+}
+
+fn query_paths(n: &mut WasmCursor, query: &Query) -> Result<Vec<Path>, CursorError> {
+    n.move_cursor(|c| c.query(query))
+}
+
+/// Walks the tree this cursor is attached to.
+/// Uses even lower level API.
+///
+/// Returns the number of nodes in the tree (all detached fields of forests), including its root.
+#[wasm_bindgen(js_name = walkSubtreeInternal2)]
+pub fn walk_subtree_internal2(n: &mut WasmCursor) -> usize {
+    match &**n.tree_data() {
+        TreeData::Uniform(t) => walk_all_field::<UniformChunkNode>(t.view()),
+        TreeData::Basic(t) => walk_all_field::<&BasicNode>(t.view()),
+        TreeData::Forest(f) => f
+            .roots()
+            .map(|(_, field)| walk_all_field::<MixedNodeRef>(field))
+            .sum(),
+    }
+}
+
+/// Editable forest, which JS can subscribe to changes of.
+///
+/// Subscription callbacks are called after each edit completes (and the forest is no longer borrowed),
+/// so they can read and edit the forest, including through cursors.
+#[wasm_bindgen]
+pub struct WasmForest {
+    forest: Rc<RefCell<Forest>>,
+}
+
+impl Default for WasmForest {
+    fn default() -> Self {
+        WasmForest::wrap(Forest::new())
+    }
+}
+
+impl WasmForest {
+    fn wrap(mut forest: Forest) -> WasmForest {
+        forest.defer_notifications();
+        WasmForest {
+            forest: Rc::new(RefCell::new(forest)),
+        }
+    }
+}
+
+/// Calls the subscribers for the edits made to `forest`.
+/// Must be called after each edit, once `forest` is no longer borrowed, so the callbacks can use it.
+fn notify(forest: &RefCell<Forest>) {
+    let notifications = forest.borrow_mut().take_notifications();
+    notifications.deliver();
+}
+
+fn parse_path(path: &str) -> Result<Path, JsValue> {
+    path.parse()
+        .map_err(|e: crate::forest::path::ParsePathError| JsValue::from_str(&e.0))
+}
+
+/// Converts an event to a plain JS object, with a `kind` property identifying the type of event.
+/// Paths are formatted as by [Path]'s Display implementation.
+fn event_to_js(event: &Event) -> JsValue {
+    let object = Object::new();
+    let set = |key: &str, value: JsValue| {
+        Reflect::set(&object, &JsValue::from_str(key), &value).unwrap();
     };
-    let mut count = 0;
-    *cursor = Cursor::Nodes(inner(cursor_inner, &mut count));
-    count
-}
-
-fn inner<'a, T: Node<'a>>(
-    c: GenericNodesCursor<'a, T>,
-    count: &mut usize,
-) -> GenericNodesCursor<'a, T> {
-    *count += 1;
-    if c.is_leaf() {
-        return c;
-    }
-    let mut in_fields = c.first_field();
-    loop {
-        match in_fields {
-            EitherCursor::Nodes(n) => {
-                return n;
-            }
-            EitherCursor::Fields(f) => {
-                let mut in_nodes = f.first_node();
-                loop {
-                    match in_nodes {
-                        EitherCursor::Nodes(n) => {
-                            in_nodes = inner(n, count).next_node();
-                        }
-                        EitherCursor::Fields(f) => {
-                            in_fields = f.next_field();
-                            break;
-                        }
-                    }
+    match event {
+        Event::NodesInserted { at, count } => {
+            set("kind", "nodesInserted".into());
+            set("parent", at.parent.to_string().into());
+            set("key", at.key.0.as_str().into());
+            set("index", at.index.into());
+            set("count", (*count).into());
+        }
+        Event::NodesRemoved { range } => {
+            set("kind", "nodesRemoved".into());
+            set("parent", range.parent.to_string().into());
+            set("key", range.key.0.as_str().into());
+            set("start", range.start.into());
+            set("end", range.end.into());
+        }
+        Event::ValueChanged { path, value } => {
+            set("kind", "valueChanged".into());
+            set("path", path.to_string().into());
+            set(
+                "value",
+                match value {
+                    Some(v) => Uint8Array::from(v.as_slice()).into(),
+                    None => JsValue::UNDEFINED,
+                },
+            );
+        }
+        Event::BatchCompleted => set("kind", "batchCompleted".into()),
+    }
+    object.into()
+}
+
+impl WasmForest {
+    fn subscribe_scope(&mut self, scope: Scope, callback: Function) -> Result<u32, JsValue> {
+        self.forest
+            .borrow_mut()
+            .subscribe(scope, move |event| {
+                if let Err(e) = callback.call1(&JsValue::NULL, &event_to_js(event)) {
+                    web_sys::console::error_1(&e);
                 }
+            })
+            .map(|s| s.0)
+            .ok_or_else(|| JsValue::from_str("node not found"))
+    }
+}
+
+#[wasm_bindgen]
+impl WasmForest {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        WasmForest::default()
+    }
+
+    /// Create a forest with the detached field "root" holding a tree of test data.
+    /// Use [WasmForest::from_tree] for other content.
+    #[wasm_bindgen(js_name = fromTestData)]
+    pub fn from_test_data(fields: usize, per_field: usize) -> Self {
+        let mut forest = Forest::new();
+        forest.set_root(
+            FieldKey("root".into()),
+            vec![Chunk::Uniform(Rc::new(chunked_test_tree(
+                fields, per_field,
+            )))],
+        );
+        WasmForest::wrap(forest)
+    }
+
+    /// Create a forest with a copy of `tree`'s content (from [WasmTreeBuilder] or [WasmTree::from_objects], for example).
+    /// Trees which are not a [Backend::Forest] become its detached field "root".
+    /// Throws a "CursorError" if any of the content to copy is pending.
+    #[wasm_bindgen(js_name = fromTree)]
+    pub fn from_tree(tree: &WasmTree) -> Result<WasmForest, JsValue> {
+        let forest = match &*tree.tree {
+            TreeData::Forest(f) => f.clone(),
+            TreeData::Uniform(t) => {
+                let mut forest = Forest::new();
+                forest.set_root(FieldKey::root(), vec![Chunk::Uniform(Rc::new(t.clone()))]);
+                forest
+            }
+            TreeData::Basic(t) => {
+                let mut builder = ChunkBuilder::default();
+                copy_nodes(
+                    GenericNodesCursor::<&BasicNode>::new(t.view()),
+                    t.0.len() as u32,
+                    &mut builder,
+                )
+                .map_err(|failure| failure.error)?;
+                let mut forest = Forest::new();
+                forest.set_root(FieldKey::root(), builder.finish());
+                forest
             }
+        };
+        Ok(WasmForest::wrap(forest))
+    }
+
+    /// Applies a changeset serialized by [Changeset::encode].
+    #[wasm_bindgen(js_name = applyChangeset)]
+    pub fn apply_changeset(&mut self, data: &[u8]) -> Result<(), JsValue> {
+        let changeset = Changeset::decode(data, None).map_err(|e| JsValue::from_str(e.0))?;
+        let result = changeset.apply(&mut self.forest.borrow_mut());
+        notify(&self.forest);
+        result.map_err(edit_error)
+    }
+
+    /// Sets the value of the node at `path` (formatted like `root[0]/child[2]`).
+    #[wasm_bindgen(js_name = setValue)]
+    pub fn set_value(&mut self, path: &str, value: Option<Vec<u8>>) -> Result<(), JsValue> {
+        let path = parse_path(path)?;
+        let result = self.forest.borrow_mut().set_value(&path, value);
+        notify(&self.forest);
+        result.map(|_| ()).map_err(edit_error)
+    }
+
+    /// Calls `callback` with an event object for each change to the subtree at `path`
+    /// (the whole forest for ""), followed by a "batchCompleted" event after each batch.
+    /// Returns an id for [WasmForest::unsubscribe].
+    pub fn subscribe(&mut self, path: &str, callback: Function) -> Result<u32, JsValue> {
+        self.subscribe_scope(Scope::Subtree(parse_path(path)?), callback)
+    }
+
+    /// Like [WasmForest::subscribe], but only for changes in the field `key` of the node at `path`.
+    #[wasm_bindgen(js_name = subscribeField)]
+    pub fn subscribe_field(
+        &mut self,
+        path: &str,
+        key: String,
+        callback: Function,
+    ) -> Result<u32, JsValue> {
+        self.subscribe_scope(Scope::Field(parse_path(path)?, FieldKey(key)), callback)
+    }
+
+    pub fn unsubscribe(&mut self, id: u32) {
+        self.forest.borrow_mut().unsubscribe(Subscription(id));
+    }
+
+    /// An editing cursor at the node at `path`, or the root for "".
+    pub fn cursor(&self, path: &str) -> Result<WasmEditCursor, JsValue> {
+        let path = parse_path(path)?;
+        if !path.is_root() && self.forest.borrow().node_at(&path).is_none() {
+            return Err(JsValue::from_str("node not found"));
         }
+        Ok(WasmEditCursor {
+            forest: self.forest.clone(),
+            position: Position::Nodes(path),
+        })
     }
 }
 
-/// Walks the tree this cursor is attached to.
-/// Uses even lower level API.
+/// Cursor which can edit a [WasmForest], with the same navigation API as [WasmCursor].
 ///
-/// Returns the number of nodes in the tree, including its root.
-#[wasm_bindgen(js_name = walkSubtreeInternal2)]
-pub fn walk_subtree_internal2(n: &mut WasmCursor) -> usize {
-    let tree = &**n.data.as_owner();
-    walk_all_field::<InnerNode>(tree.view())
+/// The cursor's position is a path, so edits made other than through this cursor may move it to a different node.
+#[wasm_bindgen]
+pub struct WasmEditCursor {
+    forest: Rc<RefCell<Forest>>,
+    position: Position,
+}
+
+enum Position {
+    Nodes(Path),
+    Fields(Path, FieldKey),
+}
+
+impl Position {
+    fn of<'f>(cursor: &EitherCursor<EditNodes<'f>, EditFields<'f>>) -> Position {
+        match cursor {
+            EitherCursor::Nodes(n) => Position::Nodes(n.path()),
+            EitherCursor::Fields(f) => Position::Fields(f.parent().clone(), f.field_key()),
+        }
+    }
+}
+
+/// Decodes content written by [crate::build::StreamWriter] into chunks.
+fn decode_content(data: &[u8]) -> Result<Vec<Chunk>, JsValue> {
+    let mut builder = ChunkBuilder::default();
+    read_stream(data, &mut builder).map_err(|e| JsValue::from_str(e.0))?;
+    Ok(builder.finish())
+}
+
+fn edit_error(e: EditError) -> JsValue {
+    JsValue::from_str(&format!("{:?}", e))
+}
+
+impl WasmEditCursor {
+    /// Runs `f` on the cursor in `Nodes` mode, and moves to where it leaves the cursor.
+    /// Returns true if that is a node.
+    /// If `f` fails (or the cursor is in `Fields` mode) the cursor stays where it was.
+    fn move_nodes(
+        &mut self,
+        f: impl for<'f> FnOnce(
+            EditNodes<'f>,
+        ) -> CursorResult<
+            EitherCursor<EditNodes<'f>, EditFields<'f>>,
+            EditNodes<'f>,
+        >,
+    ) -> Result<bool, CursorError> {
+        let mut forest = self.forest.borrow_mut();
+        let cursor = f(nodes(&mut forest, &self.position)?).map_err(|e| e.error)?;
+        self.position = Position::of(&cursor);
+        Ok(matches!(cursor, EitherCursor::Nodes(_)))
+    }
+
+    /// Runs `f` on the cursor in `Fields` mode, and moves to where it leaves the cursor.
+    /// Returns true if that is a field.
+    /// If `f` fails (or the cursor is in `Nodes` mode) the cursor stays where it was.
+    fn move_fields(
+        &mut self,
+        f: impl for<'f> FnOnce(
+            EditFields<'f>,
+        ) -> CursorResult<
+            EitherCursor<EditNodes<'f>, EditFields<'f>>,
+            EditFields<'f>,
+        >,
+    ) -> Result<bool, CursorError> {
+        let mut forest = self.forest.borrow_mut();
+        let cursor = f(fields(&mut forest, &self.position)?).map_err(|e| e.error)?;
+        self.position = Position::of(&cursor);
+        Ok(matches!(cursor, EitherCursor::Fields(_)))
+    }
+
+    /// Runs `f` on a read only cursor at the current node.
+    /// Fails like [nodes], but only borrows the forest immutably.
+    fn with_nodes<R>(
+        &self,
+        f: impl for<'f> FnOnce(GenericNodesCursor<'f, MixedNodeRef<'f>>) -> R,
+    ) -> Result<R, CursorError> {
+        let forest = self.forest.borrow();
+        match &self.position {
+            Position::Nodes(path) => {
+                Ok(f(forest.cursor_at(path).ok_or(CursorError::OutOfRange)?))
+            }
+            Position::Fields(..) => Err(CursorError::WrongMode),
+        }
+    }
+
+    /// Runs `edit` on the cursor in `Nodes` mode, then delivers the events from it.
+    fn edit_nodes<R>(
+        &mut self,
+        edit: impl FnOnce(&mut EditNodes) -> Result<R, EditError>,
+    ) -> Result<R, JsValue> {
+        let result = {
+            let mut forest = self.forest.borrow_mut();
+            let mut cursor = nodes(&mut forest, &self.position)?;
+            let result = edit(&mut cursor);
+            self.position = Position::Nodes(cursor.path());
+            result
+        };
+        notify(&self.forest);
+        result.map_err(edit_error)
+    }
+}
+
+/// The cursor at `position`.
+/// Fails with `OutOfRange` if edits made other than through the cursor removed what it was at.
+fn nodes<'f>(forest: &'f mut Forest, position: &Position) -> Result<EditNodes<'f>, CursorError> {
+    match position {
+        Position::Nodes(path) => forest.edit_cursor(path).ok_or(CursorError::OutOfRange),
+        Position::Fields(..) => Err(CursorError::WrongMode),
+    }
+}
+
+/// The cursor at `position`.
+/// Fails with `OutOfRange` if edits made other than through the cursor removed what it was at.
+fn fields<'f>(forest: &'f mut Forest, position: &Position) -> Result<EditFields<'f>, CursorError> {
+    match position {
+        Position::Fields(parent, key) => forest
+            .edit_field_cursor(parent, key.clone())
+            .ok_or(CursorError::OutOfRange),
+        Position::Nodes(_) => Err(CursorError::WrongMode),
+    }
+}
+
+/// Like [WasmCursor], methods throw a "CursorError" on misuse and leave the cursor where it was.
+#[wasm_bindgen]
+impl WasmEditCursor {
+    #[wasm_bindgen(getter)]
+    pub fn mode(&self) -> i32 {
+        match self.position {
+            Position::Nodes(_) => 0,
+            Position::Fields(..) => 1,
+        }
+    }
+
+    /// True at the root, whose fields are the forest's detached fields.
+    #[wasm_bindgen(getter, js_name = isRoot)]
+    pub fn is_root(&self) -> Result<bool, CursorError> {
+        self.with_nodes(|n| n.is_root())
+    }
+
+    /// Path to the current node, or the node containing the current field.
+    #[wasm_bindgen(getter)]
+    pub fn path(&self) -> String {
+        match &self.position {
+            Position::Nodes(path) | Position::Fields(path, _) => path.to_string(),
+        }
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn pending(&self) -> bool {
+        self.with_nodes(|n| n.pending()).unwrap_or(false)
+    }
+
+    /// If the current node is `pending` because loading it failed, a description of the error.
+    #[wasm_bindgen(getter, js_name = pendingError)]
+    pub fn pending_error(&self) -> Option<String> {
+        self.with_nodes(|n| n.pending_error())
+            .ok()
+            .flatten()
+            .map(|e| e.to_string())
+    }
+
+    #[wasm_bindgen(getter, js_name = fieldIndex)]
+    pub fn field_index(&self) -> Result<u32, CursorError> {
+        self.with_nodes(|n| n.field_index())
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn value(&self) -> Result<Option<f64>, CursorError> {
+        self.with_nodes(|n| n.value().0)
+    }
+
+    #[wasm_bindgen(getter, js_name = type)]
+    pub fn node_type(&self) -> Result<String, CursorError> {
+        self.with_nodes(|n| n.node_type().0)
+    }
+
+    #[wasm_bindgen(js_name = seekNodes)]
+    pub fn seek_nodes(&mut self, offset: i32) -> Result<bool, CursorError> {
+        self.move_nodes(|n| n.seek_nodes(offset))
+    }
+
+    #[wasm_bindgen(js_name = nextNode)]
+    pub fn next_node(&mut self) -> Result<bool, CursorError> {
+        self.move_nodes(|n| n.next_node())
+    }
+
+    #[wasm_bindgen(js_name = exitNode)]
+    pub fn exit_node(&mut self) -> Result<(), CursorError> {
+        self.move_nodes(|n| n.exit_node().map(EitherCursor::Fields))?;
+        Ok(())
+    }
+
+    #[wasm_bindgen(js_name = firstField)]
+    pub fn first_field(&mut self) -> Result<bool, CursorError> {
+        Ok(!self.move_nodes(|n| Ok(n.first_field()))?)
+    }
+
+    #[wasm_bindgen(js_name = enterField)]
+    pub fn enter_field(&mut self, key: String) -> Result<bool, CursorError> {
+        Ok(!self.move_nodes(|n| Ok(n.enter_field(FieldKey(key))))?)
+    }
+
+    #[wasm_bindgen(js_name = nextField)]
+    pub fn next_field(&mut self) -> Result<bool, CursorError> {
+        self.move_fields(|f| Ok(f.next_field()))
+    }
+
+    #[wasm_bindgen(js_name = exitField)]
+    pub fn exit_field(&mut self) -> Result<(), CursorError> {
+        self.move_fields(|f| f.exit_field().map(EitherCursor::Nodes))?;
+        Ok(())
+    }
+
+    #[wasm_bindgen(js_name = getFieldLength)]
+    pub fn get_field_length(&self) -> Result<u32, CursorError> {
+        match &self.position {
+            Position::Fields(parent, key) => self
+                .forest
+                .borrow()
+                .field_at(parent, key)
+                .map(|field| field.len() as u32)
+                .ok_or(CursorError::OutOfRange),
+            Position::Nodes(_) => Err(CursorError::WrongMode),
+        }
+    }
+
+    #[wasm_bindgen(js_name = firstNode)]
+    pub fn first_node(&mut self) -> Result<bool, CursorError> {
+        Ok(!self.move_fields(|f| Ok(f.first_node()))?)
+    }
+
+    #[wasm_bindgen(js_name = enterNode)]
+    pub fn enter_node(&mut self, child_index: u32) -> Result<(), CursorError> {
+        self.move_fields(|f| f.enter_node(child_index).map(EitherCursor::Nodes))?;
+        Ok(())
+    }
+
+    /// Sets the current node's value to `value` as an 8 byte float, or removes it if undefined.
+    #[wasm_bindgen(js_name = setValue)]
+    pub fn set_value(&mut self, value: Option<f64>) -> Result<(), JsValue> {
+        let payload = value.map(|v| v.to_le_bytes().to_vec());
+        self.edit_nodes(|n| n.set_value(payload)).map(|_| ())
+    }
+
+    /// Inserts `content` (in the format written by [crate::build::StreamWriter]) before the current node.
+    /// The cursor stays at the same node.
+    #[wasm_bindgen(js_name = insertBefore)]
+    pub fn insert_before(&mut self, content: &[u8]) -> Result<(), JsValue> {
+        let content = decode_content(content)?;
+        self.edit_nodes(|n| n.insert_before(content))
+    }
+
+    /// Inserts `content` (in the format written by [crate::build::StreamWriter]) after the current node.
+    /// The cursor stays at the same node.
+    #[wasm_bindgen(js_name = insertAfter)]
+    pub fn insert_after(&mut self, content: &[u8]) -> Result<(), JsValue> {
+        let content = decode_content(content)?;
+        self.edit_nodes(|n| n.insert_after(content))
+    }
+
+    /// Deletes `count` nodes starting at the current one.
+    /// Returns true if the cursor moved to the node after them, and false if it moved to the field because there is none.
+    pub fn delete(&mut self, count: u32) -> Result<bool, JsValue> {
+        let result = {
+            let mut forest = self.forest.borrow_mut();
+            let cursor = nodes(&mut forest, &self.position)?;
+            cursor
+                .delete(count)
+                .map(|cursor| {
+                    self.position = Position::of(&cursor);
+                    matches!(cursor, EitherCursor::Nodes(_))
+                })
+                .map_err(|(_, e)| e)
+        };
+        notify(&self.forest);
+        result.map_err(edit_error)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::forest::{
+        mixed::{MixedNode, MixedNodeRef},
+        store::{ChunkId, ChunkStore, MemoryStore},
+        test_stuff::key,
+    };
 
     #[test]
     fn walk_wasm_cursor() {
         let mut cursor = WasmCursor::new_from_test_data(10, 10);
-        assert_eq!(walk_subtree(&mut cursor), 101);
+        assert_eq!(walk_subtree(&mut cursor), Ok(101));
+    }
+
+    #[test]
+    fn walk_wasm_cursor_depth() {
+        let mut cursor = WasmCursor::new_from_test_data(10, 10);
+        assert_eq!(walk_subtree_depth(&mut cursor, 0), Ok(1));
+        assert_eq!(walk_subtree_depth(&mut cursor, 1), Ok(101));
+        assert_eq!(cursor.mode(), 0);
+        assert_eq!(walk_subtree(&mut cursor), Ok(101));
+
+        cursor.first_field().unwrap();
+        cursor.first_node().unwrap();
+        let index = cursor.field_index();
+        assert_eq!(walk_subtree_depth(&mut cursor, 0), Ok(1));
+        assert_eq!(cursor.field_index(), index);
+        cursor.exit_node().unwrap();
+        assert_eq!(walk_subtree(&mut cursor), Err(CursorError::WrongMode));
+        assert_eq!(cursor.mode(), 1);
     }
 
     #[test]
     fn walk_wasm_cursor_internal() {
         let mut cursor = WasmCursor::new_from_test_data(10, 10);
-        assert_eq!(walk_subtree_internal(&mut cursor), 101);
+        assert_eq!(walk_subtree_internal(&mut cursor), Ok(101));
+    }
+
+    /// A chain of `depth + 1` nodes in the `backend` representation, each the only child of the one above it.
+    fn deep_tree(backend: Backend, depth: u32) -> WasmTree {
+        let node_type = || TreeType("node".into());
+        let tree = match backend {
+            Backend::Uniform => {
+                let mut schema = ChunkSchema::new_leaf(node_type(), 1, None);
+                for _ in 0..depth {
+                    let field = OffsetSchema {
+                        schema,
+                        byte_offset: 0,
+                    };
+                    schema = ChunkSchema::new(node_type(), 1, 0, None, &[(key("child"), field)]);
+                }
+                TreeData::Uniform(UniformChunk::new(Rc::new(schema), vec![]))
+            }
+            Backend::Basic => {
+                let mut node = BasicNode {
+                    def: node_type(),
+                    payload: None,
+                    fields: BTreeMap::new(),
+                };
+                for _ in 0..depth {
+                    node = BasicNode {
+                        def: node_type(),
+                        payload: None,
+                        fields: BTreeMap::from([(key("child"), vec![node])]),
+                    };
+                }
+                TreeData::Basic(BasicTree(vec![node]))
+            }
+            Backend::Forest => {
+                let mut builder = WasmTreeBuilder::new();
+                for _ in 0..depth {
+                    builder.begin_node("node".into(), None).unwrap();
+                    builder.begin_field("child".into()).unwrap();
+                }
+                builder.begin_node("node".into(), None).unwrap();
+                builder.end_node().unwrap();
+                for _ in 0..depth {
+                    builder.end_field().unwrap();
+                    builder.end_node().unwrap();
+                }
+                return builder.finish().unwrap();
+            }
+        };
+        WasmTree {
+            tree: Rc::new(tree),
+        }
+    }
+
+    #[test]
+    fn walk_deep_trees() {
+        // Uniform chunks nest their schemas by value, doubling their size with each level, so can not be very deep.
+        for (backend, depth) in [
+            (Backend::Uniform, 12),
+            (Backend::Basic, 1_000_000),
+            (Backend::Forest, 1_000_000),
+        ] {
+            let tree = deep_tree(backend, depth);
+            assert_eq!(tree.backend(), backend);
+            let mut cursor = tree.cursor();
+            let count = Ok(depth as usize + 1);
+            assert_eq!(walk_subtree(&mut cursor), count, "{:?}", backend);
+            assert_eq!(walk_subtree_depth(&mut cursor, 5), Ok(6), "{:?}", backend);
+            assert_eq!(walk_subtree_internal(&mut cursor), count, "{:?}", backend);
+            assert_eq!(walk_subtree_internal2(&mut cursor), depth as usize + 1);
+            assert_eq!(cursor.path().unwrap(), "root[0]");
+        }
+    }
+
+    #[test]
+    fn edit_through_wasm_cursor() {
+        let forest = WasmForest::from_test_data(2, 3);
+        let mut cursor = forest.cursor("root[0]").unwrap();
+        assert_eq!(cursor.enter_field("1".into()), Ok(true));
+        assert_eq!(cursor.get_field_length(), Ok(3));
+        assert_eq!(cursor.enter_node(3), Err(CursorError::OutOfRange));
+        cursor.enter_node(1).unwrap();
+        assert_eq!(cursor.path(), "root[0]/1[1]");
+        cursor.set_value(Some(5.0)).unwrap();
+        assert_eq!(cursor.value(), Ok(Some(5.0)));
+        assert!(cursor.delete(1).unwrap());
+        assert_eq!(cursor.value(), Ok(None));
+        assert!(!cursor.delete(1).unwrap());
+        assert_eq!(cursor.get_field_length(), Ok(1));
+        assert_eq!(cursor.value(), Err(CursorError::WrongMode));
+        cursor.exit_field().unwrap();
+        cursor.exit_node().unwrap();
+        cursor.exit_field().unwrap();
+        assert_eq!(cursor.is_root(), Ok(true));
+        assert_eq!(cursor.exit_node(), Err(CursorError::AtRoot));
+        assert_eq!(cursor.path(), "");
+        assert_eq!(cursor.first_field(), Ok(true));
+        assert_eq!(cursor.next_field(), Ok(false));
+        assert_eq!(
+            walk_all_field::<MixedNodeRef>(forest.forest.borrow().root(&FieldKey("root".into()))),
+            5
+        );
+    }
+
+    #[test]
+    fn subscribers_can_use_the_forest() {
+        let forest = WasmForest::from_test_data(2, 3);
+        let mut cursor = forest.cursor("root[0]/1[1]").unwrap();
+        // Reads the edited value through another cursor, and edits the forest in response.
+        let reader = forest.cursor("root[0]/1[1]").unwrap();
+        let writer = RefCell::new(forest.cursor("root[0]/1[0]").unwrap());
+        let seen = Rc::new(RefCell::new(vec![]));
+        let log = seen.clone();
+        forest
+            .forest
+            .borrow_mut()
+            .subscribe(Scope::Subtree(Path::root()), move |event| {
+                if let Event::ValueChanged { path, .. } = event {
+                    log.borrow_mut().push(reader.value().unwrap());
+                    if path.to_string() == "root[0]/1[1]" {
+                        writer.borrow_mut().set_value(Some(6.0)).unwrap();
+                    }
+                }
+            })
+            .unwrap();
+
+        cursor.set_value(Some(5.0)).unwrap();
+        assert_eq!(*seen.borrow(), [Some(5.0), Some(5.0)]);
+        let written = forest.cursor("root[0]/1[0]").unwrap();
+        assert_eq!(written.value(), Ok(Some(6.0)));
+    }
+
+    #[test]
+    fn query_wasm_cursor() {
+        let mut cursor = WasmCursor::new_from_test_data(3, 4);
+        let query = |q: &str| q.parse::<Query>().unwrap();
+        assert_eq!(query_paths(&mut cursor, &query("1[2..]")).unwrap().len(), 2);
+        assert_eq!(query_paths(&mut cursor, &query("//*")).unwrap().len(), 12);
+        assert_eq!(cursor.field_index(), Ok(0));
+        cursor.enter_field("1".into()).unwrap();
+        assert_eq!(
+            query_paths(&mut cursor, &query("*")),
+            Err(CursorError::WrongMode)
+        );
+    }
+
+    #[test]
+    fn misused_wasm_cursor_stays_put() {
+        let mut cursor = WasmCursor::new_from_test_data(2, 3);
+        assert_eq!(cursor.next_field(), Err(CursorError::WrongMode));
+        assert_eq!(cursor.get_field_length(), Err(CursorError::WrongMode));
+        assert_eq!(cursor.is_root(), Ok(false));
+        assert_eq!(cursor.path(), Ok("root[0]".into()));
+        cursor.exit_node().unwrap();
+        cursor.exit_field().unwrap();
+        assert_eq!(cursor.exit_node(), Err(CursorError::AtRoot));
+        assert_eq!(cursor.next_node(), Err(CursorError::AtRoot));
+        assert_eq!(cursor.seek_nodes(-1), Err(CursorError::AtRoot));
+        assert_eq!(cursor.mode(), 0);
+        assert_eq!(cursor.is_root(), Ok(true));
+        assert_eq!(cursor.first_field(), Ok(true));
+        assert_eq!(cursor.first_node(), Ok(true));
+        assert_eq!(cursor.field_index(), Ok(0));
+
+        assert_eq!(cursor.enter_field("1".into()), Ok(true));
+        assert_eq!(cursor.value(), Err(CursorError::WrongMode));
+        assert_eq!(cursor.enter_node(3), Err(CursorError::OutOfRange));
+        assert_eq!(cursor.mode(), 1);
+        cursor.enter_node(2).unwrap();
+        assert_eq!(cursor.field_index(), Ok(2));
+        assert_eq!(cursor.next_node(), Ok(false));
+        assert_eq!(cursor.get_field_length(), Ok(3));
+        cursor.exit_field().unwrap();
+        assert_eq!(walk_subtree(&mut cursor), Ok(7));
+    }
+
+    #[test]
+    fn walk_each_backend() {
+        for backend in [Backend::Uniform, Backend::Basic, Backend::Forest] {
+            let tree = WasmTree::from_test_data(backend, 3, 4);
+            let mut cursor = tree.cursor();
+            assert_eq!(cursor.backend(), backend);
+            assert_eq!(cursor.path(), Ok("root[0]".into()));
+            assert_eq!(walk_subtree(&mut cursor), Ok(13));
+            assert_eq!(walk_subtree_internal(&mut cursor), Ok(13));
+            assert_eq!(walk_subtree_internal2(&mut cursor), 13);
+            let forest = WasmForest::from_tree(&tree).unwrap();
+            assert_eq!(
+                walk_subtree(&mut WasmTree::from_forest(&forest).cursor()),
+                Ok(13)
+            );
+            cursor.exit_node().unwrap();
+            cursor.exit_field().unwrap();
+            assert_eq!(walk_subtree(&mut cursor), Ok(14));
+        }
+
+        let forest = WasmForest::from_test_data(1, 2);
+        let tree = WasmTree::from_forest(&forest);
+        forest.forest.borrow_mut().delete_root(&FieldKey::root());
+        assert_eq!(walk_subtree(&mut tree.cursor()), Ok(3));
+    }
+
+    #[test]
+    fn share_tree_between_cursors() {
+        for backend in [Backend::Uniform, Backend::Basic, Backend::Forest] {
+            let tree = WasmTree::from_test_data(backend, 2, 3);
+            let mut a = tree.cursor();
+            let mut b = tree.cursor();
+            assert_eq!(Rc::strong_count(&tree.tree), 3);
+
+            a.enter_field("1".into()).unwrap();
+            a.enter_node(2).unwrap();
+            assert_eq!(a.path(), Ok("root[0]/1[2]".into()));
+            assert_eq!(b.path(), Ok("root[0]".into()));
+
+            let mut c = a.clone_cursor();
+            assert_eq!(c.path(), a.path());
+            c.exit_node().unwrap();
+            assert_eq!(c.get_field_length(), Ok(3));
+
+            // Clones continue iterating fields like the original.
+            assert_eq!(b.first_field(), Ok(true));
+            let mut d = b.clone_cursor();
+            assert_eq!(d.next_field(), Ok(true));
+            assert_eq!(d.next_field(), Ok(false));
+            assert_eq!(b.next_field(), Ok(true));
+            assert_eq!(b.next_field(), Ok(false));
+            assert_eq!(d.path(), b.path());
+            assert_eq!(a.path(), Ok("root[0]/1[2]".into()));
+            drop(d);
+
+            // Cursors keep the tree alive.
+            drop(tree);
+            assert_eq!(walk_subtree(&mut b), Ok(7));
+            drop(b);
+            drop(c);
+            assert_eq!(Rc::strong_count(a.tree_data()), 1);
+            assert_eq!(walk_subtree(&mut a.tree().cursor()), Ok(7));
+        }
+    }
+
+    /// Cursors borrow from the tree they own, so this is also run under Miri (see the site README).
+    #[test]
+    fn wasm_cursor_clone_move_free() {
+        for backend in [Backend::Uniform, Backend::Basic, Backend::Forest] {
+            let tree = WasmTree::from_test_data(backend, 2, 2);
+            let mut a = tree.cursor();
+            a.first_field().unwrap();
+            let mut b = a.clone_cursor();
+            drop(tree);
+            a.next_field().unwrap();
+            b.first_node().unwrap();
+            drop(a);
+            assert_eq!(b.path(), Ok("root[0]/0[0]".into()));
+            let c = b.clone_cursor();
+            drop(b);
+            assert_eq!(c.mode(), 0);
+            assert_eq!(Rc::strong_count(c.tree_data()), 1);
+        }
+    }
+
+    #[test]
+    fn build_tree() {
+        let mut builder = WasmTreeBuilder::new();
+        assert_eq!(
+            builder.begin_field("x".into()),
+            Err(BuildError::FieldOutsideNode)
+        );
+        builder.begin_node("point".into(), None).unwrap();
+        assert_eq!(
+            builder.begin_node("x".into(), None),
+            Err(BuildError::NodeOutsideField)
+        );
+        assert_eq!(builder.end_field(), Err(BuildError::NothingToEnd));
+        for key in ["x", "y"] {
+            builder.begin_field(key.into()).unwrap();
+            for i in 0..3 {
+                builder.begin_node("number".into(), Some(i as f64)).unwrap();
+                builder.end_node().unwrap();
+            }
+            assert_eq!(builder.finish().err(), Some(BuildError::Unfinished));
+            builder.end_field().unwrap();
+        }
+        builder.end_node().unwrap();
+        builder
+            .begin_node_with_payload("label".into(), Some(b"hi".to_vec()))
+            .unwrap();
+        builder.end_node().unwrap();
+        assert_eq!(builder.end_node(), Err(BuildError::NothingToEnd));
+
+        let tree = builder.finish().unwrap();
+        assert_eq!(tree.backend(), Backend::Forest);
+        match &*tree.tree {
+            TreeData::Forest(forest) => {
+                let chunks = forest.root_chunks(&FieldKey::root());
+                assert_eq!(chunks.len(), 2);
+                assert!(matches!(chunks[0], Chunk::Uniform(_)));
+            }
+            _ => panic!(),
+        }
+
+        let mut cursor = tree.cursor();
+        assert_eq!(cursor.node_type(), Ok("point".into()));
+        assert_eq!(walk_subtree(&mut cursor), Ok(7));
+        cursor.enter_field("y".into()).unwrap();
+        cursor.enter_node(2).unwrap();
+        assert_eq!(cursor.value(), Ok(Some(2.0)));
+        cursor.exit_node().unwrap();
+        cursor.exit_field().unwrap();
+        assert_eq!(cursor.next_node(), Ok(true));
+        assert_eq!(cursor.node_type(), Ok("label".into()));
+
+        // The builder is left empty.
+        let tree = builder.finish().unwrap();
+        assert_eq!(walk_subtree(&mut tree.cursor()), Ok(1));
+    }
+
+    #[test]
+    fn bulk_read() {
+        // Two uniform chunks of points (with fields x and y), separated by a node of another shape.
+        let mut builder = WasmTreeBuilder::new();
+        let point = |builder: &mut WasmTreeBuilder, x: f64, y: &[f64]| {
+            builder.begin_node("point".into(), None).unwrap();
+            builder.begin_field("x".into()).unwrap();
+            builder.begin_node("number".into(), Some(x)).unwrap();
+            builder.end_node().unwrap();
+            builder.end_field().unwrap();
+            builder.begin_field("y".into()).unwrap();
+            for y in y {
+                builder.begin_node("number".into(), Some(*y)).unwrap();
+                builder.end_node().unwrap();
+            }
+            builder.end_field().unwrap();
+            builder.end_node().unwrap();
+        };
+        point(&mut builder, 1.0, &[10.0, 11.0]);
+        point(&mut builder, 2.0, &[20.0, 21.0]);
+        point(&mut builder, 3.0, &[30.0]);
+        point(&mut builder, 4.0, &[40.0, 41.0]);
+        builder
+            .begin_node_with_payload("label".into(), Some(b"hi".to_vec()))
+            .unwrap();
+        builder.end_node().unwrap();
+        let tree = builder.finish().unwrap();
+        let mut cursor = tree.cursor();
+
+        assert_eq!(cursor.chunk_length(), Ok(2));
+        assert_eq!(cursor.read_field_values("x".into()), Ok(vec![1.0, 2.0]));
+        assert_eq!(
+            cursor.read_field_payloads("x".into()),
+            Ok([1f64.to_le_bytes(), 2f64.to_le_bytes()].concat())
+        );
+        assert_eq!(
+            cursor.read_field_values("y".into()),
+            Ok(vec![10.0, 11.0, 20.0, 21.0])
+        );
+        assert_eq!(cursor.read_field_values("z".into()), Ok(vec![]));
+        assert_eq!(cursor.read_values(), Err(CursorError::WrongMode));
+
+        // Nodes which are not in a uniform chunk are their own chunk.
+        cursor.seek_nodes(2).unwrap();
+        assert_eq!(cursor.chunk_length(), Ok(1));
+        assert_eq!(cursor.read_field_values("y".into()), Ok(vec![30.0]));
+        cursor.enter_field("y".into()).unwrap();
+        assert_eq!(cursor.read_values(), Ok(vec![30.0]));
+        assert_eq!(cursor.read_payloads(), Ok(30f64.to_le_bytes().to_vec()));
+        assert_eq!(
+            cursor.read_field_values("y".into()),
+            Err(CursorError::WrongMode)
+        );
+
+        // Fields mixing chunks are read chunk by chunk, with NaN for nodes without a value.
+        // Their payloads have different sizes, so can not be read together.
+        cursor.exit_field().unwrap();
+        cursor.exit_node().unwrap();
+        let values = cursor.read_values().unwrap();
+        assert_eq!(values.len(), 5);
+        assert!(values.iter().all(|v| v.is_nan()));
+        assert_eq!(cursor.read_payloads(), Err(CursorError::MixedPayloadSizes));
+
+        // At the root, reads a detached field.
+        cursor.exit_field().unwrap();
+        assert_eq!(
+            cursor.read_field_values("root".into()).map(|v| v.len()),
+            Ok(5)
+        );
+        assert_eq!(
+            cursor.read_field_payloads("root".into()),
+            Err(CursorError::MixedPayloadSizes)
+        );
+
+        // Matches reading node by node, for every backend.
+        for backend in [Backend::Uniform, Backend::Basic, Backend::Forest] {
+            let tree = WasmTree::from_test_data(backend, 2, 3);
+            let mut cursor = tree.cursor();
+            assert_eq!(cursor.read_field_payloads("1".into()), Ok(vec![]));
+            cursor.enter_field("1".into()).unwrap();
+            assert_eq!(cursor.read_values().map(|v| v.len()), Ok(3));
+        }
+    }
+
+    #[test]
+    fn bulk_read_pending() {
+        // A node with a field of two nodes, followed by two nodes which are pending.
+        let store: Rc<dyn ChunkStore> = Rc::new(MemoryStore::default());
+        let mut node = MixedNode::new(TreeType("node".into()), None);
+        node.fields_mut().insert(
+            FieldKey("x".into()),
+            vec![Chunk::lazy(store.clone(), ChunkId(0), 2)],
+        );
+        let mut forest = Forest::new();
+        forest.set_root(
+            FieldKey::root(),
+            vec![
+                Chunk::Node(Rc::new(node)),
+                Chunk::lazy(store, ChunkId(1), 2),
+            ],
+        );
+        let tree = WasmTree {
+            tree: Rc::new(TreeData::Forest(forest)),
+        };
+        let mut cursor = tree.cursor();
+
+        assert_eq!(
+            cursor.read_field_values("x".into()),
+            Err(CursorError::Pending)
+        );
+        cursor.next_node().unwrap();
+        assert!(cursor.pending());
+        assert_eq!(
+            cursor.read_field_payloads("x".into()),
+            Err(CursorError::Pending)
+        );
+        cursor.exit_node().unwrap();
+        assert_eq!(cursor.read_values(), Err(CursorError::Pending));
+        assert_eq!(cursor.read_payloads(), Err(CursorError::Pending));
     }
 
     #[test]
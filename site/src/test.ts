@@ -1,4 +1,4 @@
-import { walkSubtree, WasmCursor, walkSubtreeInternal, walkSubtreeInternal2, walkSubtreeDepth } from "compressed-tree";
+import { walkSubtree, WasmCursor, WasmTree, WasmTreeBuilder, Backend, walkSubtreeInternal, walkSubtreeInternal2, walkSubtreeDepth } from "compressed-tree";
 
 export {};
 
@@ -10,7 +10,6 @@ async function doLoop(): Promise<void> {
   const fields = 1000;
   const nodes = 10;
   const expected = nodes * fields + 1;
-  const cursor = new WasmCursor(fields, nodes);
   const outerRuns = 5;
   const runs = 200;
   const walkers: [string, (w: WasmCursor) => number][] = [
@@ -19,6 +18,7 @@ async function doLoop(): Promise<void> {
     ["wasm cursor depth 1", (t) => walkSubtreeDepth(t, 1)],
     ["wasm node", walkSubtreeInternal2],
     ["JS", walkSubtreeJS],
+    ["JS bulk read", walkLeavesBulkJS],
   ]
 
   const logger = document.getElementById('log') ?? fail("no log");
@@ -26,25 +26,30 @@ async function doLoop(): Promise<void> {
     logger.innerHTML += message + '<br />';
   }
 
-  for (const [name, walker] of walkers) {
-    log(`${fields} of ${nodes}: (Total Nodes: ${expected}) ${name} walk`);
-    for (let x = 1; x <= outerRuns; x++) {
-      const t0 = performance.now();
-      for (let i = 1; i <= runs; i++) {
-        const count = walker(cursor);
-        if (count !== expected) {
-          throw new Error();
+  for (const backend of [Backend.Uniform, Backend.Basic, Backend.Forest]) {
+    const tree = WasmTree.fromTestData(backend, fields, nodes);
+    const cursor = tree.cursor();
+    for (const [name, walker] of walkers) {
+      log(`${fields} of ${nodes}: (Total Nodes: ${expected}) ${Backend[backend]} ${name} walk`);
+      for (let x = 1; x <= outerRuns; x++) {
+        const t0 = performance.now();
+        for (let i = 1; i <= runs; i++) {
+          const count = walker(cursor);
+          if (count !== expected) {
+            throw new Error();
+          }
         }
+        const t1 = performance.now();
+        const perRun = (t1 - t0) / runs;
+        log(`${perRun.toFixed(3)} ms per run`);
       }
-      const t1 = performance.now();
-      const perRun = (t1 - t0) / runs;
-      log(`${perRun.toFixed(3)} ms per run`);
+      log('');
+      await new Promise(r => setTimeout(r, 0));
     }
-    log('');
-    await new Promise(r => setTimeout(r, 0));
+    cursor.free();
+    tree.free();
   }
 
-  cursor.free();
   log("done");
 }
 
@@ -62,6 +67,76 @@ if (typeof window !== "undefined") {
     }
     cursor.free();
   });
+  it("cursor over each backend", () => {
+    for (const backend of [Backend.Uniform, Backend.Basic, Backend.Forest]) {
+      const tree = WasmTree.fromTestData(backend, 2, 5);
+      const cursor = tree.cursor();
+      if (cursor.backend !== backend || walkSubtreeJS(cursor) !== 11 || walkSubtree(cursor) !== 11) {
+        throw new Error(Backend[backend]);
+      }
+      cursor.free();
+      tree.free();
+    }
+  });
+  it("cursors share a tree", () => {
+    const tree = WasmTree.fromTestData(Backend.Basic, 2, 5);
+    const a = tree.cursor();
+    tree.free();
+    a.enterField("1");
+    a.enterNode(3);
+    const b = a.clone();
+    a.free();
+    if (b.path !== "root[0]/1[3]" || walkSubtree(b) !== 1) {
+      throw new Error();
+    }
+    b.free();
+  });
+  it("build trees", () => {
+    const point = { type: "point", fields: { x: [{ type: "number", value: 1 }], y: [{ type: "number", value: 2 }] } };
+    const fromObjects = WasmTree.fromObjects([point, point]);
+
+    const builder = new WasmTreeBuilder();
+    for (let i = 0; i < 2; i++) {
+      builder.beginNode("point");
+      for (const [key, value] of [["x", 1], ["y", 2]] as const) {
+        builder.beginField(key);
+        builder.beginNode("number", value);
+        builder.endNode();
+        builder.endField();
+      }
+      builder.endNode();
+    }
+    const streamed = builder.finish();
+    builder.free();
+
+    for (const tree of [fromObjects, streamed]) {
+      const cursor = tree.cursor();
+      cursor.exitNode();
+      cursor.exitField();
+      if (walkSubtree(cursor) !== 7) {
+        throw new Error();
+      }
+      cursor.free();
+      tree.free();
+    }
+  });
+  it("bulk read", () => {
+    const tree = WasmTree.fromObjects({
+      type: "point",
+      fields: { x: [{ type: "number", value: 1 }, { type: "number", value: 2 }], y: [{ type: "number" }] },
+    });
+    const cursor = tree.cursor();
+    const x = cursor.readFieldValues("x");
+    if (!(x instanceof Float64Array) || x.length !== 2 || x[1] !== 2 || walkLeavesBulkJS(cursor) !== 4) {
+      throw new Error();
+    }
+    cursor.enterField("y");
+    if (!isNaN(cursor.readValues()[0]) || cursor.readPayloads().length !== 0) {
+      throw new Error();
+    }
+    cursor.free();
+    tree.free();
+  });
   it("cursor use wasm", () => {
     const cursor = new WasmCursor(2, 5);
     const count = walkSubtree(cursor);
@@ -81,3 +156,12 @@ function walkSubtreeJS(n: WasmCursor): number {
   }
   return count;
 }
+
+// Counts the nodes in a tree of depth 1, reading each field's values in one call.
+function walkLeavesBulkJS(n: WasmCursor): number {
+  let count = 1;
+  for (let inFields = n.firstField(); inFields; inFields = n.nextField()) {
+    count += n.readValues().length;
+  }
+  return count;
+}